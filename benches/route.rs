@@ -0,0 +1,84 @@
+//! Measures the cost of routing log events to an appender which is already cached.
+//!
+//! Run with `cargo bench`.
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+extern crate log_mdc;
+extern crate serde_value;
+extern crate serde_yaml;
+
+use log::Record;
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use log4rs_routing_appender::register;
+use serde_value::Value;
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+static CONSTRUCTED: AtomicUsize = AtomicUsize::new(0);
+
+const ITERATIONS: u32 = 1_000_000;
+
+#[derive(Debug)]
+struct NullAppender;
+
+impl Append for NullAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct NullAppenderDeserializer;
+
+impl Deserialize for NullAppenderDeserializer {
+    type Config = Value;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        _: Value,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        CONSTRUCTED.fetch_add(1, Ordering::SeqCst);
+        Ok(Box::new(NullAppender))
+    }
+}
+
+fn main() {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("null", NullAppenderDeserializer);
+
+    let config = r#"
+router:
+  kind: pattern
+  pattern:
+    kind: "null"
+    path: "log/${mdc(a)}/${mdc(b)}/${mdc(c)}/${mdc(d)}/${level}.log"
+    tags: ["${mdc(e)}", "${mdc(f)}", "${mdc(g)}", "${mdc(h)}", "${target}"]
+"#;
+    let config = serde_yaml::from_str::<Value>(config).unwrap();
+    let appender = d.deserialize::<dyn Append>("routing", config).unwrap();
+
+    for key in &["a", "b", "c", "d", "e", "f", "g", "h"] {
+        log_mdc::insert(*key, format!("value-of-{}", key));
+    }
+    let record = Record::builder()
+        .args(format_args!("message"))
+        .target("bench")
+        .build();
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        appender.append(&record).unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    // every event after the first must have been a cache hit, which never expands the template
+    assert_eq!(CONSTRUCTED.load(Ordering::SeqCst), 1);
+    println!("cache hit, 8 MDC references: {:?} per event", elapsed / ITERATIONS);
+}
@@ -0,0 +1,59 @@
+//! The limit on the number of appenders built at once.
+use std::cell::Cell;
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+// the number of appenders being built by the routing appenders which limit it
+static BUILDS: Mutex<usize> = Mutex::new(0);
+static FREED: Condvar = Condvar::new();
+
+/// The longest a build waits for a slot before going ahead anyway.
+pub const WAIT: Duration = Duration::from_secs(1);
+
+thread_local! {
+    // whether the current thread holds a permit, which the builds nested in its own reuse
+    static HELD: Cell<bool> = const { Cell::new(false) };
+}
+
+fn builds() -> MutexGuard<'static, usize> {
+    BUILDS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A slot for building an appender, released when dropped.
+pub struct Permit(());
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        HELD.with(|held| held.set(false));
+        *builds() -= 1;
+        FREED.notify_one();
+    }
+}
+
+impl Permit {
+    /// Waits until fewer than `limit` appenders are being built, for at most `WAIT`.
+    ///
+    /// Returns `None` if the current thread already holds a permit, since an appender built while
+    /// building another, such as by a nested router, is part of the same build.
+    pub fn acquire(limit: usize) -> Option<Permit> {
+        if HELD.with(|held| held.get()) {
+            return None;
+        }
+
+        let deadline = Instant::now() + WAIT;
+        let mut builds = builds();
+        while *builds >= limit {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            builds = match FREED.wait_timeout(builds, deadline - now) {
+                Ok((builds, _)) => builds,
+                Err(e) => e.into_inner().0,
+            };
+        }
+        *builds += 1;
+        HELD.with(|held| held.set(true));
+        Some(Permit(()))
+    }
+}
@@ -0,0 +1,78 @@
+//! Clocks.
+//!
+//! The cache of a routing appender reads the current time from a `Clock`, which is the system
+//! clock unless another is installed with `RoutingAppenderBuilder::clock`. The time drives idle
+//! and lifetime expiry, the minimum TTL, cold routes, file verification, circuit breakers and
+//! quota windows, so a clock which is advanced by hand, such as the `MockClock`, makes tests of
+//! those deterministic without sleeping. The durations of writes, as measured for the slow write
+//! threshold, always use the system clock.
+use antidote::Mutex;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// A source of the current time.
+pub trait Clock: fmt::Debug + Sync + Send + 'static {
+    /// Returns the current time, which must never go backwards.
+    fn now(&self) -> Instant;
+}
+
+/// The system's monotonic clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock which only moves when it is advanced.
+///
+/// Installed with `RoutingAppenderBuilder::clock`, it lets tests step a routing appender's cache
+/// past timeouts instantly. The clock is shared, so keep a handle to it to advance it.
+///
+/// ```
+/// # extern crate log4rs_routing_appender;
+/// use log4rs_routing_appender::clock::MockClock;
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// # fn main() {
+/// let clock = Arc::new(MockClock::new());
+/// let builder = log4rs_routing_appender::RoutingAppender::builder().clock(clock.clone());
+/// clock.advance(Duration::from_secs(60));
+/// # let _ = builder;
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl MockClock {
+    /// Creates a clock which starts at the current time.
+    pub fn new() -> MockClock {
+        MockClock {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::from_secs(0)),
+        }
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock()
+    }
+}
@@ -33,6 +33,83 @@
 //! info!("Starting job");
 //! # }
 //! ```
+//!
+//! The routed appender is invoked with the MDC still in place, so its encoder can refer to the
+//! same entries the router used. For example, to also prefix every line with the job ID:
+//!
+//! ```yaml
+//! appenders:
+//!   job:
+//!     kind: routing
+//!     router:
+//!       kind: pattern
+//!       pattern:
+//!         kind: file
+//!         path: "log/jobs/${mdc(job_id)}/output.log"
+//!         encoder:
+//!           pattern: "[{X(job_id)}] {d} {l} {m}{n}"
+//! ```
+//!
+//! Alternatively, the `key_mdc` option makes the key of the route itself available to the encoder
+//! under the specified MDC entry, which is useful for routers that aren't driven by the MDC.
+//!
+//! A routing appender can also be configured in code rather than in a config file. Routers are
+//! built with the builders of their modules, such as `PatternRouter::builder`, which take the
+//! configuration template of the routed appenders along with the `Deserializers` used to build
+//! them from their expanded configurations, those of log4rs's appenders included:
+//!
+//! ```no_run
+//! # extern crate log;
+//! # extern crate log4rs;
+//! # extern crate log4rs_routing_appender;
+//! # extern crate serde_value;
+//! # use log::LevelFilter;
+//! # use log4rs::config::{Appender, Config, Root};
+//! # use log4rs::file::Deserializers;
+//! # use log4rs_routing_appender::route::pattern::PatternRouter;
+//! # use log4rs_routing_appender::route::AppenderConfig;
+//! # use log4rs_routing_appender::RoutingAppender;
+//! # use serde_value::Value;
+//! # use std::collections::BTreeMap;
+//! # use std::time::Duration;
+//! # fn main() {
+//! let mut config = BTreeMap::new();
+//! config.insert(
+//!     Value::String("path".to_owned()),
+//!     Value::String("log/jobs/${mdc(job_id)}/output.log".to_owned()),
+//! );
+//! let pattern = AppenderConfig {
+//!     kind: "file".to_owned(),
+//!     config: Value::Map(config),
+//! };
+//! let router = PatternRouter::builder()
+//!     .build(Deserializers::default(), pattern)
+//!     .unwrap();
+//! let appender = RoutingAppender::builder()
+//!     .idle_timeout(Duration::from_secs(30))
+//!     .build(Box::new(router));
+//!
+//! let config = Config::builder()
+//!     .appender(Appender::builder().build("job", Box::new(appender)))
+//!     .build(Root::builder().appender("job").build(LevelFilter::Info))
+//!     .unwrap();
+//! log4rs::init_config(config).unwrap();
+//! # }
+//! ```
+//!
+//! Routed appenders are created lazily, immediately before the first log event routed to them is
+//! written, so a file appender whose events are all filtered out never creates its file.
+//!
+//! Routed appenders are built one at a time per routing appender, while its cache is locked, and
+//! `max_concurrent_builds` limits the number built at once across routing appenders.
+//!
+//! Cached appenders are evicted once they have been idle for the `idle_timeout`, and for the other
+//! reasons set by the cache options, in the order of precedence described by
+//! `RoutingAppenderBuilder::idle_timeout`. An evicted appender is flushed and then dropped.
+//!
+//! Flushing the routing appender flushes every appender it holds. log4rs never drops the appenders
+//! in effect when the process exits, so applications which must not lose buffered events should
+//! call `log::logger().flush()` or `RoutingAppender::drain` as the last step before exiting.
 #![doc(html_root_url = "https://docs.rs/log4rs-routing-appender/0.4.0")]
 #![warn(missing_docs)]
 extern crate antidote;
@@ -40,49 +117,246 @@ extern crate linked_hash_map;
 extern crate log;
 extern crate log4rs;
 
+#[cfg(feature = "chrono")]
+extern crate chrono;
 #[cfg(feature = "humantime")]
 extern crate humantime;
 #[cfg(feature = "log-mdc")]
 extern crate log_mdc;
+// renamed, since the `metrics` module documents the metrics reported through it
+#[cfg(feature = "metrics")]
+extern crate metrics as metrics_facade;
 #[cfg(feature = "ordered-float")]
 extern crate ordered_float;
+#[cfg(feature = "regex")]
+extern crate regex;
 #[cfg(feature = "serde")]
 extern crate serde;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 #[cfg(feature = "serde-value")]
 extern crate serde_value;
+#[cfg(feature = "serde_json")]
+extern crate serde_json;
 
 #[cfg(feature = "serde_derive")]
 #[macro_use]
 extern crate serde_derive;
 
-use antidote::Mutex;
-use log::Record;
+use antidote::{Mutex, MutexGuard};
+use log::{Level, Record};
 use log4rs::append::Append;
+use std::cell::RefCell;
 use std::error::Error;
-use std::fmt;
-use std::time::Duration;
+use std::fmt::{self, Write};
+use std::io::{self, Write as _};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[cfg(feature = "file")]
 use log4rs::file::{Deserialize, Deserializers};
 #[cfg(feature = "file")]
 use serde::de::{self, Deserialize as SerdeDeserialize};
 #[cfg(feature = "file")]
+use route::{AppenderConfig, RouterConfig};
+#[cfg(feature = "file")]
 use serde_value::Value;
 #[cfg(feature = "file")]
 use std::collections::BTreeMap;
-
+#[cfg(feature = "file")]
+use std::collections::hash_map::DefaultHasher;
+#[cfg(feature = "file")]
+use std::convert::TryFrom;
+#[cfg(feature = "file")]
+use std::env;
+#[cfg(feature = "file")]
+use std::hash::{Hash, Hasher};
 use route::{Cache, Route};
+#[cfg(feature = "file")]
+use warmup::{Origin, StateFile};
+use clock::Clock;
+use sweeper::Sweeper;
+use worker::{OwnedRecord, Worker};
 
+pub use worker::Overflow;
+#[cfg(feature = "chrono")]
+pub use timezone::Timezone;
+
+mod builds;
+pub mod clock;
+pub mod metrics;
+#[cfg(feature = "prometheus")]
+mod prometheus;
 pub mod route;
+mod sweeper;
+#[cfg(feature = "chrono")]
+mod timezone;
+#[cfg(feature = "test-support")]
+pub mod test_support;
+#[cfg(feature = "tracing")]
+mod tracing_mirror;
+#[cfg(feature = "file")]
+mod warmup;
+mod worker;
 
 /// Configuration for the `RoutingAppender`.
+#[cfg(feature = "file")]
+pub struct RoutingAppenderConfig(Settings);
+
+#[cfg(feature = "file")]
+impl<'de> de::Deserialize<'de> for RoutingAppenderConfig {
+    fn deserialize<D>(d: D) -> Result<RoutingAppenderConfig, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let mut map = BTreeMap::<Value, Value>::deserialize(d)?;
+        let mut take = |key: &str| -> Result<Option<String>, D::Error> {
+            match map.remove(&Value::String(key.to_owned())) {
+                Some(value) => value.deserialize_into().map(Some).map_err(|e| e.to_error()),
+                None => Ok(None),
+            }
+        };
+        let mut profile = take("profile")?;
+        if let Some(var) = take("profile_env")? {
+            if let Ok(name) = env::var(var) {
+                profile = Some(name);
+            }
+        }
+        let profiles = map.remove(&Value::String("profiles".to_owned()));
+
+        let mut config = Value::Map(map);
+        if let Some(name) = profile {
+            let mut profiles = match profiles {
+                Some(profiles) => profiles
+                    .deserialize_into::<BTreeMap<String, Value>>()
+                    .map_err(|e| e.to_error())?,
+                None => BTreeMap::new(),
+            };
+            match profiles.remove(&name) {
+                Some(overrides) => merge(&mut config, overrides),
+                None => return Err(de::Error::custom(format!("unknown profile `{}`", name))),
+            }
+        }
+
+        config
+            .deserialize_into()
+            .map(RoutingAppenderConfig)
+            .map_err(|e| e.to_error())
+    }
+}
+
+/// Merges `overrides` into `base`, recursing into maps present in both.
+#[cfg(feature = "file")]
+fn merge(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (&mut Value::Map(ref mut base), Value::Map(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(base) => merge(base, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
 #[cfg(feature = "file")]
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
-pub struct RoutingAppenderConfig {
+struct Settings {
     router: RouterConfig,
+    fallback: Option<RouterConfig>,
     #[serde(default)]
     cache: CacheConfig,
+    #[cfg(feature = "log-mdc")]
+    key_mdc: Option<String>,
+    #[serde(deserialize_with = "de_size", default)]
+    max_message_bytes: Option<u64>,
+    #[serde(deserialize_with = "de_duration", default)]
+    slow_write_threshold: Option<Duration>,
+    #[serde(deserialize_with = "de_level", default)]
+    flush_above: Option<Level>,
+    flush_every_events: Option<u64>,
+    #[serde(default)]
+    account: bool,
+    max_cardinality: Option<usize>,
+    overflow: Option<AppenderConfig>,
+    #[serde(deserialize_with = "de_duration", default)]
+    cold_after: Option<Duration>,
+    cold_appender: Option<AppenderConfig>,
+    circuit: Option<CircuitConfig>,
+    quota: Option<QuotaConfig>,
+    index_appender: Option<AppenderConfig>,
+    #[serde(default)]
+    index_evictions: bool,
+    mirror_appender: Option<AppenderConfig>,
+    #[cfg(feature = "tracing")]
+    #[serde(default)]
+    mirror_tracing: bool,
+    reentrant_appender: Option<AppenderConfig>,
+    warmup_state: Option<PathBuf>,
+    metrics_name: Option<String>,
+    #[cfg(feature = "chrono")]
+    #[serde(default)]
+    timezone: Timezone,
+    #[serde(rename = "async")]
+    asynchronous: Option<AsyncConfig>,
+}
+
+#[cfg(feature = "file")]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AsyncConfig {
+    #[serde(default = "default_queue_size")]
+    queue_size: usize,
+    #[serde(default)]
+    overflow: Overflow,
+}
+
+#[cfg(feature = "file")]
+fn default_queue_size() -> usize {
+    10000
+}
+
+#[cfg(feature = "file")]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CircuitConfig {
+    error_threshold: f64,
+    #[serde(deserialize_with = "de_duration", default)]
+    window: Option<Duration>,
+    #[serde(deserialize_with = "de_duration", default)]
+    probe: Option<Duration>,
+    min_writes: Option<u64>,
+    fallback: Option<AppenderConfig>,
+}
+
+#[cfg(feature = "file")]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QuotaConfig {
+    #[serde(deserialize_with = "de_size", default)]
+    bytes: Option<u64>,
+    #[serde(deserialize_with = "de_duration", default)]
+    window: Option<Duration>,
+    #[serde(default)]
+    on_exceed: OnExceed,
+}
+
+#[cfg(feature = "file")]
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+enum OnExceed {
+    #[default]
+    Drop,
+    RouteTo(AppenderConfig),
 }
 
 #[cfg(feature = "file")]
@@ -91,6 +365,49 @@ pub struct RoutingAppenderConfig {
 struct CacheConfig {
     #[serde(deserialize_with = "de_duration", default)]
     idle_timeout: Option<Duration>,
+    #[serde(deserialize_with = "de_duration", default)]
+    verify_files: Option<Duration>,
+    #[serde(deserialize_with = "de_duration", default)]
+    background_eviction: Option<Duration>,
+    #[serde(deserialize_with = "de_duration", default)]
+    hysteresis: Option<Duration>,
+    #[serde(deserialize_with = "de_duration", default)]
+    max_lifetime: Option<Duration>,
+    #[serde(default)]
+    protect: Vec<String>,
+    #[serde(default)]
+    discard_unwritten: bool,
+    #[serde(default)]
+    key_mode: route::KeyMode,
+    #[serde(default)]
+    dedup_by_path: bool,
+    capacity: Option<usize>,
+    #[serde(deserialize_with = "de_duration", default)]
+    min_ttl: Option<Duration>,
+    #[serde(default)]
+    eviction: EvictionConfig,
+    #[serde(default)]
+    at_capacity: AtCapacity,
+    spill_appender: Option<AppenderConfig>,
+    max_routes: Option<usize>,
+    max_concurrent_builds: Option<usize>,
+}
+
+#[cfg(feature = "file")]
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct EvictionConfig {
+    #[serde(default)]
+    policy: route::EvictionMode,
+}
+
+#[cfg(feature = "file")]
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum AtCapacity {
+    #[default]
+    Evict,
+    Spill,
 }
 
 /// Registers the following mappings:
@@ -100,35 +417,266 @@ struct CacheConfig {
 /// * Routers
 ///     * "pattern" -> `PatternAppenderDeserializer`
 ///         * Requires the `pattern-router` feature (enabled by default).
+///     * "bucketed_numeric" -> `BucketedNumericRouterDeserializer`
+///         * Requires the `range-router` feature.
+///     * "expr" -> `ExprRouterDeserializer`
+///         * Requires the `expr-router` feature.
+///     * "failover" -> `FailoverRouterDeserializer`
+///         * Requires the `failover-router` feature.
+///     * "match" -> `MatchRouterDeserializer`
+///         * Requires the `match-router` feature.
+///     * "message_prefix" -> `MessagePrefixRouterDeserializer`
+///         * Requires the `message-prefix-router` feature.
+///     * "novelty" -> `NoveltyRouterDeserializer`
+///         * Requires the `novelty-router` feature.
+///     * "ramp" -> `RampRouterDeserializer`
+///         * Requires the `ramp-router` feature.
+///     * "shard" -> `ShardRouterDeserializer`
+///         * Requires the `shard-router` feature.
+///     * "sticky" -> `StickyRouterDeserializer`
+///         * Requires the `sticky-router` feature.
+///     * "target" -> `TargetRouterDeserializer`
+///         * Requires the `target-router` feature.
+///     * "target_regex" -> `TargetRegexRouterDeserializer`
+///         * Requires the `target-regex-router` feature.
+///     * "tee" -> `TeeRouterDeserializer`
+///         * Requires the `tee-router` feature.
+///     * "time_window" -> `TimeWindowRouterDeserializer`
+///         * Requires the `time-window-router` feature.
+///
+/// Routers are resolved through the same `Deserializers` as appenders, so routers which embed a
+/// `RouterConfig` for a child router can nest any router kind registered here or by the user.
 #[cfg(feature = "file")]
 pub fn register(d: &mut Deserializers) {
     d.insert("routing", RoutingAppenderDeserializer);
 
     #[cfg(feature = "pattern-router")]
     d.insert("pattern", route::pattern::PatternRouterDeserializer);
+
+    #[cfg(feature = "range-router")]
+    d.insert(
+        "bucketed_numeric",
+        route::bucketed_numeric::BucketedNumericRouterDeserializer,
+    );
+
+    #[cfg(feature = "expr-router")]
+    d.insert("expr", route::expr::ExprRouterDeserializer);
+
+    #[cfg(feature = "failover-router")]
+    d.insert("failover", route::failover::FailoverRouterDeserializer);
+
+    #[cfg(feature = "match-router")]
+    d.insert("match", route::matching::MatchRouterDeserializer);
+
+    #[cfg(feature = "message-prefix-router")]
+    d.insert(
+        "message_prefix",
+        route::message_prefix::MessagePrefixRouterDeserializer,
+    );
+
+    #[cfg(feature = "novelty-router")]
+    d.insert("novelty", route::novelty::NoveltyRouterDeserializer);
+
+    #[cfg(feature = "ramp-router")]
+    d.insert("ramp", route::ramp::RampRouterDeserializer);
+
+    #[cfg(feature = "shard-router")]
+    d.insert("shard", route::shard::ShardRouterDeserializer);
+
+    #[cfg(feature = "sticky-router")]
+    d.insert("sticky", route::sticky::StickyRouterDeserializer);
+
+    #[cfg(feature = "target-router")]
+    d.insert("target", route::target::TargetRouterDeserializer);
+
+    #[cfg(feature = "target-regex-router")]
+    d.insert("target_regex", route::target_regex::TargetRegexRouterDeserializer);
+
+    #[cfg(feature = "tee-router")]
+    d.insert("tee", route::tee::TeeRouterDeserializer);
+
+    #[cfg(feature = "time-window-router")]
+    d.insert("time_window", route::time_window::TimeWindowRouterDeserializer);
 }
 
 /// An appender which routes log events to dynamically constructed sub-appenders.
 pub struct RoutingAppender {
+    inner: Arc<Inner>,
+    max_message_bytes: Option<usize>,
+    fingerprint: Option<u64>,
+    worker: Option<Worker>,
+    sweeper: Option<Sweeper>,
+}
+
+thread_local! {
+    // the addresses of the routing appenders whose caches are locked by this thread
+    static LOCKED: RefCell<Vec<usize>> = const { RefCell::new(vec![]) };
+}
+
+/// A lock of a routing appender's cache, during which the thread is known to hold it.
+struct CacheLock<'a> {
+    cache: MutexGuard<'a, Cache>,
+    id: usize,
+}
+
+impl<'a> Deref for CacheLock<'a> {
+    type Target = Cache;
+
+    fn deref(&self) -> &Cache {
+        &self.cache
+    }
+}
+
+impl<'a> DerefMut for CacheLock<'a> {
+    fn deref_mut(&mut self) -> &mut Cache {
+        &mut self.cache
+    }
+}
+
+impl<'a> Drop for CacheLock<'a> {
+    fn drop(&mut self) {
+        LOCKED.with(|l| {
+            let mut locked = l.borrow_mut();
+            if let Some(idx) = locked.iter().rposition(|&id| id == self.id) {
+                locked.remove(idx);
+            }
+        });
+    }
+}
+
+struct Inner {
     router: Box<dyn Route>,
+    fallback: Option<Box<dyn Route>>,
     cache: Mutex<Cache>,
+    reentrant: Option<Box<dyn Append>>,
+    #[cfg(feature = "log-mdc")]
+    key_mdc: Option<String>,
+    slow_write_threshold: Option<Duration>,
+    flush_above: Option<Level>,
+    flush_every_events: Option<u64>,
+    // the number of log events written, counted for `flush_every_events`
+    written: AtomicU64,
+    account: bool,
+    mirror: Option<Box<dyn Append>>,
+    #[cfg(feature = "tracing")]
+    mirror_tracing: bool,
+    circuit_breaker: bool,
+    quota: bool,
+    #[cfg(feature = "file")]
+    warmup: Option<StateFile>,
+    #[cfg(feature = "prometheus")]
+    metrics_name: Option<String>,
 }
 
 impl fmt::Debug for RoutingAppender {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.debug_struct("RoutingAppender")
-            .field("router", &self.router)
+            .field("router", &self.inner.router)
+            .field("async", &self.worker.is_some())
             .finish()
     }
 }
 
 impl Append for RoutingAppender {
     fn append(&self, record: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
-        let appender = self.router.route(record, &mut self.cache.lock())?;
-        appender.appender().append(record)
+        // logged by this thread while it holds the cache, which would deadlock
+        if self.inner.is_locked() {
+            return match self.inner.reentrant {
+                Some(ref reentrant) => reentrant.append(record),
+                None => Ok(()),
+            };
+        }
+        // events logged by a routed appender on the worker thread would make the worker wait on
+        // itself if they were queued, so they are routed right away, as the cache isn't locked
+        if let Some(worker) = self.worker.as_ref().filter(|w| !w.is_current()) {
+            let mut message = record.args().to_string();
+            if let Some(max) = self.max_message_bytes {
+                truncate(&mut message, max);
+            }
+            worker.send(OwnedRecord::new(record, message));
+            return Ok(());
+        }
+
+        self.append_now(record).map(|_| ())
+    }
+
+    fn flush(&self) {
+        if self.inner.is_locked() {
+            return;
+        }
+        if let Some(ref worker) = self.worker {
+            worker.wait_idle();
+        }
+        self.inner.flush_all();
+    }
+}
+
+impl Drop for RoutingAppender {
+    fn drop(&mut self) {
+        self.sweeper.take();
+        // dropping the worker handles the events still queued
+        self.worker.take();
+        self.inner.flush_all();
+        #[cfg(feature = "file")]
+        self.inner.save_warmup(true);
+    }
+}
+
+impl RoutingAppender {
+    /// Routes and writes a log event on the calling thread, returning the appender it was sent to.
+    fn append_now(&self, record: &Record) -> Result<route::Appender, Box<dyn Error + Sync + Send>> {
+        if let Some(max) = self.max_message_bytes {
+            let mut message = record.args().to_string();
+            if truncate(&mut message, max) {
+                let mut builder = Record::builder();
+                builder
+                    .level(record.level())
+                    .target(record.target())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line());
+                #[cfg(feature = "log-kv")]
+                builder.key_values(record.key_values());
+                return self
+                    .inner
+                    .route_and_append(&builder.args(format_args!("{}", message)).build());
+            }
+        }
+
+        self.inner.route_and_append(record)
+    }
+}
+
+struct ByteCounter(usize);
+
+impl fmt::Write for ByteCounter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+/// Returns the length in bytes of the formatted message of the record.
+fn message_bytes(record: &Record) -> u64 {
+    let mut counter = ByteCounter(0);
+    let _ = write!(counter, "{}", record.args());
+    counter.0 as u64
+}
+
+fn truncate(message: &mut String, max: usize) -> bool {
+    if message.len() <= max {
+        return false;
     }
 
-    fn flush(&self) {}
+    // the marker counts against the limit, and is itself cut short by a limit below its length
+    let marker = &"..."[..max.min(3)];
+    let mut end = max - marker.len();
+    while !message.is_char_boundary(end) {
+        end -= 1;
+    }
+    message.truncate(end);
+    message.push_str(marker);
+    true
 }
 
 impl RoutingAppender {
@@ -136,103 +684,1912 @@ impl RoutingAppender {
     pub fn builder() -> RoutingAppenderBuilder {
         RoutingAppenderBuilder {
             idle_timeout: Duration::from_secs(2 * 60),
+            hysteresis: None,
+            max_lifetime: None,
+            protect: vec![],
+            discard_unwritten: false,
+            key_mode: route::KeyMode::String,
+            dedup_by_path: false,
+            verify_files: None,
+            background_eviction: None,
+            #[cfg(feature = "log-mdc")]
+            key_mdc: None,
+            max_message_bytes: None,
+            fingerprint: None,
+            slow_write_threshold: None,
+            flush_above: None,
+            flush_every_events: None,
+            account: false,
+            max_cardinality: None,
+            max_capacity: None,
+            min_ttl: None,
+            eviction_mode: route::EvictionMode::Idle,
+            spill_appender: None,
+            max_routes: None,
+            max_concurrent_builds: None,
+            cold_appender: None,
+            circuit_breaker: None,
+            circuit_min_writes: 10,
+            circuit_fallback: None,
+            quota: None,
+            quota_appender: None,
+            index_appender: None,
+            mirror_appender: None,
+            #[cfg(feature = "tracing")]
+            mirror_tracing: false,
+            reentrant_appender: None,
+            fallback_router: None,
+            metrics_name: None,
+            eviction_channel: None,
+            #[cfg(feature = "file")]
+            warmup_state: None,
+            clock: None,
+            #[cfg(feature = "chrono")]
+            timezone: Timezone::Utc,
+            asynchronous: None,
         }
     }
-}
 
-/// A builder for `RoutingAppender`s.
-pub struct RoutingAppenderBuilder {
-    idle_timeout: Duration,
-}
+    /// Returns the fingerprint of the configuration of this appender's router, if known.
+    ///
+    /// Appenders created from a config file are fingerprinted automatically, from their router,
+    /// fallback router and cache `key_mode`. The fingerprint is only meaningful within a single
+    /// process.
+    pub fn fingerprint(&self) -> Option<u64> {
+        self.fingerprint
+    }
 
-impl RoutingAppenderBuilder {
-    /// Sets the duration after which an appender that has not been used will be removed from the
-    /// cache.
+    /// Returns a snapshot of the state of the cache of routed appenders.
     ///
-    /// Defaults to 2 minutes.
-    pub fn idle_timeout(mut self, idle_timeout: Duration) -> RoutingAppenderBuilder {
-        self.idle_timeout = idle_timeout;
-        self
+    /// This is intended for diagnostics, such as a debug endpoint of a service, and can be
+    /// serialized with the `serde` feature (enabled by the `file` feature).
+    pub fn snapshot(&self) -> RoutingSnapshot {
+        self.inner.snapshot()
     }
 
-    /// Consumes the builder, producing a `RoutingAppender`.
-    pub fn build(self, router: Box<dyn Route>) -> RoutingAppender {
-        RoutingAppender {
-            router: router,
-            cache: Mutex::new(Cache::new(self.idle_timeout)),
-        }
+    /// Returns the counters of the activity of the cache of routed appenders.
+    ///
+    /// Unlike `snapshot`, this doesn't visit the cached appenders, so it is cheap enough to call
+    /// on every scrape of a metrics endpoint, however many appenders are cached.
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock_cache().stats()
     }
-}
 
-/// A deserializer for the `RoutingAppender`.
-///
-/// # Configuration
-///
-/// ```yaml
-/// kind: routing
-///
-/// # The router used to determine the appender to use for a log event.
-/// # Required.
-/// router:
-///   kind: pattern
-///   pattern:
-///     kind: file
-///     path: "log/${mdc(job_id)}.log"
-///
-/// # Configuration of the cache of appenders generated by the router.
-/// cache:
-///
-///   # The duration that a cached appender has been unused after which it
-///   # will be disposed of. Defaults to 2 minutes.
-///   idle_timeout: 2 minutes
-/// ```
-#[cfg(feature = "file")]
-pub struct RoutingAppenderDeserializer;
+    /// Returns a snapshot of the state of the cache of routed appenders in the Prometheus text
+    /// exposition format.
+    ///
+    /// The metrics are those documented in the `metrics` module, with the dots replaced by
+    /// underscores and counters suffixed with `_total`, along with a series per cached appender
+    /// labelled with its key. If the appender has a metrics name, every series is also labelled
+    /// with it as `appender`. As each key is a separate series, this is only suitable for routers
+    /// with a modest number of keys; for others, serialize the result of `snapshot` instead and
+    /// aggregate it as needed.
+    ///
+    /// Requires the `prometheus` feature.
+    #[cfg(feature = "prometheus")]
+    pub fn snapshot_prometheus(&self) -> String {
+        prometheus::render(&self.inner.snapshot(), self.inner.metrics_name.as_deref())
+    }
 
-#[cfg(feature = "file")]
-impl Deserialize for RoutingAppenderDeserializer {
-    type Trait = dyn Append;
-    type Config = RoutingAppenderConfig;
+    /// Flushes the cached appenders whose keys satisfy the predicate.
+    ///
+    /// This is more targeted than `flush`, for example to flush the appenders of a single tenant
+    /// before archiving its logs. The format of the keys is determined by the router, and they are
+    /// the same as those reported by `snapshot`. If the appender is asynchronous, this first waits
+    /// until all queued events have been handled. The cache is not locked while the appenders are
+    /// flushed.
+    pub fn flush_matching<F>(&self, pred: F)
+    where
+        F: Fn(&str) -> bool,
+    {
+        if let Some(ref worker) = self.worker {
+            worker.wait_idle();
+        }
 
-    fn deserialize(
-        &self,
-        config: RoutingAppenderConfig,
-        deserializers: &Deserializers,
-    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
-        let mut builder = RoutingAppender::builder();
-        if let Some(idle_timeout) = config.cache.idle_timeout {
-            builder = builder.idle_timeout(idle_timeout);
+        let appenders = self.inner.lock_cache().matching(&pred);
+        for appender in appenders {
+            appender.appender().flush();
         }
-        let router = deserializers.deserialize(&config.router.kind, config.router.config)?;
-        Ok(Box::new(builder.build(router)))
     }
-}
 
-#[derive(PartialEq, Eq, Debug)]
-#[cfg(feature = "file")]
-struct RouterConfig {
-    kind: String,
-    config: Value,
-}
+    /// Flushes and evicts all of the cached appenders.
+    ///
+    /// This closes the files of the routed appenders right away rather than once they expire,
+    /// which is useful when shutting down, for example in a command line tool which exits right
+    /// after logging its result. If the appender is asynchronous, this first waits until all
+    /// queued events have been handled. The evictions are reported like any other, with the
+    /// `Drained` reason. The warmup state file, if any, is written first and keeps the drained
+    /// routes, so they are still rebuilt on the next start. Log events routed afterwards create
+    /// their appenders anew.
+    ///
+    /// Flushing the routing appender, which log4rs does when `log::logger().flush()` is called,
+    /// flushes every appender it holds: the cached appenders, and the overflow, cold, spill,
+    /// circuit fallback, quota overflow, index, mirror and reentrant appenders. A routing appender
+    /// which is dropped flushes as well, which log4rs does with the appenders of the previous
+    /// configuration once `Handle::set_config` has installed a new one, after which no more events
+    /// reach them. log4rs does not drop the appenders of the configuration in effect when the
+    /// process exits, however, since the logger lives in a static, so applications which must not
+    /// lose buffered events should flush the logger, or drain the routing appender if they hold it,
+    /// as the last step before exiting. Anything logged after that may be lost.
+    pub fn drain(&self) {
+        if let Some(ref worker) = self.worker {
+            worker.wait_idle();
+        }
 
-#[cfg(feature = "file")]
-impl<'de> de::Deserialize<'de> for RouterConfig {
-    fn deserialize<D>(d: D) -> Result<RouterConfig, D::Error>
-    where
-        D: de::Deserializer<'de>,
-    {
-        let mut map = BTreeMap::<Value, Value>::deserialize(d)?;
+        #[cfg(feature = "file")]
+        self.inner.save_warmup(true);
+        self.inner.lock_cache().drain();
+        self.inner.flush_all();
+    }
 
-        let kind = match map.remove(&Value::String("kind".to_owned())) {
-            Some(kind) => kind.deserialize_into().map_err(|e| e.to_error())?,
-            None => return Err(de::Error::missing_field("kind")),
-        };
+    /// Appends a log event like `Append::append`, and reports where it was routed.
+    ///
+    /// This allows a caller wrapping the appender to correlate the event with the appender it was
+    /// written to, for example to record the log file of a request elsewhere. The report is only
+    /// returned if the event was written successfully. If the appender is asynchronous, this first
+    /// waits until all queued events have been handled, and then writes the event on the calling
+    /// thread rather than queueing it.
+    pub fn append_and_report(
+        &self,
+        record: &Record,
+    ) -> Result<RouteReport, Box<dyn Error + Sync + Send>> {
+        if let Some(ref worker) = self.worker {
+            worker.wait_idle();
+        }
 
-        Ok(RouterConfig {
-            kind: kind,
-            config: Value::Map(map),
+        let appender = self.append_now(record)?;
+        Ok(RouteReport {
+            key: appender.key().to_owned(),
+            path: appender.path().map(Path::to_path_buf),
         })
     }
+
+    /// Takes over the cached appenders of another `RoutingAppender` with an equivalent router.
+    ///
+    /// When an appender is being replaced, for example as part of a reconfiguration, this allows
+    /// the replacement to keep using the appenders its predecessor has already created rather than
+    /// rebuilding them. The routers are considered equivalent if both appenders have the same
+    /// fingerprint. The caches must also store their keys the same way, as set by
+    /// `RoutingAppenderBuilder::key_mode`, since the keys of one would never match those the other
+    /// looks up. Returns `true` and empties the cache of `old` if the appenders were taken over,
+    /// and returns `false` otherwise, leaving both caches untouched.
+    pub fn inherit_cache_from(&mut self, old: &RoutingAppender) -> bool {
+        match (self.fingerprint, old.fingerprint) {
+            (Some(a), Some(b)) if a == b => {
+                let mut cache = self.inner.lock_cache();
+                let mut old = old.inner.lock_cache();
+                // keys stored under another key mode would never be looked up again
+                if cache.key_mode() != old.key_mode() {
+                    return false;
+                }
+                cache.inherit(&mut old);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Inner {
+    fn lock_cache(&self) -> CacheLock<'_> {
+        let id = self as *const Inner as usize;
+        LOCKED.with(|l| l.borrow_mut().push(id));
+        CacheLock {
+            cache: self.cache.lock(),
+            id,
+        }
+    }
+
+    /// Determines if the current thread holds the lock of the cache.
+    fn is_locked(&self) -> bool {
+        let id = self as *const Inner as usize;
+        LOCKED.with(|l| l.borrow().contains(&id))
+    }
+
+    fn snapshot(&self) -> RoutingSnapshot {
+        self.lock_cache().snapshot()
+    }
+
+    /// Rebuilds the routes recorded in the warmup state file, skipping those which fail to.
+    #[cfg(feature = "file")]
+    fn warm_up(&self) {
+        let state = match self.warmup {
+            Some(ref state) => state,
+            None => return,
+        };
+        let routes = match state.load() {
+            Ok(routes) => routes,
+            Err(e) => {
+                let _ = writeln!(
+                    io::stderr(),
+                    "log4rs: error reading routing state file `{}`: {}",
+                    state.path().display(),
+                    e
+                );
+                return;
+            }
+        };
+        let mut failed = 0;
+        for route in routes {
+            let mut cache = self.lock_cache();
+            let routed = route.origin.replay(|record| self.route(record, &mut cache));
+            cache.record_origin(|| route.origin.clone());
+            if routed.is_err() {
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            let _ = writeln!(
+                io::stderr(),
+                "log4rs: {} of the routes in routing state file `{}` failed to rebuild and were skipped",
+                failed,
+                state.path().display()
+            );
+        }
+    }
+
+    /// Routes a log event with the router, or with the fallback router if that fails.
+    fn route(
+        &self,
+        record: &Record,
+        cache: &mut Cache,
+    ) -> Result<route::Appender, Box<dyn Error + Sync + Send>> {
+        let routed = match (self.router.route(record, cache), &self.fallback) {
+            // a route refused by the route limit is an error however the router is configured
+            (Err(e), Some(fallback)) if !e.is::<route::RouteLimitError>() => cache
+                .scoped("fallback", |cache| fallback.route(record, cache))
+                .map_err(|_| e),
+            (routed, _) => routed,
+        };
+        // a router using `Cache::entry` is given a placeholder appender for a refused route
+        match cache.take_refusal() {
+            Some(e) => Err(e),
+            None => routed,
+        }
+    }
+
+    /// Rewrites the warmup state file if the cached routes have changed since it was last written,
+    /// and it is due to be rewritten or `now` is set.
+    #[cfg(feature = "file")]
+    fn save_warmup(&self, now: bool) {
+        let state = match self.warmup {
+            Some(ref state) if now || state.due() => state,
+            _ => return,
+        };
+        // written once the cache is unlocked, since the file system may be slow
+        let routes = self.lock_cache().warmup_routes();
+        if let Some(routes) = routes {
+            state.save(&routes);
+        }
+    }
+
+    fn flush_all(&self) {
+        // collected first, so that the cache isn't locked while the appenders flush
+        let appenders = self.lock_cache().appenders();
+        for appender in appenders {
+            appender.appender().flush();
+        }
+        if let Some(ref mirror) = self.mirror {
+            mirror.flush();
+        }
+        if let Some(ref reentrant) = self.reentrant {
+            reentrant.flush();
+        }
+        self.lock_cache().flush_index();
+    }
+
+    fn route_and_append(
+        &self,
+        record: &Record,
+    ) -> Result<route::Appender, Box<dyn Error + Sync + Send>> {
+        // measured before the cache is locked, since formatting the message can be slow
+        let bytes = if self.quota {
+            Some(message_bytes(record))
+        } else {
+            None
+        };
+        let (appender, diversion) = {
+            let mut cache = self.lock_cache();
+            #[cfg(feature = "file")]
+            let appender = if self.warmup.is_some() {
+                let (appender, mdc) = warmup::recording(|| self.route(record, &mut cache));
+                cache.record_origin(|| Origin::new(record, mdc.clone()));
+                appender
+            } else {
+                self.route(record, &mut cache)
+            };
+            #[cfg(not(feature = "file"))]
+            let appender = self.route(record, &mut cache);
+            let appender = appender?;
+            let mut diversion = if self.circuit_breaker {
+                cache.divert(&appender)
+            } else {
+                None
+            };
+            if let (None, Some(bytes)) = (&diversion, bytes) {
+                diversion = cache.charge(&appender, bytes);
+            }
+            (appender, diversion)
+        };
+        #[cfg(feature = "file")]
+        self.save_warmup(false);
+        match diversion {
+            Some(route::Diversion::Fallback(fallback)) => {
+                fallback.appender().append(record)?;
+                return Ok(fallback);
+            }
+            Some(route::Diversion::Drop) => return Ok(appender),
+            None => {}
+        }
+
+        #[cfg(feature = "log-mdc")]
+        let _guard = self
+            .key_mdc
+            .as_ref()
+            .map(|name| log_mdc::insert_scoped(&**name, appender.key()));
+
+        let start = Instant::now();
+        if let Err(e) = appender.appender().append(record) {
+            self.lock_cache().record_error(&appender, e.to_string());
+            return Err(e);
+        }
+        appender.set_written();
+        if self.circuit_breaker {
+            self.lock_cache().record_success(&appender);
+        }
+        if let Some(ref mirror) = self.mirror {
+            if let Err(e) = mirror.append(record) {
+                let _ = writeln!(io::stderr(), "log4rs: error writing to mirror appender: {}", e);
+            }
+        }
+        #[cfg(feature = "tracing")]
+        {
+            if self.mirror_tracing {
+                tracing_mirror::emit(record);
+            }
+        }
+        // more severe levels compare as less
+        if self.flush_above.is_some_and(|level| record.level() <= level) {
+            appender.appender().flush();
+        }
+        if let Some(every) = self.flush_every_events {
+            if (self.written.fetch_add(1, Ordering::Relaxed) + 1).is_multiple_of(every) {
+                self.flush_all();
+            }
+        }
+
+        if self.account {
+            self.lock_cache().record_write(&appender, message_bytes(record));
+        }
+
+        let elapsed = start.elapsed();
+        if self.slow_write_threshold.is_some_and(|threshold| elapsed > threshold) {
+            self.lock_cache().evict(&appender);
+            return Err(format!(
+                "routed appender `{}` took {:?} to write a log event and has been evicted",
+                appender.key(),
+                elapsed
+            )
+            .into());
+        }
+        Ok(appender)
+    }
+}
+
+/// Where a log event was routed, as returned by `RoutingAppender::append_and_report`.
+#[derive(Debug, Clone)]
+#[cfg_attr(all(feature = "serde", feature = "serde_derive"), derive(Serialize))]
+pub struct RouteReport {
+    /// The key of the appender the log event was written to, in the same format as those reported
+    /// by `RoutingAppender::snapshot`.
+    pub key: String,
+    /// The path of the file the appender writes to, if the router knows it.
+    pub path: Option<PathBuf>,
+}
+
+/// A snapshot of the state of a `RoutingAppender`'s cache.
+#[derive(Debug, Clone)]
+#[cfg_attr(all(feature = "serde", feature = "serde_derive"), derive(Serialize))]
+pub struct RoutingSnapshot {
+    /// The duration after which an unused appender is removed from the cache.
+    pub idle_timeout: Duration,
+    /// The cached appenders, from the least to the most recently used.
+    pub entries: Vec<EntrySnapshot>,
+    /// The number of lookups which found a cached appender.
+    pub hits: u64,
+    /// The number of lookups which did not find a cached appender.
+    pub misses: u64,
+    /// The number of appenders removed from the cache other than by reconfiguration, whether for
+    /// being idle or otherwise.
+    pub evictions: u64,
+}
+
+/// Counters of the activity of a `RoutingAppender`'s cache, as returned by
+/// `RoutingAppender::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(all(feature = "serde", feature = "serde_derive"), derive(Serialize))]
+pub struct CacheStats {
+    /// The number of cached appenders, including any which have expired but have not been
+    /// evicted yet.
+    pub live_entries: usize,
+    /// The number of lookups which found a cached appender.
+    pub total_hits: u64,
+    /// The number of lookups which did not find a cached appender.
+    pub total_misses: u64,
+    /// The number of appenders removed from the cache other than by reconfiguration, whether for
+    /// being idle or otherwise.
+    pub evictions: u64,
+}
+
+/// A snapshot of a cached appender.
+#[derive(Debug, Clone)]
+#[cfg_attr(all(feature = "serde", feature = "serde_derive"), derive(Serialize))]
+pub struct EntrySnapshot {
+    /// The key of the appender in the cache.
+    pub key: String,
+    /// The time since the appender was last used.
+    pub idle: Duration,
+    /// The message of the last error returned by the appender, if any.
+    pub last_error: Option<String>,
+    /// The number of log events written by the appender, if accounting is enabled.
+    pub events: u64,
+    /// The total length in bytes of the messages of the log events written by the appender, if
+    /// accounting is enabled.
+    pub bytes: u64,
+    /// The state of the appender's circuit breaker, which is always closed if the routing
+    /// appender has none.
+    pub circuit: CircuitState,
+    /// The number of bytes the appender may still write within the current window of the quota,
+    /// if the routing appender has one.
+    pub quota_remaining: Option<u64>,
+}
+
+/// The state of the circuit breaker of a routed appender.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(all(feature = "serde", feature = "serde_derive"), derive(Serialize))]
+#[cfg_attr(
+    all(feature = "serde", feature = "serde_derive"),
+    serde(rename_all = "snake_case")
+)]
+pub enum CircuitState {
+    /// Log events are written to the appender.
+    Closed,
+    /// Log events are diverted from the appender, which failed too many of its writes.
+    Open,
+    /// Log events are diverted from the appender while one of them probes whether it has
+    /// recovered.
+    HalfOpen,
+}
+
+/// A notification that a routed appender was evicted from the cache, as received from the channel
+/// returned by `RoutingAppenderBuilder::eviction_channel`.
+#[derive(Debug, Clone)]
+pub struct EvictionEvent {
+    /// The key of the appender in the cache, in the same format as those reported by
+    /// `RoutingAppender::snapshot`.
+    pub key: String,
+    /// The path of the file the appender wrote to, if the router knows it.
+    pub path: Option<PathBuf>,
+    /// The reason the appender was evicted.
+    pub reason: route::Eviction,
+}
+
+/// A builder for `RoutingAppender`s.
+pub struct RoutingAppenderBuilder {
+    idle_timeout: Duration,
+    hysteresis: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    protect: Vec<String>,
+    discard_unwritten: bool,
+    key_mode: route::KeyMode,
+    dedup_by_path: bool,
+    verify_files: Option<Duration>,
+    background_eviction: Option<Duration>,
+    #[cfg(feature = "log-mdc")]
+    key_mdc: Option<String>,
+    max_message_bytes: Option<usize>,
+    fingerprint: Option<u64>,
+    slow_write_threshold: Option<Duration>,
+    flush_above: Option<Level>,
+    flush_every_events: Option<u64>,
+    account: bool,
+    max_cardinality: Option<(usize, Box<dyn Append>)>,
+    max_capacity: Option<usize>,
+    min_ttl: Option<Duration>,
+    eviction_mode: route::EvictionMode,
+    spill_appender: Option<Box<dyn Append>>,
+    max_routes: Option<usize>,
+    max_concurrent_builds: Option<usize>,
+    cold_appender: Option<(Duration, Box<dyn Append>)>,
+    circuit_breaker: Option<(f64, Duration, Duration)>,
+    circuit_min_writes: u64,
+    circuit_fallback: Option<Box<dyn Append>>,
+    quota: Option<(u64, Duration)>,
+    quota_appender: Option<Box<dyn Append>>,
+    index_appender: Option<(Box<dyn Append>, bool)>,
+    mirror_appender: Option<Box<dyn Append>>,
+    #[cfg(feature = "tracing")]
+    mirror_tracing: bool,
+    reentrant_appender: Option<Box<dyn Append>>,
+    fallback_router: Option<Box<dyn Route>>,
+    metrics_name: Option<String>,
+    eviction_channel: Option<SyncSender<EvictionEvent>>,
+    #[cfg(feature = "file")]
+    warmup_state: Option<PathBuf>,
+    clock: Option<Arc<dyn Clock>>,
+    #[cfg(feature = "chrono")]
+    timezone: Timezone,
+    asynchronous: Option<(usize, Overflow)>,
+}
+
+impl RoutingAppenderBuilder {
+    /// Sets the duration after which an appender that has not been used will be removed from the
+    /// cache.
+    ///
+    /// The eviction settings compose in a fixed order of precedence. An appender which has outlived
+    /// the `max_lifetime` is evicted however recently it was used and whether or not it is
+    /// protected. Otherwise, an appender is evicted once it has been idle for the idle timeout, as
+    /// extended by any `hysteresis`, unless it is protected and unprotected appenders remain
+    /// cached. Independently of these, an appender is also evicted when its file is replaced
+    /// (`verify_files`), a write is too slow (`slow_write_threshold`), its first write fails
+    /// (`discard_unwritten`), or its route goes cold (`cold_appender`). Finally, a cache bounded by
+    /// `max_capacity` evicts its least recently used appender, unprotected ones first, to make room
+    /// for another, sparing those created within `min_ttl` while older ones remain. The `Lru` and
+    /// `Lfu` eviction modes turn off idle expiry, so appenders are only evicted for the other
+    /// reasons, and `Lfu` makes room by evicting the least frequently used appender instead. A
+    /// cache bounded by `max_routes` instead refuses new routes while it is full, failing to route
+    /// their events.
+    ///
+    /// Expired appenders are evicted by the lookups of routes, and also at regular intervals by a
+    /// background thread with `background_eviction`. An evicted appender is flushed and then
+    /// dropped, which closes its file, so nothing it buffered is lost.
+    ///
+    /// Defaults to 2 minutes.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> RoutingAppenderBuilder {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets the hysteresis of the idle timeout.
+    ///
+    /// A key whose events arrive at intervals close to the idle timeout can have its appender
+    /// evicted and immediately recreated over and over, reopening its file each time. With
+    /// hysteresis, an appender recreated within `hysteresis` of its predecessor being evicted for
+    /// being idle has its idle timeout extended by `hysteresis`, so it is not evicted again until
+    /// it has been idle for the full, extended timeout. Since the cache is ordered by last use,
+    /// an extended appender may delay the eviction of less recently used ones by up to
+    /// `hysteresis`.
+    ///
+    /// Defaults to no hysteresis.
+    pub fn hysteresis(mut self, hysteresis: Duration) -> RoutingAppenderBuilder {
+        self.hysteresis = Some(hysteresis);
+        self
+    }
+
+    /// Sets the duration after which an appender is removed from the cache, however recently it
+    /// has been used.
+    ///
+    /// This bounds how long any one appender is kept open, for example to pick up changes to the
+    /// environment of its file, without having to wait for it to go idle. An appender which has
+    /// outlived its lifetime is rebuilt by the next event routed to it. The lifetime takes
+    /// precedence over the other eviction settings: neither hysteresis nor protection extend it.
+    ///
+    /// Defaults to no maximum lifetime.
+    pub fn max_lifetime(mut self, max_lifetime: Duration) -> RoutingAppenderBuilder {
+        self.max_lifetime = Some(max_lifetime);
+        self
+    }
+
+    /// Protects the appenders whose keys start with `prefix` from being evicted for being idle,
+    /// for as long as any unprotected appenders are cached.
+    ///
+    /// This keeps rarely but importantly used routes, such as an error log, open while the
+    /// application is active, while chatty routes come and go. Protected appenders are evicted
+    /// like any other once every cached appender is protected. Keys are matched as reported by
+    /// `RoutingAppender::snapshot`, and the format of the keys of a router is up to the router,
+    /// so this is best combined with a router whose keys are predictable, such as one using a
+    /// custom key strategy. May be called more than once to protect several prefixes.
+    ///
+    /// Defaults to no protected appenders.
+    pub fn protect(mut self, prefix: &str) -> RoutingAppenderBuilder {
+        self.protect.push(prefix.to_owned());
+        self
+    }
+
+    /// Determines if routed appenders which fail to write their first log event are discarded.
+    ///
+    /// Appenders are only built for events which are about to be written to them, but building
+    /// a file appender creates its file, so an appender whose writes all fail, for example because
+    /// its encoder rejects every event, leaves an empty file behind. If enabled, an appender is
+    /// evicted from the cache as soon as a write fails before any write has succeeded, and the
+    /// file it was built for is deleted if it is empty. The next event routed to it builds a
+    /// fresh appender. Only routers which know the file an appender writes to can delete it; see
+    /// [file paths]. An empty file which existed before the appender was built is deleted all the
+    /// same.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// [file paths]: route/index.html#file-paths
+    pub fn discard_unwritten(mut self, discard: bool) -> RoutingAppenderBuilder {
+        self.discard_unwritten = discard;
+        self
+    }
+
+    /// Sets how the cache stores the keys of its appenders.
+    ///
+    /// With `KeyMode::Hashed`, each key is replaced by a fixed-size 128 bit hash of it, which
+    /// substantially reduces the memory used by a cache of many appenders with long keys, such as
+    /// those derived from long MDC values. Two keys sharing a hash would share an appender, but
+    /// with 128 bit hashes that is vanishingly unlikely even across billions of keys. The hashes
+    /// are what `RoutingAppender::snapshot`, `RoutingAppender::flush_matching`, the `key_mdc`
+    /// entry and the index appender see, so the keys can no longer be read back, which is why
+    /// they are stored as is by default. Protected prefixes are still matched against the keys as
+    /// provided by the router. The keys reserved by routers, starting with `!`, are never hashed.
+    ///
+    /// Defaults to `KeyMode::String`.
+    pub fn key_mode(mut self, key_mode: route::KeyMode) -> RoutingAppenderBuilder {
+        self.key_mode = key_mode;
+        self
+    }
+
+    /// Determines if keys whose appenders would write to the same file share a single appender.
+    ///
+    /// Distinct keys can expand to the same path, for example when the MDC values they refer to
+    /// differ only in characters which the template drops. By default, each key gets an appender
+    /// of its own, so the file is opened once per key and their writes may interleave or, with
+    /// appenders which rotate or truncate the file, corrupt it. If enabled, a key whose appender
+    /// would write to a file which a cached appender already writes to is given that appender
+    /// instead of building another. The shared appender stays cached as long as any of its keys
+    /// do, and is built from the configuration of the first of them. Paths are compared as
+    /// expanded, so two different spellings of the same file are not recognized. Only routers
+    /// which know the file an appender writes to can share it; see [file paths].
+    ///
+    /// The pattern router always shares one appender between keys whose configurations expand
+    /// identically; this extends the sharing to configurations which differ in anything but the
+    /// path.
+    ///
+    /// An appender evicted for outliving its maximum lifetime, for being slow, for failing its
+    /// first write or because its file was replaced is no longer shared, and the next key routed
+    /// to its file builds a fresh appender.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// [file paths]: route/index.html#file-paths
+    pub fn dedup_by_path(mut self, dedup: bool) -> RoutingAppenderBuilder {
+        self.dedup_by_path = dedup;
+        self
+    }
+
+    /// Sets the interval at which the files written by routed appenders are checked.
+    ///
+    /// If a file has been deleted or replaced since its appender was created, for example by an
+    /// external log rotation tool, the appender is discarded and recreated by the next event routed
+    /// to it, which reopens the file. Only routers which know the file an appender writes to can
+    /// support this; see [file paths].
+    ///
+    /// Defaults to not checking files.
+    ///
+    /// [file paths]: route/index.html#file-paths
+    pub fn verify_files(mut self, interval: Duration) -> RoutingAppenderBuilder {
+        self.verify_files = Some(interval);
+        self
+    }
+
+    /// Sets the interval at which a background thread evicts the expired appenders.
+    ///
+    /// Expired appenders are otherwise only evicted by the lookups of routes, a few at a time, so
+    /// the appenders of a quiet routing appender keep their files open past their idle timeout
+    /// until events arrive again. The thread evicts every expired appender at each interval, and
+    /// is stopped when the routing appender is dropped.
+    ///
+    /// Defaults to no background thread.
+    pub fn background_eviction(mut self, interval: Duration) -> RoutingAppenderBuilder {
+        self.background_eviction = Some(interval);
+        self
+    }
+
+    /// Sets the name of an MDC entry which will hold the key of the route while a log event is
+    /// passed to the routed appender.
+    ///
+    /// This allows the routed appender's encoder to include the route in its output, for example
+    /// with a `{X(route)}` pattern. The format of the key is determined by the router. The MDC
+    /// entry is restored to its previous state once the routed appender returns.
+    ///
+    /// Requires the `log-mdc` feature (enabled by default).
+    #[cfg(feature = "log-mdc")]
+    pub fn key_mdc(mut self, key_mdc: &str) -> RoutingAppenderBuilder {
+        self.key_mdc = Some(key_mdc.to_owned());
+        self
+    }
+
+    /// Sets the maximum length in bytes of a log event's message.
+    ///
+    /// Longer messages are truncated on a character boundary and suffixed with `...` before the
+    /// event is routed, so that they come to at most the limit, marker included. The limit applies
+    /// to the message alone, as rendered by the `{m}` encoder directive; the remainder of the
+    /// routed appender's encoder output is not counted against it. Rendering the message to measure
+    /// it adds some overhead to every event.
+    ///
+    /// Defaults to no limit.
+    pub fn max_message_bytes(mut self, max_message_bytes: usize) -> RoutingAppenderBuilder {
+        self.max_message_bytes = Some(max_message_bytes);
+        self
+    }
+
+    /// Sets the fingerprint identifying the configuration of the router.
+    ///
+    /// Two appenders whose routers would create identical appenders for every log event should
+    /// have the same fingerprint, which allows one to take over the cached appenders of the other
+    /// via `RoutingAppender::inherit_cache_from`. The routers must also derive the same keys for
+    /// the same events, so routers with different `PatternRouterBuilder::key_strategy`s should
+    /// have different fingerprints.
+    ///
+    /// Defaults to no fingerprint.
+    pub fn fingerprint(mut self, fingerprint: u64) -> RoutingAppenderBuilder {
+        self.fingerprint = Some(fingerprint);
+        self
+    }
+
+    /// Sets the duration after which a write to a routed appender is considered pathologically
+    /// slow.
+    ///
+    /// A write can't be interrupted once started, but a routed appender whose write takes longer
+    /// than the threshold, for example because it is writing to a stalled network filesystem, is
+    /// evicted from the cache once the write completes and an error is returned. The next event
+    /// routed to it builds a fresh appender rather than hitting the same stuck one.
+    ///
+    /// Defaults to no threshold.
+    pub fn slow_write_threshold(mut self, threshold: Duration) -> RoutingAppenderBuilder {
+        self.slow_write_threshold = Some(threshold);
+        self
+    }
+
+    /// Sets the least severe level of the log events after which the routed appender is flushed.
+    ///
+    /// Events at the level or a more severe one are flushed as soon as they have been written,
+    /// so that they are durable even if the process exits abruptly, while less severe events are
+    /// left to the routed appender's buffering for the sake of throughput. A flush can be much
+    /// slower than a buffered write, so the level should be one at which events are rare.
+    ///
+    /// Defaults to never flushing after a write.
+    pub fn flush_above(mut self, level: Level) -> RoutingAppenderBuilder {
+        self.flush_above = Some(level);
+        self
+    }
+
+    /// Sets the number of log events after which all cached appenders are flushed.
+    ///
+    /// Events are counted across all routes, and every time `events` of them have been written,
+    /// every appender held by the routing appender is flushed, as by `Append::flush`. This bounds
+    /// the number of events which can be lost if the process exits abruptly, while amortizing the
+    /// cost of flushing over many events. It combines with `RoutingAppenderBuilder::flush_above`,
+    /// which flushes a single appender right after each severe event; there is no time-based
+    /// flushing, so an appender which receives its last events shortly before the process stops
+    /// may still lose them. The appenders are flushed on the thread which writes the event that
+    /// completes the count, after that event.
+    ///
+    /// An `events` of 0 is treated as 1.
+    ///
+    /// Defaults to not flushing after any number of events.
+    pub fn flush_every_events(mut self, events: u64) -> RoutingAppenderBuilder {
+        self.flush_every_events = Some(events.max(1));
+        self
+    }
+
+    /// Enables accounting of the log events written by each routed appender.
+    ///
+    /// The number of events written and the total length of their messages are tracked for each
+    /// cached appender, and reported by `RoutingAppender::snapshot`. The length counts the message
+    /// alone, and not the rest of the routed appender's encoder output, so it is an approximation
+    /// of the number of bytes written. Accounting renders each message one more time, in addition
+    /// to the rendering by the routed appender, and briefly locks the cache after every write. The
+    /// counts are reset if the appender is evicted from the cache.
+    ///
+    /// Defaults to `false`.
+    pub fn account(mut self, account: bool) -> RoutingAppenderBuilder {
+        self.account = account;
+        self
+    }
+
+    /// Sets a budget on the number of distinct keys which will ever be given a routed appender.
+    ///
+    /// Unlike the idle timeout, which only bounds the appenders alive at any one time, the budget
+    /// counts every key routed to since the appender was built, including those whose appenders
+    /// have since been evicted. Once `max` keys have been seen, log events routed to any other
+    /// key are sent to `overflow` instead, and a warning is printed to standard error the first
+    /// time this happens. This guards against the slow, steady growth in the number of routes
+    /// caused by, for example, a pattern which refers to a unique ID.
+    ///
+    /// Every key seen is kept until the budget is reached, so the budget costs memory in
+    /// proportion to `max` times the typical length of a key, plus the overhead of a hash set
+    /// entry, even while few appenders are cached.
+    ///
+    /// Defaults to no budget.
+    pub fn max_cardinality(
+        mut self,
+        max: usize,
+        overflow: Box<dyn Append>,
+    ) -> RoutingAppenderBuilder {
+        self.max_cardinality = Some((max, overflow));
+        self
+    }
+
+    /// Sets the maximum number of routed appenders held in the cache at once.
+    ///
+    /// Before an appender is added to a full cache, the least recently used appender is evicted
+    /// to make room for it, and dropped, which closes its file unless a log event is being
+    /// written to it at that moment. Protected appenders are only evicted once no unprotected
+    /// ones are cached, and only if there is no `spill_appender`. The overflow, cold, spill and
+    /// circuit fallback appenders do not count towards the capacity.
+    ///
+    /// Defaults to no limit.
+    pub fn max_capacity(mut self, capacity: usize) -> RoutingAppenderBuilder {
+        self.max_capacity = Some(capacity.max(1));
+        self
+    }
+
+    /// Sets the duration for which a newly created appender is spared from being evicted to make
+    /// room for another in a full cache.
+    ///
+    /// Under capacity pressure from churning keys, an appender may otherwise be evicted before
+    /// its route is used again, only to be rebuilt by its next event. While the cache holds older
+    /// appenders, the least recently used of them is evicted instead of any appender created
+    /// within `min_ttl`, protected appenders still coming after unprotected ones. Once every
+    /// candidate is that fresh, the least recently used of them is evicted after all, so a new
+    /// route always gets an appender. This only affects evictions at capacity, not idle expiry.
+    /// Requires `RoutingAppenderBuilder::max_capacity`.
+    ///
+    /// Defaults to no minimum.
+    pub fn min_ttl(mut self, min_ttl: Duration) -> RoutingAppenderBuilder {
+        self.min_ttl = Some(min_ttl);
+        self
+    }
+
+    /// Sets how the cache chooses the appenders it evicts.
+    ///
+    /// With `EvictionMode::Lru` or `EvictionMode::Lfu`, appenders are no longer evicted for being
+    /// idle, so the idle timeout and hysteresis have no effect, and the cache only evicts them to
+    /// make room for others, the least recently or least frequently used first respectively. The
+    /// frequency of an appender is the number of times its route was looked up since it was
+    /// created, so an appender which is evicted and rebuilt starts over. The other constraints on
+    /// evictions still apply: the maximum lifetime, the protection of keys and the minimum TTL.
+    /// Requires `RoutingAppenderBuilder::max_capacity`.
+    ///
+    /// Defaults to `EvictionMode::Idle`.
+    pub fn eviction_mode(mut self, mode: route::EvictionMode) -> RoutingAppenderBuilder {
+        self.eviction_mode = mode;
+        self
+    }
+
+    /// Sets an appender to which the log events of new routes are written when the cache is full
+    /// of protected appenders.
+    ///
+    /// Without it, a protected appender is evicted to make room for the new route's appender.
+    /// With it, the protected appenders are kept, and an event for a route which has no appender
+    /// is written to the spill appender instead, without building one. The spill appender is
+    /// invoked with the key of the route the event was meant for, so with
+    /// `RoutingAppenderBuilder::key_mdc` set, its encoder can record the key, for example with
+    /// `{X(route)}` in a pattern encoder. Requires `RoutingAppenderBuilder::max_capacity`.
+    ///
+    /// Defaults to no spill appender.
+    pub fn spill_appender(mut self, appender: Box<dyn Append>) -> RoutingAppenderBuilder {
+        self.spill_appender = Some(appender);
+        self
+    }
+
+    /// Sets the maximum number of routes cached at once, beyond which the log events of new
+    /// routes fail to be routed.
+    ///
+    /// Unlike `RoutingAppenderBuilder::max_capacity`, which makes room for new routes by evicting
+    /// others, this turns a number of routes which should never be reached, such as one caused by a
+    /// pattern accidentally referring to a request ID, into an error identifying the offending
+    /// route, rather than a churn of appenders or a process running out of file descriptors. The
+    /// error, a `route::RouteLimitError` naming the limit, the route and, with the pattern router,
+    /// the expanded configuration of its appender, is returned by `Cache::try_entry`, which the
+    /// built-in routers use. Routers using `Cache::entry` are given a placeholder appender instead,
+    /// and the routing appender returns the same error. It is returned to log4rs, or reported on
+    /// standard error by an asynchronous appender, and no appender is built for the route. Routes
+    /// are accepted again as appenders are evicted. The limit applies after the overflow appender
+    /// of `RoutingAppenderBuilder::max_cardinality` and the spill appender, which take precedence,
+    /// and includes the internal appenders of routers, such as fallbacks, but does not apply to
+    /// them.
+    ///
+    /// Defaults to no limit.
+    pub fn max_routes(mut self, max: usize) -> RoutingAppenderBuilder {
+        self.max_routes = Some(max.max(1));
+        self
+    }
+
+    /// Sets the maximum number of appenders built at once, to smooth out bursts of new routes.
+    ///
+    /// A routing appender builds its appenders while its cache is locked, so it never builds more
+    /// than one at a time, but a configuration with many routing appenders can still open a burst
+    /// of files at once. The limit is therefore shared with every other routing appender which
+    /// sets one: a new route waits while as many appenders as its own limit are being built by
+    /// any of them. The appenders built within a build, such as those of nested routers, share its
+    /// slot.
+    ///
+    /// Threads routing events to the same new route at once wait for the first of them to build
+    /// its appender, rather than each building one, and every other event logged to the routing
+    /// appender meanwhile waits for the cache, including those of routes which are already cached.
+    /// Waiting for a slot adds to this latency; the asynchronous mode moves it off the logging
+    /// threads. A route waits at most a second for a slot, after which its appender is built
+    /// anyway, so that an appender whose construction logs to another routing appender cannot
+    /// deadlock on the slot it holds.
+    ///
+    /// Defaults to no limit.
+    pub fn max_concurrent_builds(mut self, max: usize) -> RoutingAppenderBuilder {
+        self.max_concurrent_builds = Some(max.max(1));
+        self
+    }
+
+    /// Sets an appender to which the log events of routes which have gone quiet are diverted.
+    ///
+    /// Routes which trickle out the odd event long after a burst of activity would otherwise have
+    /// their own file reopened for every one of those events. Instead, an event routed to a key
+    /// whose previous event was more than `after` ago is written to `appender`, which is shared by
+    /// all such routes, and the route's own appender is evicted if it is still cached. A route
+    /// warms back up as soon as it becomes active again: an event which follows the route's
+    /// previous one within `after` is written to the route's own appender, which is rebuilt if
+    /// necessary. Routes seen for the first time always start out with their own appender.
+    ///
+    /// The time of the last event of every key routed to is kept, so memory use grows with the
+    /// number of distinct keys, as with `RoutingAppenderBuilder::max_cardinality`, which can bound
+    /// it. `after` is independent of the idle timeout, but is usually longer, so that a route's
+    /// file is closed early on and only reopened while the route is still warm.
+    ///
+    /// Defaults to no cold appender.
+    pub fn cold_appender(
+        mut self,
+        after: Duration,
+        appender: Box<dyn Append>,
+    ) -> RoutingAppenderBuilder {
+        self.cold_appender = Some((after, appender));
+        self
+    }
+
+    /// Gives each routed appender a circuit breaker, which diverts its log events once too many
+    /// of its writes fail.
+    ///
+    /// The writes of each cached appender are counted over consecutive windows of length
+    /// `window`. Once at least `error_threshold` of the writes in the current window have failed,
+    /// between 0 for none and 1 for all of them, the appender's circuit opens: the events routed
+    /// to it are written to the `circuit_fallback` appender instead, or dropped if there is none,
+    /// so that a failing sink doesn't return an error for every event on the logging threads.
+    /// Once `probe` has passed since the circuit opened, the next event routed to the appender is
+    /// written to it as a probe. The circuit closes if the probe succeeds, and reopens for
+    /// another `probe` otherwise. Circuits are only opened once a window counts at least
+    /// `circuit_min_writes` writes, so that an appender isn't cut off by the odd failure of a
+    /// route which rarely logs. The state of each circuit is reported by `snapshot`, and is reset
+    /// if its appender is evicted from the cache.
+    ///
+    /// Defaults to no circuit breakers.
+    pub fn circuit_breaker(
+        mut self,
+        error_threshold: f64,
+        window: Duration,
+        probe: Duration,
+    ) -> RoutingAppenderBuilder {
+        self.circuit_breaker = Some((error_threshold, window, probe));
+        self
+    }
+
+    /// Sets the minimum number of writes within a window before a circuit breaker can open.
+    ///
+    /// Defaults to 10.
+    pub fn circuit_min_writes(mut self, min: u64) -> RoutingAppenderBuilder {
+        self.circuit_min_writes = min.max(1);
+        self
+    }
+
+    /// Sets the appender to which the log events of routed appenders whose circuit is open are
+    /// written.
+    ///
+    /// It is shared by every open circuit. Unlike a routed appender, it is used whether or not it
+    /// fails, and its errors are returned as usual.
+    ///
+    /// Defaults to dropping the events.
+    pub fn circuit_fallback(mut self, appender: Box<dyn Append>) -> RoutingAppenderBuilder {
+        self.circuit_fallback = Some(appender);
+        self
+    }
+
+    /// Caps the number of bytes each routed appender may write within a window of time.
+    ///
+    /// The bytes are those of the formatted messages of the log events, as counted by
+    /// `RoutingAppenderBuilder::account`. The window of an appender is fixed rather than rolling:
+    /// it starts with the first event routed to the appender, and the next one starts with the
+    /// first event routed to it once `window` has passed. An event which would take the bytes
+    /// written by its appender within the current window beyond `bytes` is written to the
+    /// `quota_appender` instead, or dropped if there is none, and is not counted against the
+    /// quota, so that no single route can monopolize the disk. Events are counted when they are
+    /// routed, whether or not the write succeeds. The bytes each appender may still write are
+    /// reported by `snapshot`, and the quota is reset if the appender is evicted from the cache.
+    ///
+    /// Defaults to no quota.
+    pub fn quota(mut self, bytes: u64, window: Duration) -> RoutingAppenderBuilder {
+        self.quota = Some((bytes, window));
+        self
+    }
+
+    /// Sets the appender to which the log events of routed appenders which have exhausted their
+    /// quota are written.
+    ///
+    /// It is shared by every routed appender, and its own writes are not counted against any
+    /// quota.
+    ///
+    /// Defaults to dropping the events.
+    pub fn quota_appender(mut self, appender: Box<dyn Append>) -> RoutingAppenderBuilder {
+        self.quota_appender = Some(appender);
+        self
+    }
+
+    /// Sets an appender to which a line is written whenever a routed appender is created.
+    ///
+    /// This gives a single overview of the routes created over time, alongside the output of the
+    /// routed appenders themselves. Each line is a log event at the `Info` level with the target
+    /// `log4rs_routing_appender::index`, and a message of the form
+    /// `created route key="<key>" path="<path>"`, where the path is only included for routers
+    /// which know the file written by the appender. Times can be added by the index appender's
+    /// encoder.
+    ///
+    /// If `evictions` is set, a line of the form
+    /// `evicted route key="<key>" reason=<reason> events=<n> bytes=<n>` is also written whenever
+    /// a routed appender is evicted from the cache, where the reason is one of `lifetime`, `idle`,
+    /// `file_replaced`, `slow`, `unwritten` or `cold`. The counts are those of
+    /// `RoutingAppenderBuilder::account`, and are zero if accounting is disabled.
+    ///
+    /// The index appender is written while the cache is locked, so it should be fast, and events
+    /// it logs to this routing appender are handled as described in
+    /// `RoutingAppenderBuilder::reentrant_appender`.
+    ///
+    /// Defaults to no index appender.
+    pub fn index_appender(
+        mut self,
+        appender: Box<dyn Append>,
+        evictions: bool,
+    ) -> RoutingAppenderBuilder {
+        self.index_appender = Some((appender, evictions));
+        self
+    }
+
+    /// Sets an appender to which every log event is also written, after it has been written to
+    /// its routed appender.
+    ///
+    /// This allows another logging system to observe the routed events, through an appender which
+    /// forwards them to it. `RoutingAppenderBuilder::mirror_tracing` does so for `tracing`. The
+    /// mirror is only passed the events which were written successfully, while the `key_mdc` entry,
+    /// if any, is still set, and an error writing to it is reported on standard error rather than
+    /// returned. The routing and caching of events are unaffected.
+    ///
+    /// If the other logging system ends up writing to the same destination as the routed
+    /// appenders, for example because `tracing` events are in turn forwarded to `log`, each event
+    /// is processed twice, or written back to this appender in a loop.
+    ///
+    /// Defaults to no mirror appender.
+    pub fn mirror_appender(mut self, appender: Box<dyn Append>) -> RoutingAppenderBuilder {
+        self.mirror_appender = Some(appender);
+        self
+    }
+
+    /// Determines if every log event is also emitted as a `tracing` event, after it has been
+    /// written to its routed appender.
+    ///
+    /// This lets a `tracing` subscriber observe the routed events during a migration from `log`
+    /// to `tracing`. Each `tracing` event has the level and message of the log event, and since
+    /// the targets of `tracing` events must be static, the target `log`, with the target of the
+    /// log event in its `log.target` field, as with the `tracing-log` crate. Like the mirror
+    /// appender, which it may be used along with, it is only passed the events which were written
+    /// successfully, and the routing and caching of events are unaffected. The event is emitted to
+    /// the subscriber of the thread routing it, which for an asynchronous appender is its
+    /// background thread rather than the logging thread.
+    ///
+    /// If the subscriber ends up writing to the same destination as the routed appenders, for
+    /// example because `tracing` events are in turn forwarded to `log`, each event is processed
+    /// twice, or emitted back to this appender in a loop.
+    ///
+    /// Requires the `tracing` feature.
+    ///
+    /// Defaults to `false`.
+    #[cfg(feature = "tracing")]
+    pub fn mirror_tracing(mut self, mirror_tracing: bool) -> RoutingAppenderBuilder {
+        self.mirror_tracing = mirror_tracing;
+        self
+    }
+
+    /// Sets an appender to which the log events logged to this routing appender while the logging
+    /// thread holds its cache are written.
+    ///
+    /// The cache is locked while a routed appender is built, so an appender whose construction
+    /// logs, such as a network appender logging its connection attempts, would deadlock if those
+    /// events were routed back to the same routing appender. Such reentrant events are detected
+    /// and written to this appender instead, which should not itself log to the routing appender,
+    /// and flushing the routing appender from within its own cache is ignored. The same applies to
+    /// the closures and appenders called with the cache locked, such as the index appender.
+    /// Reentrant events are neither routed nor accounted for, and if the appender is asynchronous
+    /// they are written on the logging thread rather than queued.
+    ///
+    /// Defaults to no reentrant appender, in which case reentrant events are dropped.
+    pub fn reentrant_appender(mut self, appender: Box<dyn Append>) -> RoutingAppenderBuilder {
+        self.reentrant_appender = Some(appender);
+        self
+    }
+
+    /// Sets a router to which the log events the router fails to route are handed instead.
+    ///
+    /// This catches the events which would otherwise be lost, such as those lacking an MDC key
+    /// the router's template refers to without a default, for example to write them to a single
+    /// catch-all file. The keys of the fallback router's appenders are scoped, so they share the
+    /// cache of the router without colliding with its keys. If the fallback router fails as well,
+    /// the error of the router is returned.
+    ///
+    /// Defaults to no fallback router.
+    pub fn fallback_router(mut self, router: Box<dyn Route>) -> RoutingAppenderBuilder {
+        self.fallback_router = Some(router);
+        self
+    }
+
+    /// Sets the name under which the appender reports its cache metrics.
+    ///
+    /// The name is the `appender` label of the metrics reported through the `metrics` facade,
+    /// which requires the `metrics` feature, and of the Prometheus rendering of snapshots, so it
+    /// should be unique among the routing appenders of the process. See the `metrics` module for
+    /// the metrics reported.
+    ///
+    /// Defaults to no name, in which case the appender reports no metrics.
+    pub fn metrics_name(mut self, name: &str) -> RoutingAppenderBuilder {
+        self.metrics_name = Some(name.to_owned());
+        self
+    }
+
+    /// Returns a channel on which a notification is received whenever a routed appender is evicted
+    /// from the cache, holding up to `capacity` pending notifications.
+    ///
+    /// This allows external code to react to evictions, for example by archiving the files of
+    /// evicted appenders, without slowing down logging: the notification is sent while the cache
+    /// is locked, but never blocks. If the channel already holds `capacity` notifications which
+    /// haven't been received, the notification is dropped instead, so a receiver which falls
+    /// behind misses evictions rather than stalling the logging threads. Notifications stop being
+    /// sent once the receiver is dropped.
+    ///
+    /// Calling this again replaces the previous channel, whose receiver stops getting
+    /// notifications. A `capacity` of 0 is treated as 1.
+    ///
+    /// Defaults to no channel.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # extern crate log4rs_routing_appender;
+    /// # use log4rs_routing_appender::route::Route;
+    /// # use log4rs_routing_appender::RoutingAppender;
+    /// # use std::path::Path;
+    /// # use std::thread;
+    /// # fn archive(_: &Path) {}
+    /// # fn main() {
+    /// # let router: Box<dyn Route> = unimplemented!();
+    /// let mut builder = RoutingAppender::builder();
+    /// let evictions = builder.eviction_channel(1024);
+    /// let appender = builder.build(router);
+    ///
+    /// thread::spawn(move || {
+    ///     for eviction in evictions {
+    ///         if let Some(path) = eviction.path {
+    ///             archive(&path);
+    ///         }
+    ///     }
+    /// });
+    /// # drop(appender);
+    /// # }
+    /// ```
+    pub fn eviction_channel(&mut self, capacity: usize) -> Receiver<EvictionEvent> {
+        let (sender, receiver) = mpsc::sync_channel(capacity.max(1));
+        self.eviction_channel = Some(sender);
+        receiver
+    }
+
+    /// Sets the path of a state file recording the cached routes, from which they are rebuilt when
+    /// the appender is created.
+    ///
+    /// This avoids the latency of creating the routed appenders, such as opening their files, for
+    /// the first events logged after a restart. Whenever an appender is created, the level and
+    /// target of the log event it was created for are recorded, along with the MDC entries the
+    /// routers read to route it. Only those entries are recorded, rather than the whole MDC, and
+    /// templates record the values they substituted, after any `redact` patterns of the pattern
+    /// router are applied. The routes cached at the time are written to the file at most every 10
+    /// seconds while events are logged, if they have changed, and when the appender is dropped.
+    /// When the appender is created, each route in the file is rebuilt by routing an empty log
+    /// event with the recorded level and target while the recorded MDC is in place. Routes which
+    /// fail to rebuild, for example because the router has since changed, are skipped, and a
+    /// missing file is treated as an empty one.
+    ///
+    /// Since the replayed events have no message, key-value pairs or thread name of their own,
+    /// this is only suitable for routers which route on the MDC, the level and the target of
+    /// events. The MDC entries read by custom routers are not recorded.
+    ///
+    /// Requires the `file` feature. Defaults to no state file.
+    #[cfg(feature = "file")]
+    pub fn warmup_state<P>(mut self, path: P) -> RoutingAppenderBuilder
+    where
+        P: Into<PathBuf>,
+    {
+        self.warmup_state = Some(path.into());
+        self
+    }
+
+    /// Sets the clock from which the cache reads the current time.
+    ///
+    /// This is meant for tests, which can install a clock they advance by hand, such as
+    /// `clock::MockClock`, to exercise idle timeouts and the other time-based settings of
+    /// the cache without sleeping. See the `clock` module for what the clock drives.
+    ///
+    /// Defaults to `clock::SystemClock`.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> RoutingAppenderBuilder {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Sets the timezone used by routers which depend on the time, unless they are configured
+    /// with a timezone of their own.
+    ///
+    /// All time-based routing within the appender uses this timezone, so that an appender
+    /// configured the same way behaves the same on every host, whatever the host's local
+    /// timezone is.
+    ///
+    /// Requires the `chrono` feature (enabled by the `time-window-router` feature).
+    ///
+    /// Defaults to `Timezone::Utc`.
+    #[cfg(feature = "chrono")]
+    pub fn timezone(mut self, timezone: Timezone) -> RoutingAppenderBuilder {
+        self.timezone = timezone;
+        self
+    }
+
+    /// Makes the appender asynchronous, handing log events to a background thread which routes
+    /// them and passes them to the routed appenders.
+    ///
+    /// Logging an event then only renders its message and captures the MDC before adding it to a
+    /// queue holding up to `queue_size` events, so the logging thread does not wait on the
+    /// routed appenders' I/O. `overflow` determines what happens to an event logged while the
+    /// queue is full. Errors from the router or routed appenders are reported on standard error,
+    /// since there is no caller to return them to. Flushing the appender waits until all queued
+    /// events have been handled, and dropping it does so as well before stopping the thread.
+    /// Events logged by the routed appenders on the background thread itself are routed right
+    /// away rather than queued, and flushing from there does not wait, since either would have the
+    /// thread wait on itself.
+    ///
+    /// A `queue_size` of 0 is treated as 1.
+    ///
+    /// Defaults to handling events synchronously on the logging thread.
+    pub fn asynchronous(mut self, queue_size: usize, overflow: Overflow) -> RoutingAppenderBuilder {
+        self.asynchronous = Some((queue_size.max(1), overflow));
+        self
+    }
+
+    /// Consumes the builder, producing a `RoutingAppender`.
+    ///
+    /// No routed appender is built yet, unless `warmup_state` rebuilds the routes of a previous
+    /// run: each is built once a log event is routed to it, immediately before the event is
+    /// written, and events rejected by the routing appender's filters never reach the router. The
+    /// other appenders, such as the overflow appender of `max_cardinality`, the `cold_appender`,
+    /// the `spill_appender`, the `circuit_fallback`, the `quota_appender`, the `index_appender`,
+    /// the `mirror_appender` and the `reentrant_appender`, have already been built by the time
+    /// they are passed to the builder, and a routing appender loaded from a config file builds
+    /// them along with itself.
+    pub fn build(self, router: Box<dyn Route>) -> RoutingAppender {
+        let mut cache = Cache::new(self.idle_timeout);
+        if let Some(hysteresis) = self.hysteresis {
+            cache.set_hysteresis(hysteresis);
+        }
+        if let Some(max_lifetime) = self.max_lifetime {
+            cache.set_max_lifetime(max_lifetime);
+        }
+        cache.set_protect(self.protect);
+        cache.set_discard_unwritten(self.discard_unwritten);
+        cache.set_key_mode(self.key_mode);
+        cache.set_dedup_by_path(self.dedup_by_path);
+        if let Some(interval) = self.verify_files {
+            cache.set_verify_files(interval);
+        }
+        if let Some((max, overflow)) = self.max_cardinality {
+            cache.set_max_cardinality(max, overflow);
+        }
+        if let Some((after, appender)) = self.cold_appender {
+            cache.set_cold(after, appender);
+        }
+        if let Some(capacity) = self.max_capacity {
+            cache.set_capacity(capacity);
+            if let Some(min_ttl) = self.min_ttl {
+                cache.set_min_ttl(min_ttl);
+            }
+            cache.set_eviction_mode(self.eviction_mode);
+            if let Some(appender) = self.spill_appender {
+                cache.set_spill(appender);
+            }
+        }
+        if let Some(max) = self.max_routes {
+            cache.set_max_routes(max);
+        }
+        if let Some(max) = self.max_concurrent_builds {
+            cache.set_max_builds(max);
+        }
+        let circuit_breaker = self.circuit_breaker.is_some();
+        if let Some((threshold, window, probe)) = self.circuit_breaker {
+            cache.set_circuit_breaker(
+                threshold,
+                window,
+                probe,
+                self.circuit_min_writes,
+                self.circuit_fallback,
+            );
+        }
+        let quota = self.quota.is_some();
+        if let Some((bytes, window)) = self.quota {
+            cache.set_quota(bytes, window, self.quota_appender);
+        }
+        if let Some((appender, evictions)) = self.index_appender {
+            cache.set_index(appender, evictions);
+        }
+        #[cfg(feature = "prometheus")]
+        let metrics_name = self.metrics_name.clone();
+        if let Some(name) = self.metrics_name {
+            cache.set_metrics_name(name);
+        }
+        if let Some(sender) = self.eviction_channel {
+            cache.set_eviction_channel(sender);
+        }
+        #[cfg(feature = "file")]
+        if self.warmup_state.is_some() {
+            cache.set_warmup();
+        }
+        if let Some(clock) = self.clock {
+            cache.set_clock(clock);
+        }
+        #[cfg(feature = "chrono")]
+        cache.set_timezone(self.timezone);
+        let inner = Arc::new(Inner {
+            router,
+            fallback: self.fallback_router,
+            cache: Mutex::new(cache),
+            reentrant: self.reentrant_appender,
+            #[cfg(feature = "log-mdc")]
+            key_mdc: self.key_mdc,
+            slow_write_threshold: self.slow_write_threshold,
+            flush_above: self.flush_above,
+            flush_every_events: self.flush_every_events,
+            written: AtomicU64::new(0),
+            account: self.account,
+            mirror: self.mirror_appender,
+            #[cfg(feature = "tracing")]
+            mirror_tracing: self.mirror_tracing,
+            circuit_breaker,
+            quota,
+            #[cfg(feature = "file")]
+            warmup: self.warmup_state.map(StateFile::new),
+            #[cfg(feature = "prometheus")]
+            metrics_name,
+        });
+        #[cfg(feature = "file")]
+        inner.warm_up();
+        let worker = self
+            .asynchronous
+            .map(|(queue_size, overflow)| Worker::new(inner.clone(), queue_size, overflow));
+
+        let sweeper = self
+            .background_eviction
+            .map(|interval| Sweeper::new(inner.clone(), interval));
+
+        RoutingAppender {
+            inner,
+            max_message_bytes: self.max_message_bytes,
+            fingerprint: self.fingerprint,
+            worker,
+            sweeper,
+        }
+    }
+}
+
+/// A deserializer for the `RoutingAppender`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: routing
+///
+/// # The router used to determine the appender to use for a log event.
+/// # Required.
+/// router:
+///   kind: pattern
+///   pattern:
+///     kind: file
+///     path: "log/${mdc(job_id)}.log"
+///
+/// # The router to which the log events the router fails to route, such as
+/// # those lacking an MDC key its template refers to, are handed instead.
+/// # Optional.
+/// fallback:
+///   kind: pattern
+///   pattern:
+///     kind: file
+///     path: "log/unrouted.log"
+///
+/// # Configuration of the cache of appenders generated by the router.
+/// cache:
+///
+///   # The duration that a cached appender has been unused after which it
+///   # will be disposed of. Defaults to 2 minutes.
+///   idle_timeout: 2 minutes
+///
+///   # An appender recreated within this long of being evicted for being idle
+///   # has its idle timeout extended by as much, which stops appenders of keys
+///   # used at intervals close to the idle timeout from being repeatedly
+///   # evicted and recreated. Optional.
+///   hysteresis: 30 seconds
+///
+///   # The duration after which a cached appender is disposed of, however
+///   # recently it was used. Takes precedence over the idle timeout, hysteresis
+///   # and protection. Optional.
+///   max_lifetime: 1 hour
+///
+///   # Prefixes of the keys of appenders which are only evicted for being idle
+///   # once no other appenders are cached. Optional.
+///   protect:
+///     - error
+///
+///   # Whether to discard a routed appender whose first write fails, deleting
+///   # its file if it is empty, so that routes which never write an event leave
+///   # no empty files behind. Defaults to false.
+///   discard_unwritten: true
+///
+///   # How the keys of the cached appenders are stored. One of `string`, or
+///   # `hashed`, which replaces each key by a 128 bit hash to save memory when
+///   # keys are long and numerous, at the cost of a negligible chance of two
+///   # keys sharing an appender and of keys which can't be read back from
+///   # snapshots or the index. Defaults to `string`.
+///   key_mode: hashed
+///
+///   # The maximum number of routed appenders cached at once. Before another is
+///   # added to a full cache, the least recently used one is evicted, protected
+///   # ones only once no others are cached. Optional.
+///   capacity: 1000
+///
+///   # How long a newly created appender is spared from being evicted to make
+///   # room for another, as long as older ones can be evicted instead. Requires
+///   # `capacity`. Optional.
+///   min_ttl: 5 seconds
+///
+///   # How the appenders to evict are chosen. Optional.
+///   eviction:
+///
+///     # One of `idle`, under which appenders are evicted once idle and the
+///     # least recently used one makes room at capacity, or `lru` or `lfu`,
+///     # under which appenders are only evicted to make room, the least
+///     # recently or frequently used first. The frequency is the number of
+///     # lookups of the route since its appender was created. `lru` and `lfu`
+///     # require `capacity`. Defaults to `idle`.
+///     policy: lru
+///
+///   # What happens to the event of a new route when the cache is full of
+///   # protected appenders. `evict` evicts the least recently used of them, and
+///   # `spill` writes the event to the `spill_appender` instead, without building
+///   # an appender for the route. Defaults to `evict`.
+///   at_capacity: spill
+///
+///   # The appender for events spilled at capacity. The key of the route each
+///   # event was meant for is available to its encoder through `key_mdc`.
+///   # Required if, and only if, `at_capacity` is `spill`.
+///   spill_appender:
+///     kind: file
+///     path: "log/spill.log"
+///     encoder:
+///       pattern: "{X(route)} {m}{n}"
+///
+///   # The maximum number of routes cached at once. The events of new routes
+///   # beyond it fail with an error naming the route, rather than building an
+///   # appender, which surfaces a misconfigured pattern instead of exhausting
+///   # file descriptors. Must be at least 1. Optional.
+///   max_routes: 10000
+///
+///   # The maximum number of appenders built at once by all of the routing
+///   # appenders which set it. Routing an event to a new route beyond it waits
+///   # for another build to finish, for at most a second. Must be at least 1.
+///   # Optional.
+///   max_concurrent_builds: 4
+///
+///   # Whether keys whose appenders would write to the same file share a single
+///   # appender, rather than each building one which opens the file again.
+///   # Defaults to false.
+///   dedup_by_path: true
+///
+///   # The interval at which the files written by routed appenders are checked.
+///   # If a file has been deleted or replaced, its appender is recreated.
+///   # Optional.
+///   verify_files: 30 seconds
+///
+///   # The interval at which a background thread evicts the expired appenders,
+///   # which would otherwise only be evicted by the lookups of routes, so that
+///   # a quiet appender doesn't hold on to its files. Optional.
+///   background_eviction: 1 minute
+///
+/// # The name of an MDC entry which will be set to the key of the route while
+/// # the routed appender handles a log event. Optional.
+/// key_mdc: route
+///
+/// # The maximum length in bytes of a log event's message. Longer messages are
+/// # truncated and suffixed with `...`, to at most this length in all. Only the
+/// # message itself is limited, not the full output of the routed appender's
+/// # encoder. Either a number of bytes or a size with a unit: `B`, the decimal
+/// # `kB`, `MB`, `GB` and `TB`, or the binary `KiB`, `MiB`, `GiB` and `TiB`, in
+/// # any case. The size may have a fractional part, like `1.5 KiB`, as long as it
+/// # comes to a whole number of bytes. Optional.
+/// max_message_bytes: 16 KiB
+///
+/// # The duration after which a write to a routed appender is considered
+/// # stuck. Such an appender is evicted from the cache once its write completes,
+/// # and an error is reported. Optional.
+/// slow_write_threshold: 1 second
+///
+/// # The least severe level of the log events after which the routed appender
+/// # is flushed, so that they are durable right away. One of `error`, `warn`,
+/// # `info`, `debug` or `trace`. Optional.
+/// flush_above: warn
+///
+/// # The number of log events, counted across all routes, after which every
+/// # cached appender is flushed. Must be at least 1. Optional.
+/// flush_every_events: 1000
+///
+/// # Whether to count the log events and message bytes written by each routed
+/// # appender, as reported by `RoutingAppender::snapshot`. Defaults to false.
+/// account: true
+///
+/// # The timezone used by routers which depend on the time, unless they set
+/// # their own. One of `utc`, `local` or an offset from UTC such as `+05:30`.
+/// # Requires the `time-window-router` feature. Defaults to `utc`.
+/// timezone: utc
+///
+/// # The maximum number of distinct routing keys which will ever be given an
+/// # appender, including those whose appenders have since been evicted. Log
+/// # events routed to any other key are sent to the `overflow` appender. Every
+/// # key seen until the budget is reached is remembered, so memory use grows
+/// # with the budget. Optional.
+/// max_cardinality: 10000
+///
+/// # The appender for log events beyond the cardinality budget. Required if,
+/// # and only if, `max_cardinality` is set.
+/// overflow:
+///   kind: file
+///   path: "log/overflow.log"
+///
+/// # The duration after which a route which has received no events is cold:
+/// # its next event is written to the `cold_appender`, shared by all cold
+/// # routes, rather than to its own appender. A route warms back up once it
+/// # receives two events within this long of each other. Optional.
+/// cold_after: 10 minutes
+///
+/// # The appender for the events of cold routes. Required if, and only if,
+/// # `cold_after` is set.
+/// cold_appender:
+///   kind: file
+///   path: "log/cold.log"
+///
+/// # A circuit breaker for each routed appender. Once at least
+/// # `error_threshold` of an appender's writes within a `window` have failed,
+/// # and the window counts at least `min_writes` writes, its events are written
+/// # to the `fallback` appender instead, or dropped if there is none. Once
+/// # `probe` has passed, the next event is written to the appender to check
+/// # whether it has recovered. `error_threshold`, `window` and `probe` are
+/// # required, and `min_writes` defaults to 10. Optional.
+/// circuit:
+///   error_threshold: 0.5
+///   window: 30 seconds
+///   probe: 10 seconds
+///   min_writes: 10
+///   fallback:
+///     kind: file
+///     path: "log/fallback.log"
+///
+/// # A cap on the bytes of the messages each routed appender may write within
+/// # a fixed `window`, which starts with the appender's first event. Once an
+/// # event would exceed it, the appender's events are handled according to
+/// # `on_exceed` until its next window starts: `drop` discards them, and
+/// # `route_to` writes them to the provided appender. `bytes` and `window` are
+/// # required, and `on_exceed` defaults to `drop`. Optional.
+/// quota:
+///   bytes: 100 MiB
+///   window: 1 hour
+///   on_exceed:
+///     route_to:
+///       kind: file
+///       path: "log/over_quota.log"
+///
+/// # An appender to which a line is written whenever a routed appender is
+/// # created, recording its key and, if known, the file it writes to. Optional.
+/// index_appender:
+///   kind: file
+///   path: "log/routes.log"
+///   encoder:
+///     pattern: "{d} {m}{n}"
+///
+/// # Whether to also write a line to the index appender whenever a routed
+/// # appender is evicted, with the number of log events and bytes it wrote.
+/// # Requires `index_appender`. Defaults to false.
+/// index_evictions: true
+///
+/// # An appender to which every log event is also written once it has been
+/// # written to its routed appender, such as one forwarding events to another
+/// # logging system. Optional.
+/// mirror_appender:
+///   kind: file
+///   path: "log/all.log"
+///
+/// # Whether every log event is also emitted as a `tracing` event once it has
+/// # been written to its routed appender, with the target `log` and the
+/// # target of the log event in the `log.target` field. Requires the `tracing`
+/// # feature. Defaults to false.
+/// mirror_tracing: true
+///
+/// # An appender to which the log events logged back to this routing appender
+/// # while its cache is locked, such as by a routed appender being built, are
+/// # written. Such events are dropped if not set. Optional.
+/// reentrant_appender:
+///   kind: console
+///
+/// # A file recording the cached routes, from which they are rebuilt when the
+/// # appender is created, so that the first events logged after a restart
+/// # don't wait for their appenders to be created. Routes are rebuilt from the
+/// # level, target and the MDC entries read to route the events they were created
+/// # for, so this only suits routers which route on those. Optional.
+/// warmup_state: "log/.routing-state.json"
+///
+/// # The name under which the appender reports the metrics of its cache
+/// # through the `metrics` facade, with the `metrics` feature. Optional.
+/// metrics_name: jobs
+///
+/// # If present, log events are handed to a background thread which routes
+/// # them, so that logging does not block on the routed appenders. Optional.
+/// async:
+///
+///   # The maximum number of log events waiting to be handled. Must be at
+///   # least 1. Defaults to 10000.
+///   queue_size: 10000
+///
+///   # What to do with a log event logged while the queue is full. One of
+///   # `block` (wait for space), `drop` (discard the new event) or
+///   # `drop_oldest` (discard the oldest queued event). Defaults to `block`.
+///   overflow: drop
+///
+/// # The name of the profile to apply. Optional.
+/// profile: dev
+///
+/// # The name of an environment variable holding the name of the profile to
+/// # apply. If the variable is set, it takes precedence over `profile`.
+/// # Optional.
+/// profile_env: DEPLOY_ENV
+///
+/// # Overrides of the settings above, by profile name. The overrides of the
+/// # selected profile are merged into the rest of the configuration, with maps
+/// # merged key by key and any other value replaced outright. Optional.
+/// profiles:
+///   dev:
+///     cache:
+///       idle_timeout: 10 seconds
+///   prod:
+///     router:
+///       pattern:
+///         path: "/var/log/jobs/${mdc(job_id)}.log"
+/// ```
+#[cfg(feature = "file")]
+pub struct RoutingAppenderDeserializer;
+
+#[cfg(feature = "file")]
+impl Deserialize for RoutingAppenderDeserializer {
+    type Trait = dyn Append;
+    type Config = RoutingAppenderConfig;
+
+    fn deserialize(
+        &self,
+        config: RoutingAppenderConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        let config = config.0;
+        let mut builder = RoutingAppender::builder();
+        if let Some(idle_timeout) = config.cache.idle_timeout {
+            builder = builder.idle_timeout(idle_timeout);
+        }
+        if let Some(hysteresis) = config.cache.hysteresis {
+            builder = builder.hysteresis(hysteresis);
+        }
+        if let Some(max_lifetime) = config.cache.max_lifetime {
+            builder = builder.max_lifetime(max_lifetime);
+        }
+        for prefix in &config.cache.protect {
+            builder = builder.protect(prefix);
+        }
+        builder = builder.discard_unwritten(config.cache.discard_unwritten);
+        builder = builder.key_mode(config.cache.key_mode);
+        builder = builder.dedup_by_path(config.cache.dedup_by_path);
+        match config.cache.capacity {
+            Some(0) => return Err("cache capacity must be at least 1".into()),
+            Some(capacity) => builder = builder.max_capacity(capacity),
+            None if config.cache.at_capacity == AtCapacity::Spill => {
+                return Err("at_capacity requires a cache capacity".into())
+            }
+            None if config.cache.min_ttl.is_some() => {
+                return Err("min_ttl requires a cache capacity".into())
+            }
+            None if config.cache.eviction.policy != route::EvictionMode::Idle => {
+                return Err("eviction policies other than idle require a cache capacity".into())
+            }
+            None => {}
+        }
+        if let Some(min_ttl) = config.cache.min_ttl {
+            builder = builder.min_ttl(min_ttl);
+        }
+        builder = builder.eviction_mode(config.cache.eviction.policy);
+        match (config.cache.at_capacity, config.cache.spill_appender) {
+            (AtCapacity::Spill, Some(spill)) => {
+                let spill = deserializers.deserialize(&spill.kind, spill.config)?;
+                builder = builder.spill_appender(spill);
+            }
+            (AtCapacity::Evict, None) => {}
+            (AtCapacity::Spill, None) => {
+                return Err("at_capacity: spill requires a spill_appender".into())
+            }
+            (AtCapacity::Evict, Some(_)) => {
+                return Err("spill_appender requires at_capacity: spill".into())
+            }
+        }
+        match config.cache.max_routes {
+            Some(0) => return Err("max_routes must be at least 1".into()),
+            Some(max) => builder = builder.max_routes(max),
+            None => {}
+        }
+        match config.cache.max_concurrent_builds {
+            Some(0) => return Err("max_concurrent_builds must be at least 1".into()),
+            Some(max) => builder = builder.max_concurrent_builds(max),
+            None => {}
+        }
+        if let Some(interval) = config.cache.verify_files {
+            builder = builder.verify_files(interval);
+        }
+        if let Some(interval) = config.cache.background_eviction {
+            builder = builder.background_eviction(interval);
+        }
+        #[cfg(feature = "log-mdc")]
+        {
+            if let Some(key_mdc) = config.key_mdc {
+                builder = builder.key_mdc(&key_mdc);
+            }
+        }
+        if let Some(max_message_bytes) = config.max_message_bytes {
+            let max_message_bytes = usize::try_from(max_message_bytes)
+                .map_err(|_| format!("max_message_bytes {} is too large", max_message_bytes))?;
+            builder = builder.max_message_bytes(max_message_bytes);
+        }
+        if let Some(threshold) = config.slow_write_threshold {
+            builder = builder.slow_write_threshold(threshold);
+        }
+        if let Some(level) = config.flush_above {
+            builder = builder.flush_above(level);
+        }
+        if let Some(events) = config.flush_every_events {
+            if events == 0 {
+                return Err("flush_every_events must be at least 1".into());
+            }
+            builder = builder.flush_every_events(events);
+        }
+        builder = builder.account(config.account);
+        #[cfg(feature = "chrono")]
+        {
+            builder = builder.timezone(config.timezone);
+        }
+        match (config.max_cardinality, config.overflow) {
+            (Some(max), Some(overflow)) => {
+                let overflow = deserializers.deserialize(&overflow.kind, overflow.config)?;
+                builder = builder.max_cardinality(max, overflow);
+            }
+            (None, None) => {}
+            (Some(_), None) => return Err("max_cardinality requires an overflow appender".into()),
+            (None, Some(_)) => return Err("overflow requires max_cardinality".into()),
+        }
+        match (config.cold_after, config.cold_appender) {
+            (Some(after), Some(cold)) => {
+                let cold = deserializers.deserialize(&cold.kind, cold.config)?;
+                builder = builder.cold_appender(after, cold);
+            }
+            (None, None) => {}
+            (Some(_), None) => return Err("cold_after requires a cold_appender".into()),
+            (None, Some(_)) => return Err("cold_appender requires cold_after".into()),
+        }
+        if let Some(circuit) = config.circuit {
+            if !(circuit.error_threshold > 0. && circuit.error_threshold <= 1.) {
+                return Err("circuit error_threshold must be greater than 0 and at most 1".into());
+            }
+            let (window, probe) = match (circuit.window, circuit.probe) {
+                (Some(window), Some(probe)) => (window, probe),
+                (None, _) => return Err("missing field `window` of circuit".into()),
+                (_, None) => return Err("missing field `probe` of circuit".into()),
+            };
+            if window == Duration::from_secs(0) {
+                return Err("circuit window must be longer than zero".into());
+            }
+            builder = builder.circuit_breaker(circuit.error_threshold, window, probe);
+            if let Some(min) = circuit.min_writes {
+                if min == 0 {
+                    return Err("circuit min_writes must be at least 1".into());
+                }
+                builder = builder.circuit_min_writes(min);
+            }
+            if let Some(fallback) = circuit.fallback {
+                let fallback = deserializers.deserialize(&fallback.kind, fallback.config)?;
+                builder = builder.circuit_fallback(fallback);
+            }
+        }
+        if let Some(quota) = config.quota {
+            let (bytes, window) = match (quota.bytes, quota.window) {
+                (Some(bytes), Some(window)) => (bytes, window),
+                (None, _) => return Err("missing field `bytes` of quota".into()),
+                (_, None) => return Err("missing field `window` of quota".into()),
+            };
+            if bytes == 0 {
+                return Err("quota bytes must be at least 1".into());
+            }
+            if window == Duration::from_secs(0) {
+                return Err("quota window must be longer than zero".into());
+            }
+            builder = builder.quota(bytes, window);
+            if let OnExceed::RouteTo(overflow) = quota.on_exceed {
+                let overflow = deserializers.deserialize(&overflow.kind, overflow.config)?;
+                builder = builder.quota_appender(overflow);
+            }
+        }
+        match config.index_appender {
+            Some(index) => {
+                let index = deserializers.deserialize(&index.kind, index.config)?;
+                builder = builder.index_appender(index, config.index_evictions);
+            }
+            None if config.index_evictions => {
+                return Err("index_evictions requires an index_appender".into())
+            }
+            None => {}
+        }
+        if let Some(mirror) = config.mirror_appender {
+            let mirror = deserializers.deserialize(&mirror.kind, mirror.config)?;
+            builder = builder.mirror_appender(mirror);
+        }
+        #[cfg(feature = "tracing")]
+        {
+            builder = builder.mirror_tracing(config.mirror_tracing);
+        }
+        if let Some(reentrant) = config.reentrant_appender {
+            let reentrant = deserializers.deserialize(&reentrant.kind, reentrant.config)?;
+            builder = builder.reentrant_appender(reentrant);
+        }
+        if let Some(path) = config.warmup_state {
+            builder = builder.warmup_state(path);
+        }
+        if let Some(name) = config.metrics_name {
+            builder = builder.metrics_name(&name);
+        }
+        if let Some(asynchronous) = config.asynchronous {
+            if asynchronous.queue_size == 0 {
+                return Err("async queue_size must be at least 1".into());
+            }
+            builder = builder.asynchronous(asynchronous.queue_size, asynchronous.overflow);
+        }
+        let mut hasher = DefaultHasher::new();
+        config.router.hash(&mut hasher);
+        config.fallback.hash(&mut hasher);
+        // the keys the routers derive are stored differently in each key mode
+        config.cache.key_mode.hash(&mut hasher);
+        builder = builder.fingerprint(hasher.finish());
+        if let Some(fallback) = config.fallback {
+            let fallback = deserializers.deserialize(&fallback.kind, fallback.config)?;
+            builder = builder.fallback_router(fallback);
+        }
+        let router = deserializers.deserialize(&config.router.kind, config.router.config)?;
+        Ok(Box::new(builder.build(router)))
+    }
 }
 
 #[cfg(feature = "file")]
@@ -262,7 +2619,7 @@ where
                 {
                     humantime::parse_duration(v)
                         .map(S)
-                        .map_err(|e| E::custom(&e.to_string()))
+                        .map_err(|e| E::custom(e.to_string()))
                 }
             }
 
@@ -273,10 +2630,213 @@ where
     Option::<S>::deserialize(d).map(|d| d.map(|d| d.0))
 }
 
+#[cfg(feature = "file")]
+fn de_size<'de, D>(d: D) -> Result<Option<u64>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    struct S(u64);
+
+    impl<'de2> de::Deserialize<'de2> for S {
+        fn deserialize<D>(d: D) -> Result<S, D::Error>
+        where
+            D: de::Deserializer<'de2>,
+        {
+            struct V;
+
+            impl<'de3> de::Visitor<'de3> for V {
+                type Value = S;
+
+                fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+                    fmt.write_str("a number of bytes or a size such as `16 MiB`")
+                }
+
+                fn visit_u64<E>(self, v: u64) -> Result<S, E>
+                where
+                    E: de::Error,
+                {
+                    Ok(S(v))
+                }
+
+                fn visit_i64<E>(self, v: i64) -> Result<S, E>
+                where
+                    E: de::Error,
+                {
+                    u64::try_from(v)
+                        .map(S)
+                        .map_err(|_| E::custom(format!("invalid size `{}`", v)))
+                }
+
+                fn visit_str<E>(self, v: &str) -> Result<S, E>
+                where
+                    E: de::Error,
+                {
+                    parse_size(v).map(S).map_err(E::custom)
+                }
+            }
+
+            d.deserialize_any(V)
+        }
+    }
+
+    Option::<S>::deserialize(d).map(|d| d.map(|d| d.0))
+}
+
+/// Parses a size such as `16 MiB` or `1GB` into a number of bytes.
+#[cfg(feature = "file")]
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let multiplier: u64 = match &*unit.trim_start().to_ascii_lowercase() {
+        "" | "b" => 1,
+        "kb" => 1000,
+        "mb" => 1000 * 1000,
+        "gb" => 1000 * 1000 * 1000,
+        "tb" => 1000 * 1000 * 1000 * 1000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => return Err(format!("unknown size unit in `{}`", s)),
+    };
+
+    if let Ok(n) = number.parse::<u64>() {
+        return n
+            .checked_mul(multiplier)
+            .ok_or_else(|| format!("size `{}` is too large", s));
+    }
+    let bytes = match number.parse::<f64>() {
+        Ok(n) => n * multiplier as f64,
+        Err(_) => return Err(format!("invalid size `{}`", s)),
+    };
+    if bytes.fract() != 0. {
+        return Err(format!("size `{}` is not a whole number of bytes", s));
+    }
+    if bytes >= u64::MAX as f64 {
+        return Err(format!("size `{}` is too large", s));
+    }
+    Ok(bytes as u64)
+}
+
+#[cfg(feature = "file")]
+fn de_level<'de, D>(d: D) -> Result<Option<Level>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    let level = match Option::<String>::deserialize(d)? {
+        Some(level) => level,
+        None => return Ok(None),
+    };
+    level
+        .parse()
+        .map(Some)
+        .map_err(|_| de::Error::custom(format!("invalid level `{}`", level)))
+}
+
 trait CacheInner {
-    fn new(expiration: Duration) -> Cache;
+    fn new(expiration: Duration) -> Self;
+
+    fn set_verify_files(&mut self, interval: Duration);
+
+    fn set_hysteresis(&mut self, hysteresis: Duration);
+
+    fn set_max_lifetime(&mut self, max_lifetime: Duration);
+
+    fn set_min_ttl(&mut self, min_ttl: Duration);
+
+    fn set_eviction_mode(&mut self, mode: route::EvictionMode);
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>);
+
+    fn set_protect(&mut self, prefixes: Vec<String>);
+
+    fn set_quota(&mut self, bytes: u64, window: Duration, overflow: Option<Box<dyn Append>>);
+
+    fn set_circuit_breaker(
+        &mut self,
+        threshold: f64,
+        window: Duration,
+        probe: Duration,
+        min_writes: u64,
+        fallback: Option<Box<dyn Append>>,
+    );
+
+    fn set_max_cardinality(&mut self, max: usize, overflow: Box<dyn Append>);
+
+    fn set_cold(&mut self, after: Duration, appender: Box<dyn Append>);
+
+    fn set_capacity(&mut self, capacity: usize);
+
+    fn set_spill(&mut self, appender: Box<dyn Append>);
+
+    fn set_max_routes(&mut self, max: usize);
+
+    fn set_max_builds(&mut self, max: usize);
+
+    fn take_refusal(&mut self) -> Option<Box<dyn Error + Sync + Send>>;
+
+    fn set_discard_unwritten(&mut self, discard: bool);
+
+    fn set_key_mode(&mut self, key_mode: route::KeyMode);
+
+    fn key_mode(&self) -> route::KeyMode;
+
+    fn set_dedup_by_path(&mut self, dedup: bool);
+
+    fn set_metrics_name(&mut self, name: String);
+
+    fn set_index(&mut self, appender: Box<dyn Append>, evictions: bool);
+
+    fn set_eviction_channel(&mut self, sender: SyncSender<EvictionEvent>);
+
+    #[cfg(feature = "file")]
+    fn set_warmup(&mut self);
+
+    #[cfg(feature = "chrono")]
+    fn set_timezone(&mut self, timezone: Timezone);
+
+    fn inherit(&mut self, old: &mut Self);
+
+    fn evict(&mut self, appender: &route::Appender);
+
+    fn record_error(&mut self, appender: &route::Appender, error: String);
+
+    fn record_success(&mut self, appender: &route::Appender);
+
+    fn divert(&mut self, appender: &route::Appender) -> Option<route::Diversion>;
+
+    fn charge(&mut self, appender: &route::Appender, bytes: u64) -> Option<route::Diversion>;
+
+    #[cfg(feature = "file")]
+    fn record_origin<F>(&mut self, origin: F)
+    where
+        F: Fn() -> Origin;
+
+    #[cfg(feature = "file")]
+    fn warmup_routes(&mut self) -> Option<Vec<warmup::Route>>;
+
+    fn record_write(&mut self, appender: &route::Appender, bytes: u64);
+
+    fn snapshot(&self) -> RoutingSnapshot;
+
+    fn stats(&self) -> CacheStats;
+
+    fn drain(&mut self);
+
+    fn sweep(&mut self);
+
+    fn matching(&self, pred: &dyn Fn(&str) -> bool) -> Vec<route::Appender>;
+
+    fn appenders(&self) -> Vec<route::Appender>;
+
+    fn flush_index(&self);
 }
 
 trait AppenderInner {
     fn appender(&self) -> &dyn Append;
+
+    fn set_written(&self);
 }
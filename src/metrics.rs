@@ -0,0 +1,31 @@
+//! Metrics.
+//!
+//! With the `metrics` feature, named routing appenders report the state of their caches through
+//! the [`metrics`](https://crates.io/crates/metrics) facade, to whichever recorder the
+//! application has installed. An appender is named with `RoutingAppenderBuilder::metrics_name`,
+//! or the `metrics_name` field of its configuration, and the name is the `appender` label of each
+//! metric. Appenders without a name report nothing.
+//!
+//! The following metrics are reported:
+//!
+//! * `routing_appender.cache.hits`: a counter of the lookups which found a cached appender.
+//! * `routing_appender.cache.misses`: a counter of the lookups which did not find a cached
+//!   appender.
+//! * `routing_appender.cache.evictions`: a counter of the appenders removed from the cache other
+//!   than by reconfiguration.
+//! * `routing_appender.cache.live`: a gauge of the number of cached appenders.
+//!
+//! The state of a single appender's cache can also be rendered in the Prometheus text exposition
+//! format with `RoutingAppender::snapshot_prometheus`, which requires the `prometheus` feature.
+//!
+//! The metrics are reported while the appender's cache is locked, so the recorder should be fast
+//! and must not log to a routing appender.
+
+/// The name of the counter of cache hits.
+pub const HITS: &str = "routing_appender.cache.hits";
+/// The name of the counter of cache misses.
+pub const MISSES: &str = "routing_appender.cache.misses";
+/// The name of the counter of cache evictions.
+pub const EVICTIONS: &str = "routing_appender.cache.evictions";
+/// The name of the gauge of cached appenders.
+pub const LIVE: &str = "routing_appender.cache.live";
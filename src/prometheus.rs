@@ -0,0 +1,126 @@
+//! Rendering of snapshots in the Prometheus text exposition format.
+use std::fmt::Write;
+
+use metrics::{EVICTIONS, HITS, LIVE, MISSES};
+use {CircuitState, EntrySnapshot, RoutingSnapshot};
+
+// the name, type, and help text of a per-appender series, and its value for an appender
+type Series = (&'static str, &'static str, &'static str, fn(&EntrySnapshot) -> String);
+
+/// Renders a snapshot in the Prometheus text exposition format.
+pub fn render(snapshot: &RoutingSnapshot, appender: Option<&str>) -> String {
+    let mut labels = String::new();
+    if let Some(appender) = appender {
+        labels.push_str("appender=\"");
+        escape_label(&mut labels, appender);
+        labels.push('"');
+    }
+
+    let mut out = String::new();
+    let cache = [
+        (HITS, "counter", "The number of lookups which found a cached appender.", snapshot.hits),
+        (
+            MISSES,
+            "counter",
+            "The number of lookups which did not find a cached appender.",
+            snapshot.misses,
+        ),
+        (
+            EVICTIONS,
+            "counter",
+            "The number of appenders removed from the cache other than by reconfiguration.",
+            snapshot.evictions,
+        ),
+        (LIVE, "gauge", "The number of cached appenders.", snapshot.entries.len() as u64),
+    ];
+    for &(name, kind, help, value) in &cache {
+        let name = metric_name(name, kind);
+        header(&mut out, &name, kind, help);
+        sample(&mut out, &name, &labels, None, &value.to_string());
+    }
+
+    let entries: [Series; 5] = [
+        (
+            "routing_appender_entry_events_total",
+            "counter",
+            "The number of log events written by a cached appender.",
+            |e| e.events.to_string(),
+        ),
+        (
+            "routing_appender_entry_bytes_total",
+            "counter",
+            "The total length in bytes of the messages written by a cached appender.",
+            |e| e.bytes.to_string(),
+        ),
+        (
+            "routing_appender_entry_idle_seconds",
+            "gauge",
+            "The time since a cached appender was last used.",
+            |e| e.idle.as_secs_f64().to_string(),
+        ),
+        (
+            "routing_appender_entry_failed",
+            "gauge",
+            "Whether the last error returned by a cached appender is still recorded.",
+            |e| (e.last_error.is_some() as u8).to_string(),
+        ),
+        (
+            "routing_appender_entry_circuit_open",
+            "gauge",
+            "Whether the circuit breaker of a cached appender is diverting its log events.",
+            |e| ((e.circuit != CircuitState::Closed) as u8).to_string(),
+        ),
+    ];
+    for &(name, kind, help, value) in &entries {
+        if snapshot.entries.is_empty() {
+            break;
+        }
+        header(&mut out, name, kind, help);
+        for entry in &snapshot.entries {
+            sample(&mut out, name, &labels, Some(&entry.key), &value(entry));
+        }
+    }
+    out
+}
+
+fn metric_name(name: &str, kind: &str) -> String {
+    let mut name = name.replace('.', "_");
+    if kind == "counter" {
+        name.push_str("_total");
+    }
+    name
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, key: Option<&str>, value: &str) {
+    out.push_str(name);
+    if !labels.is_empty() || key.is_some() {
+        out.push('{');
+        out.push_str(labels);
+        if let Some(key) = key {
+            if !labels.is_empty() {
+                out.push(',');
+            }
+            out.push_str("key=\"");
+            escape_label(out, key);
+            out.push('"');
+        }
+        out.push('}');
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+fn escape_label(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! A router which groups log events into buckets of a fixed width by a numeric MDC entry.
+//!
+//! The value of the [MDC][MDC] entry is parsed as a number, and the event is routed to the bucket
+//! labelled `floor(value / width) * width`, so with a width of 100, the values 0 to 99.9 land in
+//! bucket `0`, 100 to 199.9 in bucket `100`, and -1 in bucket `-100`. Unlike a router with
+//! explicitly configured bands, this needs no knowledge of the range of the values, and the
+//! number of appenders is bounded by that range divided by the width. Labels are written without
+//! a fractional part whenever the bucket's bound is a whole number.
+//!
+//! The appender for each bucket is built from a template, as in the [pattern router][pattern],
+//! which may additionally refer to the label of the bucket with `${bucket}`. Events whose entry is
+//! absent or not a number are routed to the default appender.
+//!
+//! Requires the `range-router` feature.
+//!
+//! # Examples
+//!
+//! ```yaml
+//! kind: bucketed_numeric
+//! key: latency_ms
+//! width: 100
+//! pattern:
+//!   kind: file
+//!   path: "log/latency-${bucket}.log"
+//! default:
+//!   kind: file
+//!   path: "log/latency-unknown.log"
+//! ```
+//!
+//! [MDC]: https://crates.io/crates/log-mdc
+//! [pattern]: ../pattern/index.html
+use log::Record;
+use log4rs::file::{Deserialize, Deserializers};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use route::{self, Appender, AppenderConfig, Cache, Entry, Route};
+use route::pattern::template::Template;
+use warmup;
+
+/// Configuration for the `BucketedNumericRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BucketedNumericRouterConfig {
+    key: String,
+    width: f64,
+    pattern: AppenderConfig,
+    default: Option<AppenderConfig>,
+}
+
+/// A router which groups log events into buckets of a fixed width by a numeric MDC entry.
+pub struct BucketedNumericRouter {
+    deserializers: Deserializers,
+    key: String,
+    width: f64,
+    kind: String,
+    config: Template,
+    default: Option<AppenderConfig>,
+}
+
+impl fmt::Debug for BucketedNumericRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("BucketedNumericRouter")
+            .field("key", &self.key)
+            .field("width", &self.width)
+            .finish()
+    }
+}
+
+impl BucketedNumericRouter {
+    /// Returns the label of the bucket of the current MDC, if the entry holds a number.
+    fn bucket(&self) -> Option<String> {
+        let value =
+            warmup::get_mdc(&self.key, |v| v.and_then(|v| v.trim().parse::<f64>().ok()))?;
+        if !value.is_finite() {
+            return None;
+        }
+        let bound = (value / self.width).floor() * self.width;
+        // `-0` would otherwise be a label of its own
+        let bound = if bound == 0. { 0. } else { bound };
+        if bound.fract() == 0. && bound.abs() < 1e15 {
+            Some((bound as i64).to_string())
+        } else {
+            Some(bound.to_string())
+        }
+    }
+}
+
+impl Route for BucketedNumericRouter {
+    fn route(&self, record: &Record, cache: &mut Cache) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+        let bucket = match self.bucket() {
+            Some(bucket) => bucket,
+            None => {
+                let default = match self.default {
+                    Some(ref default) => default,
+                    None => {
+                        return Err(format!("MDC key `{}` does not hold a number", self.key).into())
+                    }
+                };
+                // bucket keys always contain a `:`, so this can't collide with them
+                return match cache.try_entry("default".to_owned())? {
+                    Entry::Occupied(e) => Ok(e.into_value()),
+                    Entry::Vacant(e) => {
+                        let appender = self
+                            .deserializers
+                            .deserialize(&default.kind, default.config.clone())?;
+                        Ok(e.insert(appender))
+                    }
+                };
+            }
+        };
+
+        match cache.try_entry(format!("{}:{}", bucket, self.config.key(record)))? {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => {
+                let config = self.config.expand(record, &[("bucket", &bucket)])?;
+                let path = route::file_path(&config);
+                let e = match path {
+                    Some(ref path) => {
+                        route::check_path(path)?;
+                        match e.share_file(path) {
+                            Ok(appender) => return Ok(appender),
+                            Err(e) => e,
+                        }
+                    }
+                    None => e,
+                };
+                let appender = self.deserializers.deserialize(&self.kind, config)?;
+                match path {
+                    Some(path) => Ok(e.insert_file(appender, path)),
+                    None => Ok(e.insert(appender)),
+                }
+            }
+        }
+    }
+}
+
+/// A deserializer for the `BucketedNumericRouter`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: bucketed_numeric
+///
+/// # The MDC key whose value determines the bucket. Required.
+/// key: latency_ms
+///
+/// # The width of each bucket. Must be a positive number. Required.
+/// width: 100
+///
+/// # The configuration template of a bucket's appender. In addition to the
+/// # formatters supported by the pattern router, `${bucket}` expands to the
+/// # label of the bucket. Required.
+/// pattern:
+///   kind: file
+///   path: "log/latency-${bucket}.log"
+///
+/// # The appender used for log events whose MDC entry is absent or not a
+/// # number. If not set, routing such an event is an error. Optional.
+/// default:
+///   kind: file
+///   path: "log/latency-unknown.log"
+/// ```
+pub struct BucketedNumericRouterDeserializer;
+
+impl Deserialize for BucketedNumericRouterDeserializer {
+    type Trait = dyn Route;
+    type Config = BucketedNumericRouterConfig;
+
+    fn deserialize(
+        &self,
+        config: BucketedNumericRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<dyn Route>, Box<dyn Error + Sync + Send>> {
+        if !(config.width.is_finite() && config.width > 0.) {
+            return Err("width must be a positive number".into());
+        }
+
+        Ok(Box::new(BucketedNumericRouter {
+            deserializers: deserializers.clone(),
+            key: config.key,
+            width: config.width,
+            kind: config.pattern.kind,
+            config: Template::new(&config.pattern.config, HashMap::new(), &["bucket"])?,
+            default: config.default,
+        }))
+    }
+}
@@ -0,0 +1,254 @@
+//! A router which delegates its routing decisions to a closure.
+//!
+//! The closure is passed each log event and returns a `RouteDecision`, which identifies the
+//! appender the event is sent to by its cache key and knows how to build it. The appender is
+//! built the first time its key is returned, and the cached appender is reused for as long as it
+//! is not evicted, so the closure should return the same key for events which belong together and
+//! avoid any expensive work outside of the decision's builder. This allows routing rules which
+//! live outside of the logging configuration, for example in a control plane, to be plugged into
+//! the routing appender while still benefiting from its cache.
+//!
+//! Since the closure is provided in code, this router can't be configured through a log4rs
+//! configuration file and is not registered with the routing appender's deserializers. It also
+//! bypasses the other routers entirely: templates, defaults and fallbacks are up to the closure.
+//! An event for which the closure returns `None` is not written, and routing it is an error.
+//!
+//! Alternatively, the router can be created from two separate closures with
+//! `DynamicRouter::grouped`: one maps each log event to the ID of its group, whose events share an
+//! appender, and the other builds the appender of a group from the first event routed to it. This
+//! decouples which events share a cache entry from the configuration of the appender they are
+//! written to, and avoids creating a builder for every event, since the second closure is only
+//! called on a cache miss. Both closures can consult the MDC or any other context of the logging
+//! thread. Either way, the appenders are cached under the returned keys like those of any other
+//! router, so they are evicted when idle and reported by `RoutingAppender::snapshot`.
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate log;
+//! # extern crate log4rs;
+//! # extern crate log4rs_routing_appender;
+//! # use log::Record;
+//! # use log4rs::append::Append;
+//! # use std::error::Error;
+//! # #[derive(Debug)]
+//! # struct TenantAppender(String);
+//! # impl Append for TenantAppender {
+//! #     fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> { Ok(()) }
+//! #     fn flush(&self) {}
+//! # }
+//! use log4rs_routing_appender::route::dynamic::{DynamicRouter, RouteDecision};
+//! use log4rs_routing_appender::RoutingAppender;
+//!
+//! # fn main() {
+//! let router = DynamicRouter::new(|record: &Record| {
+//!     // look up the owner of the target in some external source of routing rules
+//!     let tenant = record.target().split("::").next()?.to_owned();
+//!     Some(RouteDecision::new(tenant.clone(), move || {
+//!         Ok(Box::new(TenantAppender(tenant)) as Box<dyn Append>)
+//!     }))
+//! });
+//! let appender = RoutingAppender::builder().build(Box::new(router));
+//! # let _ = appender;
+//! # }
+//! ```
+use log::Record;
+use log4rs::append::Append;
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+
+use route::{Appender, Cache, Entry, Route};
+
+type Build = Box<dyn FnOnce() -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>>>;
+type Decide = Box<dyn Fn(&Record) -> Option<RouteDecision> + Sync + Send>;
+type Group = Box<dyn Fn(&Record) -> Option<String> + Sync + Send>;
+type BuildGroup = Box<
+    dyn Fn(&Record, &str) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> + Sync + Send,
+>;
+
+enum Routing {
+    Decide(Decide),
+    Grouped { group: Group, build: BuildGroup },
+}
+
+/// The decision of a `DynamicRouter`'s closure for a log event.
+pub struct RouteDecision {
+    key: String,
+    path: Option<PathBuf>,
+    build: Build,
+}
+
+impl fmt::Debug for RouteDecision {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("RouteDecision")
+            .field("key", &self.key)
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl RouteDecision {
+    /// Creates a decision to route a log event to the appender cached under `key`, which is built
+    /// by `build` if it is not cached.
+    ///
+    /// Keys starting with `!` are reserved for the routing appender's own use.
+    pub fn new<F>(key: String, build: F) -> RouteDecision
+    where
+        F: FnOnce() -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> + 'static,
+    {
+        RouteDecision {
+            key,
+            path: None,
+            build: Box::new(build),
+        }
+    }
+
+    /// Sets the path of the file the appender writes to.
+    ///
+    /// If the cache is configured to verify files, the appender is rebuilt if the file is deleted
+    /// or replaced.
+    pub fn path(mut self, path: PathBuf) -> RouteDecision {
+        self.path = Some(path);
+        self
+    }
+
+    /// Returns the cache key of the decision.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+/// A router which delegates its routing decisions to a closure.
+pub struct DynamicRouter {
+    routing: Routing,
+}
+
+impl fmt::Debug for DynamicRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("DynamicRouter").finish()
+    }
+}
+
+impl DynamicRouter {
+    /// Creates a router which routes log events as decided by `decide`.
+    ///
+    /// The closure is called with the routing appender's cache locked, so the events it logs to
+    /// the routing appender itself are handled as described in
+    /// `RoutingAppenderBuilder::reentrant_appender`.
+    pub fn new<F>(decide: F) -> DynamicRouter
+    where
+        F: Fn(&Record) -> Option<RouteDecision> + 'static + Sync + Send,
+    {
+        DynamicRouter {
+            routing: Routing::Decide(Box::new(decide)),
+        }
+    }
+
+    /// Creates a router which routes log events to the appender of the group identified by
+    /// `group`, building it with `build` if it is not cached.
+    ///
+    /// `build` is passed the first log event routed to the group since its appender was last
+    /// cached, along with the group's ID. Events for which `group` returns `None` are not
+    /// written, and routing them is an error. IDs starting with `!` are reserved for the routing
+    /// appender's own use.
+    ///
+    /// The closures are called with the routing appender's cache locked, so the events they log
+    /// to the routing appender itself are handled as described in
+    /// `RoutingAppenderBuilder::reentrant_appender`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # extern crate log;
+    /// # extern crate log4rs;
+    /// # extern crate log4rs_routing_appender;
+    /// # extern crate log_mdc;
+    /// # use log::Record;
+    /// # use log4rs::append::file::FileAppender;
+    /// # use log4rs::append::Append;
+    /// # use log4rs_routing_appender::route::dynamic::DynamicRouter;
+    /// # fn plan_of(_: &str) -> &'static str { "free" }
+    /// # fn main() {
+    /// // events share an appender per tenant, whose file depends on the tenant's plan
+    /// let router = DynamicRouter::grouped(
+    ///     |_: &Record| log_mdc::get("tenant", |t| t.map(str::to_owned)),
+    ///     |_: &Record, tenant: &str| {
+    ///         let path = format!("log/{}/{}.log", plan_of(tenant), tenant);
+    ///         Ok(Box::new(FileAppender::builder().build(path)?) as Box<dyn Append>)
+    ///     },
+    /// );
+    /// # drop(router);
+    /// # }
+    /// ```
+    pub fn grouped<G, B>(group: G, build: B) -> DynamicRouter
+    where
+        G: Fn(&Record) -> Option<String> + 'static + Sync + Send,
+        B: Fn(&Record, &str) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>>
+            + 'static
+            + Sync
+            + Send,
+    {
+        DynamicRouter {
+            routing: Routing::Grouped {
+                group: Box::new(group),
+                build: Box::new(build),
+            },
+        }
+    }
+}
+
+fn check_key(key: &str) -> Result<(), Box<dyn Error + Sync + Send>> {
+    if key.starts_with('!') {
+        return Err(format!("route key `{}` is reserved", key).into());
+    }
+    Ok(())
+}
+
+impl Route for DynamicRouter {
+    fn route(&self, record: &Record, cache: &mut Cache) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+        let decide = match self.routing {
+            Routing::Decide(ref decide) => decide,
+            Routing::Grouped {
+                ref group,
+                ref build,
+            } => {
+                let key = match group(record) {
+                    Some(key) => key,
+                    None => return Err("no group was identified for the log event".into()),
+                };
+                check_key(&key)?;
+                return match cache.try_entry(key)? {
+                    Entry::Occupied(e) => Ok(e.into_value()),
+                    Entry::Vacant(e) => {
+                        let appender = build(record, e.key())?;
+                        Ok(e.insert(appender))
+                    }
+                };
+            }
+        };
+        let decision = match decide(record) {
+            Some(decision) => decision,
+            None => return Err("no route was decided for the log event".into()),
+        };
+        check_key(&decision.key)?;
+
+        match cache.try_entry(decision.key)? {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => {
+                let e = match decision.path {
+                    Some(ref path) => match e.share_file(path) {
+                        Ok(appender) => return Ok(appender),
+                        Err(e) => e,
+                    },
+                    None => e,
+                };
+                let appender = (decision.build)()?;
+                match decision.path {
+                    Some(path) => Ok(e.insert_file(appender, path)),
+                    None => Ok(e.insert(appender)),
+                }
+            }
+        }
+    }
+}
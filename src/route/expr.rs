@@ -0,0 +1,362 @@
+//! A router which selects an appender by evaluating rules written as expressions over the MDC.
+//!
+//! The rules are evaluated in order, and the event is routed to the appender of the first one
+//! whose expression is true, or to the default appender if none are. Expressions are made up of
+//! the following:
+//!
+//! * `mdc(key)` - The value of an entry of the [MDC][MDC].
+//! * `"text"` - A string literal. `\"` and `\\` escape a quote and a backslash respectively.
+//! * `a == b` and `a != b` - Compares two values, each either an MDC entry or a string literal. An
+//!   absent MDC entry is not equal to any string.
+//! * `present(key)` - True if the MDC entry is present.
+//! * `a && b` and `a || b` - Logical and and or, with `&&` binding more tightly than `||`.
+//! * `(a)` - Grouping.
+//!
+//! MDC keys may be written bare, as above, or as string literals if they contain spaces or
+//! parentheses. To keep evaluation cheap, an expression may consist of at most 64 terms, and be
+//! nested at most 16 levels deep. Expressions are parsed when the router is configured, so any
+//! errors are reported then.
+//!
+//! Requires the `expr-router` feature.
+//!
+//! # Examples
+//!
+//! ```yaml
+//! kind: expr
+//! rules:
+//!   - when: 'mdc(env) == "prod" && (mdc(tier) == "db" || present(query_id))'
+//!     appender:
+//!       kind: file
+//!       path: "log/prod-db.log"
+//! default:
+//!   kind: file
+//!   path: "log/app.log"
+//! ```
+//!
+//! [MDC]: https://crates.io/crates/log-mdc
+use log::Record;
+use log4rs::file::{Deserialize, Deserializers};
+use std::error::Error;
+use std::fmt;
+
+use route::{Appender, AppenderConfig, Cache, Entry, Route};
+use warmup;
+
+const MAX_TERMS: usize = 64;
+const MAX_DEPTH: usize = 16;
+
+/// Configuration for the `ExprRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExprRouterConfig {
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+    default: Option<AppenderConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    when: String,
+    appender: AppenderConfig,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Eq(Operand, Operand),
+    Ne(Operand, Operand),
+    Present(String),
+}
+
+impl Expr {
+    fn eval(&self) -> bool {
+        match *self {
+            Expr::Or(ref a, ref b) => a.eval() || b.eval(),
+            Expr::And(ref a, ref b) => a.eval() && b.eval(),
+            Expr::Eq(ref a, ref b) => a.with(|a| b.with(|b| a.is_some() && a == b)),
+            Expr::Ne(ref a, ref b) => a.with(|a| b.with(|b| a.is_none() || a != b)),
+            Expr::Present(ref key) => warmup::get_mdc(key, |v| v.is_some()),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Operand {
+    Mdc(String),
+    Literal(String),
+}
+
+impl Operand {
+    fn with<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(Option<&str>) -> T,
+    {
+        match *self {
+            Operand::Mdc(ref key) => warmup::get_mdc(key, f),
+            Operand::Literal(ref s) => f(Some(s)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    Eq,
+    Ne,
+    And,
+    Or,
+    Str(String),
+    Ident(String),
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut it = s.char_indices().peekable();
+    while let Some((i, c)) = it.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '=' | '!' | '&' | '|' => match (c, it.next()) {
+                ('=', Some((_, '='))) => Token::Eq,
+                ('!', Some((_, '='))) => Token::Ne,
+                ('&', Some((_, '&'))) => Token::And,
+                ('|', Some((_, '|'))) => Token::Or,
+                _ => return Err(format!("unexpected `{}` at offset {}", c, i)),
+            },
+            '"' => {
+                let mut lit = String::new();
+                loop {
+                    match it.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match it.next() {
+                            Some((_, c)) if c == '"' || c == '\\' => lit.push(c),
+                            _ => return Err(format!("invalid escape in string at offset {}", i)),
+                        },
+                        Some((_, c)) => lit.push(c),
+                        None => return Err(format!("unterminated string at offset {}", i)),
+                    }
+                }
+                Token::Str(lit)
+            }
+            c => {
+                let mut ident = c.to_string();
+                while let Some(&(_, c)) = it.peek() {
+                    if c.is_whitespace() || "()=!&|\"".contains(c) {
+                        break;
+                    }
+                    ident.push(c);
+                    it.next();
+                }
+                Token::Ident(ident)
+            }
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    terms: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn parse(s: &str) -> Result<Expr, String> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+            terms: 0,
+            depth: 0,
+        };
+        let expr = parser.or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(expr),
+            Some(t) => Err(format!("unexpected {:?}", t)),
+        }
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.tokens.get(self.pos) == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &Token) -> Result<(), String> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(format!("expected {:?}", token))
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.eat(&Token::Or) {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.term()?;
+        while self.eat(&Token::And) {
+            expr = Expr::And(Box::new(expr), Box::new(self.term()?));
+        }
+        Ok(expr)
+    }
+
+    fn term(&mut self) -> Result<Expr, String> {
+        self.terms += 1;
+        if self.terms > MAX_TERMS {
+            return Err(format!("expression has more than {} terms", MAX_TERMS));
+        }
+
+        if self.eat(&Token::LParen) {
+            self.depth += 1;
+            if self.depth > MAX_DEPTH {
+                return Err(format!("expression is nested more than {} levels", MAX_DEPTH));
+            }
+            let expr = self.or()?;
+            self.expect(&Token::RParen)?;
+            self.depth -= 1;
+            return Ok(expr);
+        }
+
+        if self.tokens.get(self.pos) == Some(&Token::Ident("present".to_owned())) {
+            self.pos += 1;
+            return Ok(Expr::Present(self.key()?));
+        }
+
+        let lhs = self.operand()?;
+        match self.next() {
+            Some(&Token::Eq) => Ok(Expr::Eq(lhs, self.operand()?)),
+            Some(&Token::Ne) => Ok(Expr::Ne(lhs, self.operand()?)),
+            _ => Err("expected `==` or `!=`".to_owned()),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        match self.next() {
+            Some(Token::Str(s)) => Ok(Operand::Literal(s.clone())),
+            Some(Token::Ident(name)) if name == "mdc" => Ok(Operand::Mdc(self.key()?)),
+            Some(t) => Err(format!("expected `mdc(..)` or a string, found {:?}", t)),
+            None => Err("unexpected end of expression".to_owned()),
+        }
+    }
+
+    fn key(&mut self) -> Result<String, String> {
+        self.expect(&Token::LParen)?;
+        let key = match self.next() {
+            Some(&Token::Ident(ref s)) | Some(&Token::Str(ref s)) => s.clone(),
+            _ => return Err("expected an MDC key".to_owned()),
+        };
+        self.expect(&Token::RParen)?;
+        Ok(key)
+    }
+}
+
+struct Rule {
+    when: Expr,
+    appender: AppenderConfig,
+}
+
+/// A router which selects an appender by evaluating expressions over the MDC.
+pub struct ExprRouter {
+    deserializers: Deserializers,
+    rules: Vec<Rule>,
+    default: Option<AppenderConfig>,
+}
+
+impl fmt::Debug for ExprRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ExprRouter")
+            .field("rules", &self.rules.iter().map(|r| &r.when).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Route for ExprRouter {
+    fn route(&self, _: &Record, cache: &mut Cache) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+        let (key, config) = match self.rules.iter().position(|r| r.when.eval()) {
+            Some(idx) => (idx.to_string(), &self.rules[idx].appender),
+            None => match self.default {
+                Some(ref default) => ("default".to_owned(), default),
+                None => return Err("no rule matched".into()),
+            },
+        };
+
+        match cache.try_entry(key)? {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => {
+                let appender = self
+                    .deserializers
+                    .deserialize(&config.kind, config.config.clone())?;
+                Ok(e.insert(appender))
+            }
+        }
+    }
+}
+
+/// A deserializer for the `ExprRouter`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: expr
+///
+/// # The rules evaluated, in order. Defaults to no rules.
+/// rules:
+///   - when: 'mdc(env) == "prod" && mdc(tier) == "db"'
+///     appender:
+///       kind: file
+///       path: "log/prod-db.log"
+///
+/// # The appender used if no rule matches. If not set, routing an event which
+/// # matches no rule is an error. Optional.
+/// default:
+///   kind: file
+///   path: "log/app.log"
+/// ```
+pub struct ExprRouterDeserializer;
+
+impl Deserialize for ExprRouterDeserializer {
+    type Trait = dyn Route;
+    type Config = ExprRouterConfig;
+
+    fn deserialize(
+        &self,
+        config: ExprRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<dyn Route>, Box<dyn Error + Sync + Send>> {
+        let mut rules = vec![];
+        for rule in config.rules {
+            let when = Parser::parse(&rule.when)
+                .map_err(|e| format!("invalid expression `{}`: {}", rule.when, e))?;
+            rules.push(Rule {
+                when,
+                appender: rule.appender,
+            });
+        }
+
+        Ok(Box::new(ExprRouter {
+            deserializers: deserializers.clone(),
+            rules,
+            default: config.default,
+        }))
+    }
+}
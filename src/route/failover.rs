@@ -0,0 +1,304 @@
+//! A router which sends log events to the first healthy appender of an ordered list.
+//!
+//! Events are written to the first appender in the list, the primary, for as long as it is
+//! healthy. An appender which fails to be built or to write an event a configured number of times
+//! in a row is considered down, and is skipped in favor of the next appender in the list until it
+//! is probed again. A down appender is probed by the first event after each probe interval, and
+//! becomes healthy again as soon as it writes an event successfully. An event which one appender
+//! fails to write is passed on to the next, so it is only lost if every appender fails.
+//!
+//! With `order: adaptive`, the list only sets the initial order of preference. The appenders are
+//! then ranked by their success rate over their last 32 attempts, reranked after every attempt,
+//! so that a flaky appender early in the list stops being tried first once a later one proves
+//! more reliable. Appenders with the same success rate, including those which have not been
+//! attempted yet, keep their order in the list. An appender which is not attempted keeps its
+//! success rate, so an appender which has been demoted is only tried first again once those
+//! ahead of it fail more often than it did. This makes routing depend on the history of the
+//! appenders rather than on the configuration alone, so which appender an event is written to
+//! can change over time with no change to the configuration. The default, `order: fixed`, always
+//! tries the appenders in the order of the list.
+//!
+//! The appenders other than the primary are only built once an event is actually sent to them.
+//! The health of the appenders is kept by a single cached appender wrapping all of them, so it is
+//! reset if that appender is evicted from the cache for being idle.
+//!
+//! Requires the `failover-router` feature.
+//!
+//! # Examples
+//!
+//! ```yaml
+//! kind: failover
+//! probe_interval: 10 seconds
+//! order: fixed
+//! appenders:
+//!   - kind: file
+//!     path: "/mnt/logs/app.log"
+//!   - kind: file
+//!     path: "log/app.log"
+//! ```
+use antidote::Mutex;
+use log::Record;
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use de_duration;
+use route::{Appender, AppenderConfig, Cache, Entry, Route};
+
+/// The number of most recent attempts over which the success rate of an appender is measured.
+const RATE_WINDOW: u32 = 32;
+
+/// The order in which the appenders are tried.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Order {
+    /// The appenders are tried in the order of the list.
+    #[default]
+    Fixed,
+    /// The appenders are tried in the order of their recent success rate.
+    Adaptive,
+}
+
+/// Configuration for the `FailoverRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FailoverRouterConfig {
+    appenders: Vec<AppenderConfig>,
+    #[serde(deserialize_with = "de_duration", default)]
+    probe_interval: Option<Duration>,
+    failure_threshold: Option<u32>,
+    #[serde(default)]
+    order: Order,
+}
+
+/// A router which sends log events to the first healthy appender of an ordered list.
+pub struct FailoverRouter {
+    deserializers: Deserializers,
+    appenders: Arc<Vec<AppenderConfig>>,
+    probe_interval: Duration,
+    failure_threshold: u32,
+    order: Order,
+}
+
+impl fmt::Debug for FailoverRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("FailoverRouter")
+            .field("appenders", &self.appenders.len())
+            .field("probe_interval", &self.probe_interval)
+            .field("failure_threshold", &self.failure_threshold)
+            .field("order", &self.order)
+            .finish()
+    }
+}
+
+impl Route for FailoverRouter {
+    fn route(&self, _: &Record, cache: &mut Cache) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+        match cache.try_entry("failover".to_owned())? {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => Ok(e.insert(Box::new(FailoverAppender {
+                deserializers: self.deserializers.clone(),
+                configs: self.appenders.clone(),
+                children: self.appenders.iter().map(|_| Mutex::new(Child::default())).collect(),
+                probe_interval: self.probe_interval,
+                failure_threshold: self.failure_threshold,
+                order: match self.order {
+                    Order::Fixed => None,
+                    Order::Adaptive => Some(Mutex::new((0..self.appenders.len()).collect())),
+                },
+            }))),
+        }
+    }
+}
+
+type SharedAppender = Arc<Box<dyn Append>>;
+
+#[derive(Default)]
+struct Child {
+    appender: Option<SharedAppender>,
+    failures: u32,
+    failed: Option<Instant>,
+    // the outcomes of the most recent attempts, as a bit set for each success, and their number
+    outcomes: u32,
+    attempts: u32,
+}
+
+impl Child {
+    fn success_rate(&self) -> f64 {
+        if self.attempts == 0 {
+            return 1.;
+        }
+        f64::from(self.outcomes.count_ones()) / f64::from(self.attempts)
+    }
+}
+
+struct FailoverAppender {
+    deserializers: Deserializers,
+    configs: Arc<Vec<AppenderConfig>>,
+    children: Vec<Mutex<Child>>,
+    probe_interval: Duration,
+    failure_threshold: u32,
+    // the indices of the children from the most to the least reliable, if the order is adaptive
+    order: Option<Mutex<Vec<usize>>>,
+}
+
+impl fmt::Debug for FailoverAppender {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("FailoverAppender").finish()
+    }
+}
+
+impl FailoverAppender {
+    /// Returns the child's appender, building it if necessary, if the child is healthy or due to
+    /// be probed.
+    fn available(
+        &self,
+        idx: usize,
+        now: Instant,
+    ) -> Option<Result<SharedAppender, Box<dyn Error + Sync + Send>>> {
+        let mut child = self.children[idx].lock();
+        if child.failures >= self.failure_threshold {
+            match child.failed {
+                Some(failed) if now.duration_since(failed) < self.probe_interval => return None,
+                _ => {}
+            }
+        }
+
+        if let Some(ref appender) = child.appender {
+            return Some(Ok(appender.clone()));
+        }
+        let config = &self.configs[idx];
+        match self.deserializers.deserialize::<dyn Append>(&config.kind, config.config.clone()) {
+            Ok(appender) => {
+                let appender = Arc::new(appender);
+                child.appender = Some(appender.clone());
+                Some(Ok(appender))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    fn record(&self, idx: usize, now: Instant, ok: bool) {
+        {
+            let mut child = self.children[idx].lock();
+            if ok {
+                child.failures = 0;
+                child.failed = None;
+            } else {
+                child.failures = child.failures.saturating_add(1);
+                child.failed = Some(now);
+            }
+            if self.order.is_some() {
+                child.outcomes = child.outcomes << 1 | ok as u32;
+                child.attempts = (child.attempts + 1).min(RATE_WINDOW);
+            }
+        }
+
+        if let Some(ref order) = self.order {
+            let rates = self
+                .children
+                .iter()
+                .map(|c| c.lock().success_rate())
+                .collect::<Vec<_>>();
+            let mut order = order.lock();
+            // ties keep the order of the list, so the most preferred of equally reliable children
+            // comes first
+            order.sort_by(|&a, &b| rates[b].total_cmp(&rates[a]).then(a.cmp(&b)));
+        }
+    }
+}
+
+impl Append for FailoverAppender {
+    fn append(&self, record: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let now = Instant::now();
+        let order = self.order.as_ref().map(|o| o.lock().clone());
+        let mut error = None;
+        for pos in 0..self.children.len() {
+            let idx = order.as_ref().map_or(pos, |o| o[pos]);
+            // the child's lock is not held while it writes, so a slow write doesn't block others
+            let result = match self.available(idx, now) {
+                Some(appender) => appender.and_then(|a| a.append(record)),
+                None => continue,
+            };
+            match result {
+                Ok(()) => {
+                    self.record(idx, now, true);
+                    return Ok(());
+                }
+                Err(e) => {
+                    self.record(idx, now, false);
+                    error = Some(e);
+                }
+            }
+        }
+
+        Err(error.unwrap_or_else(|| "all failover appenders are down".into()))
+    }
+
+    fn flush(&self) {
+        for child in &self.children {
+            let appender = child.lock().appender.clone();
+            if let Some(appender) = appender {
+                appender.flush();
+            }
+        }
+    }
+}
+
+/// A deserializer for the `FailoverRouter`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: failover
+///
+/// # The appenders, in order of preference. Required.
+/// appenders:
+///   - kind: file
+///     path: "/mnt/logs/app.log"
+///   - kind: file
+///     path: "log/app.log"
+///
+/// # The interval at which an appender which is down is probed with a log
+/// # event. Defaults to 10 seconds.
+/// probe_interval: 10 seconds
+///
+/// # The number of consecutive failures after which an appender is considered
+/// # down. Must be at least 1. Defaults to 1.
+/// failure_threshold: 3
+///
+/// # The order in which the appenders are tried. `fixed` tries them in the
+/// # order of the list, and `adaptive` in the order of their success rate over
+/// # their last 32 attempts, which changes over time. Defaults to `fixed`.
+/// order: fixed
+/// ```
+pub struct FailoverRouterDeserializer;
+
+impl Deserialize for FailoverRouterDeserializer {
+    type Trait = dyn Route;
+    type Config = FailoverRouterConfig;
+
+    fn deserialize(
+        &self,
+        config: FailoverRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<dyn Route>, Box<dyn Error + Sync + Send>> {
+        if config.appenders.is_empty() {
+            return Err("at least one appender is required".into());
+        }
+        let failure_threshold = config.failure_threshold.unwrap_or(1);
+        if failure_threshold == 0 {
+            return Err("failure_threshold must be at least 1".into());
+        }
+
+        Ok(Box::new(FailoverRouter {
+            deserializers: deserializers.clone(),
+            appenders: Arc::new(config.appenders),
+            probe_interval: config.probe_interval.unwrap_or(Duration::from_secs(10)),
+            failure_threshold,
+            order: config.order,
+        }))
+    }
+}
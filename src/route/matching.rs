@@ -0,0 +1,211 @@
+//! A router which selects an appender by matching the value of an MDC entry against a list of
+//! cases.
+//!
+//! The cases are checked in order, and the event is routed to the appender of the first one which
+//! matches, or to the default appender if none do. A case matches on one of the following:
+//!
+//! * `value` - The entry is present and equal to the specified string.
+//! * `in` - The entry is present and equal to one of the specified strings.
+//! * `truthy` - If `true`, the entry is present and holds a truthy value: `true`, `yes`, `on` or
+//!   `1`, ignoring case and surrounding whitespace. If `false`, the entry is absent or holds any
+//!   other value.
+//!
+//! Since the first matching case wins, a value listed both in an `in` case and in a `value` case
+//! is routed by whichever of the two comes first.
+//!
+//! Each case's appender is created the first time an event is routed to it, and is cached like any
+//! other routed appender.
+//!
+//! Requires the `match-router` feature.
+//!
+//! # Examples
+//!
+//! Canary deployments can flag the requests they serve with a `canary` MDC entry, which isolates
+//! their logs from those of the rest of the fleet:
+//!
+//! ```yaml
+//! kind: match
+//! key: canary
+//! cases:
+//!   - truthy: true
+//!     appender:
+//!       kind: file
+//!       path: "log/canary.log"
+//! default:
+//!   kind: file
+//!   path: "log/app.log"
+//! ```
+use log::Record;
+use log4rs::file::{Deserialize, Deserializers};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+
+use route::{Appender, AppenderConfig, Cache, Entry, Route};
+use warmup;
+
+/// Configuration for the `MatchRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MatchRouterConfig {
+    key: String,
+    #[serde(default)]
+    cases: Vec<CaseConfig>,
+    default: Option<AppenderConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CaseConfig {
+    value: Option<String>,
+    #[serde(rename = "in")]
+    in_: Option<HashSet<String>>,
+    truthy: Option<bool>,
+    appender: AppenderConfig,
+}
+
+#[derive(Debug)]
+enum Matcher {
+    Value(String),
+    In(HashSet<String>),
+    Truthy(bool),
+}
+
+impl Matcher {
+    fn matches(&self, value: Option<&str>) -> bool {
+        match *self {
+            Matcher::Value(ref expected) => value == Some(&**expected),
+            Matcher::In(ref values) => value.is_some_and(|v| values.contains(v)),
+            Matcher::Truthy(truthy) => value.is_some_and(is_truthy) == truthy,
+        }
+    }
+}
+
+fn is_truthy(value: &str) -> bool {
+    let value = value.trim();
+    ["true", "yes", "on", "1"]
+        .iter()
+        .any(|t| value.eq_ignore_ascii_case(t))
+}
+
+struct Case {
+    matcher: Matcher,
+    appender: AppenderConfig,
+}
+
+/// A router which selects an appender by matching the value of an MDC entry.
+pub struct MatchRouter {
+    deserializers: Deserializers,
+    key: String,
+    cases: Vec<Case>,
+    default: Option<AppenderConfig>,
+}
+
+impl fmt::Debug for MatchRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("MatchRouter")
+            .field("key", &self.key)
+            .field("cases", &self.cases.iter().map(|c| &c.matcher).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Route for MatchRouter {
+    fn route(&self, _: &Record, cache: &mut Cache) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+        let idx =
+            warmup::get_mdc(&self.key, |v| self.cases.iter().position(|c| c.matcher.matches(v)));
+        let (key, config) = match idx {
+            Some(idx) => (idx.to_string(), &self.cases[idx].appender),
+            None => match self.default {
+                Some(ref default) => ("default".to_owned(), default),
+                None => return Err(format!("no case matched MDC key `{}`", self.key).into()),
+            },
+        };
+
+        match cache.try_entry(key)? {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => {
+                let appender = self
+                    .deserializers
+                    .deserialize(&config.kind, config.config.clone())?;
+                Ok(e.insert(appender))
+            }
+        }
+    }
+}
+
+/// A deserializer for the `MatchRouter`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: match
+///
+/// # The MDC key whose value is matched. Required.
+/// key: region
+///
+/// # The cases checked, in order, the first matching case being used. Each has
+/// # exactly one of `value`, `in` or `truthy`, and an `in` case must list at
+/// # least one value. Defaults to no cases.
+/// cases:
+///   - value: eu
+///     appender:
+///       kind: file
+///       path: "log/eu.log"
+///   - in: [us-east, us-west]
+///     appender:
+///       kind: file
+///       path: "log/us.log"
+///   - truthy: false
+///     appender:
+///       kind: file
+///       path: "log/untracked.log"
+///
+/// # The appender used if no case matches. If not set, routing an event which
+/// # matches no case is an error. Optional.
+/// default:
+///   kind: file
+///   path: "log/other.log"
+/// ```
+pub struct MatchRouterDeserializer;
+
+impl Deserialize for MatchRouterDeserializer {
+    type Trait = dyn Route;
+    type Config = MatchRouterConfig;
+
+    fn deserialize(
+        &self,
+        config: MatchRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<dyn Route>, Box<dyn Error + Sync + Send>> {
+        let mut cases = vec![];
+        for case in config.cases {
+            let matcher = match (case.value, case.in_, case.truthy) {
+                (Some(value), None, None) => Matcher::Value(value),
+                (None, Some(values), None) => {
+                    if values.is_empty() {
+                        return Err("`in` must list at least one value".into());
+                    }
+                    Matcher::In(values)
+                }
+                (None, None, Some(truthy)) => Matcher::Truthy(truthy),
+                _ => {
+                    return Err(
+                        "each case must have exactly one of `value`, `in` or `truthy`".into(),
+                    )
+                }
+            };
+            cases.push(Case {
+                matcher,
+                appender: case.appender,
+            });
+        }
+
+        Ok(Box::new(MatchRouter {
+            deserializers: deserializers.clone(),
+            key: config.key,
+            cases,
+            default: config.default,
+        }))
+    }
+}
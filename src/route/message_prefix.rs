@@ -0,0 +1,208 @@
+//! A router which groups log events by the beginning of their message.
+//!
+//! The key of an event is the first `len` characters of its formatted message, or the part of
+//! the message before the first occurrence of the `delimiter`, if that comes sooner, with
+//! surrounding whitespace trimmed. Each distinct prefix is routed to its own appender, which makes
+//! it easy to see at a glance which kinds of messages dominate a log. Since messages usually
+//! start with a fixed description followed by the specifics, the number of appenders is bounded
+//! by the number of kinds of messages rather than the number of messages.
+//!
+//! The appender for each prefix is built from a template, as in the [pattern router][pattern],
+//! which may additionally refer to the prefix with `${prefix}`. Since the prefix is meant to be
+//! used in file names, control characters and path separators in it are replaced with `_`, and an
+//! empty prefix, `.` or `..` is replaced with `_` as a whole. Only the first `len` characters of
+//! the message are rendered to find the prefix.
+//!
+//! Requires the `message-prefix-router` feature.
+//!
+//! # Examples
+//!
+//! ```yaml
+//! kind: message_prefix
+//! len: 40
+//! delimiter: ":"
+//! pattern:
+//!   kind: file
+//!   path: "log/errors/${prefix}.log"
+//! ```
+//!
+//! [pattern]: ../pattern/index.html
+use log::Record;
+use log4rs::file::{Deserialize, Deserializers};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Write};
+
+use route::pattern::template::Template;
+use route::{self, Appender, AppenderConfig, Cache, Entry, Route};
+
+/// Configuration for the `MessagePrefixRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MessagePrefixRouterConfig {
+    len: usize,
+    delimiter: Option<String>,
+    pattern: AppenderConfig,
+}
+
+/// A router which groups log events by the beginning of their message.
+pub struct MessagePrefixRouter {
+    deserializers: Deserializers,
+    len: usize,
+    delimiter: Option<String>,
+    kind: String,
+    config: Template,
+}
+
+impl fmt::Debug for MessagePrefixRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("MessagePrefixRouter")
+            .field("len", &self.len)
+            .field("delimiter", &self.delimiter)
+            .finish()
+    }
+}
+
+/// A writer which keeps the first characters written to it, up to a limit, and stops formatting
+/// once the limit or the delimiter is reached.
+struct PrefixWriter<'a> {
+    prefix: String,
+    chars: usize,
+    len: usize,
+    delimiter: Option<&'a str>,
+}
+
+impl<'a> fmt::Write for PrefixWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.chars == self.len {
+                return Err(fmt::Error);
+            }
+            self.prefix.push(c);
+            self.chars += 1;
+            // the delimiter may have been split across writes, so the prefix is checked as a whole
+            if let Some(delimiter) = self.delimiter {
+                if self.prefix.ends_with(delimiter) {
+                    let end = self.prefix.len() - delimiter.len();
+                    self.prefix.truncate(end);
+                    return Err(fmt::Error);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MessagePrefixRouter {
+    /// Returns the sanitized prefix of the message of the log event.
+    fn prefix(&self, record: &Record) -> String {
+        let mut writer = PrefixWriter {
+            prefix: String::new(),
+            chars: 0,
+            len: self.len,
+            delimiter: self.delimiter.as_deref(),
+        };
+        // an error just means that the prefix is complete
+        let _ = write!(writer, "{}", record.args());
+
+        let prefix = writer
+            .prefix
+            .trim()
+            .chars()
+            .map(|c| {
+                if c.is_control() || std::path::is_separator(c) {
+                    '_'
+                } else {
+                    c
+                }
+            })
+            .collect::<String>();
+        if prefix.is_empty() || prefix == "." || prefix == ".." {
+            "_".to_owned()
+        } else {
+            prefix
+        }
+    }
+}
+
+impl Route for MessagePrefixRouter {
+    fn route(&self, record: &Record, cache: &mut Cache) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+        let prefix = self.prefix(record);
+
+        // length-prefixed, since the prefix may contain anything the template key does
+        let key = format!("{}{}{}", prefix.len(), prefix, self.config.key(record));
+        match cache.try_entry(key)? {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => {
+                let config = self.config.expand(record, &[("prefix", &prefix)])?;
+                let path = route::file_path(&config);
+                let e = match path {
+                    Some(ref path) => {
+                        route::check_path(path)?;
+                        match e.share_file(path) {
+                            Ok(appender) => return Ok(appender),
+                            Err(e) => e,
+                        }
+                    }
+                    None => e,
+                };
+                let appender = self.deserializers.deserialize(&self.kind, config)?;
+                match path {
+                    Some(path) => Ok(e.insert_file(appender, path)),
+                    None => Ok(e.insert(appender)),
+                }
+            }
+        }
+    }
+}
+
+/// A deserializer for the `MessagePrefixRouter`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: message_prefix
+///
+/// # The maximum number of characters of the message which make up the prefix.
+/// # Must be at least 1. Required.
+/// len: 40
+///
+/// # If set, the prefix ends before the first occurrence of this string in the
+/// # message, if that comes before `len` characters. Must not be empty.
+/// # Optional.
+/// delimiter: ":"
+///
+/// # The configuration template of a prefix's appender. In addition to the
+/// # formatters supported by the pattern router, `${prefix}` expands to the
+/// # sanitized prefix. Required.
+/// pattern:
+///   kind: file
+///   path: "log/errors/${prefix}.log"
+/// ```
+pub struct MessagePrefixRouterDeserializer;
+
+impl Deserialize for MessagePrefixRouterDeserializer {
+    type Trait = dyn Route;
+    type Config = MessagePrefixRouterConfig;
+
+    fn deserialize(
+        &self,
+        config: MessagePrefixRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<dyn Route>, Box<dyn Error + Sync + Send>> {
+        if config.len == 0 {
+            return Err("len must be at least 1".into());
+        }
+        if config.delimiter.as_ref().is_some_and(|d| d.is_empty()) {
+            return Err("delimiter must not be empty".into());
+        }
+
+        Ok(Box::new(MessagePrefixRouter {
+            deserializers: deserializers.clone(),
+            len: config.len,
+            delimiter: config.delimiter,
+            kind: config.pattern.kind,
+            config: Template::new(&config.pattern.config, HashMap::new(), &["prefix"])?,
+        }))
+    }
+}
@@ -1,81 +1,1615 @@
 //! Routers.
 //!
 //! A router determines the appender to which a log event should be sent.
+//!
+//! # File paths
+//!
+//! Some cache features, such as `dedup_by_path`, `discard_unwritten` and `verify_files`, need to
+//! know the file an appender writes to. Routers record it by inserting the appender with
+//! `VacantEntry::insert_file`, after offering it to `VacantEntry::share_file`. The pattern, shard,
+//! bucketed-numeric, message-prefix, target and target-regex routers use the `path` field of the
+//! appender's expanded configuration, and the dynamic router uses the path set with
+//! `RouteDecision::path`. Appenders routed any other way are not associated with a file.
 use linked_hash_map::LinkedHashMap;
-use log::Record;
+use log::{Level, Record};
 use log4rs::append::Append;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::fmt;
+use std::fmt::{self, Write};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write as _};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{SyncSender, TrySendError};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 #[cfg(feature = "file")]
 use log4rs::file::Deserializable;
+#[cfg(feature = "file")]
+use serde::de;
+#[cfg(feature = "file")]
+use serde_value::Value;
+#[cfg(feature = "file")]
+use std::collections::BTreeMap;
 
-use {AppenderInner, CacheInner};
+use builds::Permit;
+use clock::{Clock, SystemClock};
+use metrics;
+#[cfg(feature = "file")]
+use warmup::{self, Origin};
+use {
+    AppenderInner, CacheInner, CacheStats, CircuitState, EntrySnapshot, EvictionEvent,
+    RoutingSnapshot,
+};
+#[cfg(feature = "chrono")]
+use Timezone;
 
+#[cfg(feature = "range-router")]
+pub mod bucketed_numeric;
+pub mod dynamic;
+#[cfg(feature = "expr-router")]
+pub mod expr;
+#[cfg(feature = "failover-router")]
+pub mod failover;
+#[cfg(feature = "match-router")]
+pub mod matching;
+#[cfg(feature = "message-prefix-router")]
+pub mod message_prefix;
+#[cfg(feature = "novelty-router")]
+pub mod novelty;
 #[cfg(feature = "pattern-router")]
 pub mod pattern;
+#[cfg(feature = "ramp-router")]
+pub mod ramp;
+#[cfg(feature = "shard-router")]
+pub mod shard;
+#[cfg(feature = "sticky-router")]
+pub mod sticky;
+#[cfg(feature = "target-router")]
+pub mod target;
+#[cfg(feature = "target-regex-router")]
+pub mod target_regex;
+#[cfg(feature = "tee-router")]
+pub mod tee;
+#[cfg(feature = "time-window-router")]
+pub mod time_window;
+
+/// The maximum number of expired entries evicted by a single lookup.
+const SWEEP_LIMIT: usize = 16;
 
 struct TrackedAppender {
     appender: Appender,
+    created: Instant,
     used: Instant,
+    // the idle timeout of this entry, which hysteresis may extend beyond that of the cache
+    ttl: Duration,
+    // the file the appender writes to, if known by the router
+    path: Option<PathBuf>,
+    // the hash of the expanded configuration the appender was built from, if known by the router
+    config: Option<ConfigHash>,
+    file: Option<TrackedFile>,
+    last_error: Option<String>,
+    events: u64,
+    bytes: u64,
+    // the lookups of the entry since it was created, for the LFU eviction mode
+    uses: u64,
+    // protected entries are only evicted for being idle once no unprotected entries are cached
+    protected: bool,
+    circuit: Circuit,
+    quota: QuotaUsage,
+    #[cfg(feature = "file")]
+    // the log event the appender was created for, if the cache records it
+    origin: Option<Origin>,
+}
+
+/// The bytes an appender has written within the current window of the quota.
+struct QuotaUsage {
+    window: Instant,
+    used: u64,
+}
+
+/// The state of the circuit breaker of a cached appender.
+struct Circuit {
+    // the start of the current window, and the writes attempted and failed within it
+    window: Instant,
+    writes: u64,
+    failures: u64,
+    // when the circuit was last opened, or reopened by a failed probe
+    opened: Option<Instant>,
+    probing: bool,
+}
+
+impl Circuit {
+    fn state(&self) -> CircuitState {
+        match (self.opened, self.probing) {
+            (None, _) => CircuitState::Closed,
+            (Some(_), false) => CircuitState::Open,
+            (Some(_), true) => CircuitState::HalfOpen,
+        }
+    }
+}
+
+struct TrackedFile {
+    id: FileId,
+    verified: Instant,
+}
+
+/// The reason an appender was evicted from the cache.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Eviction {
+    /// The appender outlived the maximum lifetime.
+    Lifetime,
+    /// The appender was not used within the idle timeout.
+    Idle,
+    /// The file the appender wrote to was deleted or replaced.
+    FileReplaced,
+    /// The appender took longer than the slow write threshold to write a log event.
+    Slow,
+    /// The appender failed before writing any log event, and unwritten appenders are discarded.
+    Unwritten,
+    /// The route went quiet, and its log events are diverted to the cold appender.
+    Cold,
+    /// The appender made room for another one in a cache at capacity.
+    Capacity,
+    /// The cache was drained by `RoutingAppender::drain`.
+    Drained,
+}
+
+impl Eviction {
+    /// Returns the name of the reason, as written to the index appender.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Eviction::Lifetime => "lifetime",
+            Eviction::Idle => "idle",
+            Eviction::FileReplaced => "file_replaced",
+            Eviction::Slow => "slow",
+            Eviction::Unwritten => "unwritten",
+            Eviction::Cold => "cold",
+            Eviction::Capacity => "capacity",
+            Eviction::Drained => "drained",
+        }
+    }
+
+    /// Determines if the eviction is caused by the use of the appender's key rather than by the
+    /// appender itself.
+    fn by_use(self) -> bool {
+        match self {
+            Eviction::Idle | Eviction::Cold | Eviction::Capacity | Eviction::Drained => true,
+            Eviction::Lifetime | Eviction::FileReplaced | Eviction::Slow | Eviction::Unwritten => {
+                false
+            }
+        }
+    }
+}
+
+/// The constraints under which cached appenders expire.
+///
+/// The constraints compose in a fixed order of precedence:
+///
+/// 1. An appender which has outlived the maximum lifetime expires, however recently it was used
+///    and whether or not it is protected.
+/// 2. Otherwise, an appender expires once it has been idle for its idle timeout, as extended by
+///    hysteresis, unless it is protected and unprotected appenders remain cached.
+///
+/// Bounds on the cache as a whole come after these, and are enforced by evicting the least
+/// recently used appenders which have not expired, or the least frequently used in the LFU mode,
+/// sparing those created within the minimum TTL while older ones remain. In the LRU and LFU modes,
+/// appenders don't expire for being idle.
+struct EvictionPolicy {
+    mode: EvictionMode,
+    idle_timeout: Duration,
+    hysteresis: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    min_ttl: Option<Duration>,
+    protect: Vec<String>,
+}
+
+impl EvictionPolicy {
+    /// Returns the idle timeout of a new entry, which is extended by the hysteresis if its
+    /// predecessor was evicted for being idle within the hysteresis period.
+    fn idle_timeout(&self, recently_evicted: bool) -> Duration {
+        match self.hysteresis {
+            Some(hysteresis) if recently_evicted => self.idle_timeout + hysteresis,
+            _ => self.idle_timeout,
+        }
+    }
+
+    fn protects(&self, key: &str) -> bool {
+        self.protect.iter().any(|p| key.starts_with(&**p))
+    }
+
+    /// Determines if the entry was created too recently to be evicted to make room for another.
+    fn is_fresh(&self, entry: &TrackedAppender, now: Instant) -> bool {
+        self.min_ttl.is_some_and(|min| now.duration_since(entry.created) < min)
+    }
+
+    /// Returns the reason the entry has expired, if it has, where `keep_protected` determines if
+    /// protected entries are currently shielded from idle expiry.
+    fn expiry(
+        &self,
+        entry: &TrackedAppender,
+        now: Instant,
+        keep_protected: bool,
+    ) -> Option<Eviction> {
+        if self.max_lifetime.is_some_and(|max| now.duration_since(entry.created) >= max) {
+            return Some(Eviction::Lifetime);
+        }
+        if self.is_idle(entry, now) && !(entry.protected && keep_protected) {
+            return Some(Eviction::Idle);
+        }
+        None
+    }
+
+    /// Determines if the entry has been idle for its idle timeout, which never happens unless
+    /// appenders expire for being idle.
+    fn is_idle(&self, entry: &TrackedAppender, now: Instant) -> bool {
+        self.mode == EvictionMode::Idle && now.duration_since(entry.used) >= entry.ttl
+    }
+
+    /// Returns the number of uses by which entries are ranked for eviction at capacity, which is
+    /// the same for every entry unless the least frequently used are evicted.
+    fn frequency(&self, entry: &TrackedAppender) -> u64 {
+        match self.mode {
+            EvictionMode::Lfu => entry.uses,
+            EvictionMode::Idle | EvictionMode::Lru => 0,
+        }
+    }
+}
+
+/// A budget on the number of distinct keys the cache will ever hold appenders for.
+struct Cardinality {
+    max: usize,
+    // every key given an appender so far, which stops growing once the budget is reached
+    seen: HashSet<String>,
+    overflow: Appender,
+    exceeded: bool,
+}
+
+/// An appender to which the events of keys which have gone quiet are diverted.
+struct Cold {
+    after: Duration,
+    appender: Appender,
+    // the time of the last event of every key routed to
+    seen: HashMap<String, Instant>,
+}
+
+/// A circuit breaker applied to every cached appender.
+struct Breaker {
+    threshold: f64,
+    window: Duration,
+    probe: Duration,
+    min_writes: u64,
+    fallback: Option<Appender>,
+}
+
+/// A cap on the bytes each cached appender may write within a window.
+struct Quota {
+    bytes: u64,
+    window: Duration,
+    overflow: Option<Appender>,
+}
+
+impl Quota {
+    fn remaining(&self, usage: &QuotaUsage, now: Instant) -> u64 {
+        if now.duration_since(usage.window) >= self.window {
+            self.bytes
+        } else {
+            self.bytes.saturating_sub(usage.used)
+        }
+    }
+}
+
+#[cfg(feature = "file")]
+/// The tracking of the log events the cached appenders were created for, so that they can be
+/// persisted to a state file.
+struct Warmup {
+    // the keys of the entries inserted since the origin of an event was last recorded
+    inserted: Vec<String>,
+    // whether the entries with an origin have changed since they were last persisted
+    dirty: bool,
+}
+
+/// How a log event routed to an appender whose circuit is open, or which has exhausted its quota,
+/// is handled instead.
+pub(crate) enum Diversion {
+    Fallback(Appender),
+    Drop,
+}
+
+/// An appender to which a line is written for every appender created or evicted by the cache.
+struct Index {
+    appender: Box<dyn Append>,
+    evictions: bool,
+}
+
+#[cfg(unix)]
+type FileId = (u64, u64);
+#[cfg(not(unix))]
+type FileId = ();
+
+/// Returns an identifier of the file at `path`, or `None` if it doesn't exist.
+fn file_id(path: &PathBuf) -> Option<FileId> {
+    let metadata = fs::metadata(path).ok()?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some((metadata.dev(), metadata.ino()))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        Some(())
+    }
+}
+
+/// How the `Cache` stores the keys of its appenders.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "file", derive(Deserialize))]
+#[cfg_attr(feature = "file", serde(rename_all = "snake_case"))]
+pub enum KeyMode {
+    /// Keys are stored as provided by the router.
+    #[default]
+    String,
+    /// Keys are replaced by a 128 bit hash of them, formatted as `#` followed by 32 hexadecimal
+    /// digits.
+    Hashed,
+}
+
+/// How the `Cache` chooses the appenders it evicts.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "file", derive(Deserialize))]
+#[cfg_attr(feature = "file", serde(rename_all = "snake_case"))]
+pub enum EvictionMode {
+    /// Appenders expire once they have been idle for the idle timeout, and a cache at capacity
+    /// evicts the least recently used.
+    #[default]
+    Idle,
+    /// Appenders don't expire for being idle, and a cache at capacity evicts the least recently
+    /// used.
+    Lru,
+    /// Appenders don't expire for being idle, and a cache at capacity evicts the least frequently
+    /// used, which is the one looked up the fewest times since it was created, or the least
+    /// recently used of those.
+    Lfu,
+}
+
+/// A 128 bit hash of an expanded appender configuration.
+type ConfigHash = [u64; 2];
+
+/// Returns a 128 bit hash of a value.
+fn hash128<T: Hash + ?Sized>(value: &T) -> [u64; 2] {
+    // two differently seeded 64 bit hashes, as std has no 128 bit hasher
+    let mut hashes = [0u64; 2];
+    for (seed, hash) in hashes.iter_mut().enumerate() {
+        let mut hasher = DefaultHasher::new();
+        seed.hash(&mut hasher);
+        value.hash(&mut hasher);
+        *hash = hasher.finish();
+    }
+    hashes
+}
+
+/// Returns the hashed form of a key.
+fn hash_key(key: &str) -> String {
+    let hashes = hash128(key);
+    format!("#{:016x}{:016x}", hashes[0], hashes[1])
 }
 
 /// A cache of appenders.
 ///
 /// It stores appenders identified by arbitrary strings. It is up to the router to decide how those
 /// strings are formatted.
+///
+/// Expired appenders are evicted incrementally: each lookup evicts at most a small, fixed number
+/// of the least recently used appenders, so that a lookup never stalls on a large number of them
+/// expiring at once. An appender which has outlived its maximum lifetime while in use is evicted
+/// when it is next looked up.
+///
+/// A single cache is shared by the router of a `RoutingAppender` and any routers it delegates to.
+/// Routers which delegate to other routers should do so through `Cache::scoped` so that the keys
+/// of their children cannot collide.
 pub struct Cache {
     map: LinkedHashMap<String, TrackedAppender>,
-    ttl: Duration,
+    policy: EvictionPolicy,
+    clock: Arc<dyn Clock>,
+    // the keys whose appenders went idle and were evicted within the hysteresis period
+    evicted: LinkedHashMap<String, Instant>,
+    verify_files: Option<Duration>,
+    scope: String,
+    cardinality: Option<Cardinality>,
+    cold: Option<Cold>,
+    // the maximum number of cached appenders
+    capacity: Option<usize>,
+    // the appender for the events of new keys when every cached appender is protected
+    spill: Option<Appender>,
+    max_routes: Option<usize>,
+    // the maximum number of appenders built at once by the routing appenders which limit it
+    max_builds: Option<usize>,
+    // the route most recently refused through `Cache::entry` for exceeding `max_routes`
+    refusal: Option<RouteLimitError>,
+    breaker: Option<Breaker>,
+    quota: Option<Quota>,
+    #[cfg(feature = "file")]
+    warmup: Option<Warmup>,
+    index: Option<Index>,
+    // the number of protected entries cached
+    protected: usize,
+    discard_unwritten: bool,
+    key_mode: KeyMode,
+    dedup_by_path: bool,
+    // the appenders shared by the entries writing to each file, with the number of such entries
+    paths: HashMap<PathBuf, (Appender, usize)>,
+    // the appenders shared by the entries built from the same expanded configuration, along with
+    // the number of entries sharing each
+    configs: HashMap<ConfigHash, (Appender, usize)>,
+    // the name of the appender reported to the metrics facade
+    metrics_name: Option<String>,
+    eviction_channel: Option<SyncSender<EvictionEvent>>,
+    #[cfg(feature = "chrono")]
+    timezone: Timezone,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
 }
 
 impl CacheInner for Cache {
     fn new(ttl: Duration) -> Cache {
         Cache {
             map: LinkedHashMap::new(),
-            ttl: ttl,
+            policy: EvictionPolicy {
+                mode: EvictionMode::Idle,
+                idle_timeout: ttl,
+                hysteresis: None,
+                max_lifetime: None,
+                min_ttl: None,
+                protect: vec![],
+            },
+            clock: Arc::new(SystemClock),
+            evicted: LinkedHashMap::new(),
+            verify_files: None,
+            scope: String::new(),
+            cardinality: None,
+            cold: None,
+            capacity: None,
+            spill: None,
+            max_routes: None,
+            max_builds: None,
+            refusal: None,
+            breaker: None,
+            quota: None,
+            #[cfg(feature = "file")]
+            warmup: None,
+            index: None,
+            protected: 0,
+            discard_unwritten: false,
+            key_mode: KeyMode::String,
+            dedup_by_path: false,
+            paths: HashMap::new(),
+            configs: HashMap::new(),
+            metrics_name: None,
+            eviction_channel: None,
+            #[cfg(feature = "chrono")]
+            timezone: Timezone::Utc,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    fn set_verify_files(&mut self, interval: Duration) {
+        self.verify_files = Some(interval);
+    }
+
+    fn set_hysteresis(&mut self, hysteresis: Duration) {
+        self.policy.hysteresis = Some(hysteresis);
+    }
+
+    fn set_max_lifetime(&mut self, max_lifetime: Duration) {
+        self.policy.max_lifetime = Some(max_lifetime);
+    }
+
+    fn set_min_ttl(&mut self, min_ttl: Duration) {
+        self.policy.min_ttl = Some(min_ttl);
+    }
+
+    fn set_eviction_mode(&mut self, mode: EvictionMode) {
+        self.policy.mode = mode;
+    }
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    fn set_max_cardinality(&mut self, max: usize, overflow: Box<dyn Append>) {
+        self.cardinality = Some(Cardinality {
+            max,
+            seen: HashSet::new(),
+            overflow: Appender {
+                appender: Arc::new(overflow),
+                key: Arc::from("!overflow"),
+                path: None,
+                written: Arc::new(AtomicBool::new(true)),
+            },
+            exceeded: false,
+        });
+    }
+
+    fn set_cold(&mut self, after: Duration, appender: Box<dyn Append>) {
+        self.cold = Some(Cold {
+            after,
+            appender: Appender {
+                appender: Arc::new(appender),
+                key: Arc::from("!cold"),
+                path: None,
+                written: Arc::new(AtomicBool::new(true)),
+            },
+            seen: HashMap::new(),
+        });
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = Some(capacity);
+    }
+
+    fn set_spill(&mut self, appender: Box<dyn Append>) {
+        self.spill = Some(Appender {
+            appender: Arc::new(appender),
+            key: Arc::from("!spill"),
+            path: None,
+            written: Arc::new(AtomicBool::new(true)),
+        });
+    }
+
+    fn set_max_routes(&mut self, max: usize) {
+        self.max_routes = Some(max);
+    }
+
+    fn set_max_builds(&mut self, max: usize) {
+        self.max_builds = Some(max);
+    }
+
+    fn take_refusal(&mut self) -> Option<Box<dyn Error + Sync + Send>> {
+        self.refusal.take().map(Into::into)
+    }
+
+    fn set_circuit_breaker(
+        &mut self,
+        threshold: f64,
+        window: Duration,
+        probe: Duration,
+        min_writes: u64,
+        fallback: Option<Box<dyn Append>>,
+    ) {
+        self.breaker = Some(Breaker {
+            threshold,
+            window,
+            probe,
+            min_writes,
+            fallback: fallback.map(|appender| Appender {
+                appender: Arc::new(appender),
+                key: Arc::from("!circuit"),
+                path: None,
+                written: Arc::new(AtomicBool::new(true)),
+            }),
+        });
+    }
+
+    fn set_quota(&mut self, bytes: u64, window: Duration, overflow: Option<Box<dyn Append>>) {
+        self.quota = Some(Quota {
+            bytes,
+            window,
+            overflow: overflow.map(|appender| Appender {
+                appender: Arc::new(appender),
+                key: Arc::from("!quota"),
+                path: None,
+                written: Arc::new(AtomicBool::new(true)),
+            }),
+        });
+    }
+
+    fn set_protect(&mut self, prefixes: Vec<String>) {
+        self.policy.protect = prefixes;
+    }
+
+    fn set_discard_unwritten(&mut self, discard: bool) {
+        self.discard_unwritten = discard;
+    }
+
+    fn set_key_mode(&mut self, key_mode: KeyMode) {
+        self.key_mode = key_mode;
+    }
+
+    fn key_mode(&self) -> KeyMode {
+        self.key_mode
+    }
+
+    fn set_dedup_by_path(&mut self, dedup: bool) {
+        self.dedup_by_path = dedup;
+    }
+
+    fn set_metrics_name(&mut self, name: String) {
+        self.metrics_name = Some(name);
+    }
+
+    fn set_index(&mut self, appender: Box<dyn Append>, evictions: bool) {
+        self.index = Some(Index {
+            appender,
+            evictions,
+        });
+    }
+
+    fn set_eviction_channel(&mut self, sender: SyncSender<EvictionEvent>) {
+        self.eviction_channel = Some(sender);
+    }
+
+    #[cfg(feature = "file")]
+    fn set_warmup(&mut self) {
+        self.warmup = Some(Warmup {
+            inserted: vec![],
+            dirty: false,
+        });
+    }
+
+    #[cfg(feature = "chrono")]
+    fn set_timezone(&mut self, timezone: Timezone) {
+        self.timezone = timezone;
+    }
+
+    fn inherit(&mut self, old: &mut Cache) {
+        let mut map = mem::replace(&mut old.map, LinkedHashMap::new());
+        // the old cache must not hand out or protect the appenders the new one now owns
+        old.paths.clear();
+        old.configs.clear();
+        old.protected = 0;
+        if let Some(ref mut cardinality) = self.cardinality {
+            for key in map.keys() {
+                if !cardinality.seen.contains(key) && cardinality.seen.len() < cardinality.max {
+                    cardinality.seen.insert(key.clone());
+                }
+            }
+        }
+        if let (Some(cold), Some(old)) = (self.cold.as_mut(), old.cold.as_mut()) {
+            cold.seen = mem::take(&mut old.seen);
+        }
+        while let Some((key, entry)) = self.map.pop_front() {
+            map.insert(key, entry);
+        }
+        // hashed keys can't be matched against the protected prefixes, so they keep their status
+        if self.key_mode == KeyMode::String {
+            for (key, entry) in map.iter_mut() {
+                entry.protected = self.policy.protects(key);
+            }
+        }
+        self.protected = map.values().filter(|e| e.protected).count();
+        self.paths.clear();
+        self.configs.clear();
+        for entry in map.values() {
+            if let (true, Some(path)) = (self.dedup_by_path, &entry.path) {
+                self.share(path, &entry.appender);
+            }
+            if let Some(config) = entry.config {
+                self.share_config(config, &entry.appender);
+            }
+        }
+        self.map = map;
+        #[cfg(feature = "file")]
+        if let Some(ref mut warmup) = self.warmup {
+            warmup.dirty = true;
+        }
+        // the new configuration may have a smaller capacity
+        self.make_room(0);
+        self.report_live();
+    }
+
+    fn evict(&mut self, appender: &Appender) {
+        // the appender may already have been evicted and replaced by another thread
+        let current = match self.map.get(appender.key()) {
+            Some(entry) => Arc::ptr_eq(&entry.appender.appender, &appender.appender),
+            None => false,
+        };
+        if current {
+            self.evict_entry(appender.key(), Eviction::Slow);
+        }
+    }
+
+    fn record_error(&mut self, appender: &Appender, error: String) {
+        if self.discard_unwritten && !appender.written.load(Ordering::Relaxed) {
+            if self.tracked_mut(appender).is_some() {
+                if let Some(path) = self.evict_entry(appender.key(), Eviction::Unwritten) {
+                    // only an empty file is removed, so nothing written by anyone is lost
+                    if fs::metadata(&path).is_ok_and(|m| m.is_file() && m.len() == 0) {
+                        let _ = fs::remove_file(&path);
+                    }
+                }
+            }
+            return;
+        }
+        self.record_outcome(appender, false);
+        if let Some(entry) = self.tracked_mut(appender) {
+            entry.last_error = Some(error);
+        }
+    }
+
+    fn record_success(&mut self, appender: &Appender) {
+        self.record_outcome(appender, true);
+    }
+
+    fn divert(&mut self, appender: &Appender) -> Option<Diversion> {
+        let (probe, fallback) = match self.breaker {
+            Some(ref breaker) => (breaker.probe, breaker.fallback.clone()),
+            None => return None,
+        };
+        let now = self.clock.now();
+        let circuit = &mut self.tracked_mut(appender)?.circuit;
+        let opened = circuit.opened?;
+        // a single event at a time probes whether the appender has recovered
+        if !circuit.probing && now.duration_since(opened) >= probe {
+            circuit.probing = true;
+            return None;
+        }
+        match fallback {
+            Some(fallback) => Some(Diversion::Fallback(fallback)),
+            None => Some(Diversion::Drop),
+        }
+    }
+
+    fn charge(&mut self, appender: &Appender, bytes: u64) -> Option<Diversion> {
+        let quota = self.quota.as_ref()?;
+        let usage = &mut self
+            .map
+            .get_mut(appender.key())
+            .filter(|e| Arc::ptr_eq(&e.appender.appender, &appender.appender))?
+            .quota;
+        let now = self.clock.now();
+        if now.duration_since(usage.window) >= quota.window {
+            usage.window = now;
+            usage.used = 0;
+        }
+        if usage.used + bytes <= quota.bytes {
+            usage.used += bytes;
+            return None;
+        }
+        match quota.overflow {
+            Some(ref overflow) => Some(Diversion::Fallback(overflow.clone())),
+            None => Some(Diversion::Drop),
+        }
+    }
+
+    #[cfg(feature = "file")]
+    fn record_origin<F>(&mut self, origin: F)
+    where
+        F: Fn() -> Origin,
+    {
+        let warmup = match self.warmup {
+            Some(ref mut warmup) if !warmup.inserted.is_empty() => warmup,
+            _ => return,
+        };
+        for key in warmup.inserted.drain(..) {
+            // the entry may already have been evicted to make room for another
+            if let Some(entry) = self.map.get_mut(&key) {
+                entry.origin = Some(origin());
+                warmup.dirty = true;
+            }
+        }
+    }
+
+    #[cfg(feature = "file")]
+    fn warmup_routes(&mut self) -> Option<Vec<warmup::Route>> {
+        match self.warmup {
+            Some(ref mut warmup) if warmup.dirty => warmup.dirty = false,
+            _ => return None,
+        }
+        let routes = self
+            .map
+            .iter()
+            .filter_map(|(key, entry)| {
+                entry.origin.as_ref().map(|origin| warmup::Route {
+                    key: key.clone(),
+                    path: entry.path.clone(),
+                    origin: origin.clone(),
+                })
+            })
+            .collect();
+        Some(routes)
+    }
+
+    fn record_write(&mut self, appender: &Appender, bytes: u64) {
+        if let Some(entry) = self.tracked_mut(appender) {
+            entry.events += 1;
+            entry.bytes += bytes;
+        }
+    }
+
+    fn drain(&mut self) {
+        #[cfg(feature = "file")]
+        let dirty = self.warmup.as_ref().is_some_and(|w| w.dirty);
+        let keys = self.map.keys().cloned().collect::<Vec<_>>();
+        for key in keys {
+            self.evict_entry(&key, Eviction::Drained);
+        }
+        // the drained routes are still worth rebuilding on the next start
+        #[cfg(feature = "file")]
+        if let Some(ref mut warmup) = self.warmup {
+            warmup.dirty = dirty;
+        }
+    }
+
+    fn sweep(&mut self) {
+        let now = self.clock.now();
+        let keep_protected = self.protected < self.map.len();
+        let expired = self
+            .map
+            .iter()
+            .filter_map(|(key, entry)| {
+                let reason = self.policy.expiry(entry, now, keep_protected)?;
+                Some((key.clone(), reason))
+            })
+            .collect::<Vec<_>>();
+        for (key, reason) in expired {
+            self.evict_expired(&key, reason, now);
+        }
+        // reorders the idle protected entries and forgets the expired hysteresis periods, as a
+        // lookup would
+        self.purge(now);
+    }
+
+    fn matching(&self, pred: &dyn Fn(&str) -> bool) -> Vec<Appender> {
+        self.map
+            .iter()
+            .filter(|&(key, _)| pred(key))
+            .map(|(_, entry)| entry.appender.clone())
+            .collect()
+    }
+
+    fn appenders(&self) -> Vec<Appender> {
+        let mut appenders = self.matching(&|_| true);
+        if let Some(fallback) = self.breaker.as_ref().and_then(|b| b.fallback.as_ref()) {
+            appenders.push(fallback.clone());
+        }
+        if let Some(ref cardinality) = self.cardinality {
+            appenders.push(cardinality.overflow.clone());
+        }
+        if let Some(ref cold) = self.cold {
+            appenders.push(cold.appender.clone());
+        }
+        if let Some(ref spill) = self.spill {
+            appenders.push(spill.clone());
+        }
+        if let Some(overflow) = self.quota.as_ref().and_then(|q| q.overflow.as_ref()) {
+            appenders.push(overflow.clone());
+        }
+        appenders
+    }
+
+    fn flush_index(&self) {
+        if let Some(ref index) = self.index {
+            index.appender.flush();
+        }
+    }
+
+    fn snapshot(&self) -> RoutingSnapshot {
+        let now = self.clock.now();
+        RoutingSnapshot {
+            idle_timeout: self.policy.idle_timeout,
+            entries: self
+                .map
+                .iter()
+                // entries are only purged on lookup, so some may have expired since
+                .filter(|&(_, entry)| self.expired(entry, now).is_none())
+                .map(|(key, entry)| EntrySnapshot {
+                    key: key.clone(),
+                    idle: now.duration_since(entry.used),
+                    last_error: entry.last_error.clone(),
+                    events: entry.events,
+                    bytes: entry.bytes,
+                    circuit: entry.circuit.state(),
+                    quota_remaining: self.quota.as_ref().map(|q| q.remaining(&entry.quota, now)),
+                })
+                .collect(),
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+        }
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            live_entries: self.map.len(),
+            total_hits: self.hits,
+            total_misses: self.misses,
+            evictions: self.evictions,
         }
     }
 }
 
 impl Cache {
     /// Looks up the entry corresponding to the specified key.
+    ///
+    /// If the cache has a cardinality budget which has been exhausted, looking up a key which has
+    /// never been given an appender returns the overflow appender instead. Likewise, if the cache
+    /// has a cold appender, looking up a key whose previous lookup was too long ago returns the
+    /// cold appender. If the cache holds its maximum number of routes, looking up a new key returns
+    /// a placeholder appender, and the routing appender fails to route the event with a
+    /// `RouteLimitError`; `Cache::try_entry` returns the error to the router instead. Keys starting
+    /// with `!` are reserved for routers' internal appenders, such as fallbacks, and are exempt
+    /// from all of these.
+    ///
+    /// If the cache hashes its keys, the scoped key is replaced by its hash, except for reserved
+    /// keys, and the hash is the key reported by `VacantEntry::key` and `Appender::key`.
     pub fn entry<'a>(&'a mut self, key: String) -> Entry<'a> {
-        let now = Instant::now();
+        match self.lookup(key, false) {
+            Ok(entry) => entry,
+            Err(_) => unreachable!("refused routes are given a placeholder appender"),
+        }
+    }
+
+    /// Looks up the entry corresponding to the specified key, like `Cache::entry`, except that
+    /// looking up a new key once the cache holds its maximum number of routes fails with a
+    /// `RouteLimitError`, which the router should return.
+    pub fn try_entry<'a>(&'a mut self, key: String) -> Result<Entry<'a>, RouteLimitError> {
+        self.lookup(key, true)
+    }
+
+    /// Looks up an entry, returning the error refusing a new route if `fail` is set, and a
+    /// placeholder appender for it otherwise.
+    fn lookup<'a>(&'a mut self, key: String, fail: bool) -> Result<Entry<'a>, RouteLimitError> {
+        let reserved = key.starts_with('!');
+        let key = if self.scope.is_empty() {
+            key
+        } else {
+            format!("{}:{}", self.scope, key)
+        };
+        // protection is decided by the key as provided, which hashing would hide
+        let protected = self.policy.protects(&key);
+        let key = match self.key_mode {
+            KeyMode::Hashed if !reserved => hash_key(&key),
+            _ => key,
+        };
+
+        let now = self.clock.now();
         self.purge(now);
 
+        if !reserved {
+            if let Some(cold) = self.check_cold(&key, now) {
+                self.misses += 1;
+                self.count(metrics::MISSES);
+                return Ok(Entry::Occupied(OccupiedEntry(self, cold)));
+            }
+        }
+
+        // the entry may have expired without having been swept yet
+        let expired = match self.map.get(&key) {
+            Some(entry) => self.expired(entry, now),
+            None => None,
+        };
+        if let Some(reason) = expired {
+            self.evict_expired(&key, reason, now);
+        } else if self.file_replaced(&key, now) {
+            self.evict_entry(&key, Eviction::FileReplaced);
+        }
+
         let entry = match self.map.get_refresh(&key) {
             Some(entry) => {
                 entry.used = now;
-                Some(Appender(entry.appender.0.clone()))
+                entry.uses += 1;
+                Some(entry.appender.clone())
             }
             None => None,
         };
 
         match entry {
-            Some(appender) => Entry::Occupied(OccupiedEntry(self, appender)),
-            None => Entry::Vacant(VacantEntry {
-                cache: self,
-                key: key,
-                time: now,
+            Some(appender) => {
+                self.hits += 1;
+                self.count(metrics::HITS);
+                Ok(Entry::Occupied(OccupiedEntry(self, appender)))
+            }
+            None => {
+                self.misses += 1;
+                self.count(metrics::MISSES);
+                if !reserved {
+                    if let Some(overflow) = self.check_cardinality(&key) {
+                        return Ok(Entry::Occupied(OccupiedEntry(self, overflow)));
+                    }
+                    if let Some(spill) = self.check_spill(&key) {
+                        return Ok(Entry::Occupied(OccupiedEntry(self, spill)));
+                    }
+                    if let Err(e) = self.check_max_routes(&key) {
+                        if fail {
+                            return Err(e);
+                        }
+                        let refused = self.refuse(e);
+                        return Ok(Entry::Occupied(OccupiedEntry(self, refused)));
+                    }
+                }
+                // held until the appender has been built and inserted, or the entry is dropped
+                let permit = self.max_builds.and_then(Permit::acquire);
+                Ok(Entry::Vacant(VacantEntry {
+                    cache: self,
+                    key,
+                    time: now,
+                    protected,
+                    config: None,
+                    _permit: permit,
+                }))
+            }
+        }
+    }
+
+    /// Returns the timezone configured for the `RoutingAppender`, which routers depending on the
+    /// time should use unless configured with a timezone of their own.
+    ///
+    /// Requires the `chrono` feature (enabled by the `time-window-router` feature).
+    #[cfg(feature = "chrono")]
+    pub fn timezone(&self) -> Timezone {
+        self.timezone
+    }
+
+    /// Runs the provided closure with the cache's keys namespaced by `scope`.
+    ///
+    /// Scopes nest, so a router within a scope may create further scopes of its own.
+    pub fn scoped<F, T>(&mut self, scope: &str, f: F) -> T
+    where
+        F: FnOnce(&mut Cache) -> T,
+    {
+        let len = self.scope.len();
+        write!(self.scope, "{}{}", scope.len(), scope).unwrap();
+        let r = f(self);
+        self.scope.truncate(len);
+        r
+    }
+
+    /// Records that `key` is about to be given an appender, returning the overflow appender instead
+    /// if doing so would exceed the cardinality budget.
+    fn check_cardinality(&mut self, key: &str) -> Option<Appender> {
+        let cardinality = self.cardinality.as_mut()?;
+        if cardinality.seen.contains(key) {
+            return None;
+        }
+        if cardinality.seen.len() < cardinality.max {
+            cardinality.seen.insert(key.to_owned());
+            return None;
+        }
+
+        if !cardinality.exceeded {
+            cardinality.exceeded = true;
+            let _ = writeln!(
+                io::stderr(),
+                "log4rs: cardinality budget exceeded: {} distinct routing keys have been seen, \
+                 routing new keys to the overflow appender",
+                cardinality.max
+            );
+        }
+        Some(cardinality.overflow.clone())
+    }
+
+    /// Returns the spill appender, under the provided key, if the cache is full and none of its
+    /// appenders can be evicted to make room for another.
+    fn check_spill(&self, key: &str) -> Option<Appender> {
+        let spill = self.spill.as_ref()?;
+        if !self.full() || self.protected < self.map.len() {
+            return None;
+        }
+        // the key tells the spill appender's encoder where the event was meant to go
+        Some(Appender {
+            key: Arc::from(key),
+            ..spill.clone()
+        })
+    }
+
+    /// Refuses a new route if the cache already holds the maximum number of routes.
+    fn check_max_routes(&self, key: &str) -> Result<(), RouteLimitError> {
+        match self.max_routes {
+            Some(max) if self.map.len() >= max => Err(RouteLimitError {
+                max,
+                key: key.to_owned(),
+                config: None,
             }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns a placeholder appender for a route refused by the route limit, recording the
+    /// refusal for the routing appender to report.
+    fn refuse(&mut self, refusal: RouteLimitError) -> Appender {
+        let appender = Appender {
+            appender: Arc::new(Box::new(RefusedAppender)),
+            key: Arc::from(refusal.key()),
+            path: None,
+            written: Arc::new(AtomicBool::new(true)),
+        };
+        self.refusal = Some(refusal);
+        appender
+    }
+
+    fn full(&self) -> bool {
+        self.capacity.is_some_and(|capacity| self.map.len() >= capacity)
+    }
+
+    /// Evicts the least recently, or frequently, used appenders until there is room for `room`
+    /// more, preferring unprotected ones.
+    fn make_room(&mut self, room: usize) {
+        let now = self.clock.now();
+        while self.capacity.is_some_and(|capacity| self.map.len() + room > capacity) {
+            let policy = &self.policy;
+            // the least recently used entry, unprotected ones first, and within each, those past
+            // the minimum TTL first, so that fresh entries are only evicted if all are; entries
+            // are ordered by last use, so the first of those ranked lowest is the least recent
+            let key = self
+                .map
+                .iter()
+                .min_by_key(|&(_, e)| (e.protected, policy.is_fresh(e, now), policy.frequency(e)));
+            let key = match key {
+                Some((key, _)) => key.clone(),
+                None => return,
+            };
+            self.evict_entry(&key, Eviction::Capacity);
+        }
+    }
+
+    /// Records a lookup of `key`, returning the cold appender if the key's previous lookup was
+    /// longer ago than the cold threshold.
+    fn check_cold(&mut self, key: &str, now: Instant) -> Option<Appender> {
+        let cold = self.cold.as_mut()?;
+        let previous = match cold.seen.get_mut(key) {
+            Some(seen) => Some(mem::replace(seen, now)),
+            None => {
+                cold.seen.insert(key.to_owned(), now);
+                None
+            }
+        };
+        match previous {
+            Some(previous) if now.duration_since(previous) > cold.after => {}
+            _ => return None,
+        }
+
+        let appender = cold.appender.clone();
+        // the route's appender may still be cached if the idle timeout exceeds the threshold
+        if self.map.contains_key(key) {
+            self.evict_entry(key, Eviction::Cold);
+        }
+        Some(appender)
+    }
+
+    /// Updates the circuit of the provided appender with the outcome of a write to it.
+    fn record_outcome(&mut self, appender: &Appender, ok: bool) {
+        let (threshold, window, min_writes) = match self.breaker {
+            Some(ref breaker) => (breaker.threshold, breaker.window, breaker.min_writes),
+            None => return,
+        };
+        let now = self.clock.now();
+        let circuit = match self.tracked_mut(appender) {
+            Some(entry) => &mut entry.circuit,
+            None => return,
+        };
+        if circuit.probing {
+            circuit.probing = false;
+            if ok {
+                circuit.opened = None;
+                circuit.window = now;
+                circuit.writes = 0;
+                circuit.failures = 0;
+            } else {
+                circuit.opened = Some(now);
+            }
+            return;
+        }
+        // writes which were already under way when the circuit opened
+        if circuit.opened.is_some() {
+            return;
+        }
+
+        if now.duration_since(circuit.window) >= window {
+            circuit.window = now;
+            circuit.writes = 0;
+            circuit.failures = 0;
+        }
+        circuit.writes += 1;
+        if ok {
+            return;
+        }
+        circuit.failures += 1;
+        if circuit.writes >= min_writes
+            && circuit.failures as f64 >= threshold * circuit.writes as f64
+        {
+            circuit.opened = Some(now);
+            let _ = writeln!(
+                io::stderr(),
+                "log4rs: opened the circuit of routed appender `{}` after {} of {} writes failed",
+                appender.key(),
+                circuit.failures,
+                circuit.writes
+            );
+        }
+    }
+
+    /// Returns the entry of the provided appender, unless it has since been evicted.
+    fn tracked_mut(&mut self, appender: &Appender) -> Option<&mut TrackedAppender> {
+        self.map
+            .get_mut(appender.key())
+            .filter(|e| Arc::ptr_eq(&e.appender.appender, &appender.appender))
+    }
+
+    /// Determines if the file written by the appender under `key` has been deleted or replaced
+    /// since the appender was created, if it is due to be checked.
+    fn file_replaced(&mut self, key: &str, now: Instant) -> bool {
+        let interval = match self.verify_files {
+            Some(interval) => interval,
+            None => return false,
+        };
+        let entry = match self.map.get_mut(key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let (file, path) = match (entry.file.as_mut(), entry.path.as_ref()) {
+            (Some(file), Some(path)) => (file, path),
+            _ => return false,
+        };
+        if now.duration_since(file.verified) < interval {
+            return false;
+        }
+
+        file.verified = now;
+        file_id(path) != Some(file.id)
+    }
+
+    /// Returns the reason an entry is due to be evicted, if it is.
+    fn expired(&self, entry: &TrackedAppender, now: Instant) -> Option<Eviction> {
+        self.policy.expiry(entry, now, self.protected < self.map.len())
+    }
+
+    /// Removes the entry under `key`, which must exist.
+    fn remove(&mut self, key: &str) -> TrackedAppender {
+        let entry = self.map.remove(key).unwrap();
+        if entry.protected {
+            self.protected -= 1;
+        }
+        if let Some(ref path) = entry.path {
+            let unshared = match self.paths.get_mut(path) {
+                Some(&mut (ref shared, ref mut count)) if shared.same(&entry.appender) => {
+                    *count -= 1;
+                    *count == 0
+                }
+                _ => false,
+            };
+            if unshared {
+                self.paths.remove(path);
+            }
+        }
+        if let Some(ref config) = entry.config {
+            let unshared = match self.configs.get_mut(config) {
+                Some(&mut (ref shared, ref mut count)) if shared.same(&entry.appender) => {
+                    *count -= 1;
+                    *count == 0
+                }
+                _ => false,
+            };
+            if unshared {
+                self.configs.remove(config);
+            }
+        }
+        entry
+    }
+
+    /// Records that an entry writes to the file at `path` with the provided appender.
+    fn share(&mut self, path: &Path, appender: &Appender) {
+        match self.paths.get_mut(path) {
+            Some(&mut (ref shared, ref mut count)) if shared.same(appender) => *count += 1,
+            // an appender built to replace a shared one which is no longer handed out
+            _ => {
+                self.paths.insert(path.to_owned(), (appender.clone(), 1));
+            }
+        }
+    }
+
+    /// Records that an entry built from the configuration hashed to `config` uses the provided
+    /// appender.
+    fn share_config(&mut self, config: ConfigHash, appender: &Appender) {
+        match self.configs.get_mut(&config) {
+            Some(&mut (ref shared, ref mut count)) if shared.same(appender) => *count += 1,
+            _ => {
+                self.configs.insert(config, (appender.clone(), 1));
+            }
+        }
+    }
+
+    /// Evicts the entry under `key`, which must exist, returning the path of the file it wrote to,
+    /// if known.
+    ///
+    /// The appender is flushed and then dropped before this returns, so that nothing it buffered
+    /// is lost and its file is closed right away, unless it is still held by other entries sharing
+    /// it or by a log event being written to it.
+    fn evict_entry(&mut self, key: &str, reason: Eviction) -> Option<PathBuf> {
+        let mut entry = self.remove(key);
+        // evictions caused by the appender itself rather than by the use of its key stop it from
+        // being shared with keys looked up later, which get a fresh appender instead
+        if let (false, Some(path)) = (reason.by_use(), &entry.path) {
+            if self.paths.get(path).is_some_and(|s| s.0.same(&entry.appender)) {
+                self.paths.remove(path);
+            }
+        }
+        if let (false, Some(config)) = (reason.by_use(), &entry.config) {
+            if self.configs.get(config).is_some_and(|s| s.0.same(&entry.appender)) {
+                self.configs.remove(config);
+            }
+        }
+        #[cfg(feature = "file")]
+        if let (Some(warmup), Some(_)) = (self.warmup.as_mut(), &entry.origin) {
+            warmup.dirty = true;
+        }
+        self.evictions += 1;
+        self.count(metrics::EVICTIONS);
+        self.report_live();
+        self.index_eviction(key, &entry, reason);
+        self.notify_eviction(key, &entry, reason);
+        entry.appender.appender().flush();
+        let path = entry.path.take();
+        drop(entry);
+        path
+    }
+
+    /// Increments a counter of the `metrics` facade, if the appender is named.
+    #[cfg(feature = "metrics")]
+    fn count(&self, counter: &'static str) {
+        if let Some(ref name) = self.metrics_name {
+            metrics_facade::counter!(counter, "appender" => name.clone()).increment(1);
+        }
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    fn count(&self, _: &'static str) {}
+
+    /// Reports the number of cached appenders to the `metrics` facade, if the appender is named.
+    #[cfg(feature = "metrics")]
+    fn report_live(&self) {
+        if let Some(ref name) = self.metrics_name {
+            metrics_facade::gauge!(metrics::LIVE, "appender" => name.clone())
+                .set(self.map.len() as f64);
+        }
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    fn report_live(&self) {}
+
+    fn evict_expired(&mut self, key: &str, reason: Eviction, now: Instant) {
+        self.evict_entry(key, reason);
+        // only idleness can flap, since the lifetime is counted from the appender's creation
+        if reason == Eviction::Idle && self.policy.hysteresis.is_some() {
+            self.evicted.insert(key.to_owned(), now);
+        }
+    }
+
+    /// Writes a line to the index appender, if there is one.
+    fn index(&self, args: fmt::Arguments) {
+        let index = match self.index {
+            Some(ref index) => index,
+            None => return,
+        };
+        let record = Record::builder()
+            .level(Level::Info)
+            .target("log4rs_routing_appender::index")
+            .args(args)
+            .build();
+        if let Err(e) = index.appender.append(&record) {
+            let _ = writeln!(io::stderr(), "log4rs: error writing to index appender: {}", e);
+        }
+    }
+
+    fn index_eviction(&self, key: &str, entry: &TrackedAppender, reason: Eviction) {
+        if !self.index.as_ref().is_some_and(|i| i.evictions) {
+            return;
+        }
+        self.index(format_args!(
+            "evicted route key={:?} reason={} events={} bytes={}",
+            key,
+            reason.as_str(),
+            entry.events,
+            entry.bytes
+        ));
+    }
+
+    /// Sends a notification of the eviction to the eviction channel, if there is one.
+    fn notify_eviction(&mut self, key: &str, entry: &TrackedAppender, reason: Eviction) {
+        let sender = match self.eviction_channel {
+            Some(ref sender) => sender,
+            None => return,
+        };
+        let event = EvictionEvent {
+            key: key.to_owned(),
+            path: entry.path.clone(),
+            reason,
+        };
+        // a full channel drops the notification rather than blocking the logging thread
+        if let Err(TrySendError::Disconnected(_)) = sender.try_send(event) {
+            self.eviction_channel = None;
         }
     }
 
     fn purge(&mut self, now: Instant) {
-        let timeout = now - self.ttl;
-        loop {
-            match self.map.front() {
-                Some((_, v)) if v.used <= timeout => {}
-                _ => break,
+        // entries are ordered by last use, so this stops at the first entry which is still live,
+        // which delays the eviction of any behind it by at most the hysteresis
+        // decided up front, so that sweeping the last unprotected entries doesn't expose the
+        // protected ones to eviction when the entry being looked up is about to be recreated
+        let keep_protected = self.protected < self.map.len();
+        for _ in 0..SWEEP_LIMIT {
+            let (key, expired) = match self.map.front() {
+                Some((k, v)) => match self.policy.expiry(v, now, keep_protected) {
+                    Some(reason) => (k.clone(), Some(reason)),
+                    None if self.policy.is_idle(v, now) => (k.clone(), None),
+                    None => break,
+                },
+                None => break,
+            };
+            match expired {
+                Some(reason) => self.evict_expired(&key, reason, now),
+                // idle but protected, so kept behind the unprotected entries, which can still be
+                // swept
+                None => {
+                    self.map.get_refresh(&key);
+                }
+            }
+        }
+
+        if let Some(hysteresis) = self.policy.hysteresis {
+            loop {
+                match self.evicted.front() {
+                    Some((_, &evicted)) if now.duration_since(evicted) > hysteresis => {}
+                    _ => break,
+                }
+                self.evicted.pop_front();
             }
-            self.map.pop_front();
         }
     }
 }
 
+/// The appender handed to routers by `Cache::entry` for routes refused by the route limit, which
+/// the routing appender reports as an error rather than writing to.
+#[derive(Debug)]
+struct RefusedAppender;
+
+impl Append for RefusedAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        Err("route refused by the route limit".into())
+    }
+
+    fn flush(&self) {}
+}
+
+/// The error refusing a new route once the cache holds its maximum number of routes.
+///
+/// It is returned by `Cache::try_entry`, and by the routing appender for routes refused through
+/// `Cache::entry`. It is returned to log4rs like any other routing error, and the fallback router
+/// of the `RoutingAppender` is not tried, since its routes count towards the same limit.
+#[derive(Debug)]
+pub struct RouteLimitError {
+    max: usize,
+    key: String,
+    config: Option<String>,
+}
+
+impl RouteLimitError {
+    /// Returns the maximum number of routes of the cache.
+    pub fn max_routes(&self) -> usize {
+        self.max
+    }
+
+    /// Returns the key of the refused route.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Adds the kind and expanded configuration of the appender which would have been built for
+    /// the route to the error.
+    ///
+    /// Routers building appenders from templates should add it, since the configuration shows
+    /// which part of the template is responsible for the unexpected number of routes.
+    #[cfg(feature = "file")]
+    pub fn with_config(mut self, kind: &str, config: &Value) -> RouteLimitError {
+        let mut description = format!("kind: {}", kind);
+        match config {
+            Value::Map(map) if map.is_empty() => {}
+            _ => {
+                description.push_str(", ");
+                write_value(&mut description, config);
+            }
+        }
+        self.config = Some(description);
+        self
+    }
+}
+
+impl fmt::Display for RouteLimitError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "route limit of {} reached, refusing to create an appender for new route `{}`",
+            self.max, self.key
+        )?;
+        match self.config {
+            Some(ref config) => write!(fmt, " with configuration {{{}}}", config),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Error for RouteLimitError {}
+
+/// Writes a configuration value in a compact, YAML like notation, with the braces of a top level
+/// map left out.
+#[cfg(feature = "file")]
+fn write_value(out: &mut String, value: &Value) {
+    let scalar: &dyn fmt::Display = match value {
+        Value::Map(map) => {
+            for (i, (k, v)) in map.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                match k {
+                    // field names are left unquoted
+                    Value::String(k) => out.push_str(k),
+                    _ => write_nested(out, k),
+                }
+                out.push_str(": ");
+                write_nested(out, v);
+            }
+            return;
+        }
+        Value::Seq(seq) => {
+            out.push('[');
+            for (i, v) in seq.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_nested(out, v);
+            }
+            out.push(']');
+            return;
+        }
+        Value::Option(Some(v)) | Value::Newtype(v) => return write_nested(out, v),
+        Value::Option(None) | Value::Unit => return out.push('~'),
+        Value::String(s) => {
+            let _ = write!(out, "{:?}", s);
+            return;
+        }
+        Value::Char(c) => {
+            let _ = write!(out, "{:?}", c);
+            return;
+        }
+        Value::Bytes(bytes) => {
+            let _ = write!(out, "{:?}", bytes);
+            return;
+        }
+        Value::Bool(b) => b,
+        Value::U8(n) => n,
+        Value::U16(n) => n,
+        Value::U32(n) => n,
+        Value::U64(n) => n,
+        Value::I8(n) => n,
+        Value::I16(n) => n,
+        Value::I32(n) => n,
+        Value::I64(n) => n,
+        Value::F32(n) => n,
+        Value::F64(n) => n,
+    };
+    let _ = write!(out, "{}", scalar);
+}
+
+/// Writes a configuration value nested in another, with the braces of a map.
+#[cfg(feature = "file")]
+fn write_nested(out: &mut String, value: &Value) {
+    if let Value::Map(_) = value {
+        out.push('{');
+        write_value(out, value);
+        out.push('}');
+    } else {
+        write_value(out, value);
+    }
+}
+
 /// A (possibly vacant) entry of a `Cache`.
 pub enum Entry<'a> {
     /// An entry which is present in the `Cache`.
@@ -96,6 +1630,21 @@ impl<'a> Entry<'a> {
             Entry::Vacant(e) => e.insert(f()),
         }
     }
+
+    /// Returns the value of the entry, using the provided closure to create and insert it if the
+    /// entry is not present in the cache.
+    ///
+    /// If the closure fails, its error is returned and nothing is inserted, so the appender is
+    /// created again the next time the key is looked up.
+    pub fn or_try_insert_with<F>(self, f: F) -> Result<Appender, Box<dyn Error + Sync + Send>>
+    where
+        F: FnOnce() -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>>,
+    {
+        match self {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => Ok(e.insert(f()?)),
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -114,31 +1663,281 @@ pub struct VacantEntry<'a> {
     cache: &'a mut Cache,
     key: String,
     time: Instant,
+    protected: bool,
+    // the hash of the expanded configuration, once looked up by `share_config`
+    config: Option<ConfigHash>,
+    _permit: Option<Permit>,
 }
 
 impl<'a> VacantEntry<'a> {
+    /// Returns the key of the entry, including the scope of the lookup.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
     /// Inserts an appender into the cache, returning the wrapped version of it.
     pub fn insert(self, value: Box<dyn Append>) -> Appender {
-        let appender = Arc::new(value);
+        self.insert_tracked(value, None)
+    }
+
+    /// Inserts an appender which writes to the file at `path` into the cache, returning the
+    /// wrapped version of it.
+    ///
+    /// If the cache is configured to verify files, the appender is discarded and recreated if the
+    /// file is deleted or replaced, for example by log rotation. The file is only tracked if it
+    /// exists once the appender has been created.
+    pub fn insert_file(self, value: Box<dyn Append>, path: PathBuf) -> Appender {
+        self.insert_tracked(value, Some(path))
+    }
+
+    /// Shares the appender of a cached entry which writes to the file at `path` with this entry,
+    /// if the cache deduplicates appenders by path, returning the wrapped version of it.
+    ///
+    /// Otherwise, the entry is returned, so that the router can build the appender and insert it
+    /// with `insert_file`. Routers which know the file an appender would write to should call
+    /// this before building it, so that no file is opened by two appenders at once.
+    pub fn share_file(self, path: &Path) -> Result<Appender, VacantEntry<'a>> {
+        if !self.cache.dedup_by_path {
+            return Err(self);
+        }
+        let shared = match self.cache.paths.get(path) {
+            Some((shared, _)) => shared.clone(),
+            None => return Err(self),
+        };
+        Ok(self.insert_appender(shared.appender, shared.written, Some(path.to_owned())))
+    }
+
+    /// Shares the appender of a cached entry built from the same kind and expanded configuration
+    /// with this entry, returning the wrapped version of it.
+    ///
+    /// Otherwise, the entry is returned, so that the router can build the appender and insert it,
+    /// and the hash of the configuration is recorded along with the appender to share it with the
+    /// keys looked up later. Routers building appenders from templates should call this once they
+    /// have expanded the configuration, so that keys whose configurations expand the same way,
+    /// such as through default values, share one appender rather than each opening the same file.
+    /// Only the vacant entries of new keys pay for hashing the configuration.
+    ///
+    /// `distinct` is mixed into the hash, so that identical configurations which should still get
+    /// separate appenders, such as those of events with different targets under the pattern
+    /// router's `split_by_target`, only share with those given the same value.
+    #[cfg(feature = "file")]
+    pub fn share_config(
+        mut self,
+        kind: &str,
+        config: &Value,
+        distinct: Option<&str>,
+    ) -> Result<Appender, VacantEntry<'a>> {
+        let hash = hash128(&(kind, config, distinct));
+        self.config = Some(hash);
+        let shared = match self.cache.configs.get(&hash) {
+            Some((shared, _)) => shared.clone(),
+            None => return Err(self),
+        };
+        let path = shared.path.as_deref().map(Path::to_path_buf);
+        Ok(self.insert_appender(shared.appender, shared.written, path))
+    }
+
+    /// Shares the appender of the entry under `key`, which is looked up in the same cache and
+    /// passed to `f` to create it if needed, with this entry, returning the wrapped version of it.
+    ///
+    /// This lets a router caching a route to an internal appender, such as an overflow appender
+    /// under a reserved key, skip deciding the route again the next time the key is looked up.
+    pub fn share_entry<F>(self, key: String, f: F) -> Result<Appender, Box<dyn Error + Sync + Send>>
+    where
+        F: FnOnce(Entry) -> Result<Appender, Box<dyn Error + Sync + Send>>,
+    {
+        let shared = f(self.cache.try_entry(key)?)?;
+        let path = shared.path.as_deref().map(Path::to_path_buf);
+        Ok(self.insert_appender(shared.appender, shared.written, path))
+    }
+
+    fn insert_tracked(self, value: Box<dyn Append>, path: Option<PathBuf>) -> Appender {
+        self.insert_appender(
+            Arc::new(value),
+            Arc::new(AtomicBool::new(false)),
+            path,
+        )
+    }
+
+    fn insert_appender(
+        self,
+        value: Arc<Box<dyn Append>>,
+        written: Arc<AtomicBool>,
+        path: Option<PathBuf>,
+    ) -> Appender {
+        // evicted first, so that the cache never holds more appenders than its capacity
+        self.cache.make_room(1);
+        match path {
+            Some(ref path) => self.cache.index(format_args!(
+                "created route key={:?} path={:?}",
+                self.key, path
+            )),
+            None => self.cache.index(format_args!("created route key={:?}", self.key)),
+        }
+        let file = match (self.cache.verify_files, &path) {
+            (Some(_), Some(path)) => file_id(path).map(|id| TrackedFile {
+                id,
+                verified: self.time,
+            }),
+            _ => None,
+        };
+        let appender = Appender {
+            appender: value,
+            key: Arc::from(&*self.key),
+            path: path.as_deref().map(Arc::from),
+            written,
+        };
+        if self.cache.dedup_by_path {
+            if let Some(ref path) = path {
+                self.cache.share(path, &appender);
+            }
+        }
+        if let Some(config) = self.config {
+            self.cache.share_config(config, &appender);
+        }
+        // an appender recreated soon after going idle is likely to flap, so it is kept for longer
+        let recently_evicted = self.cache.evicted.remove(&self.key).is_some();
         let tracked = TrackedAppender {
-            appender: Appender(appender.clone()),
+            appender: appender.clone(),
+            created: self.time,
             used: self.time,
+            ttl: self.cache.policy.idle_timeout(recently_evicted),
+            path,
+            config: self.config,
+            file,
+            last_error: None,
+            events: 0,
+            bytes: 0,
+            uses: 0,
+            protected: self.protected,
+            circuit: Circuit {
+                window: self.time,
+                writes: 0,
+                failures: 0,
+                opened: None,
+                probing: false,
+            },
+            quota: QuotaUsage {
+                window: self.time,
+                used: 0,
+            },
+            #[cfg(feature = "file")]
+            origin: None,
         };
+        #[cfg(feature = "file")]
+        if let Some(ref mut warmup) = self.cache.warmup {
+            warmup.inserted.push(self.key.clone());
+        }
+        if tracked.protected {
+            self.cache.protected += 1;
+        }
         self.cache.map.insert(self.key, tracked);
-        Appender(appender)
+        self.cache.report_live();
+        appender
     }
 }
 
 /// An opaque, wrapped appender stored by the `Cache`.
-pub struct Appender(Arc<Box<dyn Append>>);
+#[derive(Clone)]
+pub struct Appender {
+    appender: Arc<Box<dyn Append>>,
+    key: Arc<str>,
+    path: Option<Arc<Path>>,
+    // set once the appender has written a log event successfully
+    written: Arc<AtomicBool>,
+}
+
+impl Appender {
+    /// Returns the key identifying this appender in the `Cache`.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Returns the path of the file the appender writes to, if it was inserted into the `Cache`
+    /// with one.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Determines if both wrap the same appender, whether or not under the same key.
+    fn same(&self, other: &Appender) -> bool {
+        Arc::ptr_eq(&self.appender, &other.appender)
+    }
+}
 
 impl AppenderInner for Appender {
     fn appender(&self) -> &dyn Append {
-        &**self.0
+        &**self.appender
+    }
+
+    fn set_written(&self) {
+        // checked first, so that the flag's cache line is only written to once
+        if !self.written.load(Ordering::Relaxed) {
+            self.written.store(true, Ordering::Relaxed);
+        }
     }
 }
 
 /// A trait implemented by types that can route log events to appenders.
+///
+/// The appender returned by `route` is passed the log event right away, and routers should only
+/// construct appenders through the `Cache` as they are needed for the event being routed. In
+/// particular, a router which delegates to several children should not resolve or construct the
+/// appenders of children which the event is not sent to, since constructing an appender can have
+/// side effects such as creating files.
+///
+/// Routers outside of this crate are written the same way as those within it: the router derives
+/// a key from the log event, looks it up with `Cache::entry`, and builds the appender if the entry
+/// is vacant. The cache takes care of everything else, such as evicting idle appenders, so the
+/// same key may be vacant again later on. Keys starting with `!` are reserved for the internal
+/// appenders of routers, such as fallbacks, and are exempt from the cache's cardinality budget.
+///
+/// # Examples
+///
+/// A router which spreads requests across a fixed number of files by the hash of the request ID
+/// in the MDC:
+///
+/// ```no_run
+/// # extern crate log;
+/// # extern crate log4rs;
+/// # extern crate log4rs_routing_appender;
+/// # extern crate log_mdc;
+/// # use log::Record;
+/// # use log4rs::append::file::FileAppender;
+/// # use log4rs_routing_appender::route::{Appender, Cache, Route};
+/// # use log4rs_routing_appender::RoutingAppender;
+/// # use std::error::Error;
+/// #[derive(Debug)]
+/// struct ShardRouter {
+///     shards: u64,
+/// }
+///
+/// impl Route for ShardRouter {
+///     fn route(
+///         &self,
+///         record: &Record,
+///         cache: &mut Cache,
+///     ) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+///         let id = log_mdc::get("request_id", |id| id.map(str::to_owned));
+///         let id = id.ok_or("no request ID in the MDC")?;
+///         // FNV-1a rather than `DefaultHasher`, whose output may change between Rust releases
+///         let hash = id.bytes().fold(0xcbf2_9ce4_8422_2325, |hash: u64, b| {
+///             (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+///         });
+///         let shard = hash % self.shards;
+///
+///         cache.entry(shard.to_string()).or_try_insert_with(|| {
+///             let path = format!("log/shard-{}.log", shard);
+///             Ok(Box::new(FileAppender::builder().build(path)?))
+///         })
+///     }
+/// }
+///
+/// # fn main() {
+/// let appender = RoutingAppender::builder().build(Box::new(ShardRouter { shards: 8 }));
+/// # drop(appender);
+/// # }
+/// ```
 pub trait Route: fmt::Debug + 'static + Sync + Send {
     /// Returns the appender to which the provided log event should be routed.
     fn route(
@@ -148,9 +1947,127 @@ pub trait Route: fmt::Debug + 'static + Sync + Send {
     ) -> Result<Appender, Box<dyn Error + Sync + Send>>;
 }
 
+/// A trait implemented by types which derive the cache key of a log event.
+///
+/// Routers which support it use the key returned by the strategy in place of the one they would
+/// derive themselves, which decouples caching from routing. Events with the same key share an
+/// appender, even if the router would have built different appenders for them, and the appender
+/// is built for whichever of those events is routed first.
+///
+/// It is implemented for closures of the appropriate type.
+pub trait KeyStrategy: 'static + Sync + Send {
+    /// Returns the cache key for the log event.
+    fn key(&self, record: &Record) -> String;
+}
+
+impl<F> KeyStrategy for F
+where
+    F: Fn(&Record) -> String + 'static + Sync + Send,
+{
+    fn key(&self, record: &Record) -> String {
+        self(record)
+    }
+}
+
+/// Returns the path of the file an appender with the provided configuration writes to, which by
+/// convention is held by its `path` field.
+#[cfg(feature = "pattern-router")]
+fn file_path(config: &Value) -> Option<PathBuf> {
+    match *config {
+        Value::Map(ref map) => match map.get(&Value::String("path".to_owned())) {
+            Some(Value::String(path)) => Some(PathBuf::from(path)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Returns an error if the expanded path of an appender holds nothing but whitespace and path
+/// separators, as happens when every value it refers to is missing and has an empty default.
+#[cfg(feature = "pattern-router")]
+fn check_path(path: &Path) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let path = path.to_string_lossy();
+    if path.chars().all(|c| c.is_whitespace() || std::path::is_separator(c)) {
+        return Err(format!(
+            "expanded path `{}` is empty; check that the values it refers to are present",
+            path
+        )
+        .into());
+    }
+    Ok(())
+}
+
 #[cfg(feature = "file")]
 impl Deserializable for dyn Route {
     fn name() -> &'static str {
         "router"
     }
 }
+
+/// The configuration of a router, consisting of its kind and the remainder of its configuration.
+///
+/// Routers which delegate to other routers can embed this in their own configuration and
+/// construct the child through the `Deserializers` they are provided with, which allows any
+/// registered router kind to be nested within any other.
+#[cfg(feature = "file")]
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
+pub struct RouterConfig {
+    /// The router kind.
+    pub kind: String,
+    /// The router configuration.
+    pub config: Value,
+}
+
+#[cfg(feature = "file")]
+impl<'de> de::Deserialize<'de> for RouterConfig {
+    fn deserialize<D>(d: D) -> Result<RouterConfig, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let mut map = BTreeMap::<Value, Value>::deserialize(d)?;
+
+        let kind = match map.remove(&Value::String("kind".to_owned())) {
+            Some(kind) => kind.deserialize_into().map_err(|e| e.to_error())?,
+            None => return Err(de::Error::missing_field("kind")),
+        };
+
+        Ok(RouterConfig {
+            kind,
+            config: Value::Map(map),
+        })
+    }
+}
+
+/// The configuration of an appender, consisting of its kind and the remainder of its
+/// configuration.
+///
+/// Routers which construct appenders can embed this in their own configuration and construct the
+/// appender through the `Deserializers` they are provided with.
+#[cfg(feature = "file")]
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct AppenderConfig {
+    /// The appender kind.
+    pub kind: String,
+    /// The appender configuration.
+    pub config: Value,
+}
+
+#[cfg(feature = "file")]
+impl<'de> de::Deserialize<'de> for AppenderConfig {
+    fn deserialize<D>(d: D) -> Result<AppenderConfig, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let mut map = BTreeMap::<Value, Value>::deserialize(d)?;
+
+        let kind = match map.remove(&Value::String("kind".to_owned())) {
+            Some(kind) => kind.deserialize_into().map_err(|e| e.to_error())?,
+            None => return Err(de::Error::missing_field("kind")),
+        };
+
+        Ok(AppenderConfig {
+            kind,
+            config: Value::Map(map),
+        })
+    }
+}
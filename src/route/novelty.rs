@@ -0,0 +1,191 @@
+//! A router which separates the first occurrence of each log message from its repeats.
+//!
+//! The router remembers the fingerprints of the messages it has seen, and sends a log event to the
+//! `first` appender if its fingerprint is new, and to the `repeat` appender otherwise. This allows
+//! an alerting pipeline to be notified once per distinct error, while the repeats are still logged
+//! somewhere quieter.
+//!
+//! The fingerprint is a hash of the formatted message, optionally combined with the target and
+//! level of the log event, so that the same message logged by different modules or at different
+//! levels can be told apart. Messages which differ only by an interpolated value, such as a
+//! request ID, have different fingerprints.
+//!
+//! At most `capacity` fingerprints are remembered. Once that many have been seen, the least
+//! recently seen one is forgotten to make room, so a message which has not been logged for long
+//! enough is considered new again.
+//!
+//! Each appender is only built once an event is sent to it.
+//!
+//! Requires the `novelty-router` feature.
+//!
+//! # Examples
+//!
+//! ```yaml
+//! kind: novelty
+//! capacity: 10000
+//! first:
+//!   kind: file
+//!   path: "log/alerts.log"
+//! repeat:
+//!   kind: file
+//!   path: "log/repeats.log"
+//! ```
+use antidote::Mutex;
+use linked_hash_map::LinkedHashMap;
+use log::Record;
+use log4rs::file::{Deserialize, Deserializers};
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+use route::{Appender, AppenderConfig, Cache, Entry, Route};
+
+/// Configuration for the `NoveltyRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NoveltyRouterConfig {
+    first: AppenderConfig,
+    repeat: AppenderConfig,
+    #[serde(default = "default_capacity")]
+    capacity: usize,
+    #[serde(default)]
+    with_target: bool,
+    #[serde(default)]
+    with_level: bool,
+}
+
+fn default_capacity() -> usize {
+    10000
+}
+
+/// A router which separates the first occurrence of each log message from its repeats.
+pub struct NoveltyRouter {
+    deserializers: Deserializers,
+    first: AppenderConfig,
+    repeat: AppenderConfig,
+    capacity: usize,
+    with_target: bool,
+    with_level: bool,
+    seen: Mutex<LinkedHashMap<u64, ()>>,
+}
+
+impl fmt::Debug for NoveltyRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("NoveltyRouter")
+            .field("capacity", &self.capacity)
+            .field("with_target", &self.with_target)
+            .field("with_level", &self.with_level)
+            .finish()
+    }
+}
+
+impl NoveltyRouter {
+    fn fingerprint(&self, record: &Record) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        record.args().to_string().hash(&mut hasher);
+        if self.with_target {
+            record.target().hash(&mut hasher);
+        }
+        if self.with_level {
+            record.level().hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// Records the fingerprint of the log event, returning whether it had not been seen before.
+    fn is_first(&self, record: &Record) -> bool {
+        let fingerprint = self.fingerprint(record);
+        let mut seen = self.seen.lock();
+        // refreshing a repeat keeps frequent messages from being forgotten
+        if seen.get_refresh(&fingerprint).is_some() {
+            return false;
+        }
+        if seen.len() == self.capacity {
+            seen.pop_front();
+        }
+        seen.insert(fingerprint, ());
+        true
+    }
+}
+
+impl Route for NoveltyRouter {
+    fn route(
+        &self,
+        record: &Record,
+        cache: &mut Cache,
+    ) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+        let (key, config) = if self.is_first(record) {
+            ("first", &self.first)
+        } else {
+            ("repeat", &self.repeat)
+        };
+        match cache.try_entry(key.to_owned())? {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => {
+                let appender = self
+                    .deserializers
+                    .deserialize(&config.kind, config.config.clone())?;
+                Ok(e.insert(appender))
+            }
+        }
+    }
+}
+
+/// A deserializer for the `NoveltyRouter`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: novelty
+///
+/// # The maximum number of message fingerprints remembered. Once exceeded, the
+/// # least recently seen one is forgotten. Must be at least 1. Defaults to
+/// # 10000.
+/// capacity: 10000
+///
+/// # Whether the target of a log event is part of its fingerprint. Defaults to
+/// # false.
+/// with_target: false
+///
+/// # Whether the level of a log event is part of its fingerprint. Defaults to
+/// # false.
+/// with_level: false
+///
+/// # The appender which the first occurrence of each message is sent to.
+/// # Required.
+/// first:
+///   kind: file
+///   path: "log/alerts.log"
+///
+/// # The appender which repeated messages are sent to. Required.
+/// repeat:
+///   kind: file
+///   path: "log/repeats.log"
+/// ```
+pub struct NoveltyRouterDeserializer;
+
+impl Deserialize for NoveltyRouterDeserializer {
+    type Trait = dyn Route;
+    type Config = NoveltyRouterConfig;
+
+    fn deserialize(
+        &self,
+        config: NoveltyRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<dyn Route>, Box<dyn Error + Sync + Send>> {
+        if config.capacity == 0 {
+            return Err("capacity must be at least 1".into());
+        }
+
+        Ok(Box::new(NoveltyRouter {
+            deserializers: deserializers.clone(),
+            first: config.first,
+            repeat: config.repeat,
+            capacity: config.capacity,
+            with_target: config.with_target,
+            with_level: config.with_level,
+            seen: Mutex::new(LinkedHashMap::new()),
+        }))
+    }
+}
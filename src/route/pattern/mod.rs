@@ -4,11 +4,70 @@
 //! to that of the log4rs pattern encoder, except that it is prefixed with a `$` to avoid conflicts
 //! with patterns in the templated configuration itself. Format specifications are not supported.
 //!
-//! Only one formatter is currently supported:
+//! The following formatters are supported:
 //!
 //! * `mdc` - An entry from the [MDC][MDC]. The first argument is required, and specifies the key to
-//!     look up. If the key is not present, an error is raised. A second, optional argument allows
-//!     a replacement string to be used if the key is not present.
+//!   look up. If the key is not present, an error is raised. A second, optional argument allows
+//!   a replacement string to be used if the key is not present. Defaults for keys can also be
+//!   configured once for the whole router, which are used by any reference to the key without a
+//!   replacement string of its own. A key ending in `*`, such as `${mdc(attr_*)}`, matches every
+//!   MDC entry whose key starts with the rest of it, and expands to their `key=value` pairs,
+//!   sorted by key and separated by commas, so that a change to any of them selects a different
+//!   appender. If no entries match, it expands to the replacement string if one is given, and to
+//!   nothing otherwise; router-wide defaults do not apply.
+//! * `kv` - A structured key-value pair attached to the log event, such as with
+//!   `info!(request_id = id; "...")`. The arguments are the same as those of `mdc`, except that
+//!   router-wide defaults do not apply. Requires the `log-kv` feature.
+//! * `level` - The level of the log event, in upper case (e.g. `ERROR`).
+//! * `target` - The target of the log event.
+//! * `thread` - The name of the thread which logged the event, or its numeric ID if it is unnamed.
+//!   Events logged from different threads are therefore sent to different appenders. Since the
+//!   name is that of the thread routing the event, an asynchronous routing appender expands it
+//!   to the name of its background thread.
+//! * `seq` - The next value of a process-wide counter, starting at 0. The counter is advanced
+//!   once each time a configuration is expanded, which happens when an appender is built for a
+//!   new route, so every appender gets a distinct, increasing number which it keeps for as long
+//!   as it is cached. Since the value is not part of the route, `log/${mdc(job)}-${seq}.log`
+//!   still gives a job a single appender, while a job ID which recurs once its appender has been
+//!   evicted, such as a job run again by the same process, gets a new file rather than appending
+//!   to the old one. The counter restarts with the process, so it does not tell apart the files
+//!   of different processes. Every reference in the same expansion has the same value. In a
+//!   `select` template, which is expanded for every event, the counter advances for every event.
+//! * `env` - An environment variable. The first argument is required, and specifies the name of
+//!   the variable. A second, optional argument allows a replacement string to be used if the
+//!   variable is not set; otherwise, an unset variable is an error when the router is configured.
+//!   Unlike the other formatters, it is resolved once, when the router is configured, rather than
+//!   for every route, so `${env(LOG_DIR)}/jobs/${mdc(job)}.log` reads `LOG_DIR` at startup and
+//!   the job of each event. Changes to the environment afterwards have no effect until the router
+//!   is configured again, and the value is not part of the route.
+//! * `raw` - The argument is included verbatim, without interpreting any directives within it,
+//!   so `${raw(${mdc(user_id)})}` produces the literal text `${mdc(user_id)}`. Unlike the other
+//!   formatters, the argument may contain parentheses and braces as long as they are balanced.
+//!
+//! `env` and `raw` are thus resolved when the router is configured, and the others each time the
+//! template is expanded, which is for each new route, and for each event in the case of a
+//! `select` template.
+//!
+//! The parentheses may be omitted for formatters which take no arguments, so `${level}` and
+//! `${level()}` are equivalent. A separate appender is created for each distinct combination of
+//! the values a template refers to, so `log/${mdc(service)}/${level}.log` creates one appender per
+//! service and level.
+//!
+//! A replacement string may also be written in the shell style, after `:-`, so
+//! `${mdc(user_id):-anonymous}` is equivalent to `${mdc(user_id)(anonymous)}`. This form suits
+//! replacements containing parentheses, since the replacement runs up to the closing brace; only
+//! the first `:-` separates it, so it may contain further `:-` but not a `}`. `${mdc(user_id):-}`
+//! uses an empty replacement, expanding to nothing if the key is not present.
+//!
+//! Only `$` needs escaping: `$$` produces a literal `$`, and any other `$` must start a directive,
+//! so `a$b` and a `$` at the end of a string are errors. Braces outside of a directive are
+//! literal text, so `log/{tenant}/${mdc(job)}.log` needs no escaping; within a directive, literal
+//! text can be included with `raw`.
+//!
+//! A reference to any other formatter is an error when the router is configured. Since formatters
+//! are added over time, configurations shared by binaries built against different versions of
+//! this crate can set `strict_templates: false` instead, in which case unknown formatters are
+//! reported once on standard error and are left in the expanded configuration as literal text.
 //!
 //! # Examples
 //!
@@ -33,33 +92,189 @@
 //! path: "logs/sfackler/no_job.log"
 //! ```
 //!
+//! The same result can be had by configuring a router-wide default for `job_id` in the `defaults`
+//! section of the router's configuration, leaving the template as `${mdc(job_id)}`.
+//!
+//! Alternatively, with `missing_key: named_marker`, an MDC key which is not present and has no
+//! default expands to a marker naming it, `_missing_job_id_` in this case:
+//!
+//! ```yaml
+//! kind: file
+//! path: "logs/sfackler/_missing_job_id_.log"
+//! ```
+//!
+//! Events lacking different keys then end up in different appenders, which makes it easy to tell
+//! which part of the context was incomplete.
+//!
+//! Events logged outside of any context, with none of the MDC entries the templates refer to
+//! present, can be handled as a whole with `no_context` instead, which can return an error, drop
+//! them, or route them to a dedicated appender:
+//!
+//! ```yaml
+//! no_context:
+//!   route_to:
+//!     kind: file
+//!     path: "logs/no_context.log"
+//! ```
+//!
+//! # Invalid configurations
+//!
+//! Malformed directives and references to unknown formatters are rejected when the router is
+//! configured, with an error naming the offending string and the offset, in characters, of the
+//! directive within it, such as
+//! ``unknown argument `mdcc` at offset 5 of `logs/${mdcc(user_id)}` ``.
+//!
+//! An expanded configuration may be rejected by the appender's deserializer, for example because
+//! an MDC value was substituted into a field which expects a number. Since the same values always
+//! expand to the same configuration, such an error is permanent, and the router stops trying to
+//! build an appender for those values. The error is reported once on standard error, and events
+//! for those values are sent to the router's fallback appender instead, or discarded if it has
+//! none. Errors raised while building the appender itself, such as a failure to open a file, may
+//! be transient and are returned to the caller as usual, and the next event retries the build.
+//!
+//! An expanded `path` consisting of nothing but whitespace and path separators, as happens when
+//! every value it refers to is missing and has an empty default, is treated the same way, rather
+//! than building an appender which writes to a baffling location.
+//!
+//! Map keys in the template may contain directives too, in which case two keys can expand to the
+//! same string. Rather than silently dropping one of the entries, expanding the template then
+//! fails with an error.
+//!
+//! # Redaction
+//!
+//! With the `regex` feature, the router can be configured with a list of regular expressions
+//! under `redact`, whose matches in the values substituted into the templates are replaced by
+//! `_redacted_`. This keeps sensitive data which finds its way into the MDC out of file names.
+//! The values are redacted before the cache key is derived from them too, so values which only
+//! differ in redacted parts share an appender.
+//!
+//! # Pattern sets
+//!
+//! Instead of a single template, the router may be configured with a set of named templates and
+//! a `select` template which expands to the name of the one to use. This allows the kind of the
+//! appender to vary by event, not just its configuration:
+//!
+//! ```yaml
+//! kind: pattern
+//! select: "${mdc(sink)}"
+//! pattern_set:
+//!   file:
+//!     kind: file
+//!     path: "logs/${mdc(user_id)}.log"
+//!   console:
+//!     kind: console
+//! ```
+//!
+//! The `select` template is expanded for every log event, and only the selected template is
+//! expanded and built when its appender is not already cached. If the selected name is not in
+//! the set, this is reported once per name on standard error, and the event is sent to the
+//! router's fallback appender, or discarded if it has none.
+//!
 //! [MDC]: https://crates.io/crates/log-mdc
+use antidote::Mutex;
+use log4rs::append::Append;
 use log4rs::file::{Deserialize, Deserializers};
 use log::Record;
-use serde::de;
-use serde_value::Value;
-use std::collections::BTreeMap;
+use serde_value::{DeserializerError, Value};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::ffi::OsString;
 use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
-use route::{Appender, Cache, Entry, Route};
+#[cfg(feature = "regex")]
+use regex::Regex;
+
+use route::{self, Appender, AppenderConfig, Cache, Entry, KeyStrategy, Route, VacantEntry};
 use route::pattern::template::Template;
 
-mod parser;
-mod template;
+pub mod parser;
+pub mod template;
 
 /// Configuration for the `PatternRouter`.
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct PatternRouterConfig {
-    pattern: AppenderConfig,
+    pattern: Option<AppenderConfig>,
+    pattern_set: Option<HashMap<String, AppenderConfig>>,
+    select: Option<String>,
+    #[serde(default)]
+    defaults: HashMap<String, String>,
+    #[serde(default)]
+    missing_key: MissingKey,
+    strict_templates: Option<bool>,
+    #[serde(default)]
+    split_by_target: bool,
+    #[cfg(feature = "regex")]
+    #[serde(default)]
+    redact: Vec<String>,
+    fallback: Option<AppenderConfig>,
+    no_context: Option<NoContext>,
+    max_files_per_dir: Option<usize>,
+}
+
+/// The handling of a log event logged outside of any MDC context, with none of the MDC entries the
+/// templates refer to present.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoContext {
+    /// An error is returned to the caller.
+    Error,
+    /// The event is discarded.
+    Drop,
+    /// The event is sent to the provided appender.
+    RouteTo(AppenderConfig),
+}
+
+/// The handling of an MDC key, or a key-value pair of a log event, which is not present and has
+/// no default.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingKey {
+    /// Expanding the template fails, and the error is returned to the caller.
+    #[default]
+    Error,
+    /// The key expands to `_missing_<key>_`.
+    NamedMarker,
+}
+
+struct Pattern {
+    kind: String,
+    config: Template,
+}
+
+enum Patterns {
+    Single(Pattern),
+    Set {
+        select: Template,
+        patterns: HashMap<String, Pattern>,
+    },
 }
 
 /// A router which expands an appender configuration template.
 pub struct PatternRouter {
     deserializers: Deserializers,
-    kind: String,
-    config: Template,
+    patterns: Patterns,
+    fallback: Option<AppenderConfig>,
+    no_context: Option<NoContext>,
+    key_strategy: Option<Box<dyn KeyStrategy>>,
+    // keys whose expanded configuration was rejected by the deserializer
+    invalid: Mutex<HashSet<String>>,
+    // selected pattern names which are not in the pattern set
+    unknown: Mutex<HashSet<String>>,
+    // whether the target of a log event is part of its key, and so of its configuration's
+    split_by_target: bool,
+    max_files_per_dir: Option<usize>,
+    // the files created in each directory, for `max_files_per_dir`
+    dirs: Mutex<HashMap<PathBuf, DirFiles>>,
+}
+
+#[derive(Default)]
+struct DirFiles {
+    names: HashSet<OsString>,
+    // whether the directory's limit has been reported
+    exceeded: bool,
 }
 
 impl fmt::Debug for PatternRouter {
@@ -68,19 +283,514 @@ impl fmt::Debug for PatternRouter {
     }
 }
 
-impl Route for PatternRouter {
-    fn route(&self, _: &Record, cache: &mut Cache) -> Result<Appender, Box<dyn Error + Sync + Send>> {
-        match cache.entry(self.config.key()) {
+impl PatternRouter {
+    /// Creates a new `PatternRouter` builder.
+    pub fn builder() -> PatternRouterBuilder {
+        PatternRouterBuilder {
+            defaults: HashMap::new(),
+            missing_key: MissingKey::Error,
+            strict_templates: true,
+            split_by_target: false,
+            #[cfg(feature = "regex")]
+            redact: vec![],
+            fallback: None,
+            no_context: None,
+            max_files_per_dir: None,
+            key_strategy: None,
+        }
+    }
+
+    fn fallback(&self, cache: &mut Cache) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+        // template keys never start with `!`, so this can't collide with a routed appender
+        match cache.try_entry("!fallback".to_owned())? {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => {
+                let appender: Box<dyn Append> = match self.fallback {
+                    Some(ref fallback) => self
+                        .deserializers
+                        .deserialize(&fallback.kind, fallback.config.clone())?,
+                    None => Box::new(NullAppender),
+                };
+                Ok(e.insert(appender))
+            }
+        }
+    }
+
+    /// Returns the path of the overflow file of the directory of `path` if creating `path` would
+    /// exceed the limit on the number of files in the directory.
+    fn dir_overflow_path(&self, path: &Path) -> Option<PathBuf> {
+        let max = self.max_files_per_dir?;
+        let (dir, name) = match (path.parent(), path.file_name()) {
+            (Some(dir), Some(name)) => (dir, name),
+            _ => return None,
+        };
+
+        let mut dirs = self.dirs.lock();
+        let files = dirs.entry(dir.to_owned()).or_default();
+        if files.names.len() < max || files.names.contains(name) {
+            return None;
+        }
+
+        let mut overflow = dir.join("_overflow");
+        if let Some(extension) = path.extension() {
+            overflow.set_extension(extension);
+        }
+        if !files.exceeded {
+            files.exceeded = true;
+            let _ = writeln!(
+                io::stderr(),
+                "log4rs: {} files have been created in `{}`, routing new keys in it to `{}`",
+                max,
+                dir.display(),
+                overflow.display()
+            );
+        }
+        Some(overflow)
+    }
+
+    /// Records that the file at `path` has been created, for `max_files_per_dir`.
+    fn record_file(&self, path: &Path) {
+        if self.max_files_per_dir.is_none() {
+            return;
+        }
+        if let (Some(dir), Some(name)) = (path.parent(), path.file_name()) {
+            let mut dirs = self.dirs.lock();
+            dirs.entry(dir.to_owned())
+                .or_default()
+                .names
+                .insert(name.to_owned());
+        }
+    }
+
+    /// Returns the appender writing to the overflow file `path`, built from `config` with its
+    /// path replaced, and caches it under the key of `entry` as well, so that later events for the
+    /// key go straight to it.
+    fn dir_overflow(
+        &self,
+        kind: &str,
+        config: Value,
+        path: PathBuf,
+        entry: VacantEntry,
+    ) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+        let key = format!("!dir_overflow:{}", path.display());
+        entry.share_entry(key, |entry| match entry {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => {
+                let config = match config {
+                    Value::Map(mut map) => {
+                        let value = Value::String(path.to_string_lossy().into_owned());
+                        map.insert(Value::String("path".to_owned()), value);
+                        Value::Map(map)
+                    }
+                    config => config,
+                };
+                let appender = self.deserializers.deserialize(kind, config)?;
+                Ok(e.insert_file(appender, path))
+            }
+        })
+    }
+
+    /// Returns whether any of the MDC entries the templates refer to is present, or `true` if
+    /// they refer to none.
+    fn has_mdc_context(&self) -> bool {
+        let templates: Vec<&Template> = match self.patterns {
+            Patterns::Single(ref pattern) => vec![&pattern.config],
+            Patterns::Set {
+                ref select,
+                ref patterns,
+            } => Some(select)
+                .into_iter()
+                .chain(patterns.values().map(|p| &p.config))
+                .collect(),
+        };
+        let mut templates = templates.into_iter().filter(|t| t.refers_to_mdc()).peekable();
+        templates.peek().is_none() || templates.any(|t| t.has_mdc_context())
+    }
+
+    fn no_context(
+        &self,
+        no_context: &NoContext,
+        cache: &mut Cache,
+    ) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+        let config = match *no_context {
+            NoContext::Error => {
+                return Err("none of the MDC entries referred to by the template are present".into())
+            }
+            NoContext::Drop => None,
+            NoContext::RouteTo(ref config) => Some(config),
+        };
+        match cache.try_entry("!no_context".to_owned())? {
             Entry::Occupied(e) => Ok(e.into_value()),
             Entry::Vacant(e) => {
-                let appender = self.deserializers
-                    .deserialize(&self.kind, self.config.expand()?)?;
+                let appender: Box<dyn Append> = match config {
+                    Some(config) => self
+                        .deserializers
+                        .deserialize(&config.kind, config.config.clone())?,
+                    None => Box::new(NullAppender),
+                };
                 Ok(e.insert(appender))
             }
         }
     }
 }
 
+impl Route for PatternRouter {
+    fn route(&self, record: &Record, cache: &mut Cache) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+        if let Some(ref no_context) = self.no_context {
+            if !self.has_mdc_context() {
+                return self.no_context(no_context, cache);
+            }
+        }
+
+        let (name, pattern) = match self.patterns {
+            Patterns::Single(ref pattern) => (None, pattern),
+            Patterns::Set {
+                ref select,
+                ref patterns,
+            } => {
+                let name = match select.expand(record, &[])? {
+                    Value::String(name) => name,
+                    _ => unreachable!("string templates expand to strings"),
+                };
+                match patterns.get(&name) {
+                    Some(pattern) => (Some(name), pattern),
+                    None => {
+                        if self.unknown.lock().insert(name.clone()) {
+                            let _ = writeln!(
+                                io::stderr(),
+                                "log4rs: no pattern named `{}` in pattern set, using fallback",
+                                name
+                            );
+                        }
+                        return self.fallback(cache);
+                    }
+                }
+            }
+        };
+
+        let key = match (&self.key_strategy, name) {
+            (Some(strategy), _) => strategy.key(record),
+            (None, Some(name)) => format!("{}{}{}", name.len(), name, pattern.config.key(record)),
+            (None, None) => pattern.config.key(record),
+        };
+
+        // the template is only expanded on a miss, and invalid keys are never cached, so a hit
+        // costs just the computation of the key and the lookup
+        let entry = match cache.try_entry(key) {
+            Ok(entry) => entry,
+            // the configuration shows which part of the template produces the unexpected routes
+            Err(e) => {
+                return Err(match pattern.config.expand(record, &[]) {
+                    Ok(config) => e.with_config(&pattern.kind, &config).into(),
+                    Err(_) => e.into(),
+                })
+            }
+        };
+        match entry {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => {
+                if self.invalid.lock().contains(e.key()) {
+                    return self.fallback(cache);
+                }
+
+                let config = pattern.config.expand(record, &[])?;
+                // keys whose configurations expand the same way share one appender, unless they are
+                // split by target
+                let target = if self.split_by_target {
+                    Some(record.target())
+                } else {
+                    None
+                };
+                let e = match e.share_config(&pattern.kind, &config, target) {
+                    Ok(appender) => return Ok(appender),
+                    Err(e) => e,
+                };
+                let path = route::file_path(&config);
+                // the same key always expands to the same path, so an empty one is permanent too
+                let checked = match path {
+                    Some(ref path) => route::check_path(path).map_err(|err| (err, true)),
+                    None => Ok(()),
+                };
+                let e = match (&checked, &path) {
+                    (Ok(()), Some(path)) => match e.share_file(path) {
+                        Ok(appender) => return Ok(appender),
+                        Err(e) => e,
+                    },
+                    _ => e,
+                };
+                if checked.is_ok() {
+                    if let Some(overflow) = path.as_ref().and_then(|p| self.dir_overflow_path(p)) {
+                        return self.dir_overflow(&pattern.kind, config, overflow, e);
+                    }
+                }
+                let built = checked.and_then(|()| {
+                    self.deserializers
+                        .deserialize(&pattern.kind, config)
+                        .map_err(|err| {
+                            let permanent = is_permanent(&*err, &pattern.kind);
+                            (err, permanent)
+                        })
+                });
+                match built {
+                    Ok(appender) => match path {
+                        Some(path) => {
+                            self.record_file(&path);
+                            Ok(e.insert_file(appender, path))
+                        }
+                        None => Ok(e.insert(appender)),
+                    },
+                    Err((err, true)) => {
+                        let _ = writeln!(
+                            io::stderr(),
+                            "log4rs: invalid routed appender configuration, using fallback: {}",
+                            err
+                        );
+                        self.invalid.lock().insert(e.key().to_owned());
+                        self.fallback(cache)
+                    }
+                    Err((err, false)) => Err(err),
+                }
+            }
+        }
+    }
+}
+
+/// A builder for `PatternRouter`s.
+pub struct PatternRouterBuilder {
+    defaults: HashMap<String, String>,
+    missing_key: MissingKey,
+    strict_templates: bool,
+    split_by_target: bool,
+    #[cfg(feature = "regex")]
+    redact: Vec<String>,
+    fallback: Option<AppenderConfig>,
+    no_context: Option<NoContext>,
+    max_files_per_dir: Option<usize>,
+    key_strategy: Option<Box<dyn KeyStrategy>>,
+}
+
+impl PatternRouterBuilder {
+    /// Sets the value used for an MDC key which is not present, unless the reference to the key in
+    /// the template provides a replacement of its own.
+    pub fn default(mut self, key: &str, value: &str) -> PatternRouterBuilder {
+        self.defaults.insert(key.to_owned(), value.to_owned());
+        self
+    }
+
+    /// Sets the handling of an MDC key or key-value pair which is not present and has no default.
+    ///
+    /// Defaults to `MissingKey::Error`.
+    pub fn missing_key(mut self, missing_key: MissingKey) -> PatternRouterBuilder {
+        self.missing_key = missing_key;
+        self
+    }
+
+    /// Sets whether a template referring to an unknown formatter is an error.
+    ///
+    /// If not, such references are reported once on standard error and expand to their literal
+    /// text, which lets configurations using formatters added in a newer version of this crate be
+    /// loaded by an older one. Defaults to `true`.
+    pub fn strict_templates(mut self, strict_templates: bool) -> PatternRouterBuilder {
+        self.strict_templates = strict_templates;
+        self
+    }
+
+    /// Sets whether log events with different targets are given separate appenders, even if the
+    /// template does not refer to the target.
+    ///
+    /// Only the cache key is affected: the template expands the same way for every target, so to
+    /// also separate the output by target, the template should refer to `${target}` in the path.
+    /// Without that, appenders for different targets write to the same file. Has no effect with
+    /// a custom key strategy.
+    ///
+    /// Defaults to `false`.
+    pub fn split_by_target(mut self, split_by_target: bool) -> PatternRouterBuilder {
+        self.split_by_target = split_by_target;
+        self
+    }
+
+    /// Adds a regular expression whose matches in the values substituted into the template are
+    /// replaced by `_redacted_`.
+    ///
+    /// This keeps sensitive data which finds its way into the MDC, such as personal identifiers,
+    /// out of file names. The values of MDC entries, key-value pairs, the target and the thread
+    /// name are redacted, and the values are redacted before the cache key is derived from them,
+    /// so values which only differ in redacted parts share an appender. Patterns are applied in
+    /// the order they are added. An error is returned by `build` if a pattern is invalid.
+    ///
+    /// Requires the `regex` feature.
+    #[cfg(feature = "regex")]
+    pub fn redact(mut self, pattern: &str) -> PatternRouterBuilder {
+        self.redact.push(pattern.to_owned());
+        self
+    }
+
+    /// Sets the appender used for log events whose expanded configuration is rejected by the
+    /// appender's deserializer.
+    ///
+    /// Defaults to discarding such events.
+    pub fn fallback(mut self, fallback: AppenderConfig) -> PatternRouterBuilder {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    /// Sets the handling of log events logged outside of any MDC context.
+    ///
+    /// An event is considered to be outside of any context if none of the MDC entries the
+    /// templates refer to is present, as with events logged outside of the handling of a request.
+    /// Such events would otherwise fail the expansion of any template referring to an entry
+    /// without a default, or expand to nothing but defaults. Events for which only some of the
+    /// entries are missing are still handled per entry, according to the defaults and
+    /// `missing_key`. With a pattern set, the `select` template and every template of the set
+    /// count. A router whose templates refer to no MDC entries never considers an event to be
+    /// outside of its context.
+    ///
+    /// Defaults to handling every missing entry on its own.
+    pub fn no_context(mut self, no_context: NoContext) -> PatternRouterBuilder {
+        self.no_context = Some(no_context);
+        self
+    }
+
+    /// Sets the maximum number of distinct files the router will create in a single directory.
+    ///
+    /// Some filesystems degrade with very many files in one directory. With a limit, the router
+    /// remembers the name of every file it has created in each directory of the expanded paths,
+    /// and once a directory holds `max` of them, new keys whose files would be created there are
+    /// routed to the file `_overflow` in that directory instead, with the extension of the file
+    /// they would have been written to, such as `logs/acme/_overflow.log`. The overflow file is
+    /// built from the configuration the first such key expands to, with its path replaced, so a
+    /// directory holds at most `max` files plus one overflow file for each extension. A key routed
+    /// to an overflow file is cached along with it, like any other key, and this is reported once
+    /// per directory on standard error. Files created before the router was built
+    /// are not counted, and files whose appenders are evicted still count, since they remain on
+    /// disk. Only configurations with a `path` are limited.
+    ///
+    /// Defaults to no limit.
+    pub fn max_files_per_dir(mut self, max: usize) -> PatternRouterBuilder {
+        self.max_files_per_dir = Some(max);
+        self
+    }
+
+    /// Sets the strategy used to derive the cache key of a log event.
+    ///
+    /// By default, every distinct combination of the values the template refers to has its own
+    /// appender. With a custom strategy, events with the same key share an appender, which is
+    /// built from the template as expanded for the first of them. Keys beginning with `!` are
+    /// reserved for the router's own use.
+    pub fn key_strategy<K>(mut self, key_strategy: K) -> PatternRouterBuilder
+    where
+        K: KeyStrategy,
+    {
+        self.key_strategy = Some(Box::new(key_strategy));
+        self
+    }
+
+    /// Consumes the builder, producing a `PatternRouter` which expands the `pattern` template.
+    ///
+    /// The appenders are constructed by `deserializers`, which must have a deserializer registered
+    /// for the kind of the pattern and that of the fallback, along with any other kinds their
+    /// configurations refer to, such as encoders. An error is returned if the template is invalid.
+    pub fn build(
+        self,
+        deserializers: Deserializers,
+        pattern: AppenderConfig,
+    ) -> Result<PatternRouter, Box<dyn Error + Sync + Send>> {
+        let pattern = self.pattern(pattern)?;
+        Ok(self.finish(deserializers, Patterns::Single(pattern)))
+    }
+
+    /// Consumes the builder, producing a `PatternRouter` which expands the template in `patterns`
+    /// named by the expansion of the `select` template.
+    ///
+    /// If the selected name is not in `patterns`, the event is sent to the fallback appender,
+    /// and this is reported once per name on standard error. The `select` template is expanded
+    /// for every log event. An error is returned if any of the templates is invalid.
+    pub fn build_set(
+        self,
+        deserializers: Deserializers,
+        select: &str,
+        patterns: HashMap<String, AppenderConfig>,
+    ) -> Result<PatternRouter, Box<dyn Error + Sync + Send>> {
+        let select = self.template(&Value::String(select.to_owned()))?;
+        let patterns = patterns
+            .into_iter()
+            .map(|(name, pattern)| Ok((name, self.pattern(pattern)?)))
+            .collect::<Result<_, Box<dyn Error + Sync + Send>>>()?;
+        Ok(self.finish(deserializers, Patterns::Set { select, patterns }))
+    }
+
+    fn pattern(&self, pattern: AppenderConfig) -> Result<Pattern, Box<dyn Error + Sync + Send>> {
+        Ok(Pattern {
+            config: self.template(&pattern.config)?,
+            kind: pattern.kind,
+        })
+    }
+
+    fn template(&self, value: &Value) -> Result<Template, Box<dyn Error + Sync + Send>> {
+        let mut template = if self.strict_templates {
+            Template::new(value, self.defaults.clone(), &[])?
+        } else {
+            Template::lenient(value, self.defaults.clone(), &[])?
+        };
+        template.set_named_markers(self.missing_key == MissingKey::NamedMarker);
+        template.set_split_by_target(self.split_by_target);
+        #[cfg(feature = "regex")]
+        {
+            let mut redactions = vec![];
+            for pattern in &self.redact {
+                let regex = Regex::new(pattern)
+                    .map_err(|e| format!("invalid redaction pattern `{}`: {}", pattern, e))?;
+                redactions.push(regex);
+            }
+            template.set_redactions(redactions);
+        }
+        Ok(template)
+    }
+
+    fn finish(self, deserializers: Deserializers, patterns: Patterns) -> PatternRouter {
+        PatternRouter {
+            deserializers,
+            patterns,
+            fallback: self.fallback,
+            no_context: self.no_context,
+            split_by_target: self.split_by_target && self.key_strategy.is_none(),
+            key_strategy: self.key_strategy,
+            invalid: Mutex::new(HashSet::new()),
+            unknown: Mutex::new(HashSet::new()),
+            max_files_per_dir: self.max_files_per_dir,
+            dirs: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// Determines if an error from deserializing an appender of kind `kind` is due to the
+/// configuration itself rather than a failure to construct the appender from it.
+fn is_permanent(e: &(dyn Error + 'static), kind: &str) -> bool {
+    e.is::<DeserializerError>() || e.to_string() == unregistered(kind)
+}
+
+/// Returns the error log4rs reports for an appender of kind `kind` when it is not registered.
+///
+/// The error has no type of its own, so it is recognized by comparing it with the one returned
+/// for the kind by an empty `Deserializers`, rather than by its wording.
+fn unregistered(kind: &str) -> String {
+    match Deserializers::empty().deserialize::<dyn Append>(kind, Value::Unit) {
+        Ok(_) => unreachable!(),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[derive(Debug)]
+struct NullAppender;
+
+impl Append for NullAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
 /// A deserializer for the `PatternRouter`.
 ///
 /// # Configuration
@@ -88,10 +798,74 @@ impl Route for PatternRouter {
 /// ```yaml
 /// kind: pattern
 ///
-/// # The configuration template to expand. Required.
+/// # The configuration template to expand. Exactly one of `pattern` or
+/// # `pattern_set` is required.
 /// pattern:
 ///   kind: file
 ///   path: "logs/${mdc(user_id)}/${mdc(job_id)(no_job)}.log"
+///
+/// # Named configuration templates, one of which is expanded for each log
+/// # event.
+/// pattern_set:
+///   file:
+///     kind: file
+///     path: "logs/${mdc(user_id)}.log"
+///   console:
+///     kind: console
+///
+/// # A template expanding to the name of the entry of `pattern_set` to use.
+/// # Required with `pattern_set`, and not allowed otherwise.
+/// select: "${mdc(sink)(file)}"
+///
+/// # Values used for MDC keys which are not present, unless the reference to
+/// # the key in the template provides a replacement of its own. Optional.
+/// defaults:
+///   user_id: anonymous
+///
+/// # The handling of MDC keys which are not present and have no default. One
+/// # of `error` (expanding the template fails) or `named_marker` (the key
+/// # expands to `_missing_<key>_`). Defaults to `error`.
+/// missing_key: named_marker
+///
+/// # Whether a reference to an unknown formatter in a template is an error. If
+/// # not, it is reported once on standard error and left as literal text.
+/// # Defaults to true.
+/// strict_templates: false
+///
+/// # Whether log events with different targets are given separate appenders,
+/// # even if the template does not refer to the target. Only routing is
+/// # affected, not the expanded configuration. Defaults to false.
+/// split_by_target: true
+///
+/// # Regular expressions whose matches in the values substituted into the
+/// # templates are replaced by `_redacted_`, before they are used in either
+/// # the configuration or the cache key. Requires the `regex` feature.
+/// # Defaults to no redaction.
+/// redact:
+///   - '\d{3}-\d{2}-\d{4}'
+///
+/// # The appender used for log events whose expanded configuration is rejected
+/// # by the appender's deserializer, or whose selected name is not in
+/// # `pattern_set`. Such events are discarded if not set. Optional.
+/// fallback:
+///   kind: file
+///   path: "logs/misconfigured.log"
+///
+/// # The handling of log events for which none of the MDC entries referred to
+/// # by the templates are present. One of `error` (an error is returned),
+/// # `drop` (the event is discarded) or `route_to` an appender, as below.
+/// # Events missing only some of the entries are unaffected. Defaults to
+/// # handling each missing entry according to `defaults` and `missing_key`.
+/// no_context:
+///   route_to:
+///     kind: file
+///     path: "logs/no_context.log"
+///
+/// # The maximum number of distinct files created in a single directory of the
+/// # expanded paths. Beyond it, new keys in the directory are routed to the
+/// # file `_overflow` in it, with the extension of the file they would have
+/// # been written to. Optional.
+/// max_files_per_dir: 10000
 /// ```
 pub struct PatternRouterDeserializer;
 
@@ -104,34 +878,38 @@ impl Deserialize for PatternRouterDeserializer {
         config: PatternRouterConfig,
         deserializers: &Deserializers,
     ) -> Result<Box<dyn Route>, Box<dyn Error + Sync + Send>> {
-        Ok(Box::new(PatternRouter {
-            deserializers: deserializers.clone(),
-            kind: config.pattern.kind,
-            config: Template::new(&config.pattern.config)?,
-        }))
-    }
-}
-
-struct AppenderConfig {
-    kind: String,
-    config: Value,
-}
-
-impl<'de> de::Deserialize<'de> for AppenderConfig {
-    fn deserialize<D>(d: D) -> Result<AppenderConfig, D::Error>
-    where
-        D: de::Deserializer<'de>,
-    {
-        let mut map = BTreeMap::<Value, Value>::deserialize(d)?;
-
-        let kind = match map.remove(&Value::String("kind".to_owned())) {
-            Some(kind) => kind.deserialize_into().map_err(|e| e.to_error())?,
-            None => return Err(de::Error::missing_field("kind")),
+        let mut builder = PatternRouter::builder();
+        builder.defaults = config.defaults;
+        builder = builder.missing_key(config.missing_key);
+        if let Some(strict_templates) = config.strict_templates {
+            builder = builder.strict_templates(strict_templates);
+        }
+        builder = builder.split_by_target(config.split_by_target);
+        #[cfg(feature = "regex")]
+        {
+            builder.redact = config.redact;
+        }
+        if let Some(fallback) = config.fallback {
+            builder = builder.fallback(fallback);
+        }
+        if let Some(no_context) = config.no_context {
+            builder = builder.no_context(no_context);
+        }
+        if let Some(max) = config.max_files_per_dir {
+            if max == 0 {
+                return Err("max_files_per_dir must be at least 1".into());
+            }
+            builder = builder.max_files_per_dir(max);
+        }
+        let router = match (config.pattern, config.pattern_set, config.select) {
+            (Some(pattern), None, None) => builder.build(deserializers.clone(), pattern)?,
+            (None, Some(patterns), Some(select)) => {
+                builder.build_set(deserializers.clone(), &select, patterns)?
+            }
+            (None, Some(_), None) => return Err("pattern_set requires select".into()),
+            (_, None, Some(_)) => return Err("select requires pattern_set".into()),
+            _ => return Err("exactly one of pattern or pattern_set is required".into()),
         };
-
-        Ok(AppenderConfig {
-            kind: kind,
-            config: Value::Map(map),
-        })
+        Ok(Box::new(router))
     }
 }
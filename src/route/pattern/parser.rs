@@ -1,25 +1,76 @@
+//! A parser for the substitution directives of templates.
+//!
+//! The parser splits a string into a sequence of pieces of literal text and directives such as
+//! `${mdc(user_id)(anonymous)}`, without interpreting the directives. `$$` produces a literal `$`
+//! and the argument of `${raw(..)}` is produced verbatim as text. A final argument may also be
+//! written in the shell style, after `:-`, so `${mdc(user_id):-anonymous}` produces the same
+//! directive as `${mdc(user_id)(anonymous)}`.
 use std::iter::Peekable;
 use std::str::CharIndices;
 
+/// A piece of a parsed string.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Piece<'a> {
+    /// Literal text.
     Text(&'a str),
-    Argument { name: &'a str, args: Vec<&'a str> },
+    /// A directive, such as `${mdc(user_id)}`.
+    Argument {
+        /// The name of the formatter, such as `mdc`.
+        name: &'a str,
+        /// The arguments of the formatter, in order. Parentheses with nothing in them produce an
+        /// empty argument, so `${level()}` has a single empty argument.
+        args: Vec<&'a str>,
+    },
+    /// A malformed directive. Parsing should stop at the first error, as the pieces which follow
+    /// it are unlikely to be meaningful.
     Error(&'static str),
 }
 
+/// An iterator over the pieces of a string.
+///
+/// # Examples
+///
+/// ```
+/// use log4rs_routing_appender::route::pattern::parser::{Parser, Piece};
+///
+/// let pieces = Parser::new("log/${mdc(user)}.log").collect::<Vec<_>>();
+/// assert_eq!(
+///     pieces,
+///     [
+///         Piece::Text("log/"),
+///         Piece::Argument { name: "mdc", args: vec!["user"] },
+///         Piece::Text(".log"),
+///     ]
+/// );
+/// ```
 pub struct Parser<'a> {
     pattern: &'a str,
     it: Peekable<CharIndices<'a>>,
+    offset: usize,
 }
 
 impl<'a> Parser<'a> {
+    /// Creates a parser over a string.
     pub fn new(pattern: &'a str) -> Parser<'a> {
         Parser {
-            pattern: pattern,
+            pattern,
             it: pattern.char_indices().peekable(),
+            offset: 0,
         }
     }
 
+    /// Returns the byte offset in the string at which the piece last produced starts, or, if it
+    /// is an error, the offset at which the error was found.
+    ///
+    /// This allows errors to point at the offending part of the string.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    fn position(&mut self) -> usize {
+        self.it.peek().map_or(self.pattern.len(), |&(pos, _)| pos)
+    }
+
     fn consume(&mut self, ch: char) -> bool {
         match self.it.peek() {
             Some(&(_, c)) if c == ch => {
@@ -35,16 +86,25 @@ impl<'a> Parser<'a> {
             return Piece::Error("expected `{`");
         }
         let name = self.name();
-        let args = match self.args() {
+        if name == "raw" {
+            return self.raw();
+        }
+        let mut args = match self.args() {
             Ok(args) => args,
             Err(e) => return Piece::Error(e),
         };
+        if self.consume(':') {
+            if !self.consume('-') {
+                return Piece::Error("expected `-`");
+            }
+            args.push(self.shell_arg());
+        }
         if !self.consume('}') {
             return Piece::Error("expected `}`");
         }
         Piece::Argument {
-            name: name,
-            args: args,
+            name,
+            args,
         }
     }
 
@@ -84,18 +144,63 @@ impl<'a> Parser<'a> {
         let start = match self.it.next() {
             Some((_, ')')) => return Ok(Some("")),
             Some((pos, _)) => pos,
-            None => return Err("Expected `)`"),
+            None => return Err("expected `)`"),
         };
 
         loop {
             match self.it.next() {
                 Some((pos, ')')) => return Ok(Some(&self.pattern[start..pos])),
                 Some(_) => {}
-                None => return Err("Expected `)`"),
+                None => return Err("expected `)`"),
             }
         }
     }
 
+    // A shell-style argument runs up to the closing brace, so it may contain anything else,
+    // including further `:-`.
+    fn shell_arg(&mut self) -> &'a str {
+        let start = self.position();
+        while let Some(&(_, ch)) = self.it.peek() {
+            if ch == '}' {
+                break;
+            }
+            self.it.next();
+        }
+        &self.pattern[start..self.position()]
+    }
+
+    // The argument of a raw section is emitted verbatim. It ends at the first `)` outside of any
+    // nested parentheses or braces, which must be balanced.
+    fn raw(&mut self) -> Piece<'a> {
+        if !self.consume('(') {
+            return Piece::Error("expected `(`");
+        }
+
+        let start = match self.it.peek() {
+            Some(&(pos, _)) => pos,
+            None => return Piece::Error("unterminated raw section"),
+        };
+        let mut parens = 0;
+        let mut braces = 0;
+        let end = loop {
+            match self.it.next() {
+                Some((pos, ')')) if parens == 0 && braces == 0 => break pos,
+                Some((_, '(')) => parens += 1,
+                Some((_, ')')) if parens > 0 => parens -= 1,
+                Some((_, '{')) => braces += 1,
+                Some((_, '}')) if braces > 0 => braces -= 1,
+                Some((_, ')')) | Some((_, '}')) => return Piece::Error("unbalanced raw section"),
+                Some(_) => {}
+                None => return Piece::Error("unterminated raw section"),
+            }
+        };
+
+        if !self.consume('}') {
+            return Piece::Error("expected `}`");
+        }
+        Piece::Text(&self.pattern[start..end])
+    }
+
     fn text(&mut self, start: usize) -> Piece<'a> {
         while let Some(&(pos, ch)) = self.it.peek() {
             match ch {
@@ -113,13 +218,22 @@ impl<'a> Iterator for Parser<'a> {
     type Item = Piece<'a>;
 
     fn next(&mut self) -> Option<Piece<'a>> {
+        self.offset = self.position();
         match self.it.peek() {
             Some(&(_, '$')) => {
                 self.it.next();
                 if self.consume('$') {
                     return Some(Piece::Text("$"));
                 }
-                Some(self.argument())
+                let piece = if self.it.peek().is_none() {
+                    Piece::Error("trailing `$`, which must be written as `$$`")
+                } else {
+                    self.argument()
+                };
+                if let Piece::Error(_) = piece {
+                    self.offset = self.position();
+                }
+                Some(piece)
             }
             Some(&(pos, _)) => Some(self.text(pos)),
             None => None,
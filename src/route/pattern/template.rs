@@ -1,45 +1,359 @@
+//! Configuration templates.
+//!
+//! A template is a configuration value whose strings may contain the substitution directives
+//! described in the [pattern router's documentation][pattern]. It is parsed once, and then
+//! expanded against a log event and the current MDC as many times as necessary.
+//!
+//! # Examples
+//!
+//! ```
+//! extern crate log;
+//! extern crate log4rs_routing_appender;
+//! extern crate log_mdc;
+//! extern crate serde_value;
+//!
+//! use log::Record;
+//! use log4rs_routing_appender::route::pattern::template::Template;
+//! use serde_value::Value;
+//! use std::collections::HashMap;
+//!
+//! # fn main() {
+//! let pattern = Value::String("log/${mdc(user)(anonymous)}.log".to_owned());
+//! let template = Template::new(&pattern, HashMap::new(), &[]).unwrap();
+//! let record = Record::builder().args(format_args!("")).build();
+//!
+//! log_mdc::insert("user", "sfackler");
+//! let path = template.expand(&record, &[]).unwrap();
+//! assert_eq!(path, Value::String("log/sfackler.log".to_owned()));
+//! # }
+//! ```
+//!
+//! [pattern]: ../index.html
 use serde_value::Value;
 use ordered_float::OrderedFloat;
+use std::borrow::Cow;
+use std::cell::Cell;
 use std::cmp::Ordering;
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::env;
 use std::error::Error;
-use std::fmt::Write;
+use std::fmt::{self, Write};
+use std::io::{self, Write as _};
+use std::sync::atomic::{self, AtomicU64};
+use std::thread;
+use log::Record;
 use log_mdc;
 
+#[cfg(feature = "log-kv")]
+use log::kv::Key;
+#[cfg(feature = "regex")]
+use regex::Regex;
+
 use route::pattern::parser::{Parser, Piece};
+use warmup;
 
+/// A parsed configuration template.
 pub struct Template {
     value: ValueTemplate,
-    keys: HashSet<String>,
+    defaults: HashMap<String, String>,
+    named_markers: bool,
+    redactions: Redactions,
+    // ordered, so that keys are built the same way in every process
+    keys: BTreeSet<String>,
+    prefixes: BTreeSet<String>,
+    #[cfg(feature = "log-kv")]
+    kv_keys: BTreeSet<String>,
+    level: bool,
+    target: bool,
+    thread: bool,
+}
+
+impl fmt::Debug for Template {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Template")
+            .field("keys", &self.keys)
+            .field("defaults", &self.defaults)
+            .field("named_markers", &self.named_markers)
+            .finish()
+    }
 }
 
 impl Template {
-    pub fn new(pattern: &Value) -> Result<Template, Box<dyn Error + Sync + Send>> {
-        let value = ValueTemplate::new(pattern)?;
-        let mut keys = HashSet::new();
-        value.keys(&mut keys);
+    /// Parses a template.
+    ///
+    /// `defaults` are the values used for MDC keys which are not present, unless the reference to
+    /// the key provides a replacement of its own. `variables` are the names of additional
+    /// no-argument formatters, such as `${shard}`, whose values are provided by the caller when
+    /// the template is expanded. A variable takes the place of the formatter of the same name, if
+    /// there is one.
+    ///
+    /// An error is returned if a string in the template contains a malformed directive or one
+    /// referring to an unknown formatter.
+    pub fn new(
+        pattern: &Value,
+        defaults: HashMap<String, String>,
+        variables: &[&str],
+    ) -> Result<Template, Box<dyn Error + Sync + Send>> {
+        Template::parse(pattern, defaults, variables, true)
+    }
+
+    /// Parses a template like `new`, except that directives referring to unknown formatters are
+    /// treated as literal text rather than as an error.
+    ///
+    /// This allows templates written for a newer version of the crate, which may use formatters
+    /// unknown to this one, to still be loaded. Each unknown formatter is reported once on
+    /// standard error. Malformed directives are still an error.
+    pub fn lenient(
+        pattern: &Value,
+        defaults: HashMap<String, String>,
+        variables: &[&str],
+    ) -> Result<Template, Box<dyn Error + Sync + Send>> {
+        Template::parse(pattern, defaults, variables, false)
+    }
+
+    fn parse(
+        pattern: &Value,
+        defaults: HashMap<String, String>,
+        variables: &[&str],
+        strict: bool,
+    ) -> Result<Template, Box<dyn Error + Sync + Send>> {
+        let mut unknown = HashSet::new();
+        let value = ValueTemplate::new(pattern, variables, strict, &mut unknown)?;
+        for name in &unknown {
+            let _ = writeln!(
+                io::stderr(),
+                "log4rs: unknown template formatter `{}`, treating it as literal text",
+                name
+            );
+        }
+        let mut keys = BTreeSet::new();
+        let mut prefixes = BTreeSet::new();
+        #[cfg(feature = "log-kv")]
+        let mut kv_keys = BTreeSet::new();
+        let mut level = false;
+        let mut target = false;
+        let mut thread = false;
+        value.for_each_chunk(&mut |chunk| match *chunk {
+            Chunk::Text(_) | Chunk::Variable(_) | Chunk::Seq => {}
+            Chunk::Mdc { ref key, .. } => {
+                keys.insert(key.clone());
+            }
+            Chunk::MdcWildcard { ref prefix, .. } => {
+                prefixes.insert(prefix.clone());
+            }
+            #[cfg(feature = "log-kv")]
+            Chunk::Kv { ref key, .. } => {
+                kv_keys.insert(key.clone());
+            }
+            Chunk::Level => level = true,
+            Chunk::Target => target = true,
+            Chunk::Thread => thread = true,
+        });
         Ok(Template {
-            value: value,
-            keys: keys,
+            value,
+            defaults,
+            named_markers: false,
+            redactions: Redactions::default(),
+            keys,
+            prefixes,
+            #[cfg(feature = "log-kv")]
+            kv_keys,
+            level,
+            target,
+            thread,
         })
     }
 
-    pub fn key(&self) -> String {
+    /// Sets whether MDC keys which are not present and have no default expand to a marker naming
+    /// the key, rather than failing the expansion.
+    pub fn set_named_markers(&mut self, named_markers: bool) {
+        self.named_markers = named_markers;
+    }
+
+    /// Sets patterns whose matches in the values substituted into the template are replaced by
+    /// `_redacted_`.
+    ///
+    /// The values of MDC entries, key-value pairs, the target and the thread name are redacted,
+    /// in both `expand` and `key`, so values which only differ in redacted parts share a key.
+    /// Literal text and defaults in the template are not redacted. The patterns are applied in
+    /// order, each to the result of the previous ones.
+    ///
+    /// Requires the `regex` feature.
+    #[cfg(feature = "regex")]
+    pub fn set_redactions(&mut self, redactions: Vec<Regex>) {
+        self.redactions = Redactions(redactions);
+    }
+
+    /// Sets whether the target of the log event is included in the key, even if the template does
+    /// not refer to it.
+    ///
+    /// This only affects `key`, not `expand`, so log events with different targets are told apart
+    /// while still expanding to the same configuration.
+    pub fn set_split_by_target(&mut self, split_by_target: bool) {
+        self.target = self.target || split_by_target;
+    }
+
+    /// Returns whether the template refers to any MDC entries.
+    pub fn refers_to_mdc(&self) -> bool {
+        !self.keys.is_empty() || !self.prefixes.is_empty()
+    }
+
+    /// Returns whether any of the MDC entries the template refers to is present in the current
+    /// MDC, counting a prefix such as `attr_*` as present if any entry matches it.
+    pub fn has_mdc_context(&self) -> bool {
+        self.keys.iter().any(|key| log_mdc::get(key, |v| v.is_some()))
+            || self.prefixes.iter().any(|prefix| !mdc_entries(prefix).is_empty())
+    }
+
+    /// Returns a key which uniquely identifies the expansion of the template for the record and
+    /// the current MDC.
+    ///
+    /// The key is the same in every process for the same template, record and MDC. Each MDC entry
+    /// and key-value pair is identified by its name as well as its value, so templates referring
+    /// to different entries don't produce the same keys. Variables are not included, so routers
+    /// providing them must account for their values separately.
+    pub fn key(&self, record: &Record) -> String {
         let mut s = String::new();
         for key in &self.keys {
+            write!(s, "{}{}", key.len(), key).unwrap();
             log_mdc::get(key, |k| match k {
-                Some(k) => write!(s, "{}{}", k.len(), k).unwrap(),
+                Some(k) => {
+                    let k = self.redactions.apply(k);
+                    warmup::read_mdc(key, &k);
+                    write!(s, "{}{}", k.len(), k).unwrap()
+                }
                 None => s.push('-'),
             });
         }
+        for prefix in &self.prefixes {
+            let entries = mdc_entries(prefix);
+            write!(s, "{}{}{}:", prefix.len(), prefix, entries.len()).unwrap();
+            for (k, v) in entries {
+                let v = self.redactions.apply(&v);
+                warmup::read_mdc(&k, &v);
+                write!(s, "{}{}{}{}", k.len(), k, v.len(), v).unwrap();
+            }
+        }
+        #[cfg(feature = "log-kv")]
+        for key in &self.kv_keys {
+            write!(s, "{}{}", key.len(), key).unwrap();
+            match record.key_values().get(Key::from_str(key)) {
+                Some(v) => {
+                    let v = v.to_string();
+                    let v = self.redactions.apply(&v);
+                    write!(s, "{}{}", v.len(), v).unwrap();
+                }
+                None => s.push('-'),
+            }
+        }
+        if self.level {
+            let level = record.level().as_str();
+            write!(s, "{}{}", level.len(), level).unwrap();
+        }
+        if self.target {
+            let target = self.redactions.apply(record.target());
+            write!(s, "{}{}", target.len(), target).unwrap();
+        }
+        if self.thread {
+            with_thread_name(|name| {
+                let name = self.redactions.apply(name);
+                write!(s, "{}{}", name.len(), name).unwrap()
+            });
+        }
         s
     }
 
-    pub fn expand(&self) -> Result<Value, Box<dyn Error + Sync + Send>> {
-        self.value.expand()
+    /// Expands the template for the record and the current MDC.
+    ///
+    /// `variables` provides the values of the variables named when the template was parsed. An
+    /// error is returned if an MDC key is not present and has no default, if a variable
+    /// referred to by the template is not provided, or if two map keys expand to the same value.
+    pub fn expand(
+        &self,
+        record: &Record,
+        variables: &[(&str, &str)],
+    ) -> Result<Value, Box<dyn Error + Sync + Send>> {
+        self.value.expand(&Context {
+            record,
+            defaults: &self.defaults,
+            named_markers: self.named_markers,
+            redactions: &self.redactions,
+            variables,
+            seq: Cell::new(None),
+        })
+    }
+}
+
+/// The patterns redacted from substituted values.
+#[derive(Default)]
+struct Redactions(#[cfg(feature = "regex")] Vec<Regex>);
+
+impl Redactions {
+    #[cfg(feature = "regex")]
+    fn apply<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        let mut value = Cow::Borrowed(value);
+        for pattern in &self.0 {
+            if pattern.is_match(&value) {
+                value = Cow::Owned(pattern.replace_all(&value, "_redacted_").into_owned());
+            }
+        }
+        value
+    }
+
+    #[cfg(not(feature = "regex"))]
+    fn apply<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(value)
+    }
+}
+
+/// The state a template is expanded against.
+struct Context<'a> {
+    record: &'a Record<'a>,
+    defaults: &'a HashMap<String, String>,
+    named_markers: bool,
+    redactions: &'a Redactions,
+    variables: &'a [(&'a str, &'a str)],
+    // the value of `${seq}`, taken from `SEQ` the first time it is needed
+    seq: Cell<Option<u64>>,
+}
+
+/// The process-wide counter behind `${seq}`.
+static SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Calls `f` with the current thread's name, or its numeric ID if it is unnamed.
+fn with_thread_name<F, T>(f: F) -> T
+where
+    F: FnOnce(&str) -> T,
+{
+    let thread = thread::current();
+    match thread.name() {
+        Some(name) => f(name),
+        None => {
+            // ThreadId has no stable numeric accessor, but its Debug output is `ThreadId(N)`
+            let id = format!("{:?}", thread.id());
+            f(id.trim_start_matches("ThreadId(").trim_end_matches(')'))
+        }
     }
 }
 
+/// Returns the MDC entries whose keys start with `prefix`, sorted by key.
+fn mdc_entries(prefix: &str) -> Vec<(String, String)> {
+    let mut entries = vec![];
+    log_mdc::iter(|k, v| {
+        if k.starts_with(prefix) {
+            entries.push((k.to_owned(), v.to_owned()));
+        }
+    });
+    entries.sort();
+    entries
+}
+
+/// Creates an error pointing at the byte offset `pos` of the pattern string `s`.
+fn located(msg: String, s: &str, pos: usize) -> Box<dyn Error + Sync + Send> {
+    let offset = s[..pos].chars().count();
+    format!("{} at offset {} of `{}`", msg, offset, s).into()
+}
+
 #[derive(PartialOrd, Ord, PartialEq, Eq)]
 enum Chunk {
     Text(String),
@@ -47,6 +361,20 @@ enum Chunk {
         key: String,
         default: Option<String>,
     },
+    MdcWildcard {
+        prefix: String,
+        default: Option<String>,
+    },
+    #[cfg(feature = "log-kv")]
+    Kv {
+        key: String,
+        default: Option<String>,
+    },
+    Level,
+    Target,
+    Thread,
+    Seq,
+    Variable(String),
 }
 
 enum ValueTemplate {
@@ -90,13 +418,13 @@ impl PartialEq for ValueTemplate {
             (&ValueTemplate::F64(v0), &ValueTemplate::F64(v1)) if OrderedFloat(v0) ==
                                                                   OrderedFloat(v1) => true,
             (&ValueTemplate::Char(v0), &ValueTemplate::Char(v1)) if v0 == v1 => true,
-            (&ValueTemplate::String(ref v0), &ValueTemplate::String(ref v1)) if v0 == v1 => true,
+            (ValueTemplate::String(v0), ValueTemplate::String(v1)) if v0 == v1 => true,
             (&ValueTemplate::Unit, &ValueTemplate::Unit) => true,
-            (&ValueTemplate::Option(ref v0), &ValueTemplate::Option(ref v1)) if v0 == v1 => true,
-            (&ValueTemplate::Newtype(ref v0), &ValueTemplate::Newtype(ref v1)) if v0 == v1 => true,
-            (&ValueTemplate::Seq(ref v0), &ValueTemplate::Seq(ref v1)) if v0 == v1 => true,
-            (&ValueTemplate::Map(ref v0), &ValueTemplate::Map(ref v1)) if v0 == v1 => true,
-            (&ValueTemplate::Bytes(ref v0), &ValueTemplate::Bytes(ref v1)) if v0 == v1 => true,
+            (ValueTemplate::Option(v0), ValueTemplate::Option(v1)) if v0 == v1 => true,
+            (ValueTemplate::Newtype(v0), ValueTemplate::Newtype(v1)) if v0 == v1 => true,
+            (ValueTemplate::Seq(v0), ValueTemplate::Seq(v1)) if v0 == v1 => true,
+            (ValueTemplate::Map(v0), ValueTemplate::Map(v1)) if v0 == v1 => true,
+            (ValueTemplate::Bytes(v0), ValueTemplate::Bytes(v1)) if v0 == v1 => true,
             _ => false,
         }
     }
@@ -111,48 +439,60 @@ impl PartialOrd for ValueTemplate {
 impl Ord for ValueTemplate {
     fn cmp(&self, rhs: &Self) -> Ordering {
         match (self, rhs) {
-            (&ValueTemplate::Bool(v0), &ValueTemplate::Bool(ref v1)) => v0.cmp(v1),
-            (&ValueTemplate::U8(v0), &ValueTemplate::U8(ref v1)) => v0.cmp(v1),
-            (&ValueTemplate::U16(v0), &ValueTemplate::U16(ref v1)) => v0.cmp(v1),
-            (&ValueTemplate::U32(v0), &ValueTemplate::U32(ref v1)) => v0.cmp(v1),
-            (&ValueTemplate::U64(v0), &ValueTemplate::U64(ref v1)) => v0.cmp(v1),
-            (&ValueTemplate::I8(v0), &ValueTemplate::I8(ref v1)) => v0.cmp(v1),
-            (&ValueTemplate::I16(v0), &ValueTemplate::I16(ref v1)) => v0.cmp(v1),
-            (&ValueTemplate::I32(v0), &ValueTemplate::I32(ref v1)) => v0.cmp(v1),
-            (&ValueTemplate::I64(v0), &ValueTemplate::I64(ref v1)) => v0.cmp(v1),
+            (ValueTemplate::Bool(v0), ValueTemplate::Bool(v1)) => v0.cmp(v1),
+            (ValueTemplate::U8(v0), ValueTemplate::U8(v1)) => v0.cmp(v1),
+            (ValueTemplate::U16(v0), ValueTemplate::U16(v1)) => v0.cmp(v1),
+            (ValueTemplate::U32(v0), ValueTemplate::U32(v1)) => v0.cmp(v1),
+            (ValueTemplate::U64(v0), ValueTemplate::U64(v1)) => v0.cmp(v1),
+            (ValueTemplate::I8(v0), ValueTemplate::I8(v1)) => v0.cmp(v1),
+            (ValueTemplate::I16(v0), ValueTemplate::I16(v1)) => v0.cmp(v1),
+            (ValueTemplate::I32(v0), ValueTemplate::I32(v1)) => v0.cmp(v1),
+            (ValueTemplate::I64(v0), ValueTemplate::I64(v1)) => v0.cmp(v1),
             (&ValueTemplate::F32(v0), &ValueTemplate::F32(v1)) => {
                 OrderedFloat(v0).cmp(&OrderedFloat(v1))
             }
             (&ValueTemplate::F64(v0), &ValueTemplate::F64(v1)) => {
                 OrderedFloat(v0).cmp(&OrderedFloat(v1))
             }
-            (&ValueTemplate::Char(v0), &ValueTemplate::Char(ref v1)) => v0.cmp(v1),
-            (&ValueTemplate::String(ref v0), &ValueTemplate::String(ref v1)) => v0.cmp(v1),
+            (ValueTemplate::Char(v0), ValueTemplate::Char(v1)) => v0.cmp(v1),
+            (ValueTemplate::String(v0), ValueTemplate::String(v1)) => v0.cmp(v1),
             (&ValueTemplate::Unit, &ValueTemplate::Unit) => Ordering::Equal,
-            (&ValueTemplate::Option(ref v0), &ValueTemplate::Option(ref v1)) => v0.cmp(v1),
-            (&ValueTemplate::Newtype(ref v0), &ValueTemplate::Newtype(ref v1)) => v0.cmp(v1),
-            (&ValueTemplate::Seq(ref v0), &ValueTemplate::Seq(ref v1)) => v0.cmp(v1),
-            (&ValueTemplate::Map(ref v0), &ValueTemplate::Map(ref v1)) => v0.cmp(v1),
-            (&ValueTemplate::Bytes(ref v0), &ValueTemplate::Bytes(ref v1)) => v0.cmp(v1),
-            (ref v0, ref v1) => v0.discriminant().cmp(&v1.discriminant()),
+            (ValueTemplate::Option(v0), ValueTemplate::Option(v1)) => v0.cmp(v1),
+            (ValueTemplate::Newtype(v0), ValueTemplate::Newtype(v1)) => v0.cmp(v1),
+            (ValueTemplate::Seq(v0), ValueTemplate::Seq(v1)) => v0.cmp(v1),
+            (ValueTemplate::Map(v0), ValueTemplate::Map(v1)) => v0.cmp(v1),
+            (ValueTemplate::Bytes(v0), ValueTemplate::Bytes(v1)) => v0.cmp(v1),
+            (v0, v1) => v0.discriminant().cmp(&v1.discriminant()),
         }
     }
 }
 
 impl ValueTemplate {
-    fn new(value: &Value) -> Result<ValueTemplate, Box<dyn Error + Sync + Send>> {
+    /// Parses a value. If `strict` is not set, directives referring to unknown formatters are kept
+    /// as literal text, and the names of the formatters are added to `unknown`.
+    fn new(
+        value: &Value,
+        variables: &[&str],
+        strict: bool,
+        unknown: &mut HashSet<String>,
+    ) -> Result<ValueTemplate, Box<dyn Error + Sync + Send>> {
         let value = match *value {
             Value::Map(ref m) => {
                 let mut m2 = BTreeMap::new();
                 for (k, v) in m {
-                    m2.insert(ValueTemplate::new(k)?, ValueTemplate::new(v)?);
+                    m2.insert(
+                        ValueTemplate::new(k, variables, strict, unknown)?,
+                        ValueTemplate::new(v, variables, strict, unknown)?,
+                    );
                 }
                 ValueTemplate::Map(m2)
             }
-            Value::Newtype(ref v) => ValueTemplate::Newtype(Box::new(ValueTemplate::new(v)?)),
+            Value::Newtype(ref v) => {
+                ValueTemplate::Newtype(Box::new(ValueTemplate::new(v, variables, strict, unknown)?))
+            }
             Value::Option(ref v) => {
                 let v = match *v {
-                    Some(ref v) => Some(Box::new(ValueTemplate::new(v)?)),
+                    Some(ref v) => Some(Box::new(ValueTemplate::new(v, variables, strict, unknown)?)),
                     None => None,
                 };
                 ValueTemplate::Option(v)
@@ -160,28 +500,91 @@ impl ValueTemplate {
             Value::Seq(ref vs) => {
                 let mut vs2 = vec![];
                 for v in vs {
-                    vs2.push(ValueTemplate::new(v)?);
+                    vs2.push(ValueTemplate::new(v, variables, strict, unknown)?);
                 }
                 ValueTemplate::Seq(vs2)
             }
             Value::String(ref s) => {
                 let mut chunks = vec![];
-                for piece in Parser::new(s) {
+                let mut parser = Parser::new(s);
+                while let Some(piece) = parser.next() {
+                    let at = |msg: String| located(msg, s, parser.offset());
                     let c = match piece {
                         Piece::Text(t) => Chunk::Text(t.to_owned()),
                         Piece::Argument { name: "mdc", args } => {
                             if args.is_empty() || args.len() > 2 {
-                                return Err(format!("expected 1 or 2 arguments: `{}`", s).into());
+                                return Err(at("expected 1 or 2 arguments to `mdc`".to_owned()));
+                            }
+                            let default = args.get(1).map(|&s| s.to_owned());
+                            match args[0].strip_suffix('*') {
+                                Some(prefix) => Chunk::MdcWildcard {
+                                    prefix: prefix.to_owned(),
+                                    default,
+                                },
+                                None => Chunk::Mdc {
+                                    key: args[0].to_owned(),
+                                    default,
+                                },
+                            }
+                        }
+                        // resolved once and for all, so that it costs nothing per event
+                        Piece::Argument { name: "env", args } => {
+                            if args.is_empty() || args.len() > 2 {
+                                return Err(at("expected 1 or 2 arguments to `env`".to_owned()));
+                            }
+                            match (env::var(args[0]), args.get(1)) {
+                                (Ok(value), _) => Chunk::Text(value),
+                                (Err(env::VarError::NotPresent), Some(&default)) => {
+                                    Chunk::Text(default.to_owned())
+                                }
+                                (Err(e), _) => {
+                                    let msg = format!("environment variable `{}`: {}", args[0], e);
+                                    return Err(at(msg));
+                                }
+                            }
+                        }
+                        #[cfg(feature = "log-kv")]
+                        Piece::Argument { name: "kv", args } => {
+                            if args.is_empty() || args.len() > 2 {
+                                return Err(at("expected 1 or 2 arguments to `kv`".to_owned()));
                             }
-                            Chunk::Mdc {
+                            Chunk::Kv {
                                 key: args[0].to_owned(),
                                 default: args.get(1).map(|&s| s.to_owned()),
                             }
                         }
+                        Piece::Argument { name, args }
+                            if name == "level"
+                                || name == "target"
+                                || name == "thread"
+                                || name == "seq"
+                                || variables.contains(&name) =>
+                        {
+                            if !(args.is_empty() || args == [""]) {
+                                return Err(at(format!("expected no arguments to `{}`", name)));
+                            }
+                            match name {
+                                // variables shadow the formatters of the same name
+                                _ if variables.contains(&name) => Chunk::Variable(name.to_owned()),
+                                "level" => Chunk::Level,
+                                "target" => Chunk::Target,
+                                "thread" => Chunk::Thread,
+                                _ => Chunk::Seq,
+                            }
+                        }
+                        Piece::Argument { name, args } if !strict => {
+                            unknown.insert(name.to_owned());
+                            let mut t = format!("${{{}", name);
+                            for arg in args {
+                                write!(t, "({})", arg).unwrap();
+                            }
+                            t.push('}');
+                            Chunk::Text(t)
+                        }
                         Piece::Argument { name, .. } => {
-                            return Err(format!("unknown argument `{}`: `{}`", name, s).into());
+                            return Err(at(format!("unknown argument `{}`", name)));
                         }
-                        Piece::Error(e) => return Err(format!("{}: `{}`", e, s).into()),
+                        Piece::Error(e) => return Err(at(e.to_owned())),
                     };
                     chunks.push(c);
                 }
@@ -229,56 +632,62 @@ impl ValueTemplate {
         }
     }
 
-    fn keys(&self, keys: &mut HashSet<String>) {
+    fn for_each_chunk<F>(&self, f: &mut F)
+    where
+        F: FnMut(&Chunk),
+    {
         match *self {
             ValueTemplate::Map(ref m) => {
                 for (k, v) in m {
-                    k.keys(keys);
-                    v.keys(keys);
-                }
-            }
-            ValueTemplate::Newtype(ref v) => v.keys(keys),
-            ValueTemplate::Option(ref v) => {
-                if let Some(ref v) = *v {
-                    v.keys(keys);
+                    k.for_each_chunk(f);
+                    v.for_each_chunk(f);
                 }
             }
+            ValueTemplate::Newtype(ref v) => v.for_each_chunk(f),
+            ValueTemplate::Option(Some(ref v)) => v.for_each_chunk(f),
             ValueTemplate::Seq(ref vs) => {
                 for v in vs {
-                    v.keys(keys);
+                    v.for_each_chunk(f);
                 }
             }
             ValueTemplate::String(ref chunks) => {
                 for chunk in chunks {
-                    if let Chunk::Mdc { ref key, .. } = *chunk {
-                        keys.insert(key.clone());
-                    }
+                    f(chunk);
                 }
             }
             _ => {}
         }
     }
 
-    fn expand(&self) -> Result<Value, Box<dyn Error + Sync + Send>> {
+    fn expand(&self, cx: &Context) -> Result<Value, Box<dyn Error + Sync + Send>> {
         let v = match *self {
             ValueTemplate::Map(ref m) => {
                 let mut m2 = BTreeMap::new();
                 for (k, v) in m {
-                    m2.insert(k.expand()?, v.expand()?);
+                    let k = k.expand(cx)?;
+                    // distinct templates can expand to the same key, which would lose an entry
+                    if m2.contains_key(&k) {
+                        let k = match k {
+                            Value::String(s) => s,
+                            k => format!("{:?}", k),
+                        };
+                        return Err(format!("duplicate map key `{}` after expansion", k).into());
+                    }
+                    m2.insert(k, v.expand(cx)?);
                 }
                 Value::Map(m2)
             }
-            ValueTemplate::Newtype(ref v) => Value::Newtype(Box::new(v.expand()?)),
+            ValueTemplate::Newtype(ref v) => Value::Newtype(Box::new(v.expand(cx)?)),
             ValueTemplate::Option(ref v) => {
                 match *v {
-                    Some(ref v) => Value::Option(Some(Box::new(v.expand()?))),
+                    Some(ref v) => Value::Option(Some(Box::new(v.expand(cx)?))),
                     None => Value::Option(None),
                 }
             }
             ValueTemplate::Seq(ref vs) => {
                 let mut vs2 = Vec::with_capacity(vs.len());
                 for v in vs {
-                    vs2.push(v.expand()?);
+                    vs2.push(v.expand(cx)?);
                 }
                 Value::Seq(vs2)
             }
@@ -288,14 +697,79 @@ impl ValueTemplate {
                     match *chunk {
                         Chunk::Text(ref t) => s.push_str(t),
                         Chunk::Mdc { ref key, ref default } => {
-                            log_mdc::get(key, |v| match (v, default.as_ref().map(|s| &**s)) {
-                                (Some(v), _) | (None, Some(v)) => {
+                            // a default on the reference itself takes precedence over the router's
+                            let default = default
+                                .as_ref()
+                                .or_else(|| cx.defaults.get(key))
+                                .map(|s| &**s);
+                            log_mdc::get(key, |v| match (v, default) {
+                                (Some(v), _) => {
+                                    let v = cx.redactions.apply(v);
+                                    warmup::read_mdc(key, &v);
+                                    s.push_str(&v);
+                                    Ok(())
+                                }
+                                (None, Some(v)) => {
                                     s.push_str(v);
                                     Ok(())
                                 }
+                                (None, None) if cx.named_markers => {
+                                    write!(s, "_missing_{}_", key).unwrap();
+                                    Ok(())
+                                }
                                 (None, None) => Err(format!("MDC key `{}` not present", key)),
                             })?
                         }
+                        Chunk::MdcWildcard { ref prefix, ref default } => {
+                            let entries = mdc_entries(prefix);
+                            if entries.is_empty() {
+                                s.push_str(default.as_ref().map_or("", |s| &**s));
+                            }
+                            for (i, (k, v)) in entries.iter().enumerate() {
+                                if i > 0 {
+                                    s.push(',');
+                                }
+                                let v = cx.redactions.apply(v);
+                                warmup::read_mdc(k, &v);
+                                write!(s, "{}={}", k, v).unwrap();
+                            }
+                        }
+                        #[cfg(feature = "log-kv")]
+                        Chunk::Kv { ref key, ref default } => {
+                            match (cx.record.key_values().get(Key::from_str(key)), default) {
+                                (Some(v), _) => s.push_str(&cx.redactions.apply(&v.to_string())),
+                                (None, Some(v)) => s.push_str(v),
+                                (None, None) if cx.named_markers => {
+                                    write!(s, "_missing_{}_", key).unwrap()
+                                }
+                                (None, None) => {
+                                    return Err(format!("record key `{}` not present", key).into())
+                                }
+                            }
+                        }
+                        Chunk::Level => s.push_str(cx.record.level().as_str()),
+                        Chunk::Target => s.push_str(&cx.redactions.apply(cx.record.target())),
+                        Chunk::Thread => {
+                            with_thread_name(|name| s.push_str(&cx.redactions.apply(name)))
+                        }
+                        Chunk::Seq => {
+                            let seq = match cx.seq.get() {
+                                Some(seq) => seq,
+                                None => {
+                                    let seq = SEQ.fetch_add(1, atomic::Ordering::Relaxed);
+                                    cx.seq.set(Some(seq));
+                                    seq
+                                }
+                            };
+                            write!(s, "{}", seq).unwrap();
+                        }
+                        Chunk::Variable(ref name) => {
+                            let value = cx.variables.iter().find(|v| v.0 == name).map(|v| v.1);
+                            match value {
+                                Some(value) => s.push_str(value),
+                                None => return Err(format!("variable `{}` not set", name).into()),
+                            }
+                        }
                     }
                 }
                 Value::String(s)
@@ -0,0 +1,180 @@
+//! A router which gradually shifts log events from one appender to another.
+//!
+//! The router sends a percentage of log events to the `new` appender and the rest to the `old`
+//! one. The percentage moves linearly from `from` to `to` over the duration `over`, starting when
+//! the router is built, and stays at `to` once the ramp has completed, so with the default `to` of
+//! 100, every event is sent to the new appender from then on. This allows logging to be migrated
+//! to a new destination a little at a time, without redeploying.
+//!
+//! The split is deterministic rather than random: the router counts the events it routes, and
+//! sends each one to whichever appender keeps the running share of the new appender closest to
+//! the current percentage. At 25%, for example, every fourth event goes to the new appender.
+//!
+//! Each appender is only built once an event is sent to it, so the new appender is not built
+//! before the ramp starts sending it events, and the old one is not built if the ramp starts at
+//! 100%.
+//!
+//! Requires the `ramp-router` feature.
+//!
+//! # Examples
+//!
+//! ```yaml
+//! kind: ramp
+//! from: 0
+//! to: 100
+//! over: 1 hour
+//! old:
+//!   kind: file
+//!   path: "log/app.log"
+//! new:
+//!   kind: file
+//!   path: "/mnt/logs/app.log"
+//! ```
+use log::Record;
+use log4rs::file::{Deserialize, Deserializers};
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use de_duration;
+use route::{Appender, AppenderConfig, Cache, Entry, Route};
+
+/// Configuration for the `RampRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RampRouterConfig {
+    #[serde(default)]
+    from: f64,
+    #[serde(default = "default_to")]
+    to: f64,
+    #[serde(deserialize_with = "de_duration")]
+    over: Option<Duration>,
+    old: AppenderConfig,
+    new: AppenderConfig,
+}
+
+fn default_to() -> f64 {
+    100.
+}
+
+/// A router which gradually shifts log events from one appender to another.
+pub struct RampRouter {
+    deserializers: Deserializers,
+    from: f64,
+    to: f64,
+    over: Duration,
+    started: Instant,
+    old: AppenderConfig,
+    new: AppenderConfig,
+    routed: AtomicU64,
+}
+
+impl fmt::Debug for RampRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("RampRouter")
+            .field("from", &self.from)
+            .field("to", &self.to)
+            .field("over", &self.over)
+            .finish()
+    }
+}
+
+impl RampRouter {
+    /// Returns the current percentage of events sent to the new appender.
+    fn percentage(&self) -> f64 {
+        let progress = self.started.elapsed().as_secs_f64() / self.over.as_secs_f64();
+        self.from + (self.to - self.from) * progress.min(1.)
+    }
+}
+
+impl Route for RampRouter {
+    fn route(&self, _: &Record, cache: &mut Cache) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+        // in hundredths of a percent, so that the running shares below are exact
+        let share = (self.percentage() * 100.).round() as u64;
+        let n = self.routed.fetch_add(1, Ordering::Relaxed);
+        // the event goes to the new appender if that brings its running share up to the target
+        let new = (n + 1) * share / 10000 > n * share / 10000;
+
+        let (key, config) = if new {
+            ("new", &self.new)
+        } else {
+            ("old", &self.old)
+        };
+        match cache.try_entry(key.to_owned())? {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => {
+                let appender = self
+                    .deserializers
+                    .deserialize(&config.kind, config.config.clone())?;
+                Ok(e.insert(appender))
+            }
+        }
+    }
+}
+
+/// A deserializer for the `RampRouter`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: ramp
+///
+/// # The percentage of log events sent to the new appender when the router is
+/// # built. Must be between 0 and 100. Defaults to 0.
+/// from: 0
+///
+/// # The percentage of log events sent to the new appender once the ramp has
+/// # completed. Must be between 0 and 100. Defaults to 100.
+/// to: 100
+///
+/// # The duration over which the percentage moves from `from` to `to`.
+/// # Required.
+/// over: 1 hour
+///
+/// # The appender which log events are migrated away from. Required.
+/// old:
+///   kind: file
+///   path: "log/app.log"
+///
+/// # The appender which log events are migrated to. Required.
+/// new:
+///   kind: file
+///   path: "/mnt/logs/app.log"
+/// ```
+pub struct RampRouterDeserializer;
+
+impl Deserialize for RampRouterDeserializer {
+    type Trait = dyn Route;
+    type Config = RampRouterConfig;
+
+    fn deserialize(
+        &self,
+        config: RampRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<dyn Route>, Box<dyn Error + Sync + Send>> {
+        for &percentage in &[config.from, config.to] {
+            if !(0. ..=100.).contains(&percentage) {
+                return Err(format!("percentage {} is not between 0 and 100", percentage).into());
+            }
+        }
+        let over = match config.over {
+            Some(over) => over,
+            None => return Err("missing field `over`".into()),
+        };
+        if over == Duration::from_secs(0) {
+            return Err("over must be longer than zero".into());
+        }
+
+        Ok(Box::new(RampRouter {
+            deserializers: deserializers.clone(),
+            from: config.from,
+            to: config.to,
+            over,
+            started: Instant::now(),
+            old: config.old,
+            new: config.new,
+            routed: AtomicU64::new(0),
+        }))
+    }
+}
@@ -0,0 +1,199 @@
+//! A router which spreads log events across a fixed number of shards.
+//!
+//! The shard of a log event is determined by hashing the values of one or more [MDC][MDC] entries
+//! together, so events sharing the same combination of values always land in the same shard. The
+//! values are length-prefixed before hashing so that, for example, the combinations `("ab", "c")`
+//! and `("a", "bc")` are distinguished, and a key which is not present contributes a fixed marker.
+//! The hash is stable, so a given combination maps to the same shard across runs and builds. Log
+//! events with none of the keys are not hashed but sent to the `default_shard`, which defaults to
+//! the first.
+//!
+//! The appender for each shard is built from a template, as in the [pattern router][pattern],
+//! which may additionally refer to the index of the shard with `${shard}`.
+//!
+//! Requires the `shard-router` feature.
+//!
+//! # Examples
+//!
+//! ```yaml
+//! kind: shard
+//! key: request_id
+//! shards: 8
+//! pattern:
+//!   kind: file
+//!   path: "log/shard-${shard}.log"
+//! ```
+//!
+//! [MDC]: https://crates.io/crates/log-mdc
+//! [pattern]: ../pattern/index.html
+use log::Record;
+use log4rs::file::{Deserialize, Deserializers};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Write};
+
+use route::{self, Appender, AppenderConfig, Cache, Entry, Route};
+use route::pattern::template::Template;
+use warmup;
+
+/// Configuration for the `ShardRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShardRouterConfig {
+    #[serde(default)]
+    key: Option<String>,
+    #[serde(default)]
+    keys: Vec<String>,
+    shards: u32,
+    #[serde(default)]
+    default_shard: u32,
+    pattern: AppenderConfig,
+}
+
+/// A router which spreads log events across shards by the hash of MDC entries.
+pub struct ShardRouter {
+    deserializers: Deserializers,
+    keys: Vec<String>,
+    shards: u32,
+    default_shard: u32,
+    kind: String,
+    config: Template,
+}
+
+impl fmt::Debug for ShardRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ShardRouter")
+            .field("keys", &self.keys)
+            .field("shards", &self.shards)
+            .field("default_shard", &self.default_shard)
+            .finish()
+    }
+}
+
+impl ShardRouter {
+    fn shard(&self) -> u32 {
+        let mut s = String::new();
+        let mut present = false;
+        for key in &self.keys {
+            warmup::get_mdc(key, |v| match v {
+                Some(v) => {
+                    write!(s, "{}{}", v.len(), v).unwrap();
+                    present = true;
+                }
+                None => s.push('-'),
+            });
+        }
+        if !present {
+            return self.default_shard;
+        }
+        (fnv1a(s.as_bytes()) % u64::from(self.shards)) as u32
+    }
+}
+
+impl Route for ShardRouter {
+    fn route(&self, record: &Record, cache: &mut Cache) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+        let shard = self.shard().to_string();
+        match cache.try_entry(format!("{}:{}", shard, self.config.key(record)))? {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => {
+                let config = self.config.expand(record, &[("shard", &shard)])?;
+                let path = route::file_path(&config);
+                let e = match path {
+                    Some(ref path) => {
+                        route::check_path(path)?;
+                        match e.share_file(path) {
+                            Ok(appender) => return Ok(appender),
+                            Err(e) => e,
+                        }
+                    }
+                    None => e,
+                };
+                let appender = self.deserializers.deserialize(&self.kind, config)?;
+                match path {
+                    Some(path) => Ok(e.insert_file(appender, path)),
+                    None => Ok(e.insert(appender)),
+                }
+            }
+        }
+    }
+}
+
+/// The 64 bit FNV-1a hash, which unlike `DefaultHasher` is guaranteed not to change.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325;
+    for &b in bytes {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// A deserializer for the `ShardRouter`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: shard
+///
+/// # The MDC keys whose values are hashed together to select a shard. Either
+/// # this or `key` is required.
+/// keys:
+///   - tenant
+///   - region
+///
+/// # The single MDC key whose value is hashed to select a shard, in place of
+/// # `keys`.
+/// key: request_id
+///
+/// # The number of shards. Must be at least 1. Required.
+/// shards: 32
+///
+/// # The shard of log events with none of the keys, which must be less than
+/// # `shards`. Defaults to 0.
+/// default_shard: 0
+///
+/// # The configuration template of a shard's appender. In addition to the
+/// # formatters supported by the pattern router, `${shard}` expands to the
+/// # index of the shard. Required.
+/// pattern:
+///   kind: file
+///   path: "log/shard-${shard}.log"
+/// ```
+pub struct ShardRouterDeserializer;
+
+impl Deserialize for ShardRouterDeserializer {
+    type Trait = dyn Route;
+    type Config = ShardRouterConfig;
+
+    fn deserialize(
+        &self,
+        config: ShardRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<dyn Route>, Box<dyn Error + Sync + Send>> {
+        let keys = match (config.key, config.keys) {
+            (Some(_), ref keys) if !keys.is_empty() => {
+                return Err("only one of key and keys may be set".into());
+            }
+            (Some(key), _) => vec![key],
+            (None, keys) => keys,
+        };
+        if keys.is_empty() {
+            return Err("at least one key is required".into());
+        }
+        if config.shards == 0 {
+            return Err("shards must be at least 1".into());
+        }
+        if config.default_shard >= config.shards {
+            return Err("default_shard must be less than shards".into());
+        }
+
+        Ok(Box::new(ShardRouter {
+            deserializers: deserializers.clone(),
+            keys,
+            shards: config.shards,
+            default_shard: config.default_shard,
+            kind: config.pattern.kind,
+            config: Template::new(&config.pattern.config, HashMap::new(), &["shard"])?,
+        }))
+    }
+}
@@ -0,0 +1,168 @@
+//! A router which pins each thread to one appender of a list.
+//!
+//! The first log event of a thread picks one of the appenders, and every later event of that
+//! thread is sent to the same one. With a pool of worker threads, this keeps each thread writing
+//! to a single file for its whole lifetime, so threads don't contend for the same file, while
+//! bounding the number of files to the number of appenders rather than the number of threads.
+//! Unlike a template referring to `${thread}`, the files don't depend on the names of the threads.
+//!
+//! The appender is picked either in turn, cycling through the list as threads log their first
+//! event, or by hashing the ID of the thread. The choice is kept by the thread itself, and is
+//! discarded when the thread exits. Each appender is only built once a thread picks it, and is
+//! rebuilt by the next event of one of its threads if it is evicted from the cache for being idle.
+//!
+//! Requires the `sticky-router` feature.
+//!
+//! # Examples
+//!
+//! ```yaml
+//! kind: sticky
+//! assignment: round_robin
+//! appenders:
+//!   - kind: file
+//!     path: "log/worker-0.log"
+//!   - kind: file
+//!     path: "log/worker-1.log"
+//! ```
+use log::Record;
+use log4rs::file::{Deserialize, Deserializers};
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+
+use route::{Appender, AppenderConfig, Cache, Entry, Route};
+
+thread_local! {
+    // the appender picked by this thread for each live router
+    static PICKED: RefCell<Vec<(Weak<()>, usize)>> = const { RefCell::new(vec![]) };
+}
+
+/// How a thread picks its appender.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Assignment {
+    /// The appenders are picked in turn.
+    #[default]
+    RoundRobin,
+    /// The appender is picked by the hash of the ID of the thread.
+    ThreadId,
+}
+
+/// Configuration for the `StickyRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StickyRouterConfig {
+    appenders: Vec<AppenderConfig>,
+    #[serde(default)]
+    assignment: Assignment,
+}
+
+/// A router which pins each thread to one appender of a list.
+pub struct StickyRouter {
+    deserializers: Deserializers,
+    appenders: Vec<AppenderConfig>,
+    assignment: Assignment,
+    next: AtomicUsize,
+    // identifies the router in the threads' choices, which are dropped once it is
+    id: Arc<()>,
+}
+
+impl fmt::Debug for StickyRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("StickyRouter")
+            .field("appenders", &self.appenders.len())
+            .field("assignment", &self.assignment)
+            .finish()
+    }
+}
+
+impl StickyRouter {
+    /// Returns the index of the current thread's appender, picking one if necessary.
+    fn picked(&self) -> usize {
+        PICKED.with(|p| {
+            let mut picked = p.borrow_mut();
+            let id = Arc::downgrade(&self.id);
+            if let Some(&(_, idx)) = picked.iter().find(|e| e.0.ptr_eq(&id)) {
+                return idx;
+            }
+
+            let idx = match self.assignment {
+                Assignment::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+                Assignment::ThreadId => {
+                    let mut hasher = DefaultHasher::new();
+                    thread::current().id().hash(&mut hasher);
+                    hasher.finish() as usize
+                }
+            } % self.appenders.len();
+            // forget the choices made for routers which have since been dropped
+            picked.retain(|e| e.0.strong_count() > 0);
+            picked.push((id, idx));
+            idx
+        })
+    }
+}
+
+impl Route for StickyRouter {
+    fn route(&self, _: &Record, cache: &mut Cache) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+        let idx = self.picked();
+        match cache.try_entry(idx.to_string())? {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => {
+                let config = &self.appenders[idx];
+                let appender = self
+                    .deserializers
+                    .deserialize(&config.kind, config.config.clone())?;
+                Ok(e.insert(appender))
+            }
+        }
+    }
+}
+
+/// A deserializer for the `StickyRouter`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: sticky
+///
+/// # How a thread picks its appender on its first log event. `round_robin`
+/// # picks the appenders in turn, and `thread_id` picks one by the hash of the
+/// # ID of the thread. Defaults to `round_robin`.
+/// assignment: round_robin
+///
+/// # The appenders threads are pinned to. At least one is required.
+/// appenders:
+///   - kind: file
+///     path: "log/worker-0.log"
+///   - kind: file
+///     path: "log/worker-1.log"
+/// ```
+pub struct StickyRouterDeserializer;
+
+impl Deserialize for StickyRouterDeserializer {
+    type Trait = dyn Route;
+    type Config = StickyRouterConfig;
+
+    fn deserialize(
+        &self,
+        config: StickyRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<dyn Route>, Box<dyn Error + Sync + Send>> {
+        if config.appenders.is_empty() {
+            return Err("at least one appender is required".into());
+        }
+
+        Ok(Box::new(StickyRouter {
+            deserializers: deserializers.clone(),
+            appenders: config.appenders,
+            assignment: config.assignment,
+            next: AtomicUsize::new(0),
+            id: Arc::new(()),
+        }))
+    }
+}
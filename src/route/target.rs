@@ -0,0 +1,180 @@
+//! A router which sends the log events of each target, such as a module, to its own appender.
+//!
+//! The appender for each target is built from a template, as in the [pattern router][pattern],
+//! in which `${target}` expands to the target of the log event made suitable for use in a path.
+//! A common `strip_prefix`, such as the name of the crate, is removed from the start of the
+//! target first, so that paths aren't needlessly deep. The `::` separating the segments of a
+//! module path is then replaced with the `separator`, which defaults to `/` so that nested modules
+//! map to nested directories. Control characters and path separators within a segment are
+//! replaced with `_`, as are empty segments and those consisting of `.` or `..`, so a target can't
+//! escape the directory it is written to.
+//!
+//! Since the targets are bounded by the modules of the codebase, the number of appenders is too,
+//! which makes this router a natural fit for a cache bounded by `capacity`.
+//!
+//! Requires the `target-router` feature.
+//!
+//! # Examples
+//!
+//! ```yaml
+//! kind: target
+//! strip_prefix: "my_crate::"
+//! pattern:
+//!   kind: file
+//!   path: "log/${target}.log"
+//! ```
+//!
+//! [pattern]: ../pattern/index.html
+use log::Record;
+use log4rs::file::{Deserialize, Deserializers};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use route::pattern::template::Template;
+use route::{self, Appender, AppenderConfig, Cache, Entry, Route};
+
+/// Configuration for the `TargetRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetRouterConfig {
+    pattern: AppenderConfig,
+    #[serde(default)]
+    strip_prefix: String,
+    #[serde(default = "default_separator")]
+    separator: String,
+    #[serde(default)]
+    defaults: HashMap<String, String>,
+}
+
+fn default_separator() -> String {
+    "/".to_owned()
+}
+
+/// A router which sends the log events of each target, such as a module, to its own appender.
+pub struct TargetRouter {
+    deserializers: Deserializers,
+    strip_prefix: String,
+    separator: String,
+    kind: String,
+    config: Template,
+}
+
+impl fmt::Debug for TargetRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("TargetRouter")
+            .field("strip_prefix", &self.strip_prefix)
+            .field("separator", &self.separator)
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl TargetRouter {
+    /// Returns the sanitized target of the log event.
+    fn target(&self, record: &Record) -> String {
+        let target = record.target();
+        let target = target.strip_prefix(&*self.strip_prefix).unwrap_or(target);
+
+        let mut sanitized = String::new();
+        for (i, segment) in target.split("::").enumerate() {
+            if i > 0 {
+                sanitized.push_str(&self.separator);
+            }
+            if segment.is_empty() || segment == "." || segment == ".." {
+                sanitized.push('_');
+                continue;
+            }
+            sanitized.extend(segment.chars().map(|c| {
+                if c.is_control() || std::path::is_separator(c) {
+                    '_'
+                } else {
+                    c
+                }
+            }));
+        }
+        sanitized
+    }
+}
+
+impl Route for TargetRouter {
+    fn route(&self, record: &Record, cache: &mut Cache) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+        let target = self.target(record);
+
+        // length-prefixed, since the target may contain anything the template key does
+        let key = format!("{}{}{}", target.len(), target, self.config.key(record));
+        match cache.try_entry(key)? {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => {
+                let config = self.config.expand(record, &[("target", &target)])?;
+                let path = route::file_path(&config);
+                let e = match path {
+                    Some(ref path) => {
+                        route::check_path(path)?;
+                        match e.share_file(path) {
+                            Ok(appender) => return Ok(appender),
+                            Err(e) => e,
+                        }
+                    }
+                    None => e,
+                };
+                let appender = self.deserializers.deserialize(&self.kind, config)?;
+                match path {
+                    Some(path) => Ok(e.insert_file(appender, path)),
+                    None => Ok(e.insert(appender)),
+                }
+            }
+        }
+    }
+}
+
+/// A deserializer for the `TargetRouter`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: target
+///
+/// # A prefix removed from the start of each target. Defaults to none.
+/// strip_prefix: "my_crate::"
+///
+/// # The string replacing each `::` in the target. Must not be empty. Defaults
+/// # to `/`.
+/// separator: "-"
+///
+/// # The configuration template of a target's appender. In addition to the
+/// # formatters supported by the pattern router, `${target}` expands to the
+/// # sanitized target, in place of the target as is. Required.
+/// pattern:
+///   kind: file
+///   path: "log/${target}.log"
+///
+/// # Values used for MDC keys which are not present, unless the reference to
+/// # the key provides a replacement of its own. Defaults to none.
+/// defaults:
+///   job_id: unknown
+/// ```
+pub struct TargetRouterDeserializer;
+
+impl Deserialize for TargetRouterDeserializer {
+    type Trait = dyn Route;
+    type Config = TargetRouterConfig;
+
+    fn deserialize(
+        &self,
+        config: TargetRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<dyn Route>, Box<dyn Error + Sync + Send>> {
+        if config.separator.is_empty() {
+            return Err("separator must not be empty".into());
+        }
+
+        Ok(Box::new(TargetRouter {
+            deserializers: deserializers.clone(),
+            strip_prefix: config.strip_prefix,
+            separator: config.separator,
+            kind: config.pattern.kind,
+            config: Template::new(&config.pattern.config, config.defaults, &["target"])?,
+        }))
+    }
+}
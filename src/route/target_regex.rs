@@ -0,0 +1,243 @@
+//! A router which derives the appender of a log event from a regex applied to its target.
+//!
+//! The router searches the target of each log event, such as `myapp::services::billing`, for a
+//! match of `regex`, and builds the appender of the event from a template, as in the [pattern
+//! router][pattern], which may additionally refer to the captured parts of the target. `${capture}`
+//! expands to the first capture group of the regex, or to the whole match if it has no groups, and
+//! every named group, such as `(?P<service>\w+)`, expands from a formatter of the same name. A
+//! group which did not participate in the match expands to an empty string. This gives a codebase
+//! structured in modules one file per service, without setting up any MDC. Since the captures are
+//! meant to be used in file names, control characters and path separators in them are replaced with
+//! `_`, and a capture of `.` or `..` is replaced with `_` as a whole.
+//!
+//! The cache key of an event is composed of the captured values and the values the rest of the
+//! template refers to, so all of the targets capturing the same values share an appender. The
+//! regex is not anchored, so it should start with `^` if it must match from the start of the
+//! target.
+//!
+//! Events whose target does not match are sent to the `default` appender, which is built like any
+//! other routed appender, without a template. If there is no default appender, routing those
+//! events fails.
+//!
+//! Requires the `target-regex-router` feature.
+//!
+//! # Examples
+//!
+//! ```yaml
+//! kind: target_regex
+//! regex: "^myapp::services::(\\w+)"
+//! pattern:
+//!   kind: file
+//!   path: "log/${capture}.log"
+//! default:
+//!   kind: file
+//!   path: "log/app.log"
+//! ```
+//!
+//! [pattern]: ../pattern/index.html
+use log::Record;
+use log4rs::file::{Deserialize, Deserializers};
+use regex::Regex;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Write};
+
+use route::pattern::template::Template;
+use route::{self, Appender, AppenderConfig, Cache, Entry, Route};
+
+/// Configuration for the `TargetRegexRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetRegexRouterConfig {
+    regex: String,
+    pattern: AppenderConfig,
+    #[serde(default)]
+    defaults: HashMap<String, String>,
+    #[serde(default)]
+    default: Option<AppenderConfig>,
+}
+
+/// A router which derives the appender of a log event from a regex applied to its target.
+pub struct TargetRegexRouter {
+    deserializers: Deserializers,
+    regex: Regex,
+    // the names of the groups the template may refer to, `capture` first
+    names: Vec<String>,
+    kind: String,
+    config: Template,
+    default: Option<AppenderConfig>,
+}
+
+impl fmt::Debug for TargetRegexRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("TargetRegexRouter")
+            .field("regex", &self.regex.as_str())
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl TargetRegexRouter {
+    /// Returns the values of the groups the template may refer to, in the order of `names`, or
+    /// `None` if the target does not match.
+    fn captures(&self, target: &str) -> Option<Vec<String>> {
+        let captures = self.regex.captures(target)?;
+        let first = if self.regex.captures_len() > 1 {
+            captures.get(1)
+        } else {
+            captures.get(0)
+        };
+        let mut values = vec![sanitize(first.map_or("", |m| m.as_str()))];
+        for name in &self.names[1..] {
+            values.push(sanitize(captures.name(name).map_or("", |m| m.as_str())));
+        }
+        Some(values)
+    }
+}
+
+/// Replaces control characters and path separators in a captured value with `_`, and a value of
+/// `.` or `..` with `_` as a whole, so that it can't name a directory in the expanded path.
+fn sanitize(value: &str) -> String {
+    if value == "." || value == ".." {
+        return "_".to_owned();
+    }
+    value
+        .chars()
+        .map(|c| {
+            if c.is_control() || std::path::is_separator(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+impl Route for TargetRegexRouter {
+    fn route(&self, record: &Record, cache: &mut Cache) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+        let values = match self.captures(record.target()) {
+            Some(values) => values,
+            None => {
+                let default = match self.default {
+                    Some(ref default) => default,
+                    None => {
+                        let e = format!("target `{}` does not match the regex", record.target());
+                        return Err(e.into());
+                    }
+                };
+                // matched keys start with a digit, so this can't collide with them
+                return match cache.try_entry("default".to_owned())? {
+                    Entry::Occupied(e) => Ok(e.into_value()),
+                    Entry::Vacant(e) => {
+                        let appender = self
+                            .deserializers
+                            .deserialize(&default.kind, default.config.clone())?;
+                        Ok(e.insert(appender))
+                    }
+                };
+            }
+        };
+
+        // length-prefixed, since the captures may contain anything the template key does
+        let mut key = String::new();
+        for value in &values {
+            write!(key, "{}{}", value.len(), value).unwrap();
+        }
+        key.push_str(&self.config.key(record));
+        match cache.try_entry(key)? {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => {
+                let variables = self
+                    .names
+                    .iter()
+                    .zip(&values)
+                    .map(|(name, value)| (&**name, &**value))
+                    .collect::<Vec<_>>();
+                let config = self.config.expand(record, &variables)?;
+                let path = route::file_path(&config);
+                let e = match path {
+                    Some(ref path) => {
+                        route::check_path(path)?;
+                        match e.share_file(path) {
+                            Ok(appender) => return Ok(appender),
+                            Err(e) => e,
+                        }
+                    }
+                    None => e,
+                };
+                let appender = self.deserializers.deserialize(&self.kind, config)?;
+                match path {
+                    Some(path) => Ok(e.insert_file(appender, path)),
+                    None => Ok(e.insert(appender)),
+                }
+            }
+        }
+    }
+}
+
+/// A deserializer for the `TargetRegexRouter`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: target_regex
+///
+/// # The regex searched for in the target of each log event. The names of its
+/// # named groups must consist of letters and digits, start with a letter and
+/// # not be `capture`. Required.
+/// regex: "^myapp::services::(?P<service>\\w+)"
+///
+/// # The configuration template of the appenders of matching events. In
+/// # addition to the formatters supported by the pattern router, `${capture}`
+/// # expands to the first capture group, or the whole match if there are no
+/// # groups, and each named group expands from a formatter of its name.
+/// # Required.
+/// pattern:
+///   kind: file
+///   path: "log/services/${service}.log"
+///
+/// # Values used for MDC keys which are not present, unless the reference to
+/// # the key provides a replacement of its own. Defaults to none.
+/// defaults:
+///   job_id: unknown
+///
+/// # The appender which events whose target does not match are sent to. If
+/// # not set, routing those events fails. Optional.
+/// default:
+///   kind: file
+///   path: "log/app.log"
+/// ```
+pub struct TargetRegexRouterDeserializer;
+
+impl Deserialize for TargetRegexRouterDeserializer {
+    type Trait = dyn Route;
+    type Config = TargetRegexRouterConfig;
+
+    fn deserialize(
+        &self,
+        config: TargetRegexRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<dyn Route>, Box<dyn Error + Sync + Send>> {
+        let regex = Regex::new(&config.regex)
+            .map_err(|e| format!("invalid regex `{}`: {}", config.regex, e))?;
+        let mut names = vec!["capture".to_owned()];
+        for name in regex.capture_names().flatten() {
+            let valid = name.starts_with(|c: char| c.is_alphabetic())
+                && name.chars().all(char::is_alphanumeric);
+            if !valid || name == "capture" {
+                return Err(format!("invalid capture group name `{}`", name).into());
+            }
+            names.push(name.to_owned());
+        }
+
+        let variables = names.iter().map(|n| &**n).collect::<Vec<_>>();
+        Ok(Box::new(TargetRegexRouter {
+            deserializers: deserializers.clone(),
+            regex,
+            kind: config.pattern.kind,
+            config: Template::new(&config.pattern.config, config.defaults, &variables)?,
+            names,
+            default: config.default,
+        }))
+    }
+}
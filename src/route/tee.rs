@@ -0,0 +1,221 @@
+//! A router which writes each log event to several appenders built from one template.
+//!
+//! The template is that of an appender, as in the [pattern router][pattern], except that its
+//! `path` may be a list of paths rather than a single one. Each path in the list is expanded
+//! independently, and the event is written to one appender per expanded path, each built from
+//! the template with its `path` replaced by that path. For example, a template listing both
+//! `log/jobs/${mdc(job_id)}.log` and `log/${level}.log` writes every event to the file of its
+//! job and to the file of its level.
+//!
+//! The cache holds one entry per distinct combination of the values which any of the paths, or
+//! the rest of the template, refer to, so the key of an entry is composed of the values of all of
+//! them, the same way as that of a pattern router entry. Since different combinations may share
+//! some of their paths, such as the jobs logging at the same level above, the appenders are
+//! shared by path: an appender is built the first time its path is expanded, from the event
+//! being routed, and is reused by every entry expanding to the same path for as long as one of
+//! them is cached. Paths which appear more than once in the same expansion are only written to
+//! once.
+//!
+//! An event is written to every appender even if some of them fail, and the error of the first
+//! of those which failed is returned. Cache settings which depend on the file an entry writes to,
+//! such as `verify_files` and `dedup_by_path`, do not apply to the entries of this router.
+//!
+//! Requires the `tee-router` feature.
+//!
+//! # Examples
+//!
+//! ```yaml
+//! kind: tee
+//! pattern:
+//!   kind: file
+//!   path:
+//!     - "log/jobs/${mdc(job_id)}.log"
+//!     - "log/${level}.log"
+//! ```
+//!
+//! [pattern]: ../pattern/index.html
+use antidote::Mutex;
+use log::Record;
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use serde_value::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Weak};
+
+use route::pattern::template::Template;
+use route::{self, Appender, AppenderConfig, Cache, Entry, Route};
+
+type SharedAppender = Arc<Box<dyn Append>>;
+
+/// Configuration for the `TeeRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TeeRouterConfig {
+    pattern: AppenderConfig,
+    #[serde(default)]
+    defaults: HashMap<String, String>,
+}
+
+/// A router which writes each log event to several appenders built from one template.
+pub struct TeeRouter {
+    deserializers: Deserializers,
+    kind: String,
+    config: Template,
+    // the appenders built for each path, which live as long as the entries writing to them
+    paths: Mutex<HashMap<PathBuf, Weak<Box<dyn Append>>>>,
+}
+
+impl fmt::Debug for TeeRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("TeeRouter")
+            .field("kind", &self.kind)
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl TeeRouter {
+    /// Builds the appenders of an expanded template, reusing those of paths already written to.
+    fn build(&self, config: Value) -> Result<TeeAppender, Box<dyn Error + Sync + Send>> {
+        let mut map = match config {
+            Value::Map(map) => map,
+            _ => return Err("the tee pattern must be a map".into()),
+        };
+        let path_key = Value::String("path".to_owned());
+        let expanded = match map.remove(&path_key) {
+            Some(Value::Seq(paths)) => paths,
+            Some(path) => vec![path],
+            None => vec![],
+        };
+
+        let mut paths = self.paths.lock();
+        // forget the paths no entry writes to anymore
+        paths.retain(|_, appender| appender.strong_count() > 0);
+        let mut targets = Vec::<(PathBuf, SharedAppender)>::new();
+        for path in expanded {
+            let path = match path {
+                Value::String(path) => PathBuf::from(path),
+                _ => return Err("tee paths must be strings".into()),
+            };
+            route::check_path(&path)?;
+            if targets.iter().any(|t| t.0 == path) {
+                continue;
+            }
+            if let Some(appender) = paths.get(&path).and_then(Weak::upgrade) {
+                targets.push((path, appender));
+                continue;
+            }
+
+            let mut config = map.clone();
+            config.insert(path_key.clone(), Value::String(path.to_string_lossy().into_owned()));
+            let appender = self.deserializers.deserialize(&self.kind, Value::Map(config))?;
+            let appender = Arc::new(appender);
+            paths.insert(path.clone(), Arc::downgrade(&appender));
+            targets.push((path, appender));
+        }
+
+        Ok(TeeAppender {
+            targets: targets.into_iter().map(|t| t.1).collect(),
+        })
+    }
+}
+
+impl Route for TeeRouter {
+    fn route(&self, record: &Record, cache: &mut Cache) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+        match cache.try_entry(self.config.key(record))? {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => {
+                let config = self.config.expand(record, &[])?;
+                let appender = self.build(config)?;
+                Ok(e.insert(Box::new(appender)))
+            }
+        }
+    }
+}
+
+struct TeeAppender {
+    targets: Vec<SharedAppender>,
+}
+
+impl fmt::Debug for TeeAppender {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("TeeAppender")
+            .field("targets", &self.targets.len())
+            .finish()
+    }
+}
+
+impl Append for TeeAppender {
+    fn append(&self, record: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let mut error = None;
+        for target in &self.targets {
+            if let Err(e) = target.append(record) {
+                error.get_or_insert(e);
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn flush(&self) {
+        for target in &self.targets {
+            target.flush();
+        }
+    }
+}
+
+/// A deserializer for the `TeeRouter`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: tee
+///
+/// # The configuration template of the appenders, with the same formatters as
+/// # in the pattern router. Its `path` is either a single path or a list of
+/// # paths, each of which an event is written to. Required.
+/// pattern:
+///   kind: file
+///   path:
+///     - "log/jobs/${mdc(job_id)}.log"
+///     - "log/${level}.log"
+///
+/// # Values used for MDC keys which are not present, unless the reference to
+/// # the key provides a replacement of its own. Defaults to none.
+/// defaults:
+///   job_id: unknown
+/// ```
+pub struct TeeRouterDeserializer;
+
+impl Deserialize for TeeRouterDeserializer {
+    type Trait = dyn Route;
+    type Config = TeeRouterConfig;
+
+    fn deserialize(
+        &self,
+        config: TeeRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<dyn Route>, Box<dyn Error + Sync + Send>> {
+        let path = match config.pattern.config {
+            Value::Map(ref map) => map.get(&Value::String("path".to_owned())),
+            _ => None,
+        };
+        match path {
+            Some(Value::String(_)) => {}
+            Some(Value::Seq(paths)) if !paths.is_empty() => {}
+            _ => return Err("the tee pattern requires a `path` or a list of paths".into()),
+        }
+
+        Ok(Box::new(TeeRouter {
+            deserializers: deserializers.clone(),
+            kind: config.pattern.kind,
+            config: Template::new(&config.pattern.config, config.defaults, &[])?,
+            paths: Mutex::new(HashMap::new()),
+        }))
+    }
+}
@@ -0,0 +1,177 @@
+//! A router which selects an appender based on the time of day.
+//!
+//! The day is divided into windows, each of which is identified by the clock time at which it
+//! starts and runs until the start of the next window. The last window wraps around midnight to
+//! the start of the first. A window includes its starting instant, so an event logged at exactly
+//! `08:00:00` is routed to the window starting at `08:00`.
+//!
+//! The time of day is determined in the timezone of the router, if it has one, and otherwise in
+//! that of the routing appender, which defaults to UTC.
+//!
+//! Each window's appender is created the first time an event falls into it, and is cached like
+//! any other routed appender. Once a window has passed, its appender goes unused and is disposed
+//! of after the cache's idle timeout.
+//!
+//! Requires the `time-window-router` feature.
+use chrono::{NaiveTime, Timelike};
+use log::Record;
+use log4rs::file::{Deserialize, Deserializers};
+use serde::de;
+use std::error::Error;
+use std::fmt;
+
+use route::{Appender, AppenderConfig, Cache, Entry, Route};
+
+/// Configuration for the `TimeWindowRouter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeWindowRouterConfig {
+    timezone: Option<Timezone>,
+    windows: Vec<WindowConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WindowConfig {
+    #[serde(deserialize_with = "de_clock_time")]
+    start: u32,
+    appender: AppenderConfig,
+}
+
+pub use Timezone;
+
+struct Window {
+    start: u32,
+    appender: AppenderConfig,
+}
+
+/// A router which selects an appender based on the time of day.
+pub struct TimeWindowRouter {
+    deserializers: Deserializers,
+    timezone: Option<Timezone>,
+    windows: Vec<Window>,
+}
+
+impl fmt::Debug for TimeWindowRouter {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("TimeWindowRouter")
+            .field("timezone", &self.timezone)
+            .finish()
+    }
+}
+
+impl TimeWindowRouter {
+    fn window(&self, now: u32) -> usize {
+        // windows are sorted by start time, and the last one wraps around past midnight
+        match self.windows.iter().rposition(|w| w.start <= now) {
+            Some(idx) => idx,
+            None => self.windows.len() - 1,
+        }
+    }
+}
+
+impl Route for TimeWindowRouter {
+    fn route(&self, _: &Record, cache: &mut Cache) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+        let timezone = self.timezone.unwrap_or_else(|| cache.timezone());
+        let idx = self.window(timezone.seconds_from_midnight());
+        match cache.try_entry(idx.to_string())? {
+            Entry::Occupied(e) => Ok(e.into_value()),
+            Entry::Vacant(e) => {
+                let window = &self.windows[idx];
+                let appender = self
+                    .deserializers
+                    .deserialize(&window.appender.kind, window.appender.config.clone())?;
+                Ok(e.insert(appender))
+            }
+        }
+    }
+}
+
+/// A deserializer for the `TimeWindowRouter`.
+///
+/// # Configuration
+///
+/// ```yaml
+/// kind: time_window
+///
+/// # The timezone used to determine the time of day, either `utc`, `local` or
+/// # an offset from UTC such as `-08:00`. Defaults to the timezone of the
+/// # routing appender.
+/// timezone: utc
+///
+/// # The windows the day is divided into, in order of their start times. Each
+/// # runs until the start of the next, and the last wraps around midnight to the
+/// # start of the first. Required.
+/// windows:
+///   - start: "00:00"
+///     appender:
+///       kind: file
+///       path: "log/night.log"
+///   - start: "08:00"
+///     appender:
+///       kind: file
+///       path: "log/day.log"
+///   - start: "16:00:00"
+///     appender:
+///       kind: file
+///       path: "log/evening.log"
+/// ```
+pub struct TimeWindowRouterDeserializer;
+
+impl Deserialize for TimeWindowRouterDeserializer {
+    type Trait = dyn Route;
+    type Config = TimeWindowRouterConfig;
+
+    fn deserialize(
+        &self,
+        config: TimeWindowRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<dyn Route>, Box<dyn Error + Sync + Send>> {
+        if config.windows.is_empty() {
+            return Err("at least one window is required".into());
+        }
+        if config.windows.windows(2).any(|w| w[0].start >= w[1].start) {
+            return Err("windows must be listed in increasing order of start time".into());
+        }
+
+        Ok(Box::new(TimeWindowRouter {
+            deserializers: deserializers.clone(),
+            timezone: config.timezone,
+            windows: config
+                .windows
+                .into_iter()
+                .map(|w| Window {
+                    start: w.start,
+                    appender: w.appender,
+                })
+                .collect(),
+        }))
+    }
+}
+
+fn de_clock_time<'de, D>(d: D) -> Result<u32, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    struct V;
+
+    impl<'de2> de::Visitor<'de2> for V {
+        type Value = u32;
+
+        fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+            fmt.write_str("a time of day of the form `HH:MM` or `HH:MM:SS`")
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<u32, E>
+        where
+            E: de::Error,
+        {
+            NaiveTime::parse_from_str(v, "%H:%M:%S")
+                .or_else(|_| NaiveTime::parse_from_str(v, "%H:%M"))
+                .map(|t| t.num_seconds_from_midnight())
+                .map_err(|e| E::custom(format!("invalid time of day `{}`: {}", v, e)))
+        }
+    }
+
+    d.deserialize_str(V)
+}
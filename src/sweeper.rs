@@ -0,0 +1,74 @@
+//! Background eviction of expired appenders.
+use antidote::{Condvar, Mutex};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use {CacheInner, Inner};
+
+struct Shared {
+    shutdown: Mutex<bool>,
+    signal: Condvar,
+}
+
+/// A background thread which periodically evicts the expired appenders of a cache.
+pub struct Sweeper {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        *self.shared.shutdown.lock() = true;
+        self.shared.signal.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Sweeper {
+    pub fn new(inner: Arc<Inner>, interval: Duration) -> Sweeper {
+        let shared = Arc::new(Shared {
+            shutdown: Mutex::new(false),
+            signal: Condvar::new(),
+        });
+
+        let thread = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("log4rs-routing-appender-sweeper".to_owned())
+                .spawn(move || run(&shared, &inner, interval))
+                .expect("failed to spawn routing appender sweeper thread")
+        };
+
+        Sweeper {
+            shared,
+            thread: Some(thread),
+        }
+    }
+}
+
+fn run(shared: &Shared, inner: &Inner, interval: Duration) {
+    loop {
+        {
+            // waits out the whole interval, however often the wait is woken spuriously
+            let deadline = Instant::now() + interval;
+            let mut shutdown = shared.shutdown.lock();
+            loop {
+                if *shutdown {
+                    return;
+                }
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                shutdown = shared.signal.wait_timeout(shutdown, deadline - now).0;
+            }
+        }
+
+        inner.lock_cache().sweep();
+        #[cfg(feature = "file")]
+        inner.save_warmup(false);
+    }
+}
@@ -0,0 +1,138 @@
+//! Helpers for testing routing configurations.
+//!
+//! Requires the `test-support` feature.
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate log;
+//! # extern crate log4rs;
+//! # extern crate log4rs_routing_appender;
+//! use log::Level;
+//! use log4rs::append::Append;
+//! use log4rs_routing_appender::test_support::TestRecord;
+//!
+//! # fn check(appender: &dyn Append) {
+//! TestRecord::new()
+//!     .level(Level::Warn)
+//!     .target("app::db")
+//!     .message("connection lost")
+//!     .mdc("tenant", "acme")
+//!     .append_to(appender)
+//!     .unwrap();
+//! # }
+//! # fn main() {}
+//! ```
+use log::{Level, Record};
+use log4rs::append::Append;
+use std::error::Error;
+
+pub use clock::MockClock;
+
+/// A builder of log events for tests.
+///
+/// Log events can't outlive their message, so rather than producing a `Record`, a `TestRecord`
+/// passes one to a closure, with the configured MDC entries set for the duration of the call.
+#[derive(Debug, Clone)]
+pub struct TestRecord {
+    level: Level,
+    target: String,
+    message: String,
+    mdc: Vec<(String, String)>,
+}
+
+impl Default for TestRecord {
+    fn default() -> TestRecord {
+        TestRecord::new()
+    }
+}
+
+impl TestRecord {
+    /// Creates a log event at the `Info` level with an empty target and message, and no MDC
+    /// entries.
+    pub fn new() -> TestRecord {
+        TestRecord {
+            level: Level::Info,
+            target: String::new(),
+            message: String::new(),
+            mdc: vec![],
+        }
+    }
+
+    /// Sets the level of the log event.
+    pub fn level(mut self, level: Level) -> TestRecord {
+        self.level = level;
+        self
+    }
+
+    /// Sets the target of the log event.
+    pub fn target(mut self, target: &str) -> TestRecord {
+        self.target = target.to_owned();
+        self
+    }
+
+    /// Sets the message of the log event.
+    pub fn message(mut self, message: &str) -> TestRecord {
+        self.message = message.to_owned();
+        self
+    }
+
+    /// Adds an MDC entry which is set while the log event is in use.
+    pub fn mdc(mut self, key: &str, value: &str) -> TestRecord {
+        self.mdc.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Calls `f` with the log event, with its MDC entries set.
+    pub fn with<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&Record) -> T,
+    {
+        let mdc = self
+            .mdc
+            .iter()
+            .map(|(k, v)| (&**k, &**v))
+            .collect::<Vec<_>>();
+        with_mdc(&mdc, || {
+            f(&Record::builder()
+                .level(self.level)
+                .target(&self.target)
+                .args(format_args!("{}", self.message))
+                .build())
+        })
+    }
+
+    /// Passes the log event to `appender`, with its MDC entries set.
+    pub fn append_to(&self, appender: &dyn Append) -> Result<(), Box<dyn Error + Sync + Send>> {
+        self.with(|record| appender.append(record))
+    }
+}
+
+/// Calls `f` with the provided MDC entries set, restoring the previous state of those entries
+/// afterwards, even if `f` panics.
+pub fn with_mdc<F, T>(entries: &[(&str, &str)], f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    struct Restore(Vec<(String, Option<String>)>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            // in reverse, so a key set twice is restored to its value from before both
+            for (key, value) in self.0.drain(..).rev() {
+                let _ = match value {
+                    Some(value) => log_mdc::insert(key, value),
+                    None => log_mdc::remove(&key),
+                };
+            }
+        }
+    }
+
+    let mut restore = Restore(vec![]);
+    for &(key, value) in entries {
+        restore.0.push((key.to_owned(), log_mdc::insert(key, value)));
+    }
+    let r = f();
+    drop(restore);
+    r
+}
@@ -0,0 +1,91 @@
+//! Timezones.
+use chrono::{FixedOffset, Local, Timelike, Utc};
+
+#[cfg(feature = "file")]
+use serde::de;
+#[cfg(feature = "file")]
+use std::fmt;
+
+/// The timezone in which times of day are determined.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Timezone {
+    /// Coordinated Universal Time.
+    #[default]
+    Utc,
+    /// The local timezone of the system.
+    Local,
+    /// A fixed offset from UTC, in seconds east of it.
+    ///
+    /// Offsets of a day or more in either direction are treated as UTC.
+    Offset(i32),
+}
+
+impl Timezone {
+    /// Returns the number of seconds since midnight in this timezone.
+    pub fn seconds_from_midnight(&self) -> u32 {
+        match *self {
+            Timezone::Utc => Utc::now().num_seconds_from_midnight(),
+            Timezone::Local => Local::now().num_seconds_from_midnight(),
+            Timezone::Offset(offset) => match FixedOffset::east_opt(offset) {
+                Some(offset) => Utc::now().with_timezone(&offset).num_seconds_from_midnight(),
+                None => Utc::now().num_seconds_from_midnight(),
+            },
+        }
+    }
+
+    /// Parses `utc`, `local` or an offset from UTC of the form `+HH:MM`, `-HH:MM` or `+HH`,
+    /// ignoring case.
+    #[cfg(feature = "file")]
+    fn parse(s: &str) -> Result<Timezone, String> {
+        if s.eq_ignore_ascii_case("utc") {
+            return Ok(Timezone::Utc);
+        }
+        if s.eq_ignore_ascii_case("local") {
+            return Ok(Timezone::Local);
+        }
+
+        let err = || format!("invalid timezone `{}`: expected `utc`, `local` or `+HH:MM`", s);
+        let (sign, rest) = match s.chars().next() {
+            Some('+') => (1, &s[1..]),
+            Some('-') => (-1, &s[1..]),
+            _ => return Err(err()),
+        };
+        let (hours, minutes) = match rest.find(':') {
+            Some(idx) => (&rest[..idx], &rest[idx + 1..]),
+            None => (rest, "0"),
+        };
+        let hours = hours.parse::<i32>().map_err(|_| err())?;
+        let minutes = minutes.parse::<i32>().map_err(|_| err())?;
+        if !(0..24).contains(&hours) || !(0..60).contains(&minutes) {
+            return Err(err());
+        }
+        Ok(Timezone::Offset(sign * (hours * 60 + minutes) * 60))
+    }
+}
+
+#[cfg(feature = "file")]
+impl<'de> de::Deserialize<'de> for Timezone {
+    fn deserialize<D>(d: D) -> Result<Timezone, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        struct V;
+
+        impl<'de2> de::Visitor<'de2> for V {
+            type Value = Timezone;
+
+            fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+                fmt.write_str("`utc`, `local` or an offset of the form `+HH:MM`")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Timezone, E>
+            where
+                E: de::Error,
+            {
+                Timezone::parse(v).map_err(E::custom)
+            }
+        }
+
+        d.deserialize_str(V)
+    }
+}
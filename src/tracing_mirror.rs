@@ -0,0 +1,17 @@
+//! The mirroring of routed log events to `tracing`.
+use log::{Level, Record};
+
+/// Emits a `tracing` event for the log event to the current subscriber.
+///
+/// The targets of `tracing` events must be static, so the event's target is `log`, as with the
+/// `tracing-log` crate, and the target of the log event is recorded in its `log.target` field.
+pub fn emit(record: &Record) {
+    let (target, message) = (record.target(), record.args());
+    match record.level() {
+        Level::Error => tracing::error!(target: "log", { log.target = target }, "{}", message),
+        Level::Warn => tracing::warn!(target: "log", { log.target = target }, "{}", message),
+        Level::Info => tracing::info!(target: "log", { log.target = target }, "{}", message),
+        Level::Debug => tracing::debug!(target: "log", { log.target = target }, "{}", message),
+        Level::Trace => tracing::trace!(target: "log", { log.target = target }, "{}", message),
+    }
+}
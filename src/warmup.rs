@@ -0,0 +1,248 @@
+//! The state file through which a routing appender rebuilds its routes after a restart.
+//!
+//! The file holds a JSON array with an object per cached appender, recording its key and the file
+//! it writes to, if known, along with the level and target of the log event it was created for
+//! and the MDC entries the routers read to route it:
+//!
+//! ```json
+//! [
+//!   {
+//!     "key": "6job_id5job-1",
+//!     "path": "log/job-1.log",
+//!     "level": "INFO",
+//!     "target": "app",
+//!     "mdc": {
+//!       "job_id": "job-1"
+//!     }
+//!   }
+//! ]
+//! ```
+//!
+//! An appender is rebuilt by routing an empty log event with the recorded level and target while
+//! the recorded MDC is in place, so the router builds it the same way it did originally.
+//!
+//! Only the MDC entries the routers read are recorded, rather than the whole MDC of the thread,
+//! and templates record the values they substitute, after redaction, so that the state file
+//! doesn't hold anything the routers would keep out of file names.
+use antidote::Mutex;
+use log::{Level, Record};
+use serde_json;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// How often, at most, the state file is rewritten while log events are being routed.
+const INTERVAL: Duration = Duration::from_secs(10);
+
+thread_local! {
+    // the MDC entries read while routing a log event, if they are being recorded
+    static READ: RefCell<Option<BTreeMap<String, String>>> = const { RefCell::new(None) };
+}
+
+/// Calls `f`, returning the MDC entries read through `read_mdc` meanwhile along with its result.
+pub fn recording<F, T>(f: F) -> (T, BTreeMap<String, String>)
+where
+    F: FnOnce() -> T,
+{
+    let outer = READ.with(|r| r.borrow_mut().replace(BTreeMap::new()));
+    let result = f();
+    let read = READ.with(|r| mem::replace(&mut *r.borrow_mut(), outer));
+    (result, read.unwrap_or_default())
+}
+
+/// Records that a router read the MDC entry `key`, which had the value `value` as the router used
+/// it, if the entries read are being recorded.
+///
+/// Routers which redact the values they substitute record the redacted value, which replaces
+/// any raw one recorded before.
+#[cfg(any(feature = "expr-router", feature = "match-router", feature = "pattern-router"))]
+pub fn read_mdc(key: &str, value: &str) {
+    READ.with(|r| {
+        if let Some(ref mut read) = *r.borrow_mut() {
+            read.insert(key.to_owned(), value.to_owned());
+        }
+    });
+}
+
+/// Looks up an MDC entry like `log_mdc::get`, recording it if it is present.
+#[cfg(any(
+    feature = "expr-router",
+    feature = "match-router",
+    feature = "range-router",
+    feature = "shard-router"
+))]
+pub fn get_mdc<F, T>(key: &str, f: F) -> T
+where
+    F: FnOnce(Option<&str>) -> T,
+{
+    log_mdc::get(key, |v| {
+        if let Some(v) = v {
+            read_mdc(key, v);
+        }
+        f(v)
+    })
+}
+
+/// The context of the log event for which a cached appender was created.
+#[derive(Clone)]
+pub struct Origin {
+    level: Level,
+    target: String,
+    mdc: BTreeMap<String, String>,
+}
+
+impl Origin {
+    /// Captures the context of a log event along with the MDC entries read to route it.
+    pub fn new(record: &Record, mdc: BTreeMap<String, String>) -> Origin {
+        Origin {
+            level: record.level(),
+            target: record.target().to_owned(),
+            mdc,
+        }
+    }
+
+    /// Calls `f` with an empty log event in the captured context, with the captured MDC in place
+    /// of that of the current thread for the duration of the call.
+    pub fn replay<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&Record) -> T,
+    {
+        #[cfg(feature = "log-mdc")]
+        let mut mdc = vec![];
+        #[cfg(feature = "log-mdc")]
+        {
+            log_mdc::iter(|k, v| mdc.push((k.to_owned(), v.to_owned())));
+            log_mdc::clear();
+            log_mdc::extend(self.mdc.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+
+        let result = f(&Record::builder()
+            .level(self.level)
+            .target(&self.target)
+            .args(format_args!(""))
+            .build());
+
+        #[cfg(feature = "log-mdc")]
+        {
+            log_mdc::clear();
+            log_mdc::extend(mdc);
+        }
+        result
+    }
+}
+
+/// A cached appender, as recorded in the state file.
+pub struct Route {
+    pub key: String,
+    pub path: Option<PathBuf>,
+    pub origin: Origin,
+}
+
+/// The state file of a routing appender.
+pub struct StateFile {
+    path: PathBuf,
+    // when the file was last checked for being out of date
+    saved: Mutex<Instant>,
+}
+
+impl StateFile {
+    pub fn new(path: PathBuf) -> StateFile {
+        StateFile {
+            path,
+            saved: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Determines if the file is due to be rewritten, if it is out of date.
+    pub fn due(&self) -> bool {
+        let mut saved = self.saved.lock();
+        if saved.elapsed() < INTERVAL {
+            return false;
+        }
+        *saved = Instant::now();
+        true
+    }
+
+    /// Rewrites the file with the provided routes, reporting failures on standard error.
+    ///
+    /// The routes are written to a temporary file which then replaces the state file, so that the
+    /// state file is never left half written.
+    pub fn save(&self, routes: &[Route]) {
+        let records = routes.iter().map(RouteRecord::from).collect::<Vec<_>>();
+        let mut json = serde_json::to_string_pretty(&records).expect("routes serialize to JSON");
+        json.push('\n');
+
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let result = fs::write(&tmp, json).and_then(|()| fs::rename(&tmp, &self.path));
+        if let Err(e) = result {
+            let _ = writeln!(
+                io::stderr(),
+                "log4rs: error writing routing state file `{}`: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+
+    /// Reads the routes recorded in the file, which are none if it doesn't exist.
+    pub fn load(&self) -> Result<Vec<Route>, Box<dyn Error + Sync + Send>> {
+        let json = match fs::read_to_string(&self.path) {
+            Ok(json) => json,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        let records = serde_json::from_str::<Vec<RouteRecord>>(&json)?;
+        records.into_iter().map(RouteRecord::into_route).collect()
+    }
+}
+
+/// A cached appender, in the form it takes in the state file.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteRecord {
+    key: String,
+    path: Option<String>,
+    level: String,
+    target: String,
+    mdc: BTreeMap<String, String>,
+}
+
+impl<'a> From<&'a Route> for RouteRecord {
+    fn from(route: &'a Route) -> RouteRecord {
+        RouteRecord {
+            key: route.key.clone(),
+            path: route.path.as_ref().map(|p| p.to_string_lossy().into_owned()),
+            level: route.origin.level.as_str().to_owned(),
+            target: route.origin.target.clone(),
+            mdc: route.origin.mdc.clone(),
+        }
+    }
+}
+
+impl RouteRecord {
+    fn into_route(self) -> Result<Route, Box<dyn Error + Sync + Send>> {
+        let level =
+            Level::from_str(&self.level).map_err(|_| format!("invalid level `{}`", self.level))?;
+        Ok(Route {
+            key: self.key,
+            path: self.path.map(PathBuf::from),
+            origin: Origin {
+                level,
+                target: self.target,
+                mdc: self.mdc,
+            },
+        })
+    }
+}
@@ -0,0 +1,250 @@
+//! Asynchronous delivery of log events to the router.
+use antidote::{Condvar, Mutex};
+use log::{Level, Record};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+#[cfg(feature = "log-kv")]
+use log::kv::{self, Key, Source, Value, VisitSource};
+
+use Inner;
+
+/// The behavior of an asynchronous `RoutingAppender` when its queue is full.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "file", derive(Deserialize))]
+#[cfg_attr(feature = "file", serde(rename_all = "snake_case"))]
+pub enum Overflow {
+    /// Wait for space in the queue, blocking the logging thread.
+    #[default]
+    Block,
+    /// Discard the new log event.
+    Drop,
+    /// Discard the oldest queued log event to make room for the new one.
+    DropOldest,
+}
+
+/// A log event captured on the logging thread, along with the MDC in effect when it was logged.
+pub struct OwnedRecord {
+    level: Level,
+    target: String,
+    module_path: Option<String>,
+    file: Option<String>,
+    line: Option<u32>,
+    message: String,
+    #[cfg(feature = "log-mdc")]
+    mdc: Vec<(String, String)>,
+    #[cfg(feature = "log-kv")]
+    kvs: OwnedKvs,
+}
+
+/// The key-value pairs of a log event, rendered to strings.
+#[cfg(feature = "log-kv")]
+struct OwnedKvs(Vec<(String, String)>);
+
+#[cfg(feature = "log-kv")]
+impl<'kvs> VisitSource<'kvs> for OwnedKvs {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push((key.as_str().to_owned(), value.to_string()));
+        Ok(())
+    }
+}
+
+#[cfg(feature = "log-kv")]
+impl Source for OwnedKvs {
+    fn visit<'kvs>(&'kvs self, visitor: &mut dyn VisitSource<'kvs>) -> Result<(), kv::Error> {
+        for (key, value) in &self.0 {
+            visitor.visit_pair(Key::from_str(key), Value::from(&**value))?;
+        }
+        Ok(())
+    }
+}
+
+impl OwnedRecord {
+    pub fn new(record: &Record, message: String) -> OwnedRecord {
+        #[cfg(feature = "log-mdc")]
+        let mut mdc = vec![];
+        #[cfg(feature = "log-mdc")]
+        log_mdc::iter(|k, v| mdc.push((k.to_owned(), v.to_owned())));
+
+        #[cfg(feature = "log-kv")]
+        let mut kvs = OwnedKvs(vec![]);
+        #[cfg(feature = "log-kv")]
+        let _ = record.key_values().visit(&mut kvs);
+
+        OwnedRecord {
+            level: record.level(),
+            target: record.target().to_owned(),
+            module_path: record.module_path().map(ToOwned::to_owned),
+            file: record.file().map(ToOwned::to_owned),
+            line: record.line(),
+            message,
+            #[cfg(feature = "log-mdc")]
+            mdc,
+            #[cfg(feature = "log-kv")]
+            kvs,
+        }
+    }
+
+    fn with_record<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&Record) -> T,
+    {
+        // the worker thread's MDC exists only to mirror that of the logging thread
+        #[cfg(feature = "log-mdc")]
+        {
+            log_mdc::clear();
+            log_mdc::extend(self.mdc.iter().cloned());
+        }
+
+        let mut builder = Record::builder();
+        builder
+            .level(self.level)
+            .target(&self.target)
+            .module_path(self.module_path.as_deref())
+            .file(self.file.as_deref())
+            .line(self.line);
+        #[cfg(feature = "log-kv")]
+        builder.key_values(&self.kvs);
+        f(&builder.args(format_args!("{}", self.message)).build())
+    }
+}
+
+thread_local! {
+    // the address of the shared state of the worker running on this thread, if any
+    static CURRENT: Cell<usize> = const { Cell::new(0) };
+}
+
+struct State {
+    queue: VecDeque<OwnedRecord>,
+    // queued events plus the one being handled by the worker, if any
+    pending: usize,
+    shutdown: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    idle: Condvar,
+    queue_size: usize,
+    overflow: Overflow,
+}
+
+/// A background thread which routes and appends queued log events.
+pub struct Worker {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.shared.state.lock().shutdown = true;
+        self.shared.not_empty.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Worker {
+    pub fn new(inner: Arc<Inner>, queue_size: usize, overflow: Overflow) -> Worker {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                queue: VecDeque::with_capacity(queue_size),
+                pending: 0,
+                shutdown: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            idle: Condvar::new(),
+            queue_size,
+            overflow,
+        });
+
+        let thread = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("log4rs-routing-appender".to_owned())
+                .spawn(move || run(&shared, &inner))
+                .expect("failed to spawn routing appender worker thread")
+        };
+
+        Worker {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn send(&self, record: OwnedRecord) {
+        let mut state = self.shared.state.lock();
+        if state.queue.len() >= self.shared.queue_size {
+            match self.shared.overflow {
+                Overflow::Block => {
+                    while state.queue.len() >= self.shared.queue_size {
+                        state = self.shared.not_full.wait(state);
+                    }
+                }
+                Overflow::Drop => return,
+                Overflow::DropOldest => {
+                    state.queue.pop_front();
+                    state.pending -= 1;
+                }
+            }
+        }
+        state.queue.push_back(record);
+        state.pending += 1;
+        self.shared.not_empty.notify_one();
+    }
+
+    /// Determines if this is the worker's own thread, on which events must not be queued or
+    /// waited for, since the worker would wait on itself.
+    pub fn is_current(&self) -> bool {
+        CURRENT.with(|c| c.get() == &*self.shared as *const Shared as usize)
+    }
+
+    /// Blocks until every event queued so far has been handled.
+    ///
+    /// Returns right away on the worker's own thread.
+    pub fn wait_idle(&self) {
+        if self.is_current() {
+            return;
+        }
+        let mut state = self.shared.state.lock();
+        while state.pending > 0 {
+            state = self.shared.idle.wait(state);
+        }
+    }
+}
+
+fn run(shared: &Shared, inner: &Inner) {
+    CURRENT.with(|c| c.set(shared as *const Shared as usize));
+    loop {
+        let record = {
+            let mut state = shared.state.lock();
+            loop {
+                if let Some(record) = state.queue.pop_front() {
+                    break record;
+                }
+                if state.shutdown {
+                    return;
+                }
+                state = shared.not_empty.wait(state);
+            }
+        };
+        shared.not_full.notify_one();
+
+        // there's no caller to return the error to, so report it the same way log4rs does
+        if let Err(e) = record.with_record(|r| inner.route_and_append(r)) {
+            let _ = writeln!(io::stderr(), "log4rs: {}", e);
+        }
+
+        let mut state = shared.state.lock();
+        state.pending -= 1;
+        if state.pending == 0 {
+            shared.idle.notify_all();
+        }
+    }
+}
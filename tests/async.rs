@@ -0,0 +1,261 @@
+#![cfg(feature = "pattern-router")]
+
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+extern crate log_mdc;
+extern crate serde_value;
+extern crate serde_yaml;
+
+use log::Record;
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use log4rs_routing_appender::register;
+use serde_value::Value;
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::Write;
+use std::sync::{Arc, Barrier, Mutex};
+
+// events are recorded globally since they're appended on the worker thread
+static APPENDS: Mutex<Vec<(String, Option<String>, String)>> = Mutex::new(vec![]);
+static GATES: Mutex<Option<HashMap<String, Arc<Barrier>>>> = Mutex::new(None);
+
+#[derive(Debug)]
+struct TestAppender(String);
+
+impl Append for TestAppender {
+    fn append(&self, record: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let message = record.args().to_string();
+        if message == "block" {
+            // once to signal that the worker is busy, and once to wait to be released
+            let gate = GATES.lock().unwrap().as_ref().unwrap()[&self.0].clone();
+            gate.wait();
+            gate.wait();
+        }
+
+        let job_id = log_mdc::get("job_id", |v| v.map(ToOwned::to_owned));
+        APPENDS
+            .lock()
+            .unwrap()
+            .push((self.0.clone(), job_id, message));
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct TestAppenderDeserializer;
+
+impl Deserialize for TestAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        Ok(Box::new(TestAppender(config["tag"].clone())))
+    }
+}
+
+/// Buffers its output in memory until flushed, like a file appender, but unlike a `BufWriter`,
+/// loses the buffer if dropped without being flushed.
+#[derive(Debug)]
+struct BufferedAppender(Mutex<(File, Vec<u8>)>);
+
+impl Append for BufferedAppender {
+    fn append(&self, record: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        writeln!(self.0.lock().unwrap().1, "{}", record.args())?;
+        Ok(())
+    }
+
+    fn flush(&self) {
+        let (ref mut file, ref mut buf) = *self.0.lock().unwrap();
+        file.write_all(buf).unwrap();
+        buf.clear();
+    }
+}
+
+struct BufferedAppenderDeserializer;
+
+impl Deserialize for BufferedAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        let file = File::create(&config["path"])?;
+        Ok(Box::new(BufferedAppender(Mutex::new((file, vec![])))))
+    }
+}
+
+fn routing_appender(tag: &str, overflow: &str) -> Box<dyn Append> {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("test", TestAppenderDeserializer);
+
+    let config = format!(
+        r#"
+async:
+  queue_size: 1
+  overflow: {}
+router:
+  kind: pattern
+  pattern:
+    kind: test
+    tag: {}
+    job: "${{mdc(job_id)(none)}}"
+"#,
+        overflow, tag
+    );
+    let config = serde_yaml::from_str::<Value>(&config).unwrap();
+    d.deserialize("routing", config).unwrap()
+}
+
+fn append(appender: &dyn Append, message: &str) {
+    appender
+        .append(&Record::builder().args(format_args!("{}", message)).build())
+        .unwrap();
+}
+
+fn appends(tag: &str) -> Vec<(Option<String>, String)> {
+    APPENDS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|a| a.0 == tag)
+        .map(|a| (a.1.clone(), a.2.clone()))
+        .collect()
+}
+
+fn gate(tag: &str) -> Arc<Barrier> {
+    let gate = Arc::new(Barrier::new(2));
+    GATES
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(tag.to_owned(), gate.clone());
+    gate
+}
+
+#[test]
+fn routes_on_worker_with_mdc() {
+    let appender = routing_appender("mdc", "block");
+
+    log_mdc::insert("job_id", "a");
+    append(&*appender, "one");
+    log_mdc::insert("job_id", "b");
+    append(&*appender, "two");
+    log_mdc::remove("job_id");
+    append(&*appender, "three");
+    appender.flush();
+
+    assert_eq!(
+        appends("mdc"),
+        [
+            (Some("a".to_owned()), "one".to_owned()),
+            (Some("b".to_owned()), "two".to_owned()),
+            (None, "three".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn drop_discards_new_events() {
+    let gate = gate("drop");
+    let appender = routing_appender("drop", "drop");
+
+    append(&*appender, "block");
+    gate.wait();
+    append(&*appender, "queued");
+    append(&*appender, "dropped");
+    gate.wait();
+    appender.flush();
+
+    assert_eq!(
+        appends("drop"),
+        [(None, "block".to_owned()), (None, "queued".to_owned())]
+    );
+}
+
+#[test]
+fn drop_oldest_discards_queued_events() {
+    let gate = gate("drop_oldest");
+    let appender = routing_appender("drop_oldest", "drop_oldest");
+
+    append(&*appender, "block");
+    gate.wait();
+    append(&*appender, "dropped");
+    append(&*appender, "queued");
+    gate.wait();
+    appender.flush();
+
+    assert_eq!(
+        appends("drop_oldest"),
+        [(None, "block".to_owned()), (None, "queued".to_owned())]
+    );
+}
+
+#[test]
+fn drop_drains_queue() {
+    let appender = routing_appender("drain", "block");
+    for i in 0..10 {
+        append(&*appender, &i.to_string());
+    }
+    drop(appender);
+
+    let expected = (0..10).map(|i| (None, i.to_string())).collect::<Vec<_>>();
+    assert_eq!(appends("drain"), expected);
+}
+
+#[test]
+fn drop_and_flush_write_buffered_events() {
+    let dir = env::temp_dir().join(format!("routing-shutdown-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("buffered", BufferedAppenderDeserializer);
+    let config = format!(
+        r#"
+async: {{}}
+router:
+  kind: pattern
+  pattern:
+    kind: buffered
+    path: "{}/${{mdc(job_id)}}.log"
+"#,
+        dir.display()
+    );
+    let config = serde_yaml::from_str::<Value>(&config).unwrap();
+    let lines = |job_id: &str| {
+        fs::read_to_string(dir.join(format!("{}.log", job_id)))
+            .unwrap()
+            .lines()
+            .count()
+    };
+
+    let appender = d.deserialize::<dyn Append>("routing", config.clone()).unwrap();
+    for i in 0..100 {
+        log_mdc::insert("job_id", if i % 2 == 0 { "a" } else { "b" });
+        append(&*appender, &i.to_string());
+    }
+    appender.flush();
+    assert_eq!((lines("a"), lines("b")), (50, 50));
+
+    let appender = d.deserialize::<dyn Append>("routing", config).unwrap();
+    for i in 0..100 {
+        log_mdc::insert("job_id", if i % 2 == 0 { "c" } else { "d" });
+        append(&*appender, &i.to_string());
+    }
+    drop(appender);
+    assert_eq!((lines("c"), lines("d")), (50, 50));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,127 @@
+#![cfg(feature = "range-router")]
+
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+extern crate log_mdc;
+extern crate serde_value;
+extern crate serde_yaml;
+
+use log::Record;
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use log4rs_routing_appender::register;
+use serde_value::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+
+thread_local! {
+    static CREATED: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+}
+
+#[derive(Debug)]
+struct PathAppender;
+
+impl Append for PathAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct PathAppenderDeserializer;
+
+impl Deserialize for PathAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        CREATED.with(|c| c.borrow_mut().push(config["path"].clone()));
+        Ok(Box::new(PathAppender))
+    }
+}
+
+fn bucketed_router(
+    width: &str,
+    default: bool,
+) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("path", PathAppenderDeserializer);
+
+    let mut config = format!(
+        r#"
+router:
+  kind: bucketed_numeric
+  key: latency
+  width: {}
+  pattern:
+    kind: path
+    path: "log/latency-${{bucket}}.log"
+"#,
+        width
+    );
+    if default {
+        config.push_str("  default:\n    kind: path\n    path: log/latency-unknown.log\n");
+    }
+    let config = serde_yaml::from_str::<Value>(&config).unwrap();
+    d.deserialize("routing", config)
+}
+
+fn append(
+    appender: &dyn Append,
+    latency: Option<&str>,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    match latency {
+        Some(latency) => log_mdc::insert("latency", latency),
+        None => log_mdc::remove("latency"),
+    };
+    appender.append(&Record::builder().args(format_args!("")).build())
+}
+
+fn created() -> Vec<String> {
+    CREATED.with(|c| c.borrow_mut().drain(..).collect())
+}
+
+#[test]
+fn buckets() {
+    let appender = bucketed_router("100", true).unwrap();
+
+    for latency in &["0", "99", "100", "199.5", "-1", "-0", "12", "slow"] {
+        append(&*appender, Some(latency)).unwrap();
+    }
+    append(&*appender, None).unwrap();
+
+    assert_eq!(
+        created(),
+        [
+            "log/latency-0.log",
+            "log/latency-100.log",
+            "log/latency--100.log",
+            "log/latency-unknown.log",
+        ]
+    );
+}
+
+#[test]
+fn fractional_width() {
+    let appender = bucketed_router("0.5", false).unwrap();
+
+    append(&*appender, Some("1.2")).unwrap();
+    append(&*appender, Some("1.7")).unwrap();
+    assert!(append(&*appender, Some("fast")).is_err());
+
+    assert_eq!(created(), ["log/latency-1.log", "log/latency-1.5.log"]);
+}
+
+#[test]
+fn rejects_invalid_widths() {
+    assert!(bucketed_router("0", false).is_err());
+    assert!(bucketed_router("-10", false).is_err());
+}
@@ -0,0 +1,117 @@
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+
+use log::{Level, Record};
+use log4rs::append::Append;
+use log4rs_routing_appender::route::dynamic::{DynamicRouter, RouteDecision};
+use log4rs_routing_appender::RoutingAppender;
+use std::cell::{Cell, RefCell};
+use std::error::Error;
+use std::thread;
+use std::time::Duration;
+
+thread_local! {
+    static WRITTEN: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+    static CONSTRUCTED: Cell<usize> = const { Cell::new(0) };
+}
+
+#[derive(Debug)]
+struct TestAppender(String);
+
+impl Append for TestAppender {
+    fn append(&self, record: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        WRITTEN.with(|w| {
+            w.borrow_mut()
+                .push(format!("{}: {}", self.0, record.args()))
+        });
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+fn log(
+    appender: &RoutingAppender,
+    target: &str,
+    message: &str,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    appender.append(
+        &Record::builder()
+            .level(Level::Info)
+            .target(target)
+            .args(format_args!("{}", message))
+            .build(),
+    )
+}
+
+#[test]
+fn routes_by_decision() {
+    let router = DynamicRouter::new(|record: &Record| {
+        let name = match record.target() {
+            "" => return None,
+            "reserved" => "!fallback".to_owned(),
+            target => target.to_owned(),
+        };
+        Some(RouteDecision::new(name.clone(), move || {
+            CONSTRUCTED.with(|c| c.set(c.get() + 1));
+            Ok(Box::new(TestAppender(name)) as Box<dyn Append>)
+        }))
+    });
+    let appender = RoutingAppender::builder().build(Box::new(router));
+
+    log(&appender, "db", "one").unwrap();
+    log(&appender, "web", "two").unwrap();
+    log(&appender, "db", "three").unwrap();
+    WRITTEN.with(|w| assert_eq!(*w.borrow(), ["db: one", "web: two", "db: three"]));
+    CONSTRUCTED.with(|c| assert_eq!(c.get(), 2));
+    let snapshot = appender.snapshot();
+    let keys = snapshot.entries.iter().map(|e| &*e.key).collect::<Vec<_>>();
+    assert_eq!(keys, ["web", "db"]);
+
+    let err = log(&appender, "", "four").unwrap_err();
+    assert!(err.to_string().contains("no route"), "{}", err);
+    let err = log(&appender, "reserved", "five").unwrap_err();
+    assert!(err.to_string().contains("reserved"), "{}", err);
+    CONSTRUCTED.with(|c| assert_eq!(c.get(), 2));
+}
+
+#[test]
+fn routes_by_group() {
+    let router = DynamicRouter::grouped(
+        |record: &Record| record.target().split("::").next().map(str::to_owned),
+        |record: &Record, group: &str| {
+            CONSTRUCTED.with(|c| c.set(c.get() + 1));
+            // the appender is built from the first event of its group
+            let name = format!("{} ({})", group, record.target());
+            Ok(Box::new(TestAppender(name)) as Box<dyn Append>)
+        },
+    );
+    let appender = RoutingAppender::builder()
+        .idle_timeout(Duration::from_millis(50))
+        .build(Box::new(router));
+
+    log(&appender, "db::pool", "one").unwrap();
+    log(&appender, "db::query", "two").unwrap();
+    log(&appender, "web", "three").unwrap();
+    WRITTEN.with(|w| {
+        assert_eq!(
+            *w.borrow(),
+            ["db (db::pool): one", "db (db::pool): two", "web (web): three"]
+        )
+    });
+    CONSTRUCTED.with(|c| assert_eq!(c.get(), 2));
+    let snapshot = appender.snapshot();
+    let keys = snapshot.entries.iter().map(|e| &*e.key).collect::<Vec<_>>();
+    assert_eq!(keys, ["db", "web"]);
+
+    // idle groups are evicted and rebuilt like any other route
+    thread::sleep(Duration::from_millis(100));
+    log(&appender, "db::query", "four").unwrap();
+    WRITTEN.with(|w| assert_eq!(w.borrow().last().unwrap(), "db (db::query): four"));
+    CONSTRUCTED.with(|c| assert_eq!(c.get(), 3));
+    assert_eq!(appender.snapshot().evictions, 2);
+
+    let err = log(&appender, "!fallback", "five").unwrap_err();
+    assert!(err.to_string().contains("reserved"), "{}", err);
+}
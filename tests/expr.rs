@@ -0,0 +1,133 @@
+#![cfg(feature = "expr-router")]
+
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+extern crate log_mdc;
+extern crate serde_value;
+extern crate serde_yaml;
+
+use log::Record;
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use log4rs_routing_appender::register;
+use serde_value::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+
+thread_local! {
+    static APPENDS: RefCell<Vec<u32>> = const { RefCell::new(vec![]) };
+}
+
+#[derive(Debug)]
+struct TestAppender(u32);
+
+impl Append for TestAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        APPENDS.with(|a| a.borrow_mut().push(self.0));
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct TestAppenderDeserializer;
+
+impl Deserialize for TestAppenderDeserializer {
+    type Config = HashMap<String, u32>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, u32>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        Ok(Box::new(TestAppender(config["id"])))
+    }
+}
+
+fn expr_router(rules: &[&str]) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("test", TestAppenderDeserializer);
+
+    let mut config = "router:\n  kind: expr\n  default:\n    kind: test\n    id: 0\n  rules:\n".to_owned();
+    for (i, rule) in rules.iter().enumerate() {
+        config += &format!(
+            "    - when: '{}'\n      appender:\n        kind: test\n        id: {}\n",
+            rule,
+            i + 1
+        );
+    }
+    let config = serde_yaml::from_str::<Value>(&config).unwrap();
+    d.deserialize("routing", config)
+}
+
+fn append(appender: &dyn Append, mdc: &[(&str, &str)]) {
+    log_mdc::clear();
+    log_mdc::extend(mdc.iter().cloned());
+    appender
+        .append(&Record::builder().args(format_args!("")).build())
+        .unwrap();
+}
+
+fn appends() -> Vec<u32> {
+    APPENDS.with(|a| a.borrow_mut().drain(..).collect())
+}
+
+#[test]
+fn rules_in_order() {
+    let appender = expr_router(&[
+        r#"mdc(env) == "prod" && mdc(tier) == "db""#,
+        r#"mdc(env) == "prod" && (mdc(tier) != "web" || present(query_id))"#,
+        r#""staging" == mdc(env)"#,
+    ])
+    .unwrap();
+
+    append(&*appender, &[("env", "prod"), ("tier", "db")]);
+    append(&*appender, &[("env", "prod"), ("tier", "cache")]);
+    append(&*appender, &[("env", "prod")]);
+    append(&*appender, &[("env", "prod"), ("tier", "web")]);
+    append(&*appender, &[("env", "prod"), ("tier", "web"), ("query_id", "")]);
+    append(&*appender, &[("env", "staging"), ("tier", "db")]);
+    append(&*appender, &[]);
+
+    assert_eq!(appends(), [1, 2, 2, 0, 2, 3, 0]);
+}
+
+#[test]
+fn escapes_and_quoted_keys() {
+    let appender = expr_router(&[r#"mdc("odd key") == "say \"hi\" \\ bye""#]).unwrap();
+
+    append(&*appender, &[("odd key", "say \"hi\" \\ bye")]);
+    append(&*appender, &[("odd key", "say hi")]);
+
+    assert_eq!(appends(), [1, 0]);
+}
+
+#[test]
+fn parse_errors() {
+    for rule in &[
+        "mdc(env) ==",
+        "mdc(env) = \"a\"",
+        "mdc(env)",
+        "(present(a)",
+        "present(a) present(b)",
+        "mdc(env) == \"unterminated",
+        "env == \"a\"",
+    ] {
+        assert!(expr_router(&[rule]).is_err(), "{}", rule);
+    }
+}
+
+#[test]
+fn complexity_is_bounded() {
+    let nested = format!("{}present(a){}", "(".repeat(17), ")".repeat(17));
+    assert!(expr_router(&[&nested]).is_err());
+    let nested = format!("{}present(a){}", "(".repeat(16), ")".repeat(16));
+    assert!(expr_router(&[&nested]).is_ok());
+
+    let long = vec!["present(a)"; 65].join(" || ");
+    assert!(expr_router(&[&long]).is_err());
+}
@@ -0,0 +1,179 @@
+#![cfg(feature = "failover-router")]
+
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+extern crate serde_value;
+extern crate serde_yaml;
+
+use log::Record;
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use log4rs_routing_appender::register;
+use serde_value::Value;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::thread;
+use std::time::Duration;
+
+thread_local! {
+    static DOWN: RefCell<HashSet<u32>> = RefCell::new(HashSet::new());
+    static ATTEMPTS: RefCell<Vec<u32>> = const { RefCell::new(vec![]) };
+    static CONSTRUCTED: Cell<usize> = const { Cell::new(0) };
+}
+
+#[derive(Debug)]
+struct TestAppender(u32);
+
+impl Append for TestAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        ATTEMPTS.with(|a| a.borrow_mut().push(self.0));
+        if DOWN.with(|d| d.borrow().contains(&self.0)) {
+            return Err("destination unreachable".into());
+        }
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct TestAppenderDeserializer;
+
+impl Deserialize for TestAppenderDeserializer {
+    type Config = HashMap<String, u32>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, u32>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        CONSTRUCTED.with(|c| c.set(c.get() + 1));
+        Ok(Box::new(TestAppender(config["id"])))
+    }
+}
+
+fn routing_appender(config: &str) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("test", TestAppenderDeserializer);
+
+    let config = serde_yaml::from_str::<Value>(config).unwrap();
+    d.deserialize("routing", config)
+}
+
+fn failover(extra: &str) -> Box<dyn Append> {
+    routing_appender(&format!(
+        r#"
+router:
+  kind: failover
+  appenders:
+    - kind: test
+      id: 0
+    - kind: test
+      id: 1
+{}"#,
+        extra
+    ))
+    .unwrap()
+}
+
+fn append(appender: &dyn Append) -> Result<(), Box<dyn Error + Sync + Send>> {
+    appender.append(&Record::builder().args(format_args!("")).build())
+}
+
+fn set_down(id: u32, down: bool) {
+    DOWN.with(|d| {
+        if down {
+            d.borrow_mut().insert(id);
+        } else {
+            d.borrow_mut().remove(&id);
+        }
+    });
+}
+
+fn attempts() -> Vec<u32> {
+    ATTEMPTS.with(|a| a.borrow_mut().drain(..).collect())
+}
+
+#[test]
+fn fails_over_and_recovers() {
+    let appender = failover("  probe_interval: 100ms\n");
+
+    append(&*appender).unwrap();
+    assert_eq!(attempts(), [0]);
+    // the secondary isn't built until it's needed
+    assert_eq!(CONSTRUCTED.with(Cell::get), 1);
+
+    // the failed event is passed on, and later ones skip the primary
+    set_down(0, true);
+    append(&*appender).unwrap();
+    append(&*appender).unwrap();
+    assert_eq!(attempts(), [0, 1, 1]);
+
+    // the primary is probed after the interval, and still down
+    thread::sleep(Duration::from_millis(150));
+    append(&*appender).unwrap();
+    append(&*appender).unwrap();
+    assert_eq!(attempts(), [0, 1, 1]);
+
+    set_down(0, false);
+    thread::sleep(Duration::from_millis(150));
+    append(&*appender).unwrap();
+    append(&*appender).unwrap();
+    assert_eq!(attempts(), [0, 0]);
+}
+
+#[test]
+fn failure_threshold() {
+    let appender = failover("  failure_threshold: 2\n");
+
+    set_down(0, true);
+    append(&*appender).unwrap();
+    append(&*appender).unwrap();
+    append(&*appender).unwrap();
+    assert_eq!(attempts(), [0, 1, 0, 1, 1]);
+}
+
+#[test]
+fn all_down() {
+    let appender = failover("");
+
+    set_down(0, true);
+    set_down(1, true);
+    assert!(append(&*appender).is_err());
+    assert!(append(&*appender).is_err());
+    assert_eq!(attempts(), [0, 1]);
+}
+
+#[test]
+fn adaptive_order() {
+    // the primary is never considered down, so only the order skips it
+    let appender = failover("  failure_threshold: 100\n  order: adaptive\n");
+
+    set_down(0, true);
+    append(&*appender).unwrap();
+    append(&*appender).unwrap();
+    assert_eq!(attempts(), [0, 1, 1]);
+
+    // the secondary keeps being tried first until it has failed more often than the primary
+    set_down(0, false);
+    set_down(1, true);
+    append(&*appender).unwrap();
+    append(&*appender).unwrap();
+    append(&*appender).unwrap();
+    assert_eq!(attempts(), [1, 0, 1, 0, 0]);
+
+    let appender = failover("  failure_threshold: 100\n");
+    set_down(0, true);
+    set_down(1, false);
+    append(&*appender).unwrap();
+    append(&*appender).unwrap();
+    assert_eq!(attempts(), [0, 1, 0, 1]);
+}
+
+#[test]
+fn rejects_empty_appenders() {
+    assert!(routing_appender("router:\n  kind: failover\n  appenders: []\n").is_err());
+}
@@ -0,0 +1,118 @@
+#![cfg(feature = "log-kv")]
+
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+extern crate serde_value;
+extern crate serde_yaml;
+
+use log::Record;
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use log4rs_routing_appender::register;
+use serde_value::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+
+static CREATED: Mutex<Vec<String>> = Mutex::new(vec![]);
+
+#[derive(Debug)]
+struct PathAppender;
+
+impl Append for PathAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct PathAppenderDeserializer;
+
+impl Deserialize for PathAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        CREATED.lock().unwrap().push(config["path"].clone());
+        Ok(Box::new(PathAppender))
+    }
+}
+
+fn kv_router(path: &str, extra: &str) -> Box<dyn Append> {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("path", PathAppenderDeserializer);
+
+    let config = format!(
+        "router:\n  kind: pattern\n  pattern:\n    kind: path\n    path: '{}'\n{}",
+        path, extra
+    );
+    let config = serde_yaml::from_str::<Value>(&config).unwrap();
+    d.deserialize("routing", config).unwrap()
+}
+
+fn append(appender: &dyn Append, kvs: &[(&str, &str)]) -> Result<(), Box<dyn Error + Sync + Send>> {
+    appender.append(&Record::builder().key_values(&kvs).args(format_args!("")).build())
+}
+
+fn created(prefix: &str) -> Vec<String> {
+    let mut created = CREATED.lock().unwrap();
+    let (matching, rest) = created.drain(..).partition(|p: &String| p.starts_with(prefix));
+    *created = rest;
+    matching
+}
+
+#[test]
+fn kv() {
+    let appender = kv_router("sync/${kv(request_id)}/${kv(tenant)(shared)}.log", "");
+
+    append(&*appender, &[("request_id", "r1")]).unwrap();
+    append(&*appender, &[("request_id", "r1"), ("tenant", "acme")]).unwrap();
+    append(&*appender, &[("tenant", "acme"), ("request_id", "r1")]).unwrap();
+    append(&*appender, &[("request_id", "r2")]).unwrap();
+    assert!(append(&*appender, &[]).is_err());
+
+    assert_eq!(
+        created("sync/"),
+        ["sync/r1/shared.log", "sync/r1/acme.log", "sync/r2/shared.log"]
+    );
+}
+
+#[test]
+fn kv_named_marker() {
+    let appender = kv_router("marker/${kv(request_id)}.log", "  missing_key: named_marker\n");
+
+    append(&*appender, &[]).unwrap();
+    assert_eq!(created("marker/"), ["marker/_missing_request_id_.log"]);
+}
+
+#[test]
+fn kv_survives_truncation() {
+    let appender = kv_router("truncated/${kv(request_id)}.log", "max_message_bytes: 1\n");
+
+    appender
+        .append(
+            &Record::builder()
+                .key_values(&[("request_id", "r1")])
+                .args(format_args!("too long"))
+                .build(),
+        )
+        .unwrap();
+    assert_eq!(created("truncated/"), ["truncated/r1.log"]);
+}
+
+#[test]
+fn kv_async() {
+    let appender = kv_router("async/${kv(request_id)}.log", "async: {}\n");
+
+    append(&*appender, &[("request_id", "r1")]).unwrap();
+    appender.flush();
+
+    assert_eq!(created("async/"), ["async/r1.log"]);
+}
@@ -0,0 +1,216 @@
+#![cfg(feature = "match-router")]
+
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+extern crate log_mdc;
+extern crate serde_value;
+extern crate serde_yaml;
+
+use log::Record;
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use log4rs_routing_appender::register;
+use serde_value::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+
+thread_local! {
+    static APPENDS: RefCell<Vec<u32>> = const { RefCell::new(vec![]) };
+}
+
+#[derive(Debug)]
+struct TestAppender(u32);
+
+impl Append for TestAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        APPENDS.with(|a| a.borrow_mut().push(self.0));
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct TestAppenderDeserializer;
+
+impl Deserialize for TestAppenderDeserializer {
+    type Config = HashMap<String, u32>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, u32>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        Ok(Box::new(TestAppender(config["id"])))
+    }
+}
+
+fn routing_appender(config: &str) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("test", TestAppenderDeserializer);
+
+    let config = serde_yaml::from_str::<Value>(config).unwrap();
+    d.deserialize("routing", config)
+}
+
+fn append(appender: &dyn Append, value: Option<&str>) -> Result<(), Box<dyn Error + Sync + Send>> {
+    match value {
+        Some(value) => log_mdc::insert("flag", value),
+        None => log_mdc::remove("flag"),
+    };
+    appender.append(&Record::builder().args(format_args!("")).build())
+}
+
+fn appends() -> Vec<u32> {
+    APPENDS.with(|a| a.borrow_mut().drain(..).collect())
+}
+
+#[test]
+fn truthy() {
+    let appender = routing_appender(
+        r#"
+router:
+  kind: match
+  key: flag
+  cases:
+    - truthy: true
+      appender:
+        kind: test
+        id: 1
+  default:
+    kind: test
+    id: 0
+"#,
+    )
+    .unwrap();
+
+    for value in &["true", "TRUE", "Yes", " on ", "1"] {
+        append(&*appender, Some(value)).unwrap();
+    }
+    assert_eq!(appends(), [1, 1, 1, 1, 1]);
+
+    for value in &[None, Some("false"), Some("0"), Some(""), Some("yess")] {
+        append(&*appender, *value).unwrap();
+    }
+    assert_eq!(appends(), [0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn cases_in_order() {
+    let appender = routing_appender(
+        r#"
+router:
+  kind: match
+  key: flag
+  cases:
+    - value: "yes"
+      appender:
+        kind: test
+        id: 1
+    - truthy: false
+      appender:
+        kind: test
+        id: 2
+    - truthy: true
+      appender:
+        kind: test
+        id: 3
+"#,
+    )
+    .unwrap();
+
+    append(&*appender, Some("yes")).unwrap();
+    append(&*appender, None).unwrap();
+    append(&*appender, Some("no")).unwrap();
+    append(&*appender, Some("on")).unwrap();
+    assert_eq!(appends(), [1, 2, 2, 3]);
+}
+
+#[test]
+fn set_membership() {
+    let appender = routing_appender(
+        r#"
+router:
+  kind: match
+  key: flag
+  cases:
+    - value: b
+      appender:
+        kind: test
+        id: 1
+    - in: [a, b, c]
+      appender:
+        kind: test
+        id: 2
+    - value: c
+      appender:
+        kind: test
+        id: 3
+  default:
+    kind: test
+    id: 0
+"#,
+    )
+    .unwrap();
+
+    for value in &[Some("a"), Some("b"), Some("c"), Some("d"), None] {
+        append(&*appender, *value).unwrap();
+    }
+    // the first matching case wins, whether it is a set or not
+    assert_eq!(appends(), [2, 1, 2, 0, 0]);
+}
+
+#[test]
+fn no_default() {
+    let appender = routing_appender(
+        r#"
+router:
+  kind: match
+  key: flag
+  cases:
+    - value: a
+      appender:
+        kind: test
+        id: 1
+"#,
+    )
+    .unwrap();
+
+    append(&*appender, Some("a")).unwrap();
+    assert!(append(&*appender, Some("b")).is_err());
+    assert_eq!(appends(), [1]);
+}
+
+#[test]
+fn rejects_ambiguous_cases() {
+    let config = r#"
+router:
+  kind: match
+  key: flag
+  cases:
+    - value: a
+      truthy: true
+      appender:
+        kind: test
+        id: 1
+"#;
+    assert!(routing_appender(config).is_err());
+}
+
+#[test]
+fn rejects_empty_set() {
+    let config = r#"
+router:
+  kind: match
+  key: flag
+  cases:
+    - in: []
+      appender:
+        kind: test
+        id: 1
+"#;
+    assert!(routing_appender(config).is_err());
+}
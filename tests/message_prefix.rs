@@ -0,0 +1,132 @@
+#![cfg(feature = "message-prefix-router")]
+
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+extern crate serde_value;
+extern crate serde_yaml;
+
+use log::Record;
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use log4rs_routing_appender::register;
+use serde_value::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+
+thread_local! {
+    static CREATED: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+}
+
+#[derive(Debug)]
+struct PathAppender;
+
+impl Append for PathAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct PathAppenderDeserializer;
+
+impl Deserialize for PathAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        CREATED.with(|c| c.borrow_mut().push(config["path"].clone()));
+        Ok(Box::new(PathAppender))
+    }
+}
+
+fn prefix_router(router: &str) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("path", PathAppenderDeserializer);
+
+    let config = format!(
+        r#"
+router:
+  kind: message_prefix
+  pattern:
+    kind: path
+    path: "log/${{prefix}}.log"
+{}
+"#,
+        router
+    );
+    let config = serde_yaml::from_str::<Value>(&config).unwrap();
+    d.deserialize("routing", config)
+}
+
+fn log(appender: &dyn Append, message: &str) {
+    appender
+        .append(&Record::builder().args(format_args!("{}", message)).build())
+        .unwrap();
+}
+
+#[test]
+fn prefixes() {
+    let appender = prefix_router("  len: 12\n  delimiter: \":\"").unwrap();
+
+    log(&*appender, "timeout: db-1");
+    log(&*appender, "timeout: db-2");
+    log(&*appender, "connection refused by peer");
+    log(&*appender, " disk full : /var");
+    log(&*appender, "bad\tpath/to\nfile");
+    log(&*appender, ": no prefix");
+
+    CREATED.with(|c| {
+        assert_eq!(
+            *c.borrow(),
+            [
+                "log/timeout.log",
+                "log/connection r.log",
+                "log/disk full.log",
+                "log/bad_path_to.log",
+                "log/_.log",
+            ]
+        )
+    });
+}
+
+#[test]
+fn dot_prefixes() {
+    let appender = prefix_router("  len: 12\n  delimiter: \":\"").unwrap();
+
+    // `.` and `..` would otherwise name the current and the parent directory in a path
+    log(&*appender, "..: up");
+    log(&*appender, " . : here");
+    log(&*appender, "...: dots");
+
+    CREATED.with(|c| assert_eq!(*c.borrow(), ["log/_.log", "log/....log"]));
+}
+
+#[test]
+fn formatted_arguments() {
+    let appender = prefix_router("  len: 40\n  delimiter: \" #\"").unwrap();
+
+    appender
+        .append(
+            &Record::builder()
+                .args(format_args!("{} failed #{}", "job", 7))
+                .build(),
+        )
+        .unwrap();
+    log(&*appender, "job failed #8");
+
+    CREATED.with(|c| assert_eq!(*c.borrow(), ["log/job failed.log"]));
+}
+
+#[test]
+fn invalid_config() {
+    assert!(prefix_router("  len: 0").is_err());
+    assert!(prefix_router("  len: 10\n  delimiter: \"\"").is_err());
+}
@@ -0,0 +1,173 @@
+#![cfg(all(feature = "pattern-router", feature = "metrics"))]
+
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+extern crate log_mdc;
+extern crate metrics;
+extern crate serde_value;
+extern crate serde_yaml;
+
+use log::Record;
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, Key, KeyName, Metadata, Recorder, SharedString,
+    Unit,
+};
+use log4rs_routing_appender::register;
+use serde_value::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// A recorder which records every update of a metric as a line of text.
+#[derive(Default)]
+struct TestRecorder(Arc<Mutex<Vec<String>>>);
+
+/// A handle of a metric, labelled for the lines it records.
+struct Metric {
+    name: String,
+    reported: Arc<Mutex<Vec<String>>>,
+}
+
+impl Metric {
+    fn new(recorder: &TestRecorder, key: &Key) -> Arc<Metric> {
+        let labels = key
+            .labels()
+            .map(|l| format!("{}={}", l.key(), l.value()))
+            .collect::<Vec<_>>();
+        Arc::new(Metric {
+            name: format!("{}{{{}}}", key.name(), labels.join(",")),
+            reported: recorder.0.clone(),
+        })
+    }
+}
+
+impl CounterFn for Metric {
+    fn increment(&self, value: u64) {
+        let metric = format!("{} += {}", self.name, value);
+        self.reported.lock().unwrap().push(metric);
+    }
+
+    fn absolute(&self, value: u64) {
+        let metric = format!("{} = {}", self.name, value);
+        self.reported.lock().unwrap().push(metric);
+    }
+}
+
+impl GaugeFn for Metric {
+    fn increment(&self, value: f64) {
+        let metric = format!("{} += {}", self.name, value);
+        self.reported.lock().unwrap().push(metric);
+    }
+
+    fn decrement(&self, value: f64) {
+        let metric = format!("{} -= {}", self.name, value);
+        self.reported.lock().unwrap().push(metric);
+    }
+
+    fn set(&self, value: f64) {
+        let metric = format!("{} = {}", self.name, value);
+        self.reported.lock().unwrap().push(metric);
+    }
+}
+
+impl Recorder for TestRecorder {
+    fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn register_counter(&self, key: &Key, _: &Metadata) -> Counter {
+        Counter::from_arc(Metric::new(self, key))
+    }
+
+    fn register_gauge(&self, key: &Key, _: &Metadata) -> Gauge {
+        Gauge::from_arc(Metric::new(self, key))
+    }
+
+    fn register_histogram(&self, _: &Key, _: &Metadata) -> Histogram {
+        Histogram::noop()
+    }
+}
+
+#[derive(Debug)]
+struct TestAppender;
+
+impl Append for TestAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct TestAppenderDeserializer;
+
+impl Deserialize for TestAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        _: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        Ok(Box::new(TestAppender))
+    }
+}
+
+fn routing_appender(config: &str) -> Box<dyn Append> {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("test", TestAppenderDeserializer);
+    let config = serde_yaml::from_str::<Value>(config).unwrap();
+    d.deserialize("routing", config).unwrap()
+}
+
+#[test]
+fn reports_cache_metrics() {
+    let config = r#"
+cache:
+  idle_timeout: 50ms
+router:
+  kind: pattern
+  pattern:
+    kind: test
+    key: "${mdc(job_id)}"
+"#;
+    let named = routing_appender(&format!("metrics_name: jobs\n{}", config));
+    let unnamed = routing_appender(config);
+    let append_to = |appender: &dyn Append, job_id: &str| {
+        log_mdc::insert("job_id", job_id);
+        appender
+            .append(&Record::builder().args(format_args!("")).build())
+            .unwrap();
+    };
+
+    let recorder = TestRecorder::default();
+    metrics::with_local_recorder(&recorder, || {
+        append_to(&*named, "a");
+        append_to(&*named, "a");
+        append_to(&*unnamed, "a");
+        thread::sleep(Duration::from_millis(100));
+        append_to(&*named, "b");
+    });
+
+    assert_eq!(
+        *recorder.0.lock().unwrap(),
+        [
+            "routing_appender.cache.misses{appender=jobs} += 1",
+            "routing_appender.cache.live{appender=jobs} = 1",
+            "routing_appender.cache.hits{appender=jobs} += 1",
+            "routing_appender.cache.evictions{appender=jobs} += 1",
+            "routing_appender.cache.live{appender=jobs} = 0",
+            "routing_appender.cache.misses{appender=jobs} += 1",
+            "routing_appender.cache.live{appender=jobs} = 1",
+        ]
+    );
+}
@@ -0,0 +1,236 @@
+#![cfg(feature = "pattern-router")]
+
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+extern crate log_mdc;
+extern crate serde_value;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_yaml;
+
+use log::Record;
+use log4rs::append::Append;
+use log4rs::file::{Deserialize as DeserializeTrait, Deserializers};
+use log4rs_routing_appender::{register, RoutingAppender};
+use log4rs_routing_appender::route::{Appender, Cache, Route, RouterConfig};
+use serde_value::Value;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+
+thread_local! {
+    static APPENDS: RefCell<Vec<u32>> = const { RefCell::new(vec![]) };
+}
+
+#[derive(Debug)]
+struct TestAppender(u32);
+
+impl Append for TestAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        APPENDS.with(|a| a.borrow_mut().push(self.0));
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct TestAppenderDeserializer;
+
+impl DeserializeTrait for TestAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        Ok(Box::new(TestAppender(config["id"].parse().unwrap())))
+    }
+}
+
+/// Delegates to one of several child routers based on an MDC entry.
+#[derive(Debug)]
+struct SelectRouter {
+    key: String,
+    routers: BTreeMap<String, Box<dyn Route>>,
+}
+
+impl Route for SelectRouter {
+    fn route(
+        &self,
+        record: &Record,
+        cache: &mut Cache,
+    ) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+        let name = log_mdc::get(&self.key, |v| v.map(ToOwned::to_owned)).unwrap();
+        let router = &self.routers[&name];
+        cache.scoped(&name, |cache| router.route(record, cache))
+    }
+}
+
+#[derive(Deserialize)]
+struct SelectRouterConfig {
+    key: String,
+    routers: BTreeMap<String, RouterConfig>,
+}
+
+struct SelectRouterDeserializer;
+
+impl DeserializeTrait for SelectRouterDeserializer {
+    type Config = SelectRouterConfig;
+    type Trait = dyn Route;
+
+    fn deserialize(
+        &self,
+        config: SelectRouterConfig,
+        deserializers: &Deserializers,
+    ) -> Result<Box<dyn Route>, Box<dyn Error + Sync + Send>> {
+        let mut routers = BTreeMap::new();
+        for (name, router) in config.routers {
+            routers.insert(name, deserializers.deserialize(&router.kind, router.config)?);
+        }
+        Ok(Box::new(SelectRouter {
+            key: config.key,
+            routers,
+        }))
+    }
+}
+
+/// Always routes to the same appender.
+#[derive(Debug)]
+struct FixedRouter(u32);
+
+impl Route for FixedRouter {
+    fn route(&self, _: &Record, cache: &mut Cache) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+        let id = self.0;
+        Ok(cache
+            .entry(String::new())
+            .or_insert_with(|| Box::new(TestAppender(id))))
+    }
+}
+
+/// Spreads events across shards by an MDC entry, failing to build the appender of shard 0.
+#[derive(Debug)]
+struct ShardRouter(u32);
+
+impl Route for ShardRouter {
+    fn route(&self, _: &Record, cache: &mut Cache) -> Result<Appender, Box<dyn Error + Sync + Send>> {
+        let id = log_mdc::get("id", |v| v.map(ToOwned::to_owned)).ok_or("no ID")?;
+        let shard = id.parse::<u32>()? % self.0;
+        cache.entry(shard.to_string()).or_try_insert_with(|| {
+            if shard == 0 {
+                return Err("shard 0 is unavailable".into());
+            }
+            Ok(Box::new(TestAppender(shard)))
+        })
+    }
+}
+
+struct FixedRouterDeserializer;
+
+impl DeserializeTrait for FixedRouterDeserializer {
+    type Config = HashMap<String, u32>;
+    type Trait = dyn Route;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, u32>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Route>, Box<dyn Error + Sync + Send>> {
+        Ok(Box::new(FixedRouter(config["id"])))
+    }
+}
+
+#[test]
+fn nested_routers() {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("test", TestAppenderDeserializer);
+    d.insert("select", SelectRouterDeserializer);
+    d.insert("fixed", FixedRouterDeserializer);
+
+    let config = r#"
+router:
+  kind: select
+  key: side
+  routers:
+    a:
+      kind: pattern
+      pattern:
+        kind: test
+        id: "1${mdc(n)}"
+    b:
+      kind: select
+      key: inner
+      routers:
+        x:
+          kind: fixed
+          id: 30
+        y:
+          kind: pattern
+          pattern:
+            kind: test
+            id: "2${mdc(n)}"
+"#;
+    let config = serde_yaml::from_str::<Value>(config).unwrap();
+    let appender = d.deserialize::<dyn Append>("routing", config).unwrap();
+
+    let append = |side: &str, inner: &str, n: &str| {
+        log_mdc::insert("side", side);
+        log_mdc::insert("inner", inner);
+        log_mdc::insert("n", n);
+        appender
+            .append(&Record::builder().args(format_args!("")).build())
+            .unwrap();
+    };
+
+    append("a", "x", "0");
+    append("b", "y", "0");
+    append("b", "x", "0");
+    append("a", "y", "1");
+    append("b", "y", "1");
+    append("b", "y", "0");
+
+    APPENDS.with(|a| assert_eq!(*a.borrow(), [10, 20, 30, 11, 21, 20]));
+}
+
+#[test]
+fn custom_router() {
+    let appender = RoutingAppender::builder().build(Box::new(ShardRouter(3)));
+    let append = |id: &str| {
+        log_mdc::insert("id", id);
+        appender.append(&Record::builder().args(format_args!("")).build())
+    };
+
+    append("1").unwrap();
+    append("5").unwrap();
+    append("4").unwrap();
+    APPENDS.with(|a| assert_eq!(*a.borrow(), [1, 2, 1]));
+
+    // the failed construction is not cached, so it is attempted again
+    assert!(append("3").is_err());
+    assert!(append("6").is_err());
+    assert_eq!(appender.stats().live_entries, 2);
+    assert_eq!(appender.stats().total_misses, 4);
+}
+
+#[test]
+fn custom_router_max_routes() {
+    let appender = RoutingAppender::builder()
+        .max_routes(1)
+        .build(Box::new(ShardRouter(3)));
+    let append = |id: &str| {
+        log_mdc::insert("id", id);
+        appender.append(&Record::builder().args(format_args!("")).build())
+    };
+
+    append("1").unwrap();
+    // the router is handed a placeholder for the refused route, and the appender fails instead
+    let e = append("2").unwrap_err().to_string();
+    assert!(e.contains("route limit of 1"), "{}", e);
+    assert!(e.contains("`2`"), "{}", e);
+    append("4").unwrap();
+    APPENDS.with(|a| assert_eq!(*a.borrow(), [1, 1]));
+    assert_eq!(appender.stats().live_entries, 1);
+}
@@ -0,0 +1,132 @@
+#![cfg(feature = "novelty-router")]
+
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+extern crate serde_value;
+extern crate serde_yaml;
+
+use log::{Level, Record};
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use log4rs_routing_appender::register;
+use serde_value::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+
+thread_local! {
+    static WRITTEN: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+}
+
+#[derive(Debug)]
+struct TestAppender(String);
+
+impl Append for TestAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        WRITTEN.with(|w| w.borrow_mut().push(self.0.clone()));
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct TestAppenderDeserializer;
+
+impl Deserialize for TestAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        Ok(Box::new(TestAppender(config["name"].clone())))
+    }
+}
+
+fn novelty(novelty: &str) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("test", TestAppenderDeserializer);
+
+    let config = format!(
+        r#"
+router:
+  kind: novelty
+  first:
+    kind: test
+    name: first
+  repeat:
+    kind: test
+    name: repeat
+{}
+"#,
+        novelty
+    );
+    let config = serde_yaml::from_str::<Value>(&config).unwrap();
+    d.deserialize("routing", config)
+}
+
+fn append(appender: &dyn Append, events: &[(Level, &str)]) -> Vec<String> {
+    WRITTEN.with(|w| w.borrow_mut().clear());
+    for &(level, message) in events {
+        appender
+            .append(
+                &Record::builder()
+                    .level(level)
+                    .args(format_args!("{}", message))
+                    .build(),
+            )
+            .unwrap();
+    }
+    WRITTEN.with(|w| w.borrow().clone())
+}
+
+#[test]
+fn first_and_repeat() {
+    let appender = novelty("").unwrap();
+
+    let events = [
+        (Level::Error, "disk full"),
+        (Level::Error, "disk full"),
+        (Level::Error, "timeout"),
+        (Level::Warn, "disk full"),
+    ];
+    assert_eq!(
+        append(&*appender, &events),
+        ["first", "repeat", "first", "repeat"]
+    );
+
+    let appender = novelty("  with_level: true").unwrap();
+    assert_eq!(
+        append(&*appender, &events),
+        ["first", "repeat", "first", "first"]
+    );
+}
+
+#[test]
+fn capacity() {
+    let appender = novelty("  capacity: 2").unwrap();
+
+    let events = [
+        (Level::Error, "a"),
+        (Level::Error, "b"),
+        // a repeat keeps "a" from being forgotten
+        (Level::Error, "a"),
+        (Level::Error, "c"),
+        (Level::Error, "a"),
+        (Level::Error, "b"),
+    ];
+    assert_eq!(
+        append(&*appender, &events),
+        ["first", "first", "repeat", "first", "repeat", "first"]
+    );
+}
+
+#[test]
+fn invalid_config() {
+    assert!(novelty("  capacity: 0").is_err());
+    assert!(novelty("  with_module: true").is_err());
+}
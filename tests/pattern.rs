@@ -9,24 +9,29 @@ extern crate serde;
 extern crate serde_value;
 extern crate serde_yaml;
 
-use log::Record;
+use log::{Level, Record};
 use log4rs::file::{Deserialize, Deserializers, RawConfig};
 use log4rs::config::Config;
 use log4rs::append::Append;
-use log4rs_routing_appender::register;
+use log4rs_routing_appender::route::Route;
+use log4rs_routing_appender::{register, RoutingAppender};
+use serde_value::Value;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::error::Error;
+use std::thread;
+use std::time::Duration;
 
 thread_local! {
-    static APPENDS: RefCell<Vec<u32>> = RefCell::new(vec![]);
+    static APPENDS: RefCell<Vec<u32>> = const { RefCell::new(vec![]) };
+    static CREATED: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
 }
 
 #[derive(Debug)]
 struct TestAppender(u32);
 
 impl Append for TestAppender {
-    fn append(&self, _: &Record) -> Result<(), Box<Error + Sync + Send>> {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
         APPENDS.with(|a| a.borrow_mut().push(self.0));
         Ok(())
     }
@@ -38,17 +43,551 @@ struct TestAppenderDeserializer;
 
 impl Deserialize for TestAppenderDeserializer {
     type Config = HashMap<String, String>;
-    type Trait = Append;
+    type Trait = dyn Append;
 
     fn deserialize(
         &self,
         config: HashMap<String, String>,
         _: &Deserializers,
-    ) -> Result<Box<Append>, Box<Error + Sync + Send>> {
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
         Ok(Box::new(TestAppender(config["key"].parse().unwrap())))
     }
 }
 
+#[derive(Debug)]
+struct PathAppender;
+
+impl Append for PathAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct PathAppenderDeserializer;
+
+impl Deserialize for PathAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        CREATED.with(|c| c.borrow_mut().push(config["path"].clone()));
+        Ok(Box::new(PathAppender))
+    }
+}
+
+fn routing_appender(config: &str) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("path", PathAppenderDeserializer);
+
+    let config = serde_yaml::from_str::<Value>(config).unwrap();
+    d.deserialize("routing", config)
+}
+
+fn path_router(path: &str) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+    routing_appender(&format!(
+        "router:\n  kind: pattern\n  pattern:\n    kind: path\n    path: '{}'\n",
+        path
+    ))
+}
+
+fn append(appender: &dyn Append, level: Level) {
+    appender
+        .append(
+            &Record::builder()
+                .level(level)
+                .target("app::db")
+                .args(format_args!(""))
+                .build(),
+        )
+        .unwrap();
+}
+
+fn created() -> Vec<String> {
+    CREATED.with(|c| c.borrow_mut().drain(..).collect())
+}
+
+#[test]
+fn record_functions() {
+    let appender = routing_appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: path
+    path: "log/${thread}/${target()}/${level}.log"
+"#,
+    )
+    .unwrap();
+
+    thread::Builder::new()
+        .name("worker".to_owned())
+        .spawn(move || {
+            append(&*appender, Level::Warn);
+            assert_eq!(created(), ["log/worker/app::db/WARN.log"]);
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+#[test]
+fn thread_names() {
+    let appender = path_router("log/${thread}.log").unwrap();
+    let appender = &*appender;
+    let log_on = |name: Option<&str>| {
+        thread::scope(|s| {
+            let mut builder = thread::Builder::new();
+            if let Some(name) = name {
+                builder = builder.name(name.to_owned());
+            }
+            let thread = builder.spawn_scoped(s, || {
+                append(appender, Level::Info);
+                append(appender, Level::Info);
+                created()
+            });
+            thread.unwrap().join().unwrap()
+        })
+    };
+
+    assert_eq!(log_on(Some("a")), ["log/a.log"]);
+    assert_eq!(log_on(Some("b")), ["log/b.log"]);
+    // the appender of the first thread named `a` is reused by the second
+    assert!(log_on(Some("a")).is_empty());
+
+    // unnamed threads are identified by their numeric ID
+    let created = log_on(None);
+    assert_eq!(created.len(), 1);
+    let id = created[0].trim_start_matches("log/").trim_end_matches(".log");
+    assert!(id.parse::<u64>().is_ok(), "{}", id);
+}
+
+#[test]
+fn level_and_mdc() {
+    let appender = routing_appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: path
+    path: "log/${mdc(service)}/${level}.log"
+cache:
+  idle_timeout: 300ms
+"#,
+    )
+    .unwrap();
+
+    log_mdc::insert("service", "api");
+    append(&*appender, Level::Error);
+    append(&*appender, Level::Info);
+    append(&*appender, Level::Error);
+    log_mdc::insert("service", "web");
+    append(&*appender, Level::Error);
+    log_mdc::insert("service", "api");
+    append(&*appender, Level::Info);
+    assert_eq!(
+        created(),
+        ["log/api/ERROR.log", "log/api/INFO.log", "log/web/ERROR.log"]
+    );
+
+    // keep (api, ERROR) alive while the other combinations go idle
+    thread::sleep(Duration::from_millis(200));
+    append(&*appender, Level::Error);
+    thread::sleep(Duration::from_millis(200));
+    append(&*appender, Level::Error);
+    append(&*appender, Level::Info);
+    log_mdc::insert("service", "web");
+    append(&*appender, Level::Error);
+    assert_eq!(created(), ["log/api/INFO.log", "log/web/ERROR.log"]);
+}
+
+#[test]
+fn router_defaults() {
+    let appender = routing_appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: path
+    path: "log/${mdc(region)}/${mdc(tenant)(own)}.log"
+  defaults:
+    region: unknown
+    tenant: shared
+"#,
+    )
+    .unwrap();
+
+    append(&*appender, Level::Info);
+    log_mdc::insert("region", "eu");
+    append(&*appender, Level::Info);
+    log_mdc::insert("tenant", "acme");
+    append(&*appender, Level::Info);
+
+    assert_eq!(
+        created(),
+        ["log/unknown/own.log", "log/eu/own.log", "log/eu/acme.log"]
+    );
+}
+
+#[test]
+fn missing_key_named_marker() {
+    let appender = routing_appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: path
+    path: "log/${mdc(region)}/${mdc(tenant)}/${mdc(user)(anonymous)}.log"
+  missing_key: named_marker
+"#,
+    )
+    .unwrap();
+
+    log_mdc::insert("region", "eu");
+    append(&*appender, Level::Info);
+    log_mdc::remove("region");
+    log_mdc::insert("tenant", "acme");
+    append(&*appender, Level::Info);
+    append(&*appender, Level::Info);
+
+    assert_eq!(
+        created(),
+        [
+            "log/eu/_missing_tenant_/anonymous.log",
+            "log/_missing_region_/acme/anonymous.log",
+        ]
+    );
+}
+
+#[test]
+fn no_context() {
+    let router = |no_context: &str| {
+        routing_appender(&format!(
+            r#"
+router:
+  kind: pattern
+  pattern:
+    kind: path
+    path: "log/${{mdc(region)}}/${{mdc(tenant)(none)}}.log"
+  no_context: {}
+"#,
+            no_context
+        ))
+        .unwrap()
+    };
+    let try_append = |appender: &dyn Append| {
+        appender.append(&Record::builder().args(format_args!("")).build())
+    };
+
+    let appender = router("\n    route_to:\n      kind: path\n      path: log/no_context.log");
+    try_append(&*appender).unwrap();
+    try_append(&*appender).unwrap();
+    log_mdc::insert("tenant", "acme");
+    assert!(try_append(&*appender).is_err());
+    log_mdc::insert("region", "eu");
+    try_append(&*appender).unwrap();
+    assert_eq!(created(), ["log/no_context.log", "log/eu/acme.log"]);
+
+    log_mdc::clear();
+    try_append(&*router("drop")).unwrap();
+    let err = try_append(&*router("error")).unwrap_err();
+    assert!(err.to_string().contains("none of the MDC entries"), "{}", err);
+    assert!(created().is_empty());
+}
+
+#[test]
+fn max_files_per_dir() {
+    let appender = routing_appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: path
+    path: "log/${mdc(tenant)}/${mdc(job)}.log"
+  max_files_per_dir: 2
+"#,
+    )
+    .unwrap();
+
+    for &(tenant, job) in &[
+        ("acme", "a"),
+        ("acme", "b"),
+        ("acme", "c"),
+        ("acme", "d"),
+        ("globex", "a"),
+        ("acme", "a"),
+    ] {
+        log_mdc::insert("tenant", tenant);
+        log_mdc::insert("job", job);
+        append(&*appender, Level::Info);
+    }
+
+    assert_eq!(
+        created(),
+        [
+            "log/acme/a.log",
+            "log/acme/b.log",
+            "log/acme/_overflow.log",
+            "log/globex/a.log",
+        ]
+    );
+
+    // a key routed to the overflow file is cached along with it, so it is only expanded once
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("path", PathAppenderDeserializer);
+    let config = "pattern:\n  kind: path\n  path: \"log/${mdc(job)}.log\"\nmax_files_per_dir: 1\n";
+    let config = serde_yaml::from_str::<Value>(config).unwrap();
+    let router = d.deserialize::<dyn Route>("pattern", config).unwrap();
+    let appender = RoutingAppender::builder().build(router);
+    for &job in &["a", "b", "b", "b"] {
+        log_mdc::insert("job", job);
+        append(&appender, Level::Info);
+    }
+    assert_eq!(created(), ["log/a.log", "log/_overflow.log"]);
+    let stats = appender.stats();
+    // the misses of `a`, `b` and the overflow appender, which is cached on its own as well
+    assert_eq!((stats.total_misses, stats.live_entries), (3, 3));
+
+    let config = "router:\n  kind: pattern\n  pattern:\n    kind: path\n    path: a.log\n  \
+                  max_files_per_dir: 0\n";
+    assert!(routing_appender(config).is_err());
+}
+
+#[test]
+fn dedup_by_path() {
+    let config = r#"
+router:
+  kind: pattern
+  pattern:
+    kind: path
+    path: "log/${mdc(job)}.log"
+    level: "${level}"
+"#;
+
+    log_mdc::insert("job", "a");
+    let appender = routing_appender(config).unwrap();
+    append(&*appender, Level::Info);
+    append(&*appender, Level::Warn);
+    assert_eq!(created(), ["log/a.log", "log/a.log"]);
+
+    let appender = routing_appender(&format!("{}cache:\n  dedup_by_path: true\n", config)).unwrap();
+    append(&*appender, Level::Info);
+    append(&*appender, Level::Warn);
+    append(&*appender, Level::Info);
+    log_mdc::insert("job", "b");
+    append(&*appender, Level::Warn);
+    assert_eq!(created(), ["log/a.log", "log/b.log"]);
+}
+
+#[test]
+fn identical_configs() {
+    let appender = path_router("log/${mdc(job)(default)}.log").unwrap();
+
+    // a job named after the default and a missing job have different keys but the same config
+    log_mdc::insert("job", "default");
+    append(&*appender, Level::Info);
+    log_mdc::remove("job");
+    append(&*appender, Level::Info);
+    append(&*appender, Level::Warn);
+    log_mdc::insert("job", "other");
+    append(&*appender, Level::Info);
+    assert_eq!(created(), ["log/default.log", "log/other.log"]);
+}
+
+#[test]
+fn duplicate_map_keys() {
+    let appender = routing_appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: path
+    path: "log/app.log"
+    "${mdc(primary)}": first
+    "${mdc(secondary)}": second
+"#,
+    )
+    .unwrap();
+    let append = || appender.append(&Record::builder().args(format_args!("")).build());
+
+    log_mdc::insert("primary", "a");
+    log_mdc::insert("secondary", "b");
+    append().unwrap();
+    log_mdc::insert("secondary", "a");
+    let err = append().unwrap_err();
+    assert!(err.to_string().contains("`a`"), "{}", err);
+
+    assert_eq!(created(), ["log/app.log"]);
+}
+
+#[test]
+fn pattern_set() {
+    let appender = routing_appender(
+        r#"
+router:
+  kind: pattern
+  select: "${mdc(sink)(file)}"
+  pattern_set:
+    file:
+      kind: path
+      path: "log/${mdc(user)}.log"
+    audit:
+      kind: path
+      path: "audit/${mdc(user)}.log"
+  fallback:
+    kind: path
+    path: "log/unknown-sink.log"
+"#,
+    )
+    .unwrap();
+
+    log_mdc::insert("user", "alice");
+    append(&*appender, Level::Info);
+    log_mdc::insert("sink", "audit");
+    append(&*appender, Level::Info);
+    append(&*appender, Level::Info);
+    log_mdc::insert("sink", "metrics");
+    append(&*appender, Level::Info);
+    append(&*appender, Level::Info);
+
+    assert_eq!(
+        created(),
+        ["log/alice.log", "audit/alice.log", "log/unknown-sink.log"]
+    );
+}
+
+#[test]
+fn pattern_set_requires_select() {
+    let err = routing_appender(
+        r#"
+router:
+  kind: pattern
+  pattern_set:
+    file:
+      kind: path
+      path: "log/app.log"
+"#,
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("select"), "{}", err);
+
+    let err = routing_appender(
+        r#"
+router:
+  kind: pattern
+  select: file
+  pattern:
+    kind: path
+    path: "log/app.log"
+"#,
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("pattern_set"), "{}", err);
+}
+
+#[test]
+fn strict_templates() {
+    let config = r#"
+router:
+  kind: pattern
+  pattern:
+    kind: path
+    path: "log/${mdc(user)}/${upper(user)}${future}.log"
+"#;
+    let err = routing_appender(config).unwrap_err();
+    assert!(err.to_string().contains("unknown argument `upper`"), "{}", err);
+    // the routing appender's deserializer passes the location through
+    let location = "at offset 17 of `log/${mdc(user)}/${upper(user)}${future}.log`";
+    assert!(err.to_string().contains(location), "{}", err);
+
+    let appender = routing_appender(&format!("{}  strict_templates: false\n", config)).unwrap();
+    log_mdc::insert("user", "alice");
+    append(&*appender, Level::Info);
+    assert_eq!(created(), ["log/alice/${upper(user)}${future}.log"]);
+}
+
+#[test]
+fn split_by_target() {
+    let appender = routing_appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: path
+    path: "log/${mdc(user)}.log"
+  split_by_target: true
+"#,
+    )
+    .unwrap();
+    let append_to = |target: &str| {
+        appender
+            .append(&Record::builder().target(target).args(format_args!("")).build())
+            .unwrap();
+    };
+
+    log_mdc::insert("user", "alice");
+    append_to("app::db");
+    append_to("app::http");
+    append_to("app::db");
+
+    assert_eq!(created(), ["log/alice.log", "log/alice.log"]);
+}
+
+#[test]
+#[cfg(feature = "regex")]
+fn redact() {
+    let appender = routing_appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: path
+    path: "log/${mdc(user)}/${mdc(file)(ssn-000-00-0000)}.log"
+  redact:
+    - '\d{3}-\d{2}-\d{4}'
+    - '@[a-z.]+'
+"#,
+    )
+    .unwrap();
+
+    log_mdc::insert("user", "123-45-6789");
+    append(&*appender, Level::Info);
+    log_mdc::insert("user", "987-65-4321");
+    append(&*appender, Level::Info);
+    log_mdc::insert("user", "ann@example.com");
+    append(&*appender, Level::Info);
+
+    // values which only differ in redacted parts share an appender, and defaults are untouched
+    assert_eq!(
+        created(),
+        [
+            "log/_redacted_/ssn-000-00-0000.log",
+            "log/ann_redacted_/ssn-000-00-0000.log",
+        ]
+    );
+}
+
+#[test]
+fn raw() {
+    let appender = path_router("log/${raw(${mdc(job_id)})}/${raw({a(b)c})}${raw()}.log").unwrap();
+    append(&*appender, Level::Info);
+    assert_eq!(created(), ["log/${mdc(job_id)}/{a(b)c}.log"]);
+}
+
+#[test]
+fn raw_unbalanced() {
+    assert!(path_router("log/${raw(a}b)}.log").is_err());
+    assert!(path_router("log/${raw(a(b)}.log").is_err());
+    assert!(path_router("log/${raw{a}}.log").is_err());
+}
+
 #[test]
 fn pattern() {
     let mut d = Deserializers::new();
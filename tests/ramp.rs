@@ -0,0 +1,115 @@
+#![cfg(feature = "ramp-router")]
+
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+extern crate serde_value;
+extern crate serde_yaml;
+
+use log::Record;
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use log4rs_routing_appender::register;
+use serde_value::Value;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::error::Error;
+use std::thread;
+use std::time::Duration;
+
+thread_local! {
+    static WRITTEN: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+    static CONSTRUCTED: Cell<usize> = const { Cell::new(0) };
+}
+
+#[derive(Debug)]
+struct TestAppender(String);
+
+impl Append for TestAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        WRITTEN.with(|w| w.borrow_mut().push(self.0.clone()));
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct TestAppenderDeserializer;
+
+impl Deserialize for TestAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        CONSTRUCTED.with(|c| c.set(c.get() + 1));
+        Ok(Box::new(TestAppender(config["name"].clone())))
+    }
+}
+
+fn ramp(ramp: &str) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("test", TestAppenderDeserializer);
+
+    let config = format!(
+        r#"
+router:
+  kind: ramp
+  old:
+    kind: test
+    name: old
+  new:
+    kind: test
+    name: new
+{}
+"#,
+        ramp
+    );
+    let config = serde_yaml::from_str::<Value>(&config).unwrap();
+    d.deserialize("routing", config)
+}
+
+fn append(appender: &dyn Append, count: usize) -> Vec<String> {
+    WRITTEN.with(|w| w.borrow_mut().clear());
+    for _ in 0..count {
+        appender
+            .append(&Record::builder().args(format_args!("")).build())
+            .unwrap();
+    }
+    WRITTEN.with(|w| w.borrow().clone())
+}
+
+#[test]
+fn deterministic_split() {
+    let appender = ramp("  from: 25\n  to: 25\n  over: 1 hour").unwrap();
+
+    assert_eq!(
+        append(&*appender, 8),
+        ["old", "old", "old", "new", "old", "old", "old", "new"]
+    );
+}
+
+#[test]
+fn ramps_up() {
+    let appender = ramp("  over: 200ms").unwrap();
+
+    assert_eq!(append(&*appender, 1), ["old"]);
+    // the new appender isn't built before it is sent an event
+    assert_eq!(CONSTRUCTED.with(Cell::get), 1);
+
+    thread::sleep(Duration::from_millis(250));
+    assert_eq!(append(&*appender, 3), ["new", "new", "new"]);
+    assert_eq!(CONSTRUCTED.with(Cell::get), 2);
+}
+
+#[test]
+fn invalid_config() {
+    assert!(ramp("  from: 120\n  over: 1 hour").is_err());
+    assert!(ramp("  to: -1\n  over: 1 hour").is_err());
+    assert!(ramp("  over: 0s").is_err());
+    assert!(ramp("").is_err());
+}
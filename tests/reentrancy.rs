@@ -0,0 +1,150 @@
+#[macro_use]
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+
+use log::{LevelFilter, Log, Metadata, Record};
+use log4rs::append::Append;
+use log4rs_routing_appender::route::dynamic::DynamicRouter;
+use log4rs_routing_appender::{Overflow, RoutingAppender};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+// the events written by each appender, which may be written by the worker thread
+static WRITTEN: Mutex<Vec<String>> = Mutex::new(vec![]);
+// the routing appender the global logger forwards to
+static CURRENT: Mutex<Option<Arc<RoutingAppender>>> = Mutex::new(None);
+// held by each test, since they share the global logger
+static SERIAL: Mutex<()> = Mutex::new(());
+
+#[derive(Debug)]
+struct TestAppender(&'static str);
+
+impl Append for TestAppender {
+    fn append(&self, record: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let written = format!("{}: {}", self.0, record.args());
+        WRITTEN.lock().unwrap().push(written);
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+/// An appender which logs every event it writes, as well as flushing the logger.
+#[derive(Debug)]
+struct EchoAppender;
+
+impl Append for EchoAppender {
+    fn append(&self, record: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let message = record.args().to_string();
+        WRITTEN.lock().unwrap().push(format!("echo: {}", message));
+        if !message.starts_with("wrote") {
+            info!("wrote {}", message);
+            log::logger().flush();
+        }
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let appender = CURRENT.lock().unwrap().clone();
+        if let Some(appender) = appender {
+            appender.append(record).unwrap();
+        }
+    }
+
+    fn flush(&self) {
+        let appender = CURRENT.lock().unwrap().clone();
+        if let Some(appender) = appender {
+            appender.flush();
+        }
+    }
+}
+
+/// Builds a routing appender whose routed appender logs while it is built.
+fn chatty(asynchronous: bool, reentrant: bool) -> Arc<RoutingAppender> {
+    let router = DynamicRouter::grouped(
+        |_: &Record| Some("chatty".to_owned()),
+        |_: &Record, _: &str| {
+            info!("connecting");
+            log::logger().flush();
+            Ok(Box::new(TestAppender("routed")) as Box<dyn Append>)
+        },
+    );
+    let mut builder = RoutingAppender::builder();
+    if asynchronous {
+        builder = builder.asynchronous(10, Overflow::Block);
+    }
+    if reentrant {
+        builder = builder.reentrant_appender(Box::new(TestAppender("reentrant")));
+    }
+    let appender = Arc::new(builder.build(Box::new(router)));
+    *CURRENT.lock().unwrap() = Some(appender.clone());
+    appender
+}
+
+#[test]
+fn logs_during_construction() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let _ = log::set_logger(&Logger);
+    log::set_max_level(LevelFilter::Info);
+
+    for &asynchronous in &[false, true] {
+        for &reentrant in &[false, true] {
+            WRITTEN.lock().unwrap().clear();
+            let appender = chatty(asynchronous, reentrant);
+
+            info!("one");
+            info!("two");
+            appender.flush();
+
+            let mut expected = vec![];
+            if reentrant {
+                expected.push("reentrant: connecting");
+            }
+            expected.extend(&["routed: one", "routed: two"]);
+            assert_eq!(*WRITTEN.lock().unwrap(), expected);
+        }
+    }
+}
+
+#[test]
+fn logs_on_worker_thread() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let _ = log::set_logger(&Logger);
+    log::set_max_level(LevelFilter::Info);
+    WRITTEN.lock().unwrap().clear();
+
+    // with a full queue, queueing the echoed events would block the worker on itself, as would
+    // the flushes waiting for the queue to empty
+    let router = DynamicRouter::grouped(
+        |_: &Record| Some("echo".to_owned()),
+        |_: &Record, _: &str| Ok(Box::new(EchoAppender) as Box<dyn Append>),
+    );
+    let appender = Arc::new(
+        RoutingAppender::builder()
+            .asynchronous(1, Overflow::Block)
+            .build(Box::new(router)),
+    );
+    *CURRENT.lock().unwrap() = Some(appender.clone());
+
+    for i in 0..20 {
+        info!("{}", i);
+    }
+    appender.flush();
+
+    let written = WRITTEN.lock().unwrap();
+    assert_eq!(written.len(), 40);
+    for (i, pair) in written.chunks(2).enumerate() {
+        assert_eq!(pair, [format!("echo: {}", i), format!("echo: wrote {}", i)]);
+    }
+}
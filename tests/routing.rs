@@ -0,0 +1,2225 @@
+#![cfg(feature = "pattern-router")]
+
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+extern crate log_mdc;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_value;
+extern crate serde_yaml;
+
+use log::{Level, Record};
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use log4rs_routing_appender::clock::MockClock;
+use log4rs_routing_appender::route::pattern::PatternRouter;
+use log4rs_routing_appender::route::{AppenderConfig, Eviction, EvictionMode, KeyMode, Route};
+use log4rs_routing_appender::{register, CircuitState, RoutingAppender};
+use serde::de;
+use serde_value::Value;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Duration;
+
+thread_local! {
+    static ROUTES: RefCell<Vec<Option<String>>> = const { RefCell::new(vec![]) };
+    static MESSAGES: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+    static CONSTRUCTED: Cell<usize> = const { Cell::new(0) };
+    static FLUSHED: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+    static FAILING: Cell<bool> = const { Cell::new(false) };
+    static LIVE: Cell<usize> = const { Cell::new(0) };
+    static LIFECYCLE: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+}
+
+#[derive(Debug)]
+struct TestAppender {
+    delay: Duration,
+    fail: bool,
+}
+
+impl Append for TestAppender {
+    fn append(&self, record: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        thread::sleep(self.delay);
+        if self.fail {
+            return Err("disk full".into());
+        }
+        let route = log_mdc::get("route", |v| v.map(ToOwned::to_owned));
+        ROUTES.with(|r| r.borrow_mut().push(route));
+        MESSAGES.with(|m| m.borrow_mut().push(record.args().to_string()));
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct TestAppenderDeserializer;
+
+impl Deserialize for TestAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        CONSTRUCTED.with(|c| c.set(c.get() + 1));
+        let delay = config.get("delay_ms").map_or(0, |d| d.parse().unwrap());
+        Ok(Box::new(TestAppender {
+            delay: Duration::from_millis(delay),
+            fail: config.get("fail").is_some_and(|f| f == "true"),
+        }))
+    }
+}
+
+#[derive(Deserialize)]
+struct ModeAppenderConfig {
+    #[serde(deserialize_with = "de_mode")]
+    mode: bool,
+    name: String,
+}
+
+/// Counts the attempts to deserialize the mode, accepting `ok` and `fail`.
+fn de_mode<'de, D>(d: D) -> Result<bool, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    CONSTRUCTED.with(|c| c.set(c.get() + 1));
+    match &*<String as de::Deserialize>::deserialize(d)? {
+        "ok" => Ok(true),
+        "fail" => Ok(false),
+        mode => Err(de::Error::custom(format!("invalid mode `{}`", mode))),
+    }
+}
+
+struct ModeAppenderDeserializer;
+
+impl Deserialize for ModeAppenderDeserializer {
+    type Config = ModeAppenderConfig;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: ModeAppenderConfig,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        if config.mode {
+            Ok(Box::new(NamedAppender(config.name)))
+        } else {
+            Err(Box::new(io::Error::other("failed to open file")))
+        }
+    }
+}
+
+#[derive(Debug)]
+struct NamedAppender(String);
+
+impl Append for NamedAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        MESSAGES.with(|m| m.borrow_mut().push(self.0.clone()));
+        Ok(())
+    }
+
+    fn flush(&self) {
+        FLUSHED.with(|f| f.borrow_mut().push(self.0.clone()));
+    }
+}
+
+/// Creates the file at `path`, like a file appender.
+struct FileAppenderDeserializer;
+
+impl Deserialize for FileAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        CONSTRUCTED.with(|c| c.set(c.get() + 1));
+        fs::File::create(&config["path"])?;
+        Ok(Box::new(TestAppender {
+            delay: Duration::from_secs(0),
+            fail: config.get("fail").is_some_and(|f| f == "true"),
+        }))
+    }
+}
+
+/// Fails its writes while `FAILING` is set.
+#[derive(Debug)]
+struct FlakyAppender;
+
+impl Append for FlakyAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        if FAILING.with(Cell::get) {
+            return Err("sink unavailable".into());
+        }
+        MESSAGES.with(|m| m.borrow_mut().push("flaky".to_owned()));
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+/// Counts the appenders which haven't been dropped in `LIVE`, and records when each is flushed
+/// and dropped in `LIFECYCLE`.
+#[derive(Debug)]
+struct LiveAppender(String);
+
+impl Append for LiveAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        Ok(())
+    }
+
+    fn flush(&self) {
+        LIFECYCLE.with(|l| l.borrow_mut().push(format!("flushed {}", self.0)));
+    }
+}
+
+impl Drop for LiveAppender {
+    fn drop(&mut self) {
+        LIVE.with(|l| l.set(l.get() - 1));
+        LIFECYCLE.with(|l| l.borrow_mut().push(format!("dropped {}", self.0)));
+    }
+}
+
+struct LiveAppenderDeserializer;
+
+impl Deserialize for LiveAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        LIVE.with(|l| l.set(l.get() + 1));
+        Ok(Box::new(LiveAppender(config["name"].clone())))
+    }
+}
+
+struct FlakyAppenderDeserializer;
+
+impl Deserialize for FlakyAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        _: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        Ok(Box::new(FlakyAppender))
+    }
+}
+
+/// Counts the appenders built across all threads, which unlike `CONSTRUCTED` is shared.
+static SHARED_CONSTRUCTED: AtomicUsize = AtomicUsize::new(0);
+
+struct SharedAppenderDeserializer;
+
+impl Deserialize for SharedAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        SHARED_CONSTRUCTED.fetch_add(1, Ordering::SeqCst);
+        // widen the window in which other threads look up the same key
+        thread::sleep(Duration::from_millis(20));
+        Ok(Box::new(NamedAppender(config["name"].clone())))
+    }
+}
+
+/// The number of `BuildingAppenderDeserializer` builds in progress, and the most seen at once.
+static BUILDING: AtomicUsize = AtomicUsize::new(0);
+static MAX_BUILDING: AtomicUsize = AtomicUsize::new(0);
+
+struct BuildingAppenderDeserializer;
+
+impl Deserialize for BuildingAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        let building = BUILDING.fetch_add(1, Ordering::SeqCst) + 1;
+        MAX_BUILDING.fetch_max(building, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        BUILDING.fetch_sub(1, Ordering::SeqCst);
+        Ok(Box::new(NamedAppender(config["name"].clone())))
+    }
+}
+
+fn deserializers() -> Deserializers {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("test", TestAppenderDeserializer);
+    d.insert("mode", ModeAppenderDeserializer);
+    d.insert("file", FileAppenderDeserializer);
+    d.insert("shared", SharedAppenderDeserializer);
+    d.insert("flaky", FlakyAppenderDeserializer);
+    d.insert("live", LiveAppenderDeserializer);
+    d.insert("building", BuildingAppenderDeserializer);
+    d
+}
+
+fn routing_appender(config: &str) -> Box<dyn Append> {
+    let config = serde_yaml::from_str::<Value>(config).unwrap();
+    deserializers().deserialize("routing", config).unwrap()
+}
+
+fn pattern_router(config: &str) -> Box<dyn Route> {
+    let config = serde_yaml::from_str::<Value>(config).unwrap();
+    deserializers().deserialize("pattern", config).unwrap()
+}
+
+fn append(appender: &dyn Append) {
+    appender
+        .append(&Record::builder().args(format_args!("")).build())
+        .unwrap();
+}
+
+#[test]
+fn key_mdc() {
+    let appender = routing_appender(
+        r#"
+key_mdc: route
+router:
+  kind: pattern
+  pattern:
+    kind: test
+    key: "${mdc(job_id)}"
+"#,
+    );
+
+    log_mdc::insert("job_id", "abc");
+    append(&*appender);
+    log_mdc::insert("job_id", "de");
+    append(&*appender);
+
+    ROUTES.with(|r| {
+        assert_eq!(*r.borrow(), [Some("6job_id3abc".to_owned()), Some("6job_id2de".to_owned())])
+    });
+    log_mdc::get("route", |v| assert_eq!(v, None));
+}
+
+#[test]
+fn key_mdc_restores_previous_value() {
+    let appender = routing_appender(
+        r#"
+key_mdc: route
+router:
+  kind: pattern
+  pattern:
+    kind: test
+    key: "${mdc(job_id)}"
+"#,
+    );
+
+    log_mdc::insert("job_id", "abc");
+    log_mdc::insert("route", "outer");
+    append(&*appender);
+
+    ROUTES.with(|r| assert_eq!(*r.borrow(), [Some("6job_id3abc".to_owned())]));
+    log_mdc::get("route", |v| assert_eq!(v, Some("outer")));
+}
+
+#[test]
+fn max_message_bytes() {
+    let appender = routing_appender(
+        r#"
+max_message_bytes: 5
+router:
+  kind: pattern
+  pattern:
+    kind: test
+"#,
+    );
+
+    for message in &["hello world", "hello", "\u{e9}\u{e9}\u{e9}"] {
+        appender
+            .append(&Record::builder().args(format_args!("{}", message)).build())
+            .unwrap();
+    }
+
+    MESSAGES.with(|m| {
+        let m = m.borrow();
+        assert_eq!(*m, ["he...", "hello", "\u{e9}..."]);
+        assert!(m.iter().all(|m| m.len() <= 5), "{:?}", m);
+    });
+
+    let appender = routing_appender(
+        r#"
+max_message_bytes: 2
+router:
+  kind: pattern
+  pattern:
+    kind: test
+"#,
+    );
+    appender
+        .append(&Record::builder().args(format_args!("hello")).build())
+        .unwrap();
+    MESSAGES.with(|m| assert_eq!(m.borrow()[3], ".."));
+}
+
+#[test]
+fn size_units() {
+    let appender = routing_appender(
+        r#"
+max_message_bytes: 1 KiB
+router:
+  kind: pattern
+  pattern:
+    kind: test
+"#,
+    );
+
+    let message = "a".repeat(1030);
+    appender
+        .append(&Record::builder().args(format_args!("{}", message)).build())
+        .unwrap();
+    MESSAGES.with(|m| {
+        assert_eq!(m.borrow()[0], format!("{}...", &message[..1021]));
+        assert_eq!(m.borrow()[0].len(), 1024);
+    });
+
+    let size = |size: &str| {
+        let config = format!(
+            "max_message_bytes: {}\nrouter:\n  kind: pattern\n  pattern:\n    kind: test\n",
+            size
+        );
+        let config = serde_yaml::from_str::<Value>(&config).unwrap();
+        deserializers().deserialize::<dyn Append>("routing", config)
+    };
+    for valid in &["16384", "16 kB", "1.5MiB", "2 gb", "0"] {
+        assert!(size(valid).is_ok(), "{}", valid);
+    }
+    for invalid in &["-1", "16 parsecs", "0.5 B", "1.2.3 MB", "MB", "99999999 TiB"] {
+        assert!(size(invalid).is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn inherit_cache_from() {
+    let config = r#"
+pattern:
+  kind: test
+  key: "${mdc(job_id)}"
+"#;
+    let old = RoutingAppender::builder()
+        .fingerprint(1)
+        .build(pattern_router(config));
+    let mut new = RoutingAppender::builder()
+        .fingerprint(1)
+        .build(pattern_router(config));
+    let mut other = RoutingAppender::builder()
+        .fingerprint(2)
+        .build(pattern_router(config));
+
+    log_mdc::insert("job_id", "abc");
+    append(&old);
+    assert_eq!(CONSTRUCTED.with(Cell::get), 1);
+
+    assert!(!other.inherit_cache_from(&old));
+    assert!(new.inherit_cache_from(&old));
+    append(&new);
+    assert_eq!(CONSTRUCTED.with(Cell::get), 1);
+
+    // the old appender no longer has the inherited entry
+    append(&old);
+    assert_eq!(CONSTRUCTED.with(Cell::get), 2);
+}
+
+#[test]
+fn inherit_cache_from_key_mode() {
+    let config = r#"
+pattern:
+  kind: test
+  key: "${mdc(job_id)}"
+"#;
+    let old = RoutingAppender::builder()
+        .fingerprint(1)
+        .build(pattern_router(config));
+    let mut new = RoutingAppender::builder()
+        .fingerprint(1)
+        .key_mode(KeyMode::Hashed)
+        .build(pattern_router(config));
+
+    log_mdc::insert("job_id", "abc");
+    append(&old);
+    assert_eq!(CONSTRUCTED.with(Cell::get), 1);
+
+    // keys stored as strings would never match the hashed ones the new appender looks up
+    assert!(!new.inherit_cache_from(&old));
+    assert_eq!(old.snapshot().entries.len(), 1);
+    append(&new);
+    assert_eq!(CONSTRUCTED.with(Cell::get), 2);
+}
+
+#[test]
+fn inherit_cache_from_shared_paths() {
+    let config = r#"
+pattern:
+  kind: test
+  path: "log/${mdc(job_id)}.log"
+  level: "${level}"
+"#;
+    let build = || {
+        RoutingAppender::builder()
+            .fingerprint(1)
+            .dedup_by_path(true)
+            .build(pattern_router(config))
+    };
+    let append_level = |appender: &RoutingAppender, level: Level| {
+        appender
+            .append(&Record::builder().level(level).args(format_args!("")).build())
+            .unwrap();
+    };
+    let old = build();
+    let mut new = build();
+
+    log_mdc::insert("job_id", "abc");
+    append_level(&old, Level::Info);
+    assert!(new.inherit_cache_from(&old));
+
+    // the old appender no longer shares the file of the inherited entry
+    append_level(&old, Level::Warn);
+    assert_eq!(CONSTRUCTED.with(Cell::get), 2);
+    append_level(&new, Level::Warn);
+    assert_eq!(CONSTRUCTED.with(Cell::get), 2);
+}
+
+#[test]
+fn invalid_config_uses_fallback() {
+    let appender = routing_appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: mode
+    mode: "${mdc(mode)}"
+    name: "${mdc(mode)}"
+  fallback:
+    kind: mode
+    mode: ok
+    name: fallback
+"#,
+    );
+    let append_with = |mode: &str| {
+        log_mdc::insert("mode", mode);
+        appender.append(&Record::builder().args(format_args!("")).build())
+    };
+
+    append_with("ok").unwrap();
+    assert_eq!(CONSTRUCTED.with(Cell::get), 1);
+
+    // rejected once, after which the fallback is used without retrying
+    append_with("bogus").unwrap();
+    append_with("bogus").unwrap();
+    assert_eq!(CONSTRUCTED.with(Cell::get), 3);
+
+    // construction errors are returned, and retried
+    assert!(append_with("fail").is_err());
+    assert!(append_with("fail").is_err());
+    assert_eq!(CONSTRUCTED.with(Cell::get), 5);
+
+    MESSAGES.with(|m| assert_eq!(*m.borrow(), ["ok", "fallback", "fallback"]));
+}
+
+#[test]
+fn fallback_router() {
+    let config = |fallback: &str| {
+        format!(
+            "router:\n  kind: pattern\n  pattern:\n    kind: mode\n    mode: ok\n    \
+             name: \"${{mdc(job_id)}}\"\n{}",
+            fallback
+        )
+    };
+    let appender = routing_appender(&config(
+        r#"
+fallback:
+  kind: pattern
+  pattern:
+    kind: mode
+    mode: ok
+    name: unrouted
+"#,
+    ));
+
+    log_mdc::insert("job_id", "a");
+    append(&*appender);
+    log_mdc::remove("job_id");
+    append(&*appender);
+    append(&*appender);
+    assert_eq!(CONSTRUCTED.with(Cell::get), 2);
+    MESSAGES.with(|m| assert_eq!(*m.borrow(), ["a", "unrouted", "unrouted"]));
+
+    // without a fallback router, or with one which fails as well, the router's error is returned
+    let append_to = |config: &str| {
+        let appender = routing_appender(config);
+        appender.append(&Record::builder().args(format_args!("")).build())
+    };
+    let e = append_to(&config("")).unwrap_err();
+    assert_eq!(e.to_string(), "MDC key `job_id` not present");
+    let failing = "fallback:\n  kind: pattern\n  pattern:\n    kind: mode\n    mode: fail\n    \
+                   name: unrouted\n";
+    let e = append_to(&config(failing)).unwrap_err();
+    assert_eq!(e.to_string(), "MDC key `job_id` not present");
+}
+
+#[test]
+fn empty_path_uses_fallback() {
+    let appender = routing_appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: file
+    path: "${mdc(dir)()}/ ${mdc(file)()}"
+  fallback:
+    kind: mode
+    mode: ok
+    name: fallback
+"#,
+    );
+
+    append(&*appender);
+    append(&*appender);
+
+    // only the fallback is built, never the file appender with the empty path
+    assert_eq!(CONSTRUCTED.with(Cell::get), 1);
+    MESSAGES.with(|m| assert_eq!(*m.borrow(), ["fallback", "fallback"]));
+}
+
+#[test]
+fn invalid_config_without_fallback_is_discarded() {
+    let appender = routing_appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: mode
+    mode: "${mdc(mode)}"
+    name: "${mdc(mode)}"
+"#,
+    );
+
+    log_mdc::insert("mode", "bogus");
+    append(&*appender);
+    append(&*appender);
+
+    assert_eq!(CONSTRUCTED.with(Cell::get), 1);
+    MESSAGES.with(|m| assert!(m.borrow().is_empty()));
+}
+
+#[test]
+fn unregistered_kind_uses_fallback() {
+    let appender = routing_appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: missing
+    name: "${mdc(job_id)}"
+  fallback:
+    kind: mode
+    mode: ok
+    name: fallback
+"#,
+    );
+
+    log_mdc::insert("job_id", "a");
+    append(&*appender);
+    append(&*appender);
+
+    assert_eq!(CONSTRUCTED.with(Cell::get), 1);
+    MESSAGES.with(|m| assert_eq!(*m.borrow(), ["fallback", "fallback"]));
+}
+
+#[test]
+fn programmatic_pattern_router() {
+    let pattern = AppenderConfig {
+        kind: "test".to_owned(),
+        config: serde_yaml::from_str("key: \"${mdc(job_id)}\"").unwrap(),
+    };
+    let router = PatternRouter::builder()
+        .default("job_id", "none")
+        .build(deserializers(), pattern)
+        .unwrap();
+    let appender = RoutingAppender::builder()
+        .key_mdc("route")
+        .build(Box::new(router));
+
+    append(&appender);
+    log_mdc::insert("job_id", "abc");
+    append(&appender);
+    append(&appender);
+
+    assert_eq!(CONSTRUCTED.with(Cell::get), 2);
+    ROUTES.with(|r| {
+        assert_eq!(
+            *r.borrow(),
+            [
+                Some("6job_id-".to_owned()),
+                Some("6job_id3abc".to_owned()),
+                Some("6job_id3abc".to_owned())
+            ]
+        )
+    });
+}
+
+#[test]
+fn appenders_are_constructed_lazily() {
+    let appender = routing_appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: test
+    key: "${mdc(job_id)}"
+"#,
+    );
+    assert_eq!(CONSTRUCTED.with(Cell::get), 0);
+
+    log_mdc::insert("job_id", "abc");
+    append(&*appender);
+    append(&*appender);
+    assert_eq!(CONSTRUCTED.with(Cell::get), 1);
+
+    log_mdc::insert("job_id", "de");
+    append(&*appender);
+    assert_eq!(CONSTRUCTED.with(Cell::get), 2);
+}
+
+#[test]
+fn slow_write_threshold() {
+    let appender = routing_appender(
+        r#"
+slow_write_threshold: 50ms
+router:
+  kind: pattern
+  pattern:
+    kind: test
+    delay_ms: "${mdc(delay_ms)}"
+"#,
+    );
+
+    log_mdc::insert("delay_ms", "0");
+    append(&*appender);
+    append(&*appender);
+    assert_eq!(CONSTRUCTED.with(Cell::get), 1);
+
+    // the slow appender is evicted after each write
+    log_mdc::insert("delay_ms", "100");
+    let record = Record::builder().args(format_args!("")).build();
+    assert!(appender.append(&record).is_err());
+    assert!(appender.append(&record).is_err());
+    assert_eq!(CONSTRUCTED.with(Cell::get), 3);
+
+    MESSAGES.with(|m| assert_eq!(m.borrow().len(), 4));
+}
+
+#[test]
+fn profiles() {
+    let config = |profile: &str| {
+        format!(
+            r#"
+profile: {}
+profile_env: ROUTING_TEST_PROFILE
+router:
+  kind: pattern
+  pattern:
+    kind: test
+    key: "${{mdc(job_id)}}"
+profiles:
+  base: {{}}
+  prod:
+    key_mdc: route
+    router:
+      pattern:
+        key: "${{mdc(region)}}"
+"#,
+            profile
+        )
+    };
+    log_mdc::insert("job_id", "abc");
+    log_mdc::insert("region", "eu");
+
+    append(&*routing_appender(&config("base")));
+    append(&*routing_appender(&config("prod")));
+    ROUTES.with(|r| assert_eq!(*r.borrow(), [None, Some("6region2eu".to_owned())]));
+
+    let missing = serde_yaml::from_str::<Value>(&config("missing")).unwrap();
+    assert!(deserializers()
+        .deserialize::<dyn Append>("routing", missing)
+        .is_err());
+
+    // the environment variable takes precedence
+    env::set_var("ROUTING_TEST_PROFILE", "prod");
+    append(&*routing_appender(&config("base")));
+    ROUTES.with(|r| assert_eq!(r.borrow()[2], Some("6region2eu".to_owned())));
+}
+
+#[test]
+fn key_strategy() {
+    let pattern = AppenderConfig {
+        kind: "test".to_owned(),
+        config: serde_yaml::from_str("key: \"${mdc(job_id)}\"").unwrap(),
+    };
+    let router = PatternRouter::builder()
+        .key_strategy(|_: &Record| log_mdc::get("trace_id", |v| v.unwrap_or("-").to_owned()))
+        .build(deserializers(), pattern)
+        .unwrap();
+    let appender = RoutingAppender::builder()
+        .key_mdc("route")
+        .build(Box::new(router));
+
+    log_mdc::insert("trace_id", "t1");
+    log_mdc::insert("job_id", "a");
+    append(&appender);
+    log_mdc::insert("job_id", "b");
+    append(&appender);
+    log_mdc::insert("trace_id", "t2");
+    append(&appender);
+
+    assert_eq!(CONSTRUCTED.with(Cell::get), 2);
+    ROUTES.with(|r| {
+        assert_eq!(
+            *r.borrow(),
+            [Some("t1".to_owned()), Some("t1".to_owned()), Some("t2".to_owned())]
+        )
+    });
+}
+
+#[test]
+fn append_and_report() {
+    let dir = env::temp_dir().join(format!("routing-report-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let pattern = AppenderConfig {
+        kind: "file".to_owned(),
+        config: serde_yaml::from_str(&format!("path: \"{}/${{mdc(job_id)}}.log\"", dir.display()))
+            .unwrap(),
+    };
+    let router = PatternRouter::builder()
+        .build(deserializers(), pattern)
+        .unwrap();
+    let appender = RoutingAppender::builder().build(Box::new(router));
+    let record = Record::builder().args(format_args!("")).build();
+
+    log_mdc::insert("job_id", "a");
+    let report = appender.append_and_report(&record).unwrap();
+    assert_eq!(report.key, appender.snapshot().entries[0].key);
+    assert_eq!(report.path, Some(dir.join("a.log")));
+
+    log_mdc::remove("job_id");
+    assert!(appender.append_and_report(&record).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn hits_do_not_expand() {
+    let pattern = AppenderConfig {
+        kind: "test".to_owned(),
+        config: serde_yaml::from_str("key: \"${mdc(job_id)}\"").unwrap(),
+    };
+    let router = PatternRouter::builder()
+        .key_strategy(|_: &Record| "job".to_owned())
+        .build(deserializers(), pattern)
+        .unwrap();
+    let appender = RoutingAppender::builder().build(Box::new(router));
+
+    log_mdc::insert("job_id", "a");
+    append(&appender);
+    // expanding the template would fail without the MDC entry
+    log_mdc::remove("job_id");
+    append(&appender);
+
+    assert_eq!(CONSTRUCTED.with(Cell::get), 1);
+}
+
+#[test]
+fn verify_files() {
+    let dir = env::temp_dir().join(format!("routing-verify-files-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("job.log");
+    let appender = routing_appender(&format!(
+        r#"
+cache:
+  verify_files: 0s
+router:
+  kind: pattern
+  pattern:
+    kind: file
+    path: "{}"
+"#,
+        path.display()
+    ));
+
+    append(&*appender);
+    append(&*appender);
+    assert_eq!(CONSTRUCTED.with(Cell::get), 1);
+
+    fs::remove_file(&path).unwrap();
+    append(&*appender);
+    assert_eq!(CONSTRUCTED.with(Cell::get), 2);
+    assert!(path.exists());
+
+    // replaced with a different file, as by log rotation
+    let other = dir.join("other.log");
+    fs::File::create(&other).unwrap();
+    fs::rename(&other, &path).unwrap();
+    append(&*appender);
+    append(&*appender);
+    assert_eq!(CONSTRUCTED.with(Cell::get), 3);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn snapshot() {
+    let appender = RoutingAppender::builder()
+        .idle_timeout(Duration::from_secs(60))
+        .build(pattern_router(
+            r#"
+pattern:
+  kind: test
+  key: "${mdc(job_id)}"
+  fail: "${mdc(fail)(false)}"
+"#,
+        ));
+
+    log_mdc::insert("job_id", "a");
+    append(&appender);
+    append(&appender);
+    log_mdc::insert("job_id", "b");
+    log_mdc::insert("fail", "true");
+    let record = Record::builder().args(format_args!("")).build();
+    assert!(appender.append(&record).is_err());
+
+    let snapshot = appender.snapshot();
+    assert_eq!(snapshot.idle_timeout, Duration::from_secs(60));
+    assert_eq!((snapshot.hits, snapshot.misses, snapshot.evictions), (1, 2, 0));
+    let errors = snapshot
+        .entries
+        .iter()
+        .map(|e| e.last_error.as_deref())
+        .collect::<Vec<_>>();
+    assert_eq!(errors, [None, Some("disk full")]);
+    assert!(snapshot.entries[1].key.contains("1b"));
+
+    let yaml = serde_yaml::to_string(&snapshot).unwrap();
+    assert!(yaml.contains("disk full"));
+}
+
+#[test]
+fn stats() {
+    let appender = RoutingAppender::builder().max_capacity(1).build(pattern_router(
+        r#"
+pattern:
+  kind: test
+  key: "${mdc(job_id)}"
+"#,
+    ));
+    let stats = appender.stats();
+    assert_eq!((stats.live_entries, stats.total_hits, stats.total_misses), (0, 0, 0));
+
+    log_mdc::insert("job_id", "a");
+    append(&appender);
+    append(&appender);
+    log_mdc::insert("job_id", "b");
+    append(&appender);
+
+    // the appender of `a` made room for that of `b`
+    let stats = appender.stats();
+    assert_eq!(stats.live_entries, 1);
+    assert_eq!((stats.total_hits, stats.total_misses, stats.evictions), (1, 2, 1));
+}
+
+#[test]
+#[cfg(feature = "prometheus")]
+fn snapshot_prometheus() {
+    let pattern = AppenderConfig {
+        kind: "test".to_owned(),
+        config: serde_yaml::from_str("{}").unwrap(),
+    };
+    let router = PatternRouter::builder()
+        .key_strategy(|_: &Record| "job \"a\"".to_owned())
+        .build(deserializers(), pattern)
+        .unwrap();
+    let appender = RoutingAppender::builder()
+        .metrics_name("jobs")
+        .build(Box::new(router));
+
+    append(&appender);
+    append(&appender);
+
+    let text = appender.snapshot_prometheus();
+    let lines = text.lines().collect::<Vec<_>>();
+    assert!(lines.contains(&"# TYPE routing_appender_cache_hits_total counter"));
+    assert!(lines.contains(&"routing_appender_cache_hits_total{appender=\"jobs\"} 1"));
+    assert!(lines.contains(&"routing_appender_cache_misses_total{appender=\"jobs\"} 1"));
+    assert!(lines.contains(&"routing_appender_cache_live{appender=\"jobs\"} 1"));
+    assert!(lines.contains(&r#"routing_appender_entry_failed{appender="jobs",key="job \"a\""} 0"#));
+}
+
+#[test]
+fn circuit_breaker() {
+    let clock = Arc::new(MockClock::new());
+    let appender = RoutingAppender::builder()
+        .clock(clock.clone())
+        .circuit_breaker(0.5, Duration::from_secs(60), Duration::from_millis(100))
+        .circuit_min_writes(4)
+        .circuit_fallback(Box::new(NamedAppender("fallback".to_owned())))
+        .build(pattern_router("pattern:\n  kind: flaky\n"));
+    let record = Record::builder().args(format_args!("")).build();
+    let circuit = || appender.snapshot().entries[0].circuit;
+    MESSAGES.with(|m| m.borrow_mut().clear());
+
+    append(&appender);
+    FAILING.with(|f| f.set(true));
+    for _ in 0..2 {
+        assert!(appender.append(&record).is_err());
+    }
+    // 2 of 3 writes failed, but too few to tell
+    assert_eq!(circuit(), CircuitState::Closed);
+    assert!(appender.append(&record).is_err());
+    assert_eq!(circuit(), CircuitState::Open);
+    append(&appender);
+
+    // a failed probe reopens the circuit
+    clock.advance(Duration::from_millis(150));
+    assert!(appender.append(&record).is_err());
+    append(&appender);
+    assert_eq!(circuit(), CircuitState::Open);
+
+    clock.advance(Duration::from_millis(150));
+    FAILING.with(|f| f.set(false));
+    append(&appender);
+    assert_eq!(circuit(), CircuitState::Closed);
+    append(&appender);
+
+    let written = MESSAGES.with(|m| m.borrow().clone());
+    assert_eq!(written, ["flaky", "fallback", "fallback", "flaky", "flaky"]);
+
+    let config = |circuit: &str| {
+        let config = format!(
+            "router:\n  kind: pattern\n  pattern:\n    kind: flaky\ncircuit:\n{}",
+            circuit
+        );
+        let config = serde_yaml::from_str::<Value>(&config).unwrap();
+        deserializers().deserialize::<dyn Append>("routing", config)
+    };
+    assert!(config("  error_threshold: 0.5\n  window: 30s\n  probe: 10s\n").is_ok());
+    assert!(config("  error_threshold: 0\n  window: 30s\n  probe: 10s\n").is_err());
+    assert!(config("  error_threshold: 0.5\n  probe: 10s\n").is_err());
+    let min_writes = "  error_threshold: 0.5\n  window: 30s\n  probe: 10s\n  min_writes: 0\n";
+    assert!(config(min_writes).is_err());
+}
+
+#[test]
+fn quota() {
+    let clock = Arc::new(MockClock::new());
+    let appender = RoutingAppender::builder()
+        .clock(clock.clone())
+        .quota(10, Duration::from_millis(200))
+        .quota_appender(Box::new(NamedAppender("over".to_owned())))
+        .build(pattern_router("pattern:\n  kind: test\n"));
+    let log = |message: &str| {
+        appender
+            .append(&Record::builder().args(format_args!("{}", message)).build())
+            .unwrap();
+    };
+    let remaining = || appender.snapshot().entries[0].quota_remaining;
+    MESSAGES.with(|m| m.borrow_mut().clear());
+
+    log("hello");
+    assert_eq!(remaining(), Some(5));
+    log("world");
+    log("!");
+    assert_eq!(remaining(), Some(0));
+
+    // the next window resets the quota
+    clock.advance(Duration::from_millis(250));
+    assert_eq!(remaining(), Some(10));
+    log("again");
+    assert_eq!(remaining(), Some(5));
+
+    let written = MESSAGES.with(|m| m.borrow().clone());
+    assert_eq!(written, ["hello", "world", "over", "again"]);
+
+    let config = |quota: &str| {
+        let config = format!(
+            "router:\n  kind: pattern\n  pattern:\n    kind: test\nquota:\n{}",
+            quota
+        );
+        let config = serde_yaml::from_str::<Value>(&config).unwrap();
+        deserializers().deserialize::<dyn Append>("routing", config)
+    };
+    assert!(config("  bytes: 100 MiB\n  window: 1 hour\n").is_ok());
+    let route_to = "  on_exceed:\n    route_to:\n      kind: test\n";
+    assert!(config(&format!("  bytes: 1 KiB\n  window: 1 hour\n{}", route_to)).is_ok());
+    assert!(config("  bytes: 0\n  window: 1 hour\n").is_err());
+    assert!(config("  bytes: 100\n").is_err());
+    assert!(config("  bytes: 100\n  window: 0s\n").is_err());
+}
+
+#[test]
+fn warmup_state() {
+    let dir = env::temp_dir().join(format!("routing-warmup-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let state = dir.join("state.json");
+    let config = format!(
+        "warmup_state: \"{}\"\nrouter:\n  kind: pattern\n  pattern:\n    kind: file\n    \
+         path: \"{}/${{mdc(job_id)}}-${{level}}.log\"\n",
+        state.display(),
+        dir.display()
+    );
+    let log = |appender: &dyn Append, job: &str, level: Level| {
+        log_mdc::insert("job_id", job);
+        appender
+            .append(&Record::builder().level(level).args(format_args!("")).build())
+            .unwrap();
+    };
+
+    // the routes are written out when the appender is dropped
+    let appender = routing_appender(&config);
+    log_mdc::insert("session", "s3cr3t");
+    log(&*appender, "a", Level::Info);
+    log(&*appender, "b", Level::Warn);
+    log(&*appender, "a", Level::Info);
+    log_mdc::remove("session");
+    drop(appender);
+    let written = fs::read_to_string(&state).unwrap();
+    assert!(written.contains("\"mdc\": {\n      \"job_id\": \"a\"\n    }"), "{}", written);
+    assert!(written.contains("\"level\": \"WARN\""));
+    // only the MDC entries the router reads are recorded
+    assert!(!written.contains("s3cr3t"));
+
+    // and rebuilt when the next one is created, leaving the MDC of the thread alone
+    fs::remove_file(dir.join("a-INFO.log")).unwrap();
+    log_mdc::insert("job_id", "c");
+    CONSTRUCTED.with(|c| c.set(0));
+    let appender = routing_appender(&config);
+    assert_eq!(CONSTRUCTED.with(Cell::get), 2);
+    assert!(dir.join("a-INFO.log").exists());
+    assert_eq!(log_mdc::get("job_id", |v| v.map(ToOwned::to_owned)), Some("c".to_owned()));
+    log(&*appender, "a", Level::Info);
+    log(&*appender, "b", Level::Warn);
+    assert_eq!(CONSTRUCTED.with(Cell::get), 2);
+    drop(appender);
+
+    // routes which fail to rebuild are skipped, as is a state file which can't be read
+    let stale = "[\n  {\"key\": \"1a\", \"path\": null, \"level\": \"INFO\", \"target\": \"app\", \
+                 \"mdc\": {}},\n  {\"key\": \"1b\", \"path\": null, \"level\": \"INFO\", \
+                 \"target\": \"app\", \"mdc\": {\"job_id\": \"d\\u0021\"}}\n]\n";
+    fs::write(&state, stale).unwrap();
+    CONSTRUCTED.with(|c| c.set(0));
+    let appender = routing_appender(&config);
+    assert_eq!(CONSTRUCTED.with(Cell::get), 1);
+    assert!(dir.join("d!-INFO.log").exists());
+    drop(appender);
+    fs::write(&state, "[{\"key\": ").unwrap();
+    let appender = routing_appender(&config);
+    assert!(appender.append(&Record::builder().args(format_args!("")).build()).is_ok());
+    drop(appender);
+
+    log_mdc::remove("job_id");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+#[cfg(feature = "regex")]
+fn warmup_state_redacted() {
+    let dir = env::temp_dir().join(format!("routing-warmup-redacted-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let state = dir.join("state.json");
+    let config = format!(
+        "warmup_state: \"{}\"\nrouter:\n  kind: pattern\n  pattern:\n    kind: file\n    \
+         path: \"{}/${{mdc(job_id)}}.log\"\n  redact:\n    - 'id-[a-z]+'\n",
+        state.display(),
+        dir.display()
+    );
+
+    // the redacted values are recorded, rather than those of the MDC
+    let appender = routing_appender(&config);
+    log_mdc::insert("job_id", "job-id-zebra");
+    append(&*appender);
+    drop(appender);
+    let written = fs::read_to_string(&state).unwrap();
+    assert!(written.contains("\"job_id\": \"job-_redacted_\""), "{}", written);
+    assert!(!written.contains("zebra"), "{}", written);
+
+    // and rebuild the same appender
+    log_mdc::remove("job_id");
+    fs::remove_file(dir.join("job-_redacted_.log")).unwrap();
+    let appender = routing_appender(&config);
+    assert!(dir.join("job-_redacted_.log").exists());
+    drop(appender);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn account() {
+    let appender = RoutingAppender::builder().account(true).build(pattern_router(
+        r#"
+pattern:
+  kind: test
+  fail: "${mdc(fail)(false)}"
+"#,
+    ));
+
+    log_mdc::remove("fail");
+    let record = Record::builder().args(format_args!("lost")).build();
+    for message in &["hello", "héllo"] {
+        appender
+            .append(&Record::builder().args(format_args!("{}", message)).build())
+            .unwrap();
+    }
+    log_mdc::insert("fail", "true");
+    assert!(appender.append(&record).is_err());
+    log_mdc::remove("fail");
+
+    let snapshot = appender.snapshot();
+    let mut counts = snapshot
+        .entries
+        .iter()
+        .map(|e| (e.events, e.bytes))
+        .collect::<Vec<_>>();
+    counts.sort();
+    assert_eq!(counts, [(0, 0), (2, 11)]);
+}
+
+#[test]
+fn max_cardinality() {
+    let clock = Arc::new(MockClock::new());
+    let appender = RoutingAppender::builder()
+        .idle_timeout(Duration::from_millis(50))
+        .clock(clock.clone())
+        .max_cardinality(2, Box::new(NamedAppender("overflow".to_owned())))
+        .build(pattern_router(
+            "pattern:\n  kind: mode\n  mode: ok\n  name: \"${mdc(job_id)}\"\n",
+        ));
+    let append_to = |job_id: &str| {
+        log_mdc::insert("job_id", job_id);
+        append(&appender);
+    };
+
+    append_to("a");
+    append_to("b");
+    append_to("c");
+    append_to("a");
+
+    // evicted keys still count against the budget, and can be recreated
+    clock.advance(Duration::from_millis(100));
+    append_to("a");
+    append_to("c");
+
+    MESSAGES.with(|m| assert_eq!(*m.borrow(), ["a", "b", "overflow", "a", "a", "overflow"]));
+
+    let config = |overflow: &str| {
+        let config = format!(
+            "router:\n  kind: pattern\n  pattern:\n    kind: test\nmax_cardinality: 2\n{}",
+            overflow
+        );
+        let config = serde_yaml::from_str::<Value>(&config).unwrap();
+        deserializers().deserialize::<dyn Append>("routing", config)
+    };
+    assert!(config("overflow:\n  kind: test\n").is_ok());
+    assert!(config("").is_err());
+}
+
+#[test]
+fn flush_matching() {
+    let pattern = AppenderConfig {
+        kind: "mode".to_owned(),
+        config: serde_yaml::from_str("{mode: ok, name: \"${mdc(job_id)}\"}").unwrap(),
+    };
+    let router = PatternRouter::builder()
+        .key_strategy(|_: &Record| {
+            let tenant = log_mdc::get("tenant", |t| t.unwrap().to_owned());
+            log_mdc::get("job_id", |j| format!("{}/{}", tenant, j.unwrap()))
+        })
+        .build(deserializers(), pattern)
+        .unwrap();
+    let appender = RoutingAppender::builder().build(Box::new(router));
+
+    for &(tenant, job_id) in &[("acme", "1"), ("initech", "2"), ("acme", "3")] {
+        log_mdc::insert("tenant", tenant);
+        log_mdc::insert("job_id", job_id);
+        append(&appender);
+    }
+
+    appender.flush_matching(|key| key.starts_with("acme/"));
+    FLUSHED.with(|f| assert_eq!(*f.borrow(), ["1", "3"]));
+}
+
+#[test]
+fn hysteresis() {
+    let clock = Arc::new(MockClock::new());
+    let appender = RoutingAppender::builder()
+        .idle_timeout(Duration::from_millis(100))
+        .hysteresis(Duration::from_millis(150))
+        .clock(clock.clone())
+        .build(pattern_router("pattern:\n  kind: test\n"));
+
+    // each event arrives just after the previous appender went idle
+    for _ in 0..6 {
+        append(&appender);
+        clock.advance(Duration::from_millis(130));
+    }
+
+    // the first recreation extends the timeout, after which the appender sticks
+    assert_eq!(CONSTRUCTED.with(Cell::get), 2);
+    assert_eq!(appender.snapshot().evictions, 1);
+}
+
+#[test]
+fn incremental_sweep() {
+    let clock = Arc::new(MockClock::new());
+    let appender = RoutingAppender::builder()
+        .idle_timeout(Duration::from_millis(50))
+        .clock(clock.clone())
+        .build(pattern_router("pattern:\n  kind: test\n  key: \"${mdc(job_id)}\"\n"));
+    let append_to = |job_id: &str| {
+        log_mdc::insert("job_id", job_id);
+        append(&appender);
+    };
+
+    for i in 0..40 {
+        append_to(&i.to_string());
+    }
+    clock.advance(Duration::from_millis(100));
+
+    // each lookup only sweeps a bounded number of the expired appenders
+    append_to("new");
+    assert_eq!(appender.snapshot().evictions, 16);
+
+    // an expired appender which hasn't been swept yet is still replaced when looked up
+    append_to("39");
+    assert_eq!(appender.snapshot().evictions, 33);
+    assert_eq!(CONSTRUCTED.with(Cell::get), 42);
+}
+
+#[test]
+fn index_appender() {
+    let dir = env::temp_dir().join(format!("routing-index-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let index = TestAppender {
+        delay: Duration::from_secs(0),
+        fail: false,
+    };
+    let clock = Arc::new(MockClock::new());
+    let appender = RoutingAppender::builder()
+        .idle_timeout(Duration::from_millis(50))
+        .clock(clock.clone())
+        .account(true)
+        .index_appender(Box::new(index), true)
+        .build(pattern_router(&format!(
+            "pattern:\n  kind: file\n  path: \"{}/${{mdc(job_id)}}.log\"\n",
+            dir.display()
+        )));
+    let append_to = |job_id: &str| {
+        log_mdc::insert("job_id", job_id);
+        appender
+            .append(&Record::builder().args(format_args!("hello")).build())
+            .unwrap();
+    };
+
+    append_to("a");
+    append_to("a");
+    append_to("b");
+    clock.advance(Duration::from_millis(100));
+    append_to("a");
+
+    let path = |job_id: &str| format!("{:?}", dir.join(format!("{}.log", job_id)));
+    let index = MESSAGES.with(|m| {
+        m.borrow()
+            .iter()
+            .filter(|m| *m != "hello")
+            .cloned()
+            .collect::<Vec<_>>()
+    });
+    assert_eq!(
+        index,
+        [
+            format!("created route key=\"6job_id1a\" path={}", path("a")),
+            format!("created route key=\"6job_id1b\" path={}", path("b")),
+            "evicted route key=\"6job_id1a\" reason=idle events=2 bytes=10".to_owned(),
+            "evicted route key=\"6job_id1b\" reason=idle events=1 bytes=5".to_owned(),
+            format!("created route key=\"6job_id1a\" path={}", path("a")),
+        ]
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+
+    let config = serde_yaml::from_str::<Value>(
+        r#"
+account: true
+index_appender:
+  kind: test
+index_evictions: true
+router:
+  kind: pattern
+  pattern:
+    kind: test
+"#,
+    )
+    .unwrap();
+    assert!(deserializers().deserialize::<dyn Append>("routing", config).is_ok());
+}
+
+#[test]
+fn hashed_keys() {
+    let pattern = AppenderConfig {
+        kind: "test".to_owned(),
+        config: serde_yaml::from_str("key: \"${mdc(job_id)}\"").unwrap(),
+    };
+    let router = PatternRouter::builder()
+        .build(deserializers(), pattern)
+        .unwrap();
+    let appender = RoutingAppender::builder()
+        .key_mode(KeyMode::Hashed)
+        .key_mdc("route")
+        .build(Box::new(router));
+
+    for job_id in &["a", "b", "a"] {
+        log_mdc::insert("job_id", *job_id);
+        append(&appender);
+    }
+
+    assert_eq!(CONSTRUCTED.with(Cell::get), 2);
+    let keys = appender
+        .snapshot()
+        .entries
+        .into_iter()
+        .map(|e| e.key)
+        .collect::<Vec<_>>();
+    assert_eq!(keys.len(), 2);
+    assert_ne!(keys[0], keys[1]);
+    for key in &keys {
+        assert!(key.starts_with('#') && key.len() == 33, "{}", key);
+    }
+    ROUTES.with(|r| {
+        assert_eq!(
+            *r.borrow(),
+            // the snapshot lists `b` first, as `a` was used last
+            [Some(keys[1].clone()), Some(keys[0].clone()), Some(keys[1].clone())]
+        )
+    });
+}
+
+#[test]
+fn flush_above() {
+    let appender = routing_appender(
+        r#"
+flush_above: warn
+router:
+  kind: pattern
+  pattern:
+    kind: mode
+    mode: ok
+    name: "${level}"
+"#,
+    );
+
+    for &level in &[Level::Info, Level::Warn, Level::Debug, Level::Error] {
+        appender
+            .append(&Record::builder().level(level).args(format_args!("")).build())
+            .unwrap();
+    }
+
+    FLUSHED.with(|f| assert_eq!(*f.borrow(), ["WARN", "ERROR"]));
+    let config = serde_yaml::from_str::<Value>(
+        "flush_above: severe\nrouter:\n  kind: pattern\n  pattern:\n    kind: test\n",
+    )
+    .unwrap();
+    assert!(deserializers().deserialize::<dyn Append>("routing", config).is_err());
+}
+
+#[test]
+fn flush_every_events() {
+    let appender = routing_appender(
+        r#"
+flush_every_events: 3
+router:
+  kind: pattern
+  pattern:
+    kind: mode
+    mode: ok
+    name: "${level}"
+"#,
+    );
+    let flushed = || {
+        FLUSHED.with(|f| {
+            let mut flushed = f.borrow_mut().drain(..).collect::<Vec<_>>();
+            flushed.sort();
+            flushed
+        })
+    };
+
+    for &level in &[Level::Info, Level::Warn] {
+        appender
+            .append(&Record::builder().level(level).args(format_args!("")).build())
+            .unwrap();
+    }
+    assert!(flushed().is_empty());
+    appender
+        .append(&Record::builder().level(Level::Info).args(format_args!("")).build())
+        .unwrap();
+    assert_eq!(flushed(), ["INFO", "WARN"]);
+
+    let config = serde_yaml::from_str::<Value>(
+        "flush_every_events: 0\nrouter:\n  kind: pattern\n  pattern:\n    kind: test\n",
+    )
+    .unwrap();
+    assert!(deserializers().deserialize::<dyn Append>("routing", config).is_err());
+}
+
+#[test]
+fn mirror_appender() {
+    let appender = routing_appender(
+        r#"
+mirror_appender:
+  kind: mode
+  mode: ok
+  name: mirror
+router:
+  kind: pattern
+  pattern:
+    kind: mode
+    mode: "${mdc(mode)}"
+    name: "${level}"
+"#,
+    );
+    let append = |level| {
+        appender
+            .append(&Record::builder().level(level).args(format_args!("")).build())
+    };
+
+    log_mdc::insert("mode", "ok");
+    append(Level::Info).unwrap();
+    append(Level::Warn).unwrap();
+    log_mdc::insert("mode", "fail");
+    assert!(append(Level::Error).is_err());
+
+    MESSAGES.with(|m| assert_eq!(*m.borrow(), ["INFO", "mirror", "WARN", "mirror"]));
+}
+
+#[test]
+fn concurrent_first_touch() {
+    let appender: Arc<dyn Append> = Arc::from(routing_appender(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: shared
+    name: "${mdc(job_id)}"
+"#,
+    ));
+    let barrier = Arc::new(Barrier::new(16));
+
+    let threads = (0..16)
+        .map(|_| {
+            let appender = appender.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                log_mdc::insert("job_id", "new");
+                barrier.wait();
+                append(&*appender);
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    // the threads wait for the first of them to build the appender rather than building their own
+    assert_eq!(SHARED_CONSTRUCTED.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn max_capacity() {
+    let capped = |spill: bool| {
+        let pattern = AppenderConfig {
+            kind: "mode".to_owned(),
+            config: serde_yaml::from_str("{mode: ok, name: \"${level}\"}").unwrap(),
+        };
+        let router = PatternRouter::builder()
+            .key_strategy(|r: &Record| r.level().to_string())
+            .build(deserializers(), pattern)
+            .unwrap();
+        let mut builder = RoutingAppender::builder()
+            .max_capacity(2)
+            .protect("ERROR")
+            .protect("WARN")
+            .key_mdc("route");
+        if spill {
+            builder = builder.spill_appender(Box::new(TestAppender {
+                delay: Duration::from_secs(0),
+                fail: false,
+            }));
+        }
+        builder.build(Box::new(router))
+    };
+    let append_at = |appender: &RoutingAppender, level: Level| {
+        appender
+            .append(&Record::builder().level(level).args(format_args!("")).build())
+            .unwrap();
+    };
+    let keys = |appender: &RoutingAppender| {
+        let mut keys = appender
+            .snapshot()
+            .entries
+            .into_iter()
+            .map(|e| e.key)
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    };
+
+    for &spill in &[false, true] {
+        let appender = capped(spill);
+        append_at(&appender, Level::Info);
+        append_at(&appender, Level::Debug);
+        // unprotected appenders are evicted first
+        append_at(&appender, Level::Error);
+        assert_eq!(keys(&appender), ["DEBUG", "ERROR"]);
+        append_at(&appender, Level::Warn);
+        assert_eq!(keys(&appender), ["ERROR", "WARN"]);
+
+        ROUTES.with(|r| r.borrow_mut().clear());
+        append_at(&appender, Level::Info);
+        if spill {
+            assert_eq!(keys(&appender), ["ERROR", "WARN"]);
+            ROUTES.with(|r| assert_eq!(*r.borrow(), [Some("INFO".to_owned())]));
+        } else {
+            assert_eq!(keys(&appender), ["INFO", "WARN"]);
+            ROUTES.with(|r| assert!(r.borrow().is_empty()));
+        }
+    }
+
+    let config = |cache: &str| {
+        let config = format!(
+            "router:\n  kind: pattern\n  pattern:\n    kind: test\ncache:\n{}",
+            cache
+        );
+        let config = serde_yaml::from_str::<Value>(&config).unwrap();
+        deserializers().deserialize::<dyn Append>("routing", config)
+    };
+    let spill = "  spill_appender:\n    kind: test\n";
+    assert!(config(&format!("  capacity: 10\n  at_capacity: spill\n{}", spill)).is_ok());
+    assert!(config("  capacity: 0\n").is_err());
+    assert!(config("  capacity: 10\n  at_capacity: spill\n").is_err());
+    assert!(config(&format!("  at_capacity: spill\n{}", spill)).is_err());
+    assert!(config(&format!("  capacity: 10\n{}", spill)).is_err());
+}
+
+#[test]
+fn max_routes() {
+    let appender = RoutingAppender::builder().max_routes(2).build(pattern_router(
+        r#"
+pattern:
+  kind: test
+  key: "${mdc(job_id)}"
+  job: "${mdc(job_id)}"
+"#,
+    ));
+    let append_job = |job: &str| {
+        log_mdc::insert("job_id", job);
+        appender.append(&Record::builder().args(format_args!("")).build())
+    };
+
+    append_job("a").unwrap();
+    append_job("b").unwrap();
+    let e = append_job("c").unwrap_err().to_string();
+    assert!(e.contains("route limit of 2"), "{}", e);
+    assert!(e.contains("`6job_id1c`"), "{}", e);
+    assert!(e.contains("with configuration {kind: test, job: \"c\", key: \"c\"}"), "{}", e);
+    // the refused route was not built, and the cached ones are unaffected
+    append_job("a").unwrap();
+    assert_eq!(CONSTRUCTED.with(Cell::get), 2);
+    assert_eq!(appender.stats().live_entries, 2);
+
+    // routes are accepted again once there is room
+    appender.drain();
+    append_job("c").unwrap();
+    assert_eq!(CONSTRUCTED.with(Cell::get), 3);
+
+    let config = |max: usize| {
+        let config = format!(
+            "router:\n  kind: pattern\n  pattern:\n    kind: test\ncache:\n  max_routes: {}\n",
+            max
+        );
+        let config = serde_yaml::from_str::<Value>(&config).unwrap();
+        deserializers().deserialize::<dyn Append>("routing", config)
+    };
+    assert!(config(1).is_ok());
+    assert!(config(0).is_err());
+}
+
+#[test]
+fn max_concurrent_builds() {
+    let config = r#"
+router:
+  kind: pattern
+  pattern:
+    kind: building
+    name: "${mdc(job_id)}"
+cache:
+  max_concurrent_builds: 1
+"#;
+    // the limit is shared by routing appenders, which each build one appender at a time anyway
+    let appenders = (0..2).map(|_| routing_appender(config)).collect::<Vec<_>>();
+    let barrier = Barrier::new(4);
+    thread::scope(|s| {
+        for appender in &appenders {
+            for &job in &["a", "b"] {
+                let barrier = &barrier;
+                s.spawn(move || {
+                    log_mdc::insert("job_id", job);
+                    barrier.wait();
+                    append(&**appender);
+                });
+            }
+        }
+    });
+    assert_eq!(MAX_BUILDING.load(Ordering::SeqCst), 1);
+
+    let config = |max: usize| {
+        let config = format!(
+            "router:\n  kind: pattern\n  pattern:\n    kind: test\ncache:\n  \
+             max_concurrent_builds: {}\n",
+            max
+        );
+        let config = serde_yaml::from_str::<Value>(&config).unwrap();
+        deserializers().deserialize::<dyn Append>("routing", config)
+    };
+    assert!(config(4).is_ok());
+    assert!(config(0).is_err());
+}
+
+#[test]
+fn capacity_drops_evicted() {
+    let appender = routing_appender(
+        r#"
+cache:
+  capacity: 2
+router:
+  kind: pattern
+  pattern:
+    kind: live
+    name: "${mdc(job_id)}"
+"#,
+    );
+
+    for &job in &["a", "b", "c", "a", "d", "e"] {
+        log_mdc::insert("job_id", job);
+        append(&*appender);
+        // the appenders evicted to make room are dropped rather than lingering
+        assert!(LIVE.with(Cell::get) <= 2);
+    }
+    drop(appender);
+    assert_eq!(LIVE.with(Cell::get), 0);
+    log_mdc::remove("job_id");
+}
+
+#[test]
+fn flush_before_eviction() {
+    let clock = Arc::new(MockClock::new());
+    let appender = RoutingAppender::builder()
+        .idle_timeout(Duration::from_millis(50))
+        .clock(clock.clone())
+        .build(pattern_router("pattern:\n  kind: live\n  name: \"${mdc(job_id)}\"\n"));
+    let lifecycle = || LIFECYCLE.with(|l| l.borrow_mut().drain(..).collect::<Vec<_>>());
+
+    log_mdc::insert("job_id", "a");
+    append(&appender);
+    clock.advance(Duration::from_millis(100));
+    log_mdc::insert("job_id", "b");
+    append(&appender);
+    // the expired appender is flushed, and then dropped by the lookup which evicted it
+    assert_eq!(lifecycle(), ["flushed a", "dropped a"]);
+
+    clock.advance(Duration::from_millis(100));
+    append(&appender);
+    assert_eq!(lifecycle(), ["flushed b", "dropped b"]);
+    drop(appender);
+    assert_eq!(lifecycle(), ["flushed b", "dropped b"]);
+    log_mdc::remove("job_id");
+}
+
+#[test]
+fn drain() {
+    let config = r#"
+pattern:
+  kind: live
+  name: "${mdc(job_id)}"
+"#;
+    let mut builder = RoutingAppender::builder();
+    let evictions = builder.eviction_channel(2);
+    let appender = builder.build(pattern_router(config));
+    let lifecycle = || LIFECYCLE.with(|l| l.borrow_mut().drain(..).collect::<Vec<_>>());
+
+    // draining an empty cache does nothing
+    appender.drain();
+    assert!(lifecycle().is_empty());
+
+    for &job in &["a", "b"] {
+        log_mdc::insert("job_id", job);
+        append(&appender);
+    }
+    appender.drain();
+    assert_eq!(lifecycle(), ["flushed a", "dropped a", "flushed b", "dropped b"]);
+    assert!(appender.snapshot().entries.is_empty());
+    assert_eq!(evictions.try_recv().unwrap().reason, Eviction::Drained);
+    assert_eq!(evictions.try_recv().unwrap().reason, Eviction::Drained);
+
+    // routed afterwards, an appender is created anew
+    append(&appender);
+    assert_eq!(LIVE.with(Cell::get), 1);
+    drop(appender);
+    assert_eq!(LIVE.with(Cell::get), 0);
+    log_mdc::remove("job_id");
+}
+
+#[test]
+fn background_eviction() {
+    let clock = Arc::new(MockClock::new());
+    let mut builder = RoutingAppender::builder()
+        .idle_timeout(Duration::from_secs(60))
+        .clock(clock.clone())
+        .background_eviction(Duration::from_millis(10));
+    let evictions = builder.eviction_channel(1);
+    let appender = builder.build(pattern_router(
+        r#"
+pattern:
+  kind: test
+  key: "${mdc(job_id)}"
+"#,
+    ));
+
+    log_mdc::insert("job_id", "a");
+    append(&appender);
+    assert!(evictions.recv_timeout(Duration::from_millis(50)).is_err());
+
+    // evicted without any further event being routed
+    clock.advance(Duration::from_secs(61));
+    let eviction = evictions.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(eviction.reason, Eviction::Idle);
+    assert!(appender.snapshot().entries.is_empty());
+
+    // dropping the appender stops the thread
+    drop(appender);
+    log_mdc::remove("job_id");
+
+    let config = |cache: &str| {
+        let config = format!(
+            "router:\n  kind: pattern\n  pattern:\n    kind: test\ncache:\n{}",
+            cache
+        );
+        let config = serde_yaml::from_str::<Value>(&config).unwrap();
+        deserializers().deserialize::<dyn Append>("routing", config)
+    };
+    assert!(config("  background_eviction: 30 seconds\n").is_ok());
+    assert!(config("  background_eviction: often\n").is_err());
+}
+
+#[test]
+fn min_ttl() {
+    let pattern = AppenderConfig {
+        kind: "mode".to_owned(),
+        config: serde_yaml::from_str("{mode: ok, name: \"${level}\"}").unwrap(),
+    };
+    let router = PatternRouter::builder()
+        .key_strategy(|r: &Record| r.level().to_string())
+        .build(deserializers(), pattern)
+        .unwrap();
+    let clock = Arc::new(MockClock::new());
+    let appender = RoutingAppender::builder()
+        .max_capacity(2)
+        .min_ttl(Duration::from_millis(200))
+        .clock(clock.clone())
+        .build(Box::new(router));
+    let append_at = |level: Level| {
+        appender
+            .append(&Record::builder().level(level).args(format_args!("")).build())
+            .unwrap();
+    };
+    let keys = || {
+        let mut keys = appender
+            .snapshot()
+            .entries
+            .into_iter()
+            .map(|e| e.key)
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    };
+
+    append_at(Level::Info);
+    clock.advance(Duration::from_millis(250));
+    append_at(Level::Debug);
+    append_at(Level::Info);
+    // the least recently used appender is fresh, so the older one is evicted instead
+    append_at(Level::Warn);
+    assert_eq!(keys(), ["DEBUG", "WARN"]);
+    // every appender is fresh, so the least recently used one is evicted after all
+    append_at(Level::Error);
+    assert_eq!(keys(), ["ERROR", "WARN"]);
+
+    let config = |cache: &str| {
+        let config = format!(
+            "router:\n  kind: pattern\n  pattern:\n    kind: test\ncache:\n{}",
+            cache
+        );
+        let config = serde_yaml::from_str::<Value>(&config).unwrap();
+        deserializers().deserialize::<dyn Append>("routing", config)
+    };
+    assert!(config("  capacity: 10\n  min_ttl: 5 seconds\n").is_ok());
+    assert!(config("  min_ttl: 5 seconds\n").is_err());
+}
+
+#[test]
+fn eviction_policy() {
+    let clock = Arc::new(MockClock::new());
+    let appender = |mode: EvictionMode| {
+        RoutingAppender::builder()
+            .idle_timeout(Duration::from_millis(10))
+            .max_capacity(2)
+            .eviction_mode(mode)
+            .clock(clock.clone())
+            .build(pattern_router("pattern:\n  kind: live\n  name: \"${mdc(job_id)}\"\n"))
+    };
+    let route = |appender: &dyn Append, jobs: &[&str]| {
+        for &job in jobs {
+            log_mdc::insert("job_id", job);
+            append(appender);
+        }
+    };
+    let dropped = || {
+        LIFECYCLE.with(|l| {
+            l.borrow_mut()
+                .drain(..)
+                .filter_map(|e| e.strip_prefix("dropped ").map(ToOwned::to_owned))
+                .collect::<Vec<_>>()
+        })
+    };
+
+    // idle appenders are kept, and the least recently used one makes room
+    let lru = appender(EvictionMode::Lru);
+    route(&lru, &["a", "b"]);
+    clock.advance(Duration::from_millis(30));
+    route(&lru, &["a"]);
+    assert!(dropped().is_empty());
+    route(&lru, &["c"]);
+    assert_eq!(dropped(), ["b"]);
+    drop(lru);
+    dropped();
+
+    // the least frequently used appender makes room, however recently it was used
+    let lfu = appender(EvictionMode::Lfu);
+    route(&lfu, &["a", "a", "a", "b", "c"]);
+    assert_eq!(dropped(), ["b"]);
+    // a rebuilt appender starts over
+    route(&lfu, &["b"]);
+    assert_eq!(dropped(), ["c"]);
+    route(&lfu, &["d"]);
+    assert_eq!(dropped(), ["b"]);
+    drop(lfu);
+    dropped();
+    log_mdc::remove("job_id");
+
+    let config = |cache: &str| {
+        let config = format!(
+            "router:\n  kind: pattern\n  pattern:\n    kind: test\ncache:\n{}",
+            cache
+        );
+        let config = serde_yaml::from_str::<Value>(&config).unwrap();
+        deserializers().deserialize::<dyn Append>("routing", config)
+    };
+    assert!(config("  eviction:\n    policy: idle\n").is_ok());
+    assert!(config("  capacity: 2\n  eviction:\n    policy: lru\n").is_ok());
+    assert!(config("  capacity: 2\n  eviction:\n    policy: lfu\n").is_ok());
+    assert!(config("  eviction:\n    policy: lfu\n").is_err());
+    assert!(config("  capacity: 10\n  eviction:\n    policy: mru\n").is_err());
+}
+
+#[test]
+fn eviction_channel() {
+    let dir = env::temp_dir().join(format!("routing-eviction-channel-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let pattern = AppenderConfig {
+        kind: "file".to_owned(),
+        config: serde_yaml::from_str(&format!("path: \"{}/${{mdc(job_id)}}.log\"", dir.display()))
+            .unwrap(),
+    };
+    let router = PatternRouter::builder()
+        .build(deserializers(), pattern)
+        .unwrap();
+    let mut builder = RoutingAppender::builder().max_capacity(1);
+    let evictions = builder.eviction_channel(1);
+    let appender = builder.build(Box::new(router));
+    let append_job = |job: &str| {
+        log_mdc::insert("job_id", job);
+        append(&appender);
+    };
+
+    append_job("a");
+    append_job("b");
+    // the channel is full, so the eviction of `b` is dropped
+    append_job("c");
+    let eviction = evictions.try_recv().unwrap();
+    assert_eq!(eviction.path, Some(dir.join("a.log")));
+    assert_eq!(eviction.reason, Eviction::Capacity);
+    assert!(evictions.try_recv().is_err());
+
+    let key = appender.snapshot().entries[0].key.clone();
+    append_job("d");
+    let eviction = evictions.try_recv().unwrap();
+    assert_eq!(eviction.key, key);
+    assert_eq!(eviction.path, Some(dir.join("c.log")));
+
+    // evictions without a receiver are ignored
+    drop(evictions);
+    append_job("e");
+    append_job("f");
+
+    log_mdc::remove("job_id");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn protect() {
+    let pattern = AppenderConfig {
+        kind: "mode".to_owned(),
+        config: serde_yaml::from_str("{mode: ok, name: \"${level}\"}").unwrap(),
+    };
+    let router = PatternRouter::builder()
+        .key_strategy(|r: &Record| r.level().to_string())
+        .build(deserializers(), pattern)
+        .unwrap();
+    let clock = Arc::new(MockClock::new());
+    let appender = RoutingAppender::builder()
+        .idle_timeout(Duration::from_millis(50))
+        .protect("ERROR")
+        .clock(clock.clone())
+        .build(Box::new(router));
+    let append_at = |level: Level| {
+        appender
+            .append(&Record::builder().level(level).args(format_args!("")).build())
+            .unwrap();
+    };
+    let keys = || {
+        let mut keys = appender
+            .snapshot()
+            .entries
+            .into_iter()
+            .map(|e| e.key)
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    };
+
+    append_at(Level::Error);
+    append_at(Level::Info);
+    clock.advance(Duration::from_millis(100));
+
+    // the idle error appender survives while unprotected appenders come and go
+    append_at(Level::Info);
+    assert_eq!(keys(), ["ERROR", "INFO"]);
+    append_at(Level::Error);
+    assert_eq!(appender.snapshot().evictions, 1);
+
+    clock.advance(Duration::from_millis(100));
+    append_at(Level::Debug);
+    assert_eq!(keys(), ["DEBUG", "ERROR"]);
+    assert_eq!(appender.snapshot().evictions, 2);
+}
+
+#[test]
+fn discard_unwritten() {
+    let dir = env::temp_dir().join(format!("routing-discard-unwritten-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let appender = routing_appender(&format!(
+        r#"
+cache:
+  discard_unwritten: true
+router:
+  kind: pattern
+  pattern:
+    kind: file
+    path: "{}/${{mdc(job)}}.log"
+    fail: "${{mdc(fail)}}"
+"#,
+        dir.display()
+    ));
+    let record = Record::builder().args(format_args!("")).build();
+
+    log_mdc::insert("job", "rejected");
+    log_mdc::insert("fail", "true");
+    assert!(appender.append(&record).is_err());
+    assert!(!dir.join("rejected.log").exists());
+    assert!(appender.append(&record).is_err());
+    assert_eq!(CONSTRUCTED.with(Cell::get), 2);
+
+    log_mdc::insert("job", "written");
+    log_mdc::insert("fail", "false");
+    appender.append(&record).unwrap();
+    appender.append(&record).unwrap();
+    assert!(dir.join("written.log").exists());
+    assert_eq!(CONSTRUCTED.with(Cell::get), 3);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn max_lifetime() {
+    let clock = Arc::new(MockClock::new());
+    let appender = RoutingAppender::builder()
+        .idle_timeout(Duration::from_secs(60))
+        .max_lifetime(Duration::from_millis(150))
+        .clock(clock.clone())
+        .build(pattern_router("pattern:\n  kind: test\n"));
+
+    // in constant use, so never idle, but rebuilt once it outlives its lifetime
+    for _ in 0..6 {
+        append(&appender);
+        clock.advance(Duration::from_millis(50));
+    }
+
+    assert_eq!(CONSTRUCTED.with(Cell::get), 2);
+    assert_eq!(appender.snapshot().evictions, 1);
+}
+
+#[test]
+fn eviction_precedence() {
+    let pattern = AppenderConfig {
+        kind: "mode".to_owned(),
+        config: serde_yaml::from_str("{mode: ok, name: \"${level}\"}").unwrap(),
+    };
+    let router = PatternRouter::builder()
+        .key_strategy(|r: &Record| r.level().to_string())
+        .build(deserializers(), pattern)
+        .unwrap();
+    let index = TestAppender {
+        delay: Duration::from_secs(0),
+        fail: false,
+    };
+    let clock = Arc::new(MockClock::new());
+    let appender = RoutingAppender::builder()
+        .idle_timeout(Duration::from_millis(50))
+        .hysteresis(Duration::from_millis(200))
+        .max_lifetime(Duration::from_millis(300))
+        .protect("ERROR")
+        .clock(clock.clone())
+        .index_appender(Box::new(index), true)
+        .build(Box::new(router));
+    let append_at = |level: Level| {
+        appender
+            .append(&Record::builder().level(level).args(format_args!("")).build())
+            .unwrap();
+    };
+
+    append_at(Level::Error);
+    append_at(Level::Info);
+    clock.advance(Duration::from_millis(100));
+
+    // the idle info appender is evicted, but the protected error appender isn't, and the info
+    // appender's replacement has its idle timeout extended by the hysteresis
+    append_at(Level::Info);
+    clock.advance(Duration::from_millis(100));
+    append_at(Level::Info);
+    assert_eq!(appender.snapshot().evictions, 1);
+
+    // the lifetime overrides both protection and hysteresis
+    clock.advance(Duration::from_millis(150));
+    append_at(Level::Info);
+    clock.advance(Duration::from_millis(100));
+    append_at(Level::Info);
+
+    let evictions = MESSAGES.with(|m| {
+        m.borrow()
+            .iter()
+            .filter(|m| m.starts_with("evicted"))
+            .map(|m| m.split(" events").next().unwrap().to_owned())
+            .collect::<Vec<_>>()
+    });
+    assert_eq!(
+        evictions,
+        [
+            "evicted route key=\"INFO\" reason=idle",
+            "evicted route key=\"ERROR\" reason=lifetime",
+            "evicted route key=\"INFO\" reason=lifetime",
+        ]
+    );
+    let keys = appender
+        .snapshot()
+        .entries
+        .into_iter()
+        .map(|e| e.key)
+        .collect::<Vec<_>>();
+    assert_eq!(keys, ["INFO"]);
+}
+
+#[test]
+fn cold_appender() {
+    let clock = Arc::new(MockClock::new());
+    let appender = RoutingAppender::builder()
+        .idle_timeout(Duration::from_millis(50))
+        .cold_appender(
+            Duration::from_millis(100),
+            Box::new(NamedAppender("cold".to_owned())),
+        )
+        .clock(clock.clone())
+        .build(pattern_router(
+            "pattern:\n  kind: mode\n  mode: ok\n  name: \"${mdc(job_id)}\"\n",
+        ));
+    let append_to = |job_id: &str| {
+        log_mdc::insert("job_id", job_id);
+        append(&appender);
+    };
+
+    append_to("a");
+    clock.advance(Duration::from_millis(150));
+
+    // a trickle of events for a quiet route goes to the shared appender, until it warms back up
+    append_to("a");
+    append_to("b");
+    append_to("a");
+    clock.advance(Duration::from_millis(150));
+    append_to("a");
+
+    MESSAGES.with(|m| assert_eq!(*m.borrow(), ["a", "cold", "b", "a", "cold"]));
+
+    let config = |cold: &str| {
+        let config = format!(
+            "cold_after: 1s\n{}router:\n  kind: pattern\n  pattern:\n    kind: test\n",
+            cold
+        );
+        let config = serde_yaml::from_str::<Value>(&config).unwrap();
+        deserializers().deserialize::<dyn Append>("routing", config)
+    };
+    assert!(config("cold_appender:\n  kind: test\n").is_ok());
+    assert!(config("").is_err());
+}
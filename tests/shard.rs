@@ -0,0 +1,160 @@
+#![cfg(feature = "shard-router")]
+
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+extern crate log_mdc;
+extern crate serde_value;
+extern crate serde_yaml;
+
+use log::Record;
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use log4rs_routing_appender::register;
+use serde_value::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+
+thread_local! {
+    static CREATED: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+}
+
+#[derive(Debug)]
+struct PathAppender;
+
+impl Append for PathAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct PathAppenderDeserializer;
+
+impl Deserialize for PathAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        CREATED.with(|c| c.borrow_mut().push(config["path"].clone()));
+        Ok(Box::new(PathAppender))
+    }
+}
+
+fn router(config: &str) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("path", PathAppenderDeserializer);
+
+    let config = format!("router:\n  kind: shard\n{}", config);
+    let config = serde_yaml::from_str::<Value>(&config).unwrap();
+    d.deserialize("routing", config)
+}
+
+fn shard_router(shards: u32, path: &str) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+    router(&format!(
+        "  keys: [tenant, region]\n  shards: {}\n  pattern:\n    kind: path\n    path: \"{}\"\n",
+        shards, path
+    ))
+}
+
+fn append(appender: &dyn Append, tenant: Option<&str>, region: Option<&str>) {
+    log_mdc::clear();
+    log_mdc::extend(tenant.map(|t| ("tenant", t)));
+    log_mdc::extend(region.map(|r| ("region", r)));
+    appender
+        .append(&Record::builder().args(format_args!("")).build())
+        .unwrap();
+}
+
+fn created() -> Vec<String> {
+    CREATED.with(|c| c.borrow_mut().drain(..).collect())
+}
+
+#[test]
+fn composite_keys() {
+    let appender = shard_router(32, "log/shard-${shard}.log").unwrap();
+
+    append(&*appender, Some("acme"), Some("eu"));
+    append(&*appender, Some("acme"), Some("us"));
+    append(&*appender, Some("acme"), Some("eu"));
+    append(&*appender, Some("acme"), None);
+    append(&*appender, None, None);
+
+    // the shards are fixed by the hash, so these must never change
+    assert_eq!(
+        created(),
+        [
+            "log/shard-27.log",
+            "log/shard-29.log",
+            "log/shard-12.log",
+            "log/shard-0.log",
+        ]
+    );
+}
+
+#[test]
+fn values_are_length_prefixed() {
+    let appender = shard_router(32, "log/shard-${shard}.log").unwrap();
+
+    append(&*appender, Some("ab"), Some("c"));
+    append(&*appender, Some("a"), Some("bc"));
+
+    assert_eq!(created(), ["log/shard-22.log", "log/shard-28.log"]);
+}
+
+#[test]
+fn template_refers_to_mdc() {
+    let appender = shard_router(1, "log/${mdc(tenant)}/shard-${shard}.log").unwrap();
+
+    append(&*appender, Some("acme"), Some("eu"));
+    append(&*appender, Some("initech"), Some("eu"));
+
+    assert_eq!(created(), ["log/acme/shard-0.log", "log/initech/shard-0.log"]);
+}
+
+#[test]
+fn rejects_zero_shards() {
+    assert!(shard_router(0, "log/shard-${shard}.log").is_err());
+}
+
+#[test]
+fn single_key_and_default_shard() {
+    let appender = router(
+        r#"
+  key: tenant
+  shards: 32
+  default_shard: 7
+  pattern:
+    kind: path
+    path: "log/shard-${shard}.log"
+"#,
+    )
+    .unwrap();
+
+    append(&*appender, Some("acme"), None);
+    append(&*appender, None, Some("eu"));
+    append(&*appender, None, None);
+
+    assert_eq!(created(), ["log/shard-9.log", "log/shard-7.log"]);
+}
+
+#[test]
+fn invalid_config() {
+    let config = |keys: &str, default_shard: u32| {
+        router(&format!(
+            "{}  shards: 8\n  default_shard: {}\n  pattern:\n    kind: path\n    path: a\n",
+            keys, default_shard
+        ))
+    };
+    assert!(config("  key: tenant\n", 7).is_ok());
+    assert!(config("  key: tenant\n", 8).is_err());
+    assert!(config("  key: tenant\n  keys: [region]\n", 0).is_err());
+    assert!(config("", 0).is_err());
+}
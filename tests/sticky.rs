@@ -0,0 +1,118 @@
+#![cfg(feature = "sticky-router")]
+
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+extern crate serde_value;
+extern crate serde_yaml;
+
+use log::Record;
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use log4rs_routing_appender::register;
+use serde_value::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+use std::thread;
+
+// the appenders written to by each thread, which unlike a thread local is visible to the test
+static WRITTEN: Mutex<Vec<(String, String)>> = Mutex::new(vec![]);
+
+#[derive(Debug)]
+struct TestAppender(String);
+
+impl Append for TestAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let thread = thread::current().name().unwrap_or("").to_owned();
+        WRITTEN.lock().unwrap().push((thread, self.0.clone()));
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct TestAppenderDeserializer;
+
+impl Deserialize for TestAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        Ok(Box::new(TestAppender(config["name"].clone())))
+    }
+}
+
+fn sticky(router: &str) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("test", TestAppenderDeserializer);
+
+    let config = format!("router:\n  kind: sticky\n{}", router);
+    let config = serde_yaml::from_str::<Value>(&config).unwrap();
+    d.deserialize("routing", config)
+}
+
+/// Logs `events` events from each of the named threads, one thread after the other.
+fn log_from(appender: &dyn Append, threads: &[&str], events: usize) -> Vec<(String, String)> {
+    WRITTEN.lock().unwrap().clear();
+    for &name in threads {
+        thread::scope(|s| {
+            thread::Builder::new()
+                .name(name.to_owned())
+                .spawn_scoped(s, || {
+                    for _ in 0..events {
+                        appender
+                            .append(&Record::builder().args(format_args!("")).build())
+                            .unwrap();
+                    }
+                })
+                .unwrap();
+        });
+    }
+    WRITTEN.lock().unwrap().drain(..).collect()
+}
+
+const APPENDERS: &str = r#"
+  appenders:
+    - kind: test
+      name: a
+    - kind: test
+      name: b
+"#;
+
+#[test]
+fn sticky_threads() {
+    let appender = sticky(APPENDERS).unwrap();
+
+    let written = log_from(&*appender, &["w1", "w2", "w3"], 2);
+    let expected = [
+        ("w1", "a"),
+        ("w1", "a"),
+        ("w2", "b"),
+        ("w2", "b"),
+        ("w3", "a"),
+        ("w3", "a"),
+    ];
+    let expected = expected
+        .iter()
+        .map(|&(t, a)| (t.to_owned(), a.to_owned()))
+        .collect::<Vec<_>>();
+    assert_eq!(written, expected);
+
+    let appender = sticky(&format!("  assignment: thread_id{}", APPENDERS)).unwrap();
+    let written = log_from(&*appender, &["w1", "w2", "w3"], 3);
+    for events in written.chunks(3) {
+        assert!(events.iter().all(|e| *e == events[0]), "{:?}", events);
+    }
+}
+
+#[test]
+fn invalid_config() {
+    assert!(sticky("  appenders: []").is_err());
+    assert!(sticky(&format!("  assignment: random{}", APPENDERS)).is_err());
+}
@@ -0,0 +1,104 @@
+#![cfg(feature = "target-router")]
+
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+extern crate serde_value;
+extern crate serde_yaml;
+
+use log::Record;
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use log4rs_routing_appender::register;
+use serde_value::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+
+thread_local! {
+    static CREATED: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+}
+
+#[derive(Debug)]
+struct PathAppender;
+
+impl Append for PathAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct PathAppenderDeserializer;
+
+impl Deserialize for PathAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        CREATED.with(|c| c.borrow_mut().push(config["path"].clone()));
+        Ok(Box::new(PathAppender))
+    }
+}
+
+fn target_router(router: &str) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("path", PathAppenderDeserializer);
+
+    let config = format!(
+        "router:\n  kind: target\n  pattern:\n    kind: path\n    \
+         path: \"log/${{target}}.log\"\n{}",
+        router
+    );
+    let config = serde_yaml::from_str::<Value>(&config).unwrap();
+    d.deserialize("routing", config)
+}
+
+fn log(appender: &dyn Append, target: &str) {
+    appender
+        .append(&Record::builder().target(target).args(format_args!("")).build())
+        .unwrap();
+}
+
+fn created() -> Vec<String> {
+    CREATED.with(|c| c.borrow_mut().drain(..).collect())
+}
+
+#[test]
+fn module_paths() {
+    let appender = target_router("  strip_prefix: \"my_crate::\"").unwrap();
+
+    log(&*appender, "my_crate::db::pool");
+    log(&*appender, "my_crate::db::pool");
+    log(&*appender, "my_crate::api");
+    log(&*appender, "hyper::client");
+    log(&*appender, "my_crate");
+    assert_eq!(
+        created(),
+        ["log/db/pool.log", "log/api.log", "log/hyper/client.log", "log/my_crate.log"]
+    );
+}
+
+#[test]
+fn separator_and_sanitizing() {
+    let appender = target_router("  separator: \"-\"").unwrap();
+
+    log(&*appender, "my_crate::db::pool");
+    log(&*appender, "../etc/passwd");
+    log(&*appender, "a::..::::b\tc");
+    assert_eq!(
+        created(),
+        ["log/my_crate-db-pool.log", "log/.._etc_passwd.log", "log/a-_-_-b_c.log"]
+    );
+}
+
+#[test]
+fn invalid_config() {
+    assert!(target_router("  separator: \"\"").is_err());
+}
@@ -0,0 +1,141 @@
+#![cfg(feature = "target-regex-router")]
+
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+extern crate serde_value;
+extern crate serde_yaml;
+
+use log::Record;
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use log4rs_routing_appender::register;
+use serde_value::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+
+thread_local! {
+    static CREATED: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+}
+
+#[derive(Debug)]
+struct PathAppender;
+
+impl Append for PathAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct PathAppenderDeserializer;
+
+impl Deserialize for PathAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        CREATED.with(|c| c.borrow_mut().push(config["path"].clone()));
+        Ok(Box::new(PathAppender))
+    }
+}
+
+fn target_router(router: &str) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("path", PathAppenderDeserializer);
+
+    let config = format!("router:\n  kind: target_regex\n{}", router);
+    let config = serde_yaml::from_str::<Value>(&config).unwrap();
+    d.deserialize("routing", config)
+}
+
+fn log(appender: &dyn Append, target: &str) -> Result<(), Box<dyn Error + Sync + Send>> {
+    appender.append(&Record::builder().target(target).args(format_args!("")).build())
+}
+
+fn created() -> Vec<String> {
+    CREATED.with(|c| c.borrow_mut().drain(..).collect())
+}
+
+#[test]
+fn captures() {
+    let appender = target_router(
+        r#"
+  regex: "^myapp::services::(\\w+)"
+  pattern:
+    kind: path
+    path: "log/${capture}.log"
+  default:
+    kind: path
+    path: "log/app.log"
+"#,
+    )
+    .unwrap();
+
+    log(&*appender, "myapp::services::billing").unwrap();
+    log(&*appender, "myapp::services::billing::db").unwrap();
+    log(&*appender, "myapp::services::auth").unwrap();
+    log(&*appender, "myapp::main").unwrap();
+    log(&*appender, "hyper::client").unwrap();
+    assert_eq!(created(), ["log/billing.log", "log/auth.log", "log/app.log"]);
+}
+
+#[test]
+fn named_groups() {
+    let appender = target_router(
+        r#"
+  regex: "^(?P<app>\\w+)::(?P<service>\\w+)(::)?"
+  pattern:
+    kind: path
+    path: "log/${app}/${service}-${level}.log"
+"#,
+    )
+    .unwrap();
+
+    log(&*appender, "myapp::billing").unwrap();
+    log(&*appender, "myapp::billing::db").unwrap();
+    log(&*appender, "other::billing").unwrap();
+    assert_eq!(created(), ["log/myapp/billing-INFO.log", "log/other/billing-INFO.log"]);
+
+    // without a default appender, events which don't match fail to route
+    assert!(log(&*appender, "main").is_err());
+}
+
+#[test]
+fn unsafe_captures() {
+    let appender = target_router(
+        r#"
+  regex: "^app::(.*)$"
+  pattern:
+    kind: path
+    path: "log/${capture}/out.log"
+"#,
+    )
+    .unwrap();
+
+    // `.` and `..` would otherwise name the current and the parent directory in a path
+    log(&*appender, "app::..").unwrap();
+    log(&*appender, "app::.").unwrap();
+    log(&*appender, "app::../etc").unwrap();
+    log(&*appender, "app::...").unwrap();
+    assert_eq!(created(), ["log/_/out.log", "log/.._etc/out.log", "log/.../out.log"]);
+}
+
+#[test]
+fn invalid_config() {
+    let router = |regex: &str| {
+        let config = format!("  regex: \"{}\"\n  pattern:\n    kind: path\n    path: a\n", regex);
+        target_router(&config)
+    };
+    assert!(router("(\\\\w+)").is_ok());
+    assert!(router("(\\\\w+").is_err());
+    assert!(router("(?P<capture>\\\\w+)").is_err());
+    assert!(router("(?P<a_b>\\\\w+)").is_err());
+}
@@ -0,0 +1,144 @@
+#![cfg(feature = "tee-router")]
+
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+extern crate log_mdc;
+extern crate serde_value;
+extern crate serde_yaml;
+
+use log::{Level, Record};
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use log4rs_routing_appender::register;
+use serde_value::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+
+thread_local! {
+    static CREATED: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+    static WRITTEN: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+}
+
+#[derive(Debug)]
+struct PathAppender(String);
+
+impl Append for PathAppender {
+    fn append(&self, record: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        WRITTEN.with(|w| w.borrow_mut().push(format!("{}: {}", self.0, record.args())));
+        if self.0.contains("broken") {
+            return Err("disk full".into());
+        }
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct PathAppenderDeserializer;
+
+impl Deserialize for PathAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        CREATED.with(|c| c.borrow_mut().push(config["path"].clone()));
+        Ok(Box::new(PathAppender(config["path"].clone())))
+    }
+}
+
+fn tee_router(paths: &str) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("path", PathAppenderDeserializer);
+
+    let config = format!(
+        "router:\n  kind: tee\n  pattern:\n    kind: path\n    path: {}\n",
+        paths
+    );
+    let config = serde_yaml::from_str::<Value>(&config).unwrap();
+    d.deserialize("routing", config)
+}
+
+fn append(
+    appender: &dyn Append,
+    job: &str,
+    level: Level,
+    message: &str,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    log_mdc::insert("job_id", job);
+    appender.append(
+        &Record::builder()
+            .level(level)
+            .args(format_args!("{}", message))
+            .build(),
+    )
+}
+
+fn created() -> Vec<String> {
+    CREATED.with(|c| c.borrow_mut().drain(..).collect())
+}
+
+fn written() -> Vec<String> {
+    WRITTEN.with(|w| w.borrow_mut().drain(..).collect())
+}
+
+#[test]
+fn writes_to_every_path() {
+    let appender = tee_router(r#"["jobs/${mdc(job_id)}.log", "${level}.log"]"#).unwrap();
+
+    append(&*appender, "a", Level::Info, "one").unwrap();
+    assert_eq!(created(), ["jobs/a.log", "INFO.log"]);
+    assert_eq!(written(), ["jobs/a.log: one", "INFO.log: one"]);
+
+    // the file of the level is shared with the entry of the first job
+    append(&*appender, "b", Level::Info, "two").unwrap();
+    assert_eq!(created(), ["jobs/b.log"]);
+    assert_eq!(written(), ["jobs/b.log: two", "INFO.log: two"]);
+
+    append(&*appender, "a", Level::Info, "three").unwrap();
+    append(&*appender, "a", Level::Warn, "four").unwrap();
+    assert_eq!(created(), ["WARN.log"]);
+    assert_eq!(
+        written(),
+        [
+            "jobs/a.log: three",
+            "INFO.log: three",
+            "jobs/a.log: four",
+            "WARN.log: four"
+        ]
+    );
+}
+
+#[test]
+fn duplicate_and_failing_paths() {
+    let paths = r#"["${mdc(job_id)}.log", "${mdc(job_id)}.log", "out.log"]"#;
+    let appender = tee_router(paths).unwrap();
+
+    append(&*appender, "a", Level::Info, "one").unwrap();
+    assert_eq!(created(), ["a.log", "out.log"]);
+    assert_eq!(written(), ["a.log: one", "out.log: one"]);
+
+    // a failing path doesn't stop the others from being written
+    assert!(append(&*appender, "broken", Level::Info, "two").is_err());
+    assert_eq!(written(), ["broken.log: two", "out.log: two"]);
+}
+
+#[test]
+fn single_path() {
+    let appender = tee_router(r#""${mdc(job_id)}.log""#).unwrap();
+
+    append(&*appender, "a", Level::Info, "one").unwrap();
+    assert_eq!(written(), ["a.log: one"]);
+}
+
+#[test]
+fn invalid_config() {
+    assert!(tee_router("[]").is_err());
+    assert!(tee_router("{a: b}").is_err());
+}
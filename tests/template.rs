@@ -0,0 +1,293 @@
+#![cfg(feature = "pattern-router")]
+
+extern crate log;
+extern crate log4rs_routing_appender;
+extern crate log_mdc;
+extern crate serde_value;
+extern crate serde_yaml;
+
+use log::{Level, Record};
+use log4rs_routing_appender::route::pattern::parser::{Parser, Piece};
+use log4rs_routing_appender::route::pattern::template::Template;
+use serde_value::Value;
+use std::collections::{HashMap, HashSet};
+use std::env;
+
+fn template(yaml: &str) -> Template {
+    let pattern = serde_yaml::from_str::<Value>(yaml).unwrap();
+    Template::new(&pattern, HashMap::new(), &[]).unwrap()
+}
+
+#[test]
+fn parse() {
+    let pieces = Parser::new("$${level}: ${mdc(user)(anonymous)} ${raw(${x})}").collect::<Vec<_>>();
+    assert_eq!(
+        pieces,
+        [
+            Piece::Text("$"),
+            Piece::Text("{level}: "),
+            Piece::Argument {
+                name: "mdc",
+                args: vec!["user", "anonymous"],
+            },
+            Piece::Text(" "),
+            Piece::Text("${x}"),
+        ]
+    );
+
+    let pieces = Parser::new("${mdc(user}").collect::<Vec<_>>();
+    assert_eq!(pieces, [Piece::Error("expected `)`")]);
+}
+
+#[test]
+fn escapes() {
+    let pieces = Parser::new("a$$b{c}").collect::<Vec<_>>();
+    assert_eq!(pieces, [Piece::Text("a"), Piece::Text("$"), Piece::Text("b{c}")]);
+    let expanded = template(r#""a$$b/{c}$$""#)
+        .expand(&Record::builder().args(format_args!("")).build(), &[])
+        .unwrap();
+    assert_eq!(expanded, Value::String("a$b/{c}$".to_owned()));
+
+    let mut parser = Parser::new("a$");
+    assert_eq!(parser.next(), Some(Piece::Text("a")));
+    assert_eq!(
+        parser.next(),
+        Some(Piece::Error("trailing `$`, which must be written as `$$`"))
+    );
+    assert_eq!(parser.offset(), 2);
+}
+
+#[test]
+fn shell_style_default() {
+    let pieces = Parser::new("${mdc(user):-a:-b (c)}/${mdc(user):-}").collect::<Vec<_>>();
+    assert_eq!(
+        pieces,
+        [
+            Piece::Argument {
+                name: "mdc",
+                args: vec!["user", "a:-b (c)"],
+            },
+            Piece::Text("/"),
+            Piece::Argument {
+                name: "mdc",
+                args: vec!["user", ""],
+            },
+        ]
+    );
+    assert_eq!(Parser::new("${mdc(user):a}").next(), Some(Piece::Error("expected `-`")));
+    assert_eq!(Parser::new("${mdc(user):-a").next(), Some(Piece::Error("expected `}`")));
+
+    let record = Record::builder().args(format_args!("")).build();
+    log_mdc::remove("user");
+    let shell = template(r#""${mdc(user):-anonymous}""#).expand(&record, &[]).unwrap();
+    let parens = template(r#""${mdc(user)(anonymous)}""#).expand(&record, &[]).unwrap();
+    assert_eq!(shell, parens);
+    let empty = template(r#""log/${mdc(user):-}.log""#).expand(&record, &[]).unwrap();
+    assert_eq!(empty, Value::String("log/.log".to_owned()));
+}
+
+#[test]
+fn expand() {
+    let template = template(
+        r#"
+kind: file
+path: "log/${mdc(tenant)}/${level}.log"
+append: true
+"#,
+    );
+    let record = Record::builder()
+        .level(Level::Warn)
+        .args(format_args!(""))
+        .build();
+
+    log_mdc::insert("tenant", "acme");
+    let expected = serde_yaml::from_str::<Value>(
+        r#"
+kind: file
+path: "log/acme/WARN.log"
+append: true
+"#,
+    )
+    .unwrap();
+    assert_eq!(template.expand(&record, &[]).unwrap(), expected);
+
+    log_mdc::remove("tenant");
+    let err = template.expand(&record, &[]).unwrap_err();
+    assert!(err.to_string().contains("`tenant`"), "{}", err);
+}
+
+#[test]
+fn key() {
+    let template = template(r#""${mdc(tenant)}-${level}""#);
+    let info = Record::builder()
+        .level(Level::Info)
+        .args(format_args!(""))
+        .build();
+    let error = Record::builder()
+        .level(Level::Error)
+        .args(format_args!(""))
+        .build();
+
+    log_mdc::insert("tenant", "acme");
+    let acme_info = template.key(&info);
+    assert_eq!(template.key(&info), acme_info);
+    assert_ne!(template.key(&error), acme_info);
+    log_mdc::insert("tenant", "globex");
+    assert_ne!(template.key(&info), acme_info);
+}
+
+#[test]
+fn key_is_deterministic_and_unambiguous() {
+    let record = Record::builder().args(format_args!("")).build();
+
+    // the entries are ordered by name, whatever their order in the template
+    let ordered = template(r#""${mdc(b)}/${mdc(c)}/${mdc(a)}""#);
+    log_mdc::insert("a", "1");
+    log_mdc::insert("b", "22");
+    log_mdc::insert("c", "3");
+    assert_eq!(ordered.key(&record), "1a111b2221c13");
+
+    // distinct MDC states never share a key
+    let values = [None, Some(""), Some("-"), Some("1"), Some("1a"), Some("a1"), Some("1b-")];
+    let mut keys = HashSet::new();
+    for a in &values {
+        for b in &values {
+            log_mdc::clear();
+            if let Some(a) = *a {
+                log_mdc::insert("a", a);
+            }
+            if let Some(b) = *b {
+                log_mdc::insert("b", b);
+            }
+            assert!(keys.insert(ordered.key(&record)), "{:?} {:?}", a, b);
+        }
+    }
+
+    // nor do templates referring to different entries with the same value
+    log_mdc::insert("a", "x");
+    log_mdc::insert("b", "x");
+    let a = template(r#""${mdc(a)}""#);
+    let b = template(r#""${mdc(b)}""#);
+    assert_ne!(a.key(&record), b.key(&record));
+    log_mdc::clear();
+}
+
+#[test]
+fn mdc_wildcard() {
+    let template = template(r#""log/${mdc(attr_*)(none)}.log""#);
+    let record = Record::builder().args(format_args!("")).build();
+    let expand = || template.expand(&record, &[]).unwrap();
+
+    log_mdc::insert("user", "sfackler");
+    assert_eq!(expand(), Value::String("log/none.log".to_owned()));
+    let empty = template.key(&record);
+
+    log_mdc::insert("attr_zone", "b");
+    log_mdc::insert("attr_color", "red");
+    assert_eq!(
+        expand(),
+        Value::String("log/attr_color=red,attr_zone=b.log".to_owned())
+    );
+    let red = template.key(&record);
+    assert_ne!(red, empty);
+
+    log_mdc::insert("attr_color", "blue");
+    assert_ne!(template.key(&record), red);
+    log_mdc::insert("attr_color", "red");
+    assert_eq!(template.key(&record), red);
+}
+
+#[test]
+fn seq() {
+    let template = template(r#""${seq}-${seq()}""#);
+    let record = Record::builder().args(format_args!("")).build();
+    let seq = || match template.expand(&record, &[]).unwrap() {
+        Value::String(s) => {
+            let (a, b) = s.split_once('-').unwrap();
+            assert_eq!(a, b);
+            a.parse::<u64>().unwrap()
+        }
+        v => panic!("{:?}", v),
+    };
+
+    let first = seq();
+    assert!(seq() > first);
+    assert!(template.key(&record).is_empty());
+}
+
+#[test]
+fn env() {
+    env::set_var("ROUTING_TEMPLATE_LOG_DIR", "/var/log/app");
+    let template = template(r#""${env(ROUTING_TEMPLATE_LOG_DIR)}/${mdc(job)}.log""#);
+    // resolved when the template is built
+    env::set_var("ROUTING_TEMPLATE_LOG_DIR", "/tmp");
+    let record = Record::builder().args(format_args!("")).build();
+
+    log_mdc::insert("job", "a");
+    assert_eq!(
+        template.expand(&record, &[]).unwrap(),
+        Value::String("/var/log/app/a.log".to_owned())
+    );
+
+    let pattern = Value::String("${env(ROUTING_TEMPLATE_UNSET)(log)}/a.log".to_owned());
+    let template = Template::new(&pattern, HashMap::new(), &[]).unwrap();
+    assert_eq!(
+        template.expand(&record, &[]).unwrap(),
+        Value::String("log/a.log".to_owned())
+    );
+
+    let pattern = Value::String("${env(ROUTING_TEMPLATE_UNSET)}/a.log".to_owned());
+    assert!(Template::new(&pattern, HashMap::new(), &[]).is_err());
+}
+
+#[test]
+fn defaults_and_variables() {
+    let pattern = Value::String("${mdc(region)}/${shard}".to_owned());
+    let mut defaults = HashMap::new();
+    defaults.insert("region".to_owned(), "eu".to_owned());
+    let template = Template::new(&pattern, defaults, &["shard"]).unwrap();
+    let record = Record::builder().args(format_args!("")).build();
+
+    assert_eq!(
+        template.expand(&record, &[("shard", "3")]).unwrap(),
+        Value::String("eu/3".to_owned())
+    );
+    assert!(template.expand(&record, &[]).is_err());
+
+    let pattern = Value::String("${shard}".to_owned());
+    let err = Template::new(&pattern, HashMap::new(), &[]).unwrap_err();
+    assert!(err.to_string().contains("unknown argument `shard`"), "{}", err);
+}
+
+#[test]
+fn located_errors() {
+    let err = |pattern: &str| {
+        let pattern = Value::String(pattern.to_owned());
+        Template::new(&pattern, HashMap::new(), &[])
+            .unwrap_err()
+            .to_string()
+    };
+
+    assert_eq!(
+        err("log/${mdcc(user)}.log"),
+        "unknown argument `mdcc` at offset 4 of `log/${mdcc(user)}.log`"
+    );
+    assert_eq!(
+        err("log/${mdc}.log"),
+        "expected 1 or 2 arguments to `mdc` at offset 4 of `log/${mdc}.log`"
+    );
+    assert_eq!(err("a/${mdc(user}"), "expected `)` at offset 13 of `a/${mdc(user}`");
+    // offsets count characters rather than bytes
+    assert_eq!(
+        err("é/${level(x)}"),
+        "expected no arguments to `level` at offset 2 of `é/${level(x)}`"
+    );
+
+    let mut parser = Parser::new("ab${level}$}");
+    assert_eq!(parser.next(), Some(Piece::Text("ab")));
+    assert_eq!(parser.offset(), 0);
+    assert!(parser.next().is_some());
+    assert_eq!(parser.offset(), 2);
+    assert_eq!(parser.next(), Some(Piece::Error("expected `{`")));
+    assert_eq!(parser.offset(), 11);
+}
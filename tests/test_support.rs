@@ -0,0 +1,151 @@
+#![cfg(all(feature = "test-support", feature = "pattern-router"))]
+
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+extern crate log_mdc;
+extern crate serde_value;
+extern crate serde_yaml;
+
+use log::{Level, Record};
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use log4rs_routing_appender::route::Route;
+use log4rs_routing_appender::test_support::{with_mdc, MockClock, TestRecord};
+use log4rs_routing_appender::{register, RoutingAppender};
+use serde_value::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+thread_local! {
+    static WRITTEN: RefCell<Vec<(String, String)>> = const { RefCell::new(vec![]) };
+}
+
+#[derive(Debug)]
+struct PathAppender(String);
+
+impl Append for PathAppender {
+    fn append(&self, record: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        WRITTEN.with(|w| w.borrow_mut().push((self.0.clone(), record.args().to_string())));
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct PathAppenderDeserializer;
+
+impl Deserialize for PathAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        Ok(Box::new(PathAppender(config["path"].clone())))
+    }
+}
+
+#[test]
+fn test_record() {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("path", PathAppenderDeserializer);
+    let config = serde_yaml::from_str::<Value>(
+        r#"
+router:
+  kind: pattern
+  pattern:
+    kind: path
+    path: "log/${mdc(tenant)}/${target}-${level}.log"
+"#,
+    )
+    .unwrap();
+    let appender = d.deserialize::<dyn Append>("routing", config).unwrap();
+
+    TestRecord::new()
+        .level(Level::Warn)
+        .target("db")
+        .message("connection lost")
+        .mdc("tenant", "acme")
+        .append_to(&*appender)
+        .unwrap();
+    assert!(TestRecord::new().append_to(&*appender).is_err());
+
+    WRITTEN.with(|w| {
+        assert_eq!(
+            *w.borrow(),
+            [("log/acme/db-WARN.log".to_owned(), "connection lost".to_owned())]
+        )
+    });
+}
+
+#[test]
+fn with_mdc_restores() {
+    log_mdc::insert("tenant", "acme");
+    log_mdc::remove("region");
+
+    let seen = with_mdc(&[("tenant", "initech"), ("region", "eu")], || {
+        log_mdc::get("tenant", |t| t.map(ToOwned::to_owned))
+    });
+
+    assert_eq!(seen.as_deref(), Some("initech"));
+    log_mdc::get("tenant", |t| assert_eq!(t, Some("acme")));
+    log_mdc::get("region", |r| assert_eq!(r, None));
+}
+
+#[test]
+fn mock_clock() {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("path", PathAppenderDeserializer);
+    let config = serde_yaml::from_str::<Value>(
+        r#"
+pattern:
+  kind: path
+  path: "log/${mdc(tenant)}.log"
+"#,
+    )
+    .unwrap();
+    let router = d.deserialize::<dyn Route>("pattern", config).unwrap();
+    let clock = Arc::new(MockClock::new());
+    let appender = RoutingAppender::builder()
+        .idle_timeout(Duration::from_secs(60))
+        .clock(clock.clone())
+        .build(router);
+    let log = |tenant: &str| {
+        TestRecord::new()
+            .mdc("tenant", tenant)
+            .append_to(&appender)
+            .unwrap()
+    };
+    let cached = || {
+        let snapshot = appender.snapshot();
+        snapshot
+            .entries
+            .into_iter()
+            .map(|e| (e.key, e.idle))
+            .collect::<Vec<_>>()
+    };
+
+    log("a");
+    clock.advance(Duration::from_secs(30));
+    log("b");
+    assert_eq!(
+        cached(),
+        [
+            ("6tenant1a".to_owned(), Duration::from_secs(30)),
+            ("6tenant1b".to_owned(), Duration::from_secs(0))
+        ]
+    );
+
+    // `a` has been idle for the idle timeout, and is evicted by the next lookup
+    clock.advance(Duration::from_secs(30));
+    log("b");
+    assert_eq!(cached(), [("6tenant1b".to_owned(), Duration::from_secs(0))]);
+}
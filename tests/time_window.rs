@@ -0,0 +1,147 @@
+#![cfg(feature = "time-window-router")]
+
+extern crate chrono;
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+extern crate serde_value;
+extern crate serde_yaml;
+
+use chrono::{Timelike, Utc};
+use log::Record;
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use log4rs_routing_appender::register;
+use serde_value::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+
+thread_local! {
+    static APPENDS: RefCell<Vec<u32>> = const { RefCell::new(vec![]) };
+}
+
+#[derive(Debug)]
+struct TestAppender(u32);
+
+impl Append for TestAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        APPENDS.with(|a| a.borrow_mut().push(self.0));
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct TestAppenderDeserializer;
+
+impl Deserialize for TestAppenderDeserializer {
+    type Config = HashMap<String, u32>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, u32>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        Ok(Box::new(TestAppender(config["id"])))
+    }
+}
+
+fn routing_appender(config: &str) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("test", TestAppenderDeserializer);
+
+    let config = serde_yaml::from_str::<Value>(config).unwrap();
+    d.deserialize("routing", config)
+}
+
+fn window_config(starts: &[String]) -> String {
+    let mut config = "router:\n  kind: time_window\n  timezone: utc\n  windows:\n".to_owned();
+    for (i, start) in starts.iter().enumerate() {
+        config += &format!(
+            "    - start: \"{}\"\n      appender:\n        kind: test\n        id: {}\n",
+            start, i
+        );
+    }
+    config
+}
+
+#[test]
+fn routes_by_time_of_day() {
+    // put the second window's start half a day away from now so the test can't straddle it
+    let now = Utc::now().num_seconds_from_midnight();
+    let split = (now + 12 * 60 * 60) % (24 * 60 * 60);
+    let starts = [
+        "00:00".to_owned(),
+        format!("{:02}:{:02}:{:02}", split / 3600, split / 60 % 60, split % 60),
+    ];
+    let expected = if now < split { 0 } else { 1 };
+
+    let appender = routing_appender(&window_config(&starts)).unwrap();
+    for _ in 0..2 {
+        appender
+            .append(&Record::builder().args(format_args!("")).build())
+            .unwrap();
+    }
+
+    APPENDS.with(|a| assert_eq!(*a.borrow(), [expected, expected]));
+}
+
+#[test]
+fn wraps_around_midnight() {
+    // a single window covers the whole day, including the time before its start
+    let now = Utc::now().num_seconds_from_midnight();
+    let start = (now + 12 * 60 * 60) % (24 * 60 * 60);
+    let starts = [format!("{:02}:{:02}", start / 3600, start / 60 % 60)];
+
+    let appender = routing_appender(&window_config(&starts)).unwrap();
+    appender
+        .append(&Record::builder().args(format_args!("")).build())
+        .unwrap();
+
+    APPENDS.with(|a| assert_eq!(*a.borrow(), [0]));
+}
+
+#[test]
+fn appender_timezone() {
+    // the router has no timezone of its own, so it uses the appender's
+    let offset = 5 * 60 * 60 + 30 * 60;
+    let now = (Utc::now().num_seconds_from_midnight() + offset) % (24 * 60 * 60);
+    let split = (now + 12 * 60 * 60) % (24 * 60 * 60);
+    let starts = [
+        "00:00".to_owned(),
+        format!("{:02}:{:02}:{:02}", split / 3600, split / 60 % 60, split % 60),
+    ];
+    let expected = if now < split { 0 } else { 1 };
+
+    let config = window_config(&starts).replace("  timezone: utc\n", "");
+    let appender = routing_appender(&format!("timezone: \"+05:30\"\n{}", config)).unwrap();
+    appender
+        .append(&Record::builder().args(format_args!("")).build())
+        .unwrap();
+
+    APPENDS.with(|a| assert_eq!(*a.borrow(), [expected]));
+}
+
+#[test]
+fn rejects_invalid_timezones() {
+    for timezone in &["mars", "+24:00", "05:30", "+05:60"] {
+        let config = window_config(&["00:00".to_owned()])
+            .replace("timezone: utc", &format!("timezone: \"{}\"", timezone));
+        assert!(routing_appender(&config).is_err(), "{}", timezone);
+    }
+}
+
+#[test]
+fn rejects_unordered_windows() {
+    let starts = ["08:00".to_owned(), "00:00".to_owned()];
+    assert!(routing_appender(&window_config(&starts)).is_err());
+}
+
+#[test]
+fn rejects_invalid_times() {
+    let starts = ["25:00".to_owned()];
+    assert!(routing_appender(&window_config(&starts)).is_err());
+}
@@ -0,0 +1,145 @@
+#![cfg(all(feature = "pattern-router", feature = "tracing"))]
+
+extern crate log;
+extern crate log4rs;
+extern crate log4rs_routing_appender;
+extern crate log_mdc;
+extern crate serde_value;
+extern crate serde_yaml;
+extern crate tracing;
+
+use log::{Level, Record};
+use log4rs::append::Append;
+use log4rs::file::{Deserialize, Deserializers};
+use log4rs_routing_appender::register;
+use serde_value::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Write};
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Metadata, Subscriber};
+
+/// A subscriber which records every event as a line of text.
+#[derive(Clone, Default)]
+struct TestSubscriber(Arc<Mutex<Vec<String>>>);
+
+struct Fields(String);
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        write!(self.0, " {}={}", field.name(), value).unwrap();
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        write!(self.0, " {}={:?}", field.name(), value).unwrap();
+    }
+}
+
+impl Subscriber for TestSubscriber {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn new_span(&self, _: &Attributes) -> Id {
+        Id::from_u64(1)
+    }
+
+    fn record(&self, _: &Id, _: &tracing::span::Record) {}
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event) {
+        let metadata = event.metadata();
+        let mut fields = Fields(format!("{} {}:", metadata.level(), metadata.target()));
+        event.record(&mut fields);
+        self.0.lock().unwrap().push(fields.0);
+    }
+
+    fn enter(&self, _: &Id) {}
+
+    fn exit(&self, _: &Id) {}
+}
+
+#[derive(Debug)]
+struct TestAppender {
+    fail: bool,
+}
+
+impl Append for TestAppender {
+    fn append(&self, _: &Record) -> Result<(), Box<dyn Error + Sync + Send>> {
+        if self.fail {
+            return Err("disk full".into());
+        }
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+struct TestAppenderDeserializer;
+
+impl Deserialize for TestAppenderDeserializer {
+    type Config = HashMap<String, String>;
+    type Trait = dyn Append;
+
+    fn deserialize(
+        &self,
+        config: HashMap<String, String>,
+        _: &Deserializers,
+    ) -> Result<Box<dyn Append>, Box<dyn Error + Sync + Send>> {
+        Ok(Box::new(TestAppender {
+            fail: config["fail"] == "true",
+        }))
+    }
+}
+
+fn routing_appender(config: &str) -> Box<dyn Append> {
+    let mut d = Deserializers::new();
+    register(&mut d);
+    d.insert("test", TestAppenderDeserializer);
+    let config = serde_yaml::from_str::<Value>(config).unwrap();
+    d.deserialize("routing", config).unwrap()
+}
+
+#[test]
+fn mirror_tracing() {
+    let appender = routing_appender(
+        r#"
+mirror_tracing: true
+router:
+  kind: pattern
+  pattern:
+    kind: test
+    fail: "${mdc(fail)(false)}"
+"#,
+    );
+    let append = |level: Level, target: &str, message: &str| {
+        appender.append(
+            &Record::builder()
+                .level(level)
+                .target(target)
+                .args(format_args!("{}", message))
+                .build(),
+        )
+    };
+
+    let subscriber = TestSubscriber::default();
+    tracing::subscriber::with_default(subscriber.clone(), || {
+        append(Level::Info, "app::db", "connected").unwrap();
+        append(Level::Error, "app::http", "request failed").unwrap();
+        // events which fail to be written are not mirrored
+        log_mdc::insert("fail", "true");
+        assert!(append(Level::Warn, "app::db", "lost").is_err());
+        log_mdc::remove("fail");
+    });
+
+    assert_eq!(
+        *subscriber.0.lock().unwrap(),
+        [
+            "INFO log: message=connected log.target=app::db",
+            "ERROR log: message=request failed log.target=app::http",
+        ]
+    );
+}